@@ -4,28 +4,28 @@ use jtag_taps::taps::Taps;
 
 fn main() {
     let cable = cable::new_from_string("jlink", 1 << 20).expect("cable");
-    let jtag = JtagSM::new(cable);
+    let jtag = JtagSM::new(cable).expect("reset");
     let mut taps = Taps::new(jtag);
-    taps.detect();
+    taps.detect().expect("detect");
 
     let ir = vec![235, 0];
-    taps.select_tap(0, &ir);
-    let readback = taps.read_ir();
+    taps.select_tap(0, &ir).expect("select");
+    let readback = taps.read_ir().expect("read ir");
     print!("ir: ");
     for x in readback {
         print!("{:x} ", x);
     }
     println!();
 
-    taps.write_ir(&ir);
+    taps.write_ir(&ir).expect("write ir");
     let buf = vec![
         0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
         0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
         0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
         0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
     ];
-    taps.write_dr(&buf, 8);
-    let dr = taps.read_dr(256);
+    taps.write_dr(&buf, 8).expect("write dr");
+    let dr = taps.read_dr(256).expect("read dr");
     print!("dr: ");
     for x in dr {
         print!("{:x} ", x);
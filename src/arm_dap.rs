@@ -0,0 +1,214 @@
+//! Access the registers of an ARM Debug Port through its JTAG-DP, as found on Cortex-M and
+//! Cortex-A parts.  Every access is a scan of the 35-bit DPACC or APACC register: a read/write
+//! bit, two address bits, and 32 bits of data going in, and a 3-bit ACK and the result of the
+//! previous access coming out.  `Dap` takes care of the posted reads and of repeating accesses
+//! the DP answers with WAIT.  JTAG uses the same ACK for OK and FAULT, so a faulting access
+//! isn't reported here; check the sticky error bits in CTRL/STAT after a sequence of accesses.
+use crate::bits::{dr_to_u32, dr_to_u64, u64_to_dr};
+use crate::cable::{Cable, CableError};
+use crate::idcode::IdCode;
+use crate::statemachine::JtagSM;
+
+use std::fmt;
+
+const IR_LENGTH: u8 = 4;
+const IR_DPACC: u8 = 0xa;
+const IR_APACC: u8 = 0xb;
+const IR_IDCODE: u8 = 0xe;
+
+const ACK_WAIT: u8 = 0b001;
+const ACK_OK_FAULT: u8 = 0b010;
+
+/// DP register holding the selected AP and register bank
+const DP_SELECT: u8 = 0x8;
+/// DP register that returns the result of the last AP read without starting another access
+const DP_RDBUFF: u8 = 0xc;
+
+/// The JEP106 code in an ARM JTAG-DP's IDCODE
+const ARM_MANUFACTURER: u16 = 0x23b;
+
+const DEFAULT_MAX_RETRIES: usize = 100;
+
+#[derive(Debug)]
+pub enum DapError {
+    Cable(CableError),
+    /// The device's IDCODE isn't that of an ARM JTAG-DP
+    NotDap(IdCode),
+    /// The DP kept answering WAIT
+    Timeout { tries: usize },
+    /// The DP answered with an ACK that isn't defined for JTAG, usually because nothing is
+    /// driving TDO
+    Ack(u8),
+}
+
+impl fmt::Display for DapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DapError::Cable(e) => write!(f, "{}", e),
+            DapError::NotDap(id) => write!(f, "{} is not an ARM JTAG-DP", id),
+            DapError::Timeout { tries } => write!(f, "DP still answering WAIT after {} tries", tries),
+            DapError::Ack(ack) => write!(f, "invalid ACK {:#05b}", ack),
+        }
+    }
+}
+
+impl std::error::Error for DapError {}
+
+impl From<CableError> for DapError {
+    fn from(e: CableError) -> Self {
+        DapError::Cable(e)
+    }
+}
+
+pub struct Dap<T> {
+    pub sm: JtagSM<T>,
+    pub idcode: IdCode,
+    max_retries: usize,
+    // The instruction last loaded into the IR
+    ir: u8,
+    // The value last written to SELECT, if known
+    select: Option<u32>,
+}
+
+impl<T, U> Dap<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Read the IDCODE of the DP on `sm` and check that it is an ARM JTAG-DP.  Any other devices
+    /// on the chain must be covered by `JtagSM::set_ir_prefix` and friends.
+    pub fn new(mut sm: JtagSM<T>) -> Result<Self, DapError> {
+        sm.shift_ir(&[IR_IDCODE], IR_LENGTH)?;
+        let tdo = sm.shift_dr(&[0; 4], 8)?;
+        let idcode = IdCode(dr_to_u32(&tdo, 32));
+        if idcode.manufacturer_id() != ARM_MANUFACTURER {
+            return Err(DapError::NotDap(idcode));
+        }
+
+        Ok(Self {
+            sm,
+            idcode,
+            max_retries: DEFAULT_MAX_RETRIES,
+            ir: IR_IDCODE,
+            select: None,
+        })
+    }
+
+    /// Set how many times an access answered with WAIT is tried before giving up with
+    /// `DapError::Timeout`
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.max_retries = max_retries;
+    }
+
+    /// Scan one access into DPACC or APACC, repeating it for as long as the DP answers WAIT.
+    /// Returns the data shifted out, which is the result of the previous read.
+    fn scan(&mut self, ir: u8, addr: u8, read: bool, value: u32) -> Result<u32, DapError> {
+        if self.ir != ir {
+            self.sm.shift_ir(&[ir], IR_LENGTH)?;
+            self.ir = ir;
+        }
+
+        let request = ((value as u64) << 3) | (((addr as u64 >> 2) & 3) << 1) | read as u64;
+        let request = u64_to_dr(request, 35);
+        for _ in 0..self.max_retries {
+            let tdo = self.sm.shift_dr(&request, 3)?;
+            let response = dr_to_u64(&tdo, 35);
+            match (response & 7) as u8 {
+                // The DP ignored the request, so it has to be made again
+                ACK_WAIT => continue,
+                ACK_OK_FAULT => return Ok((response >> 3) as u32),
+                ack => return Err(DapError::Ack(ack)),
+            }
+        }
+        Err(DapError::Timeout { tries: self.max_retries })
+    }
+
+    /// Read DP register `addr`
+    pub fn read_dp(&mut self, addr: u8) -> Result<u32, DapError> {
+        self.scan(IR_DPACC, addr, true, 0)?;
+        self.scan(IR_DPACC, DP_RDBUFF, true, 0)
+    }
+
+    /// Write `value` to DP register `addr`.  Waits for the write to finish, so a following
+    /// access sees its effect.
+    pub fn write_dp(&mut self, addr: u8, value: u32) -> Result<(), DapError> {
+        self.scan(IR_DPACC, addr, false, value)?;
+        self.scan(IR_DPACC, DP_RDBUFF, true, 0)?;
+        if addr == DP_SELECT {
+            self.select = Some(value);
+        }
+        Ok(())
+    }
+
+    /// Point SELECT at register bank `addr` of AP `ap`, unless it already is
+    fn select_ap(&mut self, ap: u8, addr: u8) -> Result<(), DapError> {
+        let select = ((ap as u32) << 24) | (addr as u32 & 0xf0);
+        if self.select != Some(select) {
+            self.write_dp(DP_SELECT, select)?;
+        }
+        Ok(())
+    }
+
+    /// Read register `addr` of AP `ap`, selecting the AP and register bank first if needed
+    pub fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32, DapError> {
+        self.select_ap(ap, addr)?;
+        self.scan(IR_APACC, addr, true, 0)?;
+        self.scan(IR_DPACC, DP_RDBUFF, true, 0)
+    }
+
+    /// Write `value` to register `addr` of AP `ap`, selecting the AP and register bank first if
+    /// needed.  Waits for the write to finish, so a following access sees its effect.
+    pub fn write_ap(&mut self, ap: u8, addr: u8, value: u32) -> Result<(), DapError> {
+        self.select_ap(ap, addr)?;
+        self.scan(IR_APACC, addr, false, value)?;
+        self.scan(IR_DPACC, DP_RDBUFF, true, 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    /// The TDO of a DPACC or APACC scan answering `ack` with `data`
+    fn response(ack: u8, data: u32) -> Vec<u8> {
+        u64_to_dr(((data as u64) << 3) | ack as u64, 35)
+    }
+
+    /// TDO for the IDCODE instruction and an ARM JTAG-DP IDCODE, then for the DPACC instruction
+    fn connect() -> Vec<Vec<u8>> {
+        vec![vec![0x01], 0x4ba00477_u32.to_le_bytes().to_vec(), vec![0x01]]
+    }
+
+    #[test]
+    fn wait_then_ok() {
+        let mut tdo = connect();
+        tdo.extend([response(ACK_WAIT, 0), response(ACK_OK_FAULT, 0), response(ACK_OK_FAULT, 0x12345678)]);
+        let mut cable = MockCable::with_tdo(tdo);
+        let mut dap = Dap::new(JtagSM::new(&mut cable).unwrap()).unwrap();
+        assert_eq!(dap.idcode, IdCode(0x4ba00477));
+        assert_eq!(dap.read_dp(0x4).unwrap(), 0x12345678);
+        drop(dap);
+
+        // The CTRL/STAT read is repeated after the WAIT, then RDBUFF is read
+        let scans: Vec<_> = cable.recorded().iter().filter_map(|op| match op {
+            Operation::ReadWrite { data, bits: 3, .. } => Some(data.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(scans, vec![
+            vec![0x03, 0, 0, 0, 0],
+            vec![0x03, 0, 0, 0, 0],
+            vec![0x07, 0, 0, 0, 0],
+        ]);
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let mut tdo = connect();
+        tdo.extend(std::iter::repeat_n(response(ACK_WAIT, 0), 3));
+        let mut cable = MockCable::with_tdo(tdo);
+        let mut dap = Dap::new(JtagSM::new(&mut cable).unwrap()).unwrap();
+        dap.set_max_retries(3);
+        assert!(matches!(dap.read_dp(0x4), Err(DapError::Timeout { tries: 3 })));
+    }
+}
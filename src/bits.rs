@@ -0,0 +1,176 @@
+//! A bit vector that carries its own length, so that callers don't have to remember how many
+//! bits of the last byte are valid, and helpers to convert register values to and from the
+//! bytes that are shifted.
+use std::fmt;
+
+/// A sequence of bits, packed LSB first into bytes in the order they were shifted, as everywhere
+/// else in this crate.  Any unused bits of the last byte are always zero.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct Bits {
+    data: Vec<u8>,
+    len: usize,
+}
+
+impl Bits {
+    /// Take the first `len` bits of `data`.  Panics if `data` is shorter than `len` bits.
+    pub fn new(mut data: Vec<u8>, len: usize) -> Self {
+        assert!(data.len() * 8 >= len, "{} bytes don't hold {} bits", data.len(), len);
+        data.truncate(len.div_ceil(8));
+        if !len.is_multiple_of(8) {
+            let end = data.len() - 1;
+            data[end] &= (1 << (len % 8)) - 1;
+        }
+        Self { data, len }
+    }
+
+    /// Collect bits from an iterator, the first one being the first shifted
+    pub fn from_bools(bits: impl IntoIterator<Item = bool>) -> Self {
+        let mut result = Self::default();
+        for b in bits {
+            result.push(b);
+        }
+        result
+    }
+
+    /// The number of bits
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bit `i`, or `None` if there are no more than `i` bits
+    pub fn get(&self, i: usize) -> Option<bool> {
+        if i < self.len {
+            Some(self.data[i / 8] & (1 << (i % 8)) != 0)
+        } else {
+            None
+        }
+    }
+
+    /// Add a bit to the end
+    pub fn push(&mut self, bit: bool) {
+        if self.len.is_multiple_of(8) {
+            self.data.push(0);
+        }
+        if bit {
+            self.data[self.len / 8] |= 1 << (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(|i| self.get(i).unwrap())
+    }
+
+    /// The packed bytes, as taken by `Cable::write_data`
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The number of bits used in the last byte, as taken by `Cable::write_data`.  This is 8
+    /// when the length is a multiple of 8.
+    pub fn last_bits(&self) -> u8 {
+        (self.len - (self.data.len().max(1) - 1) * 8) as u8
+    }
+}
+
+/// Shows the bits in the order they were shifted, e.g. "Bits(0110)"
+impl fmt::Debug for Bits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s: String = self.iter().map(|b| if b { '1' } else { '0' }).collect();
+        write!(f, "Bits({})", s)
+    }
+}
+
+/// Pack the low `bits` bits of `value` into bytes for shifting into a data register, least
+/// significant bit first, as taken by `Cable::write_data`.  Any higher bits of `value` are
+/// dropped.  Panics if `bits` is more than 64.
+pub fn u64_to_dr(value: u64, bits: usize) -> Vec<u8> {
+    assert!(bits <= 64, "{} bits don't fit in a u64", bits);
+    Bits::new(value.to_le_bytes().to_vec(), bits).into_bytes()
+}
+
+/// Unpack the first `bits` bits shifted out of a data register, least significant bit first.
+/// Panics if `bits` is more than 64 or `data` is shorter than `bits`.
+pub fn dr_to_u64(data: &[u8], bits: usize) -> u64 {
+    assert!(bits <= 64, "{} bits don't fit in a u64", bits);
+    let mut bytes = Bits::new(data.to_vec(), bits).into_bytes();
+    bytes.resize(8, 0);
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+/// Like `u64_to_dr`, for registers of up to 32 bits
+pub fn u32_to_dr(value: u32, bits: usize) -> Vec<u8> {
+    assert!(bits <= 32, "{} bits don't fit in a u32", bits);
+    u64_to_dr(value.into(), bits)
+}
+
+/// Like `dr_to_u64`, for registers of up to 32 bits
+pub fn dr_to_u32(data: &[u8], bits: usize) -> u32 {
+    assert!(bits <= 32, "{} bits don't fit in a u32", bits);
+    dr_to_u64(data, bits) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_values() {
+        assert_eq!(u32_to_dr(0x12345678, 32), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(u32_to_dr(0xabc, 12), vec![0xbc, 0x0a]);
+        // Bits above the register are dropped
+        assert_eq!(u32_to_dr(0xffff_ffff, 5), vec![0x1f]);
+        assert_eq!(u64_to_dr(0x0123_4567_89ab_cdef, 64),
+                   vec![0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01]);
+        assert_eq!(u64_to_dr(0x1_ffff_ffff, 33), vec![0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert!(u64_to_dr(0, 0).is_empty());
+    }
+
+    #[test]
+    fn unpack_values() {
+        assert_eq!(dr_to_u32(&[0x78, 0x56, 0x34, 0x12], 32), 0x12345678);
+        // Bits past the register in the last byte are ignored
+        assert_eq!(dr_to_u32(&[0xbc, 0xfa], 12), 0xabc);
+        assert_eq!(dr_to_u32(&[0xff, 0xff, 0xff], 3), 0x7);
+        assert_eq!(dr_to_u64(&[0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01], 64), 0x0123_4567_89ab_cdef);
+        assert_eq!(dr_to_u64(&[0xff, 0xff, 0xff, 0xff, 0xff], 33), 0x1_ffff_ffff);
+    }
+
+    #[test]
+    fn partial_last_byte() {
+        let bits = Bits::new(vec![0xff, 0xff], 11);
+        assert_eq!(bits.len(), 11);
+        assert_eq!(bits.as_bytes(), &[0xff, 0x07]);
+        assert_eq!(bits.last_bits(), 3);
+        assert_eq!(bits.get(10), Some(true));
+        assert_eq!(bits.get(11), None);
+
+        let bits = Bits::new(vec![0xa5, 0x5a, 0xff], 16);
+        assert_eq!(bits.as_bytes(), &[0xa5, 0x5a]);
+        assert_eq!(bits.last_bits(), 8);
+        assert_eq!(Bits::default().last_bits(), 0);
+    }
+
+    #[test]
+    fn push_and_iterate() {
+        let bits = Bits::from_bools([false, true, true, false, true, false, false, false, true]);
+        assert_eq!(bits.as_bytes(), &[0x16, 0x01]);
+        assert_eq!(bits.last_bits(), 1);
+        assert_eq!(format!("{:?}", bits), "Bits(011010001)");
+        assert_eq!(Bits::from_bools(bits.iter()), bits);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 bytes don't hold 9 bits")]
+    fn too_short() {
+        Bits::new(vec![0xff], 9);
+    }
+}
@@ -0,0 +1,224 @@
+//! Drive and sense a device's pins through its boundary register, using the description from a
+//! BSDL file.  This is the basis of board interconnect testing: one device drives a net with
+//! EXTEST, and the pin at the other end of it is checked.
+use crate::bits::Bits;
+use crate::bsdl::{BoundaryCell, Bsdl};
+use crate::cable::{Cable, CableError};
+use crate::statemachine::JtagSM;
+
+use std::fmt;
+
+/// Cell functions that capture the value on a pin
+const INPUT_FUNCTIONS: &[&str] = &["input", "bidir", "clock", "observe_only"];
+
+#[derive(Debug)]
+pub enum BoundaryError {
+    Cable(CableError),
+    /// The BSDL file doesn't define this instruction
+    UnknownInstruction(&'static str),
+    /// The BSDL file doesn't have a suitable cell for this pin
+    UnknownPin(String),
+}
+
+impl fmt::Display for BoundaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BoundaryError::Cable(e) => write!(f, "{}", e),
+            BoundaryError::UnknownInstruction(name) => write!(f, "no {} instruction", name),
+            BoundaryError::UnknownPin(name) => write!(f, "no boundary cell for pin {}", name),
+        }
+    }
+}
+
+impl std::error::Error for BoundaryError {}
+
+impl From<CableError> for BoundaryError {
+    fn from(e: CableError) -> Self {
+        BoundaryError::Cable(e)
+    }
+}
+
+pub struct BoundaryScan<T> {
+    pub sm: JtagSM<T>,
+    bsdl: Bsdl,
+    // What to shift into each cell on the next `apply`, indexed by cell number
+    cells: Vec<bool>,
+    // What each cell captured in the last `apply`
+    captured: Vec<bool>,
+}
+
+impl<T, U> BoundaryScan<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Control the pins of the device described by `bsdl`, which must be the only device on the
+    /// chain, or the rest of the chain must be covered by `JtagSM::set_ir_prefix` and friends.
+    /// Every cell starts out holding its safe value.
+    pub fn new(sm: JtagSM<T>, bsdl: Bsdl) -> Self {
+        let mut cells = vec![false; bsdl.boundary_length];
+        for c in &bsdl.boundary_cells {
+            cells[c.number] = c.safe.unwrap_or(false);
+        }
+        let captured = vec![false; bsdl.boundary_length];
+        Self {
+            sm,
+            bsdl,
+            cells,
+            captured,
+        }
+    }
+
+    /// Shift the instruction `name` into the instruction register
+    fn load_instruction(&mut self, name: &'static str) -> Result<(), BoundaryError> {
+        let opcode = self.bsdl.ir_opcode(name).ok_or(BoundaryError::UnknownInstruction(name))?;
+        let opcode = Bits::from_bools(opcode.iter().copied());
+        self.sm.shift_ir(opcode.as_bytes(), opcode.last_bits())?;
+        Ok(())
+    }
+
+    /// Put the device in EXTEST, so its pins are driven from the boundary register.  The
+    /// boundary register is loaded with SAMPLE/PRELOAD first, if the device has it, so the pins
+    /// go straight to the values set so far rather than whatever was left in the register.
+    pub fn extest(&mut self) -> Result<(), BoundaryError> {
+        if let Some(preload) = self.preload_instruction() {
+            self.load_instruction(preload)?;
+            self.apply()?;
+        }
+        self.load_instruction("EXTEST")
+    }
+
+    /// The name of the SAMPLE/PRELOAD instruction, which varies between BSDL files
+    fn preload_instruction(&self) -> Option<&'static str> {
+        ["SAMPLE", "PRELOAD"].into_iter().find(|x| self.bsdl.ir_opcode(x).is_some())
+    }
+
+    /// Capture the state of every input pin while the device carries on running normally.  This
+    /// loads SAMPLE/PRELOAD rather than EXTEST, so the pins keep being driven by the device
+    /// itself; the cells set with `set_pin` are only preloaded.  After this, the device stays in
+    /// SAMPLE/PRELOAD until `extest` is called.
+    pub fn sample(&mut self) -> Result<Vec<(String, bool)>, BoundaryError> {
+        let preload = self.preload_instruction().ok_or(BoundaryError::UnknownInstruction("SAMPLE"))?;
+        self.load_instruction(preload)?;
+        self.apply()?;
+
+        Ok(self.bsdl.boundary_cells.iter()
+            .filter(|c| c.port != "*" && INPUT_FUNCTIONS.contains(&c.function.as_str()))
+            .map(|c| (c.port.clone(), self.captured[c.number]))
+            .collect())
+    }
+
+    /// Find the cell whose port is `name` and whose function is one of `functions`
+    fn find_cell(&self, name: &str, functions: &[&str]) -> Result<BoundaryCell, BoundaryError> {
+        self.bsdl.boundary_cells.iter()
+            .find(|c| c.port.eq_ignore_ascii_case(name) && functions.contains(&c.function.as_str()))
+            .cloned()
+            .ok_or_else(|| BoundaryError::UnknownPin(name.to_string()))
+    }
+
+    /// Drive pin `name` to `value` on the next `apply`.  If the output has a control cell, the
+    /// output is enabled as well.
+    pub fn set_pin(&mut self, name: &str, value: bool) -> Result<(), BoundaryError> {
+        let cell = self.find_cell(name, &["output2", "output3", "bidir"])?;
+        self.cells[cell.number] = value;
+        if let Some((control, disable)) = cell.control {
+            self.cells[control] = !disable;
+        }
+        Ok(())
+    }
+
+    /// Stop driving pin `name` on the next `apply`, by disabling its output.  Pins without a
+    /// control cell can't be released.
+    pub fn release_pin(&mut self, name: &str) -> Result<(), BoundaryError> {
+        let cell = self.find_cell(name, &["output3", "bidir"])?;
+        let (control, disable) = cell.control
+            .ok_or_else(|| BoundaryError::UnknownPin(name.to_string()))?;
+        self.cells[control] = disable;
+        Ok(())
+    }
+
+    /// The value of pin `name` as captured by the last `apply`
+    pub fn get_pin(&self, name: &str) -> Result<bool, BoundaryError> {
+        let cell = self.find_cell(name, INPUT_FUNCTIONS)?;
+        Ok(self.captured[cell.number])
+    }
+
+    /// Shift the whole boundary register, updating every pin set since the last `apply` at once,
+    /// and capturing the state of every pin for `get_pin`
+    pub fn apply(&mut self) -> Result<(), BoundaryError> {
+        if self.cells.is_empty() {
+            return Ok(());
+        }
+
+        // Cell 0 is closest to TDO, so it is shifted first
+        let data = Bits::from_bools(self.cells.iter().copied());
+        let mut tdo = self.sm.shift_dr(data.as_bytes(), data.last_bits())?;
+        tdo.resize(data.as_bytes().len(), 0);
+        self.captured = Bits::new(tdo, data.len()).iter().collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    const THREE_PINS: &str = r#"
+entity three is
+    port (PA0: out bit; PA1: inout bit; PA2: in bit; TCK, TDI, TMS: in bit; TDO: out bit);
+    attribute INSTRUCTION_LENGTH of three : entity is 4;
+    attribute INSTRUCTION_OPCODE of three : entity is
+        "BYPASS (1111)," &
+        "EXTEST (0000)," &
+        "SAMPLE (0001)";
+    attribute BOUNDARY_LENGTH of three : entity is 5;
+    attribute BOUNDARY_REGISTER of three : entity is
+        "4 (BC_1, *, control, 1)," &
+        "3 (BC_1, PA1, output3, X, 4, 1, Z)," &
+        "2 (BC_1, PA0, output2, 0)," &
+        "1 (BC_1, PA1, input, X)," &
+        "0 (BC_1, PA2, input, X)";
+end three;
+"#;
+
+    /// The data of every shift made on `cable`, in order
+    fn shifts(cable: &MockCable) -> Vec<(Vec<u8>, u8)> {
+        cable.recorded().iter().filter_map(|op| match op {
+            Operation::ReadWrite { data, bits, .. } => Some((data.clone(), *bits)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn pin_bit_positions() {
+        let mut cable = MockCable::new();
+        let mut bs = BoundaryScan::new(JtagSM::new(&mut cable).unwrap(), Bsdl::parse(THREE_PINS).unwrap());
+        bs.set_pin("PA0", true).unwrap();
+        bs.set_pin("pa1", false).unwrap();
+        bs.apply().unwrap();
+        // PA1's output is enabled by clearing cell 4
+        bs.release_pin("PA1").unwrap();
+        bs.apply().unwrap();
+        assert!(matches!(bs.set_pin("PA2", true), Err(BoundaryError::UnknownPin(_))));
+        drop(bs);
+
+        // Cell 0 is the first bit shifted
+        assert_eq!(shifts(&cable), vec![(vec![0x04], 5), (vec![0x14], 5)]);
+    }
+
+    #[test]
+    fn sample_inputs() {
+        // TDO for the SAMPLE instruction, then the captured cells, with PA2 high and PA1 low
+        let mut cable = MockCable::with_tdo(vec![vec![0x01], vec![0x1d]]);
+        let mut bs = BoundaryScan::new(JtagSM::new(&mut cable).unwrap(), Bsdl::parse(THREE_PINS).unwrap());
+        bs.set_pin("PA0", true).unwrap();
+        let mut pins = bs.sample().unwrap();
+        pins.sort();
+        assert_eq!(pins, vec![("PA1".to_string(), false), ("PA2".to_string(), true)]);
+        assert!(bs.get_pin("PA2").unwrap());
+        drop(bs);
+
+        // SAMPLE, then the boundary register with PA0 preloaded, but never EXTEST
+        assert_eq!(shifts(&cable), vec![(vec![0x01], 4), (vec![0x14], 5)]);
+    }
+}
@@ -0,0 +1,349 @@
+//! Extract what is needed for boundary scan from a BSDL (Boundary Scan Description Language)
+//! file.  BSDL is a subset of VHDL, but only a handful of its attributes matter here: the
+//! instruction register length and opcodes, the IDCODE, and the boundary register.  Everything
+//! else in the file is ignored, so this is not a validating parser.
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum BsdlError {
+    /// A required attribute isn't in the file
+    Missing(&'static str),
+    /// An attribute is present but its value couldn't be understood
+    Invalid { attribute: String, msg: String },
+}
+
+impl fmt::Display for BsdlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BsdlError::Missing(a) => write!(f, "missing attribute {}", a),
+            BsdlError::Invalid { attribute, msg } => write!(f, "attribute {}: {}", attribute, msg),
+        }
+    }
+}
+
+impl std::error::Error for BsdlError {}
+
+/// One cell of the boundary register, from the BOUNDARY_REGISTER attribute
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundaryCell {
+    /// Position of the cell in the boundary register, 0 being closest to TDO
+    pub number: usize,
+    /// The cell type, such as BC_1
+    pub cell: String,
+    /// The port the cell is attached to, or "*" for internal cells
+    pub port: String,
+    /// What the cell does, such as "input", "output3", or "control"
+    pub function: String,
+    /// The value to load for safe operation, or `None` if it doesn't matter
+    pub safe: Option<bool>,
+    /// For output cells, the control cell that disables the output and the value that cell must
+    /// hold to do so
+    pub control: Option<(usize, bool)>,
+}
+
+pub struct Bsdl {
+    /// Length of the instruction register in bits
+    pub instruction_length: usize,
+    /// The IDCODE with any "don't care" bits set to zero, if the device has one
+    pub idcode: Option<u32>,
+    /// The bits of `idcode` that are significant
+    pub idcode_mask: u32,
+    /// Number of cells in the boundary register
+    pub boundary_length: usize,
+    /// The cells of the boundary register, in order of cell number
+    pub boundary_cells: Vec<BoundaryCell>,
+    // Instruction name to opcode, with the first bit to shift first
+    opcodes: HashMap<String, Vec<bool>>,
+}
+
+/// Remove comments, which run from "--" to the end of the line
+fn strip_comments(s: &str) -> String {
+    s.lines()
+        .map(|line| match line.find("--") {
+            Some(i) => &line[..i],
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Split `s` into statements at each ';' that isn't inside a string
+fn statements(s: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut in_string = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => {
+                result.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    result
+}
+
+/// Parse `attribute NAME of ENTITY : entity is VALUE`, returning the upper case name and the
+/// value.  Strings joined with '&' are concatenated into a single value.
+fn parse_attribute(statement: &str) -> Option<(String, String)> {
+    let mut words = statement.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("attribute") {
+        return None;
+    }
+    let name = words.next()?.to_ascii_uppercase();
+
+    // The value follows the first "is" surrounded by whitespace
+    let bytes = statement.as_bytes();
+    let is = (1..bytes.len().saturating_sub(2)).find(|&i| {
+        bytes[i..i + 2].eq_ignore_ascii_case(b"is") &&
+            bytes[i - 1].is_ascii_whitespace() && bytes[i + 2].is_ascii_whitespace()
+    })?;
+    let value = statement[is + 2..].trim();
+
+    if value.contains('"') {
+        let strings: String = value.split('"').skip(1).step_by(2).collect();
+        Some((name, strings))
+    } else {
+        Some((name, value.to_string()))
+    }
+}
+
+fn invalid(attribute: &str, msg: impl Into<String>) -> BsdlError {
+    BsdlError::Invalid { attribute: attribute.to_string(), msg: msg.into() }
+}
+
+/// Convert a string of '0' and '1' characters, most significant first, into bits with the first
+/// bit to shift first
+fn parse_opcode(attribute: &str, s: &str) -> Result<Vec<bool>, BsdlError> {
+    s.chars().rev().map(|c| match c {
+        '0' => Ok(false),
+        '1' => Ok(true),
+        _ => Err(invalid(attribute, format!("bad opcode {}", s))),
+    }).collect()
+}
+
+fn parse_number(attribute: &str, s: &str) -> Result<usize, BsdlError> {
+    s.trim().parse().map_err(|_| invalid(attribute, format!("bad number {}", s)))
+}
+
+/// Split `s` at commas that aren't inside parentheses
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    result.push(s[start..].trim());
+    result
+}
+
+/// Split "NAME (a, b, c)" into NAME and the comma separated items inside the parentheses
+fn split_call(attribute: &str, s: &str) -> Result<(String, Vec<String>), BsdlError> {
+    let open = s.find('(').ok_or_else(|| invalid(attribute, format!("expected '(' in {}", s)))?;
+    let close = s.rfind(')').ok_or_else(|| invalid(attribute, format!("expected ')' in {}", s)))?;
+    let name = s[..open].trim().to_string();
+    let items = s[open + 1..close].split(',').map(|x| x.trim().to_string()).collect();
+    Ok((name, items))
+}
+
+fn parse_cell(s: &str) -> Result<BoundaryCell, BsdlError> {
+    const ATTR: &str = "BOUNDARY_REGISTER";
+
+    let (number, fields) = split_call(ATTR, s)?;
+    if fields.len() != 4 && fields.len() != 7 {
+        return Err(invalid(ATTR, format!("expected 4 or 7 fields in {}", s)));
+    }
+
+    let bit = |x: &str| match x {
+        "0" => Ok(Some(false)),
+        "1" => Ok(Some(true)),
+        "X" | "x" => Ok(None),
+        _ => Err(invalid(ATTR, format!("bad value {}", x))),
+    };
+
+    let control = if fields.len() == 7 {
+        let disable = bit(&fields[5])?.ok_or_else(|| invalid(ATTR, "control cell disable value can't be X"))?;
+        Some((parse_number(ATTR, &fields[4])?, disable))
+    } else {
+        None
+    };
+
+    Ok(BoundaryCell {
+        number: parse_number(ATTR, &number)?,
+        cell: fields[0].clone(),
+        port: fields[1].clone(),
+        function: fields[2].to_ascii_lowercase(),
+        safe: bit(&fields[3])?,
+        control,
+    })
+}
+
+impl Bsdl {
+    /// Parse the contents of a BSDL file
+    pub fn parse(s: &str) -> Result<Bsdl, BsdlError> {
+        let text = strip_comments(s);
+        let attributes: HashMap<String, String> = statements(&text).into_iter()
+            .filter_map(parse_attribute)
+            .collect();
+        let get = |name: &'static str| attributes.get(name).ok_or(BsdlError::Missing(name));
+
+        let instruction_length = parse_number("INSTRUCTION_LENGTH", get("INSTRUCTION_LENGTH")?)?;
+
+        // e.g. "BYPASS (11111111), EXTEST (00000000), SAMPLE (00000001, 00000010)"
+        let mut opcodes = HashMap::new();
+        for entry in split_top_level(get("INSTRUCTION_OPCODE")?) {
+            if entry.is_empty() {
+                continue;
+            }
+            let (name, codes) = split_call("INSTRUCTION_OPCODE", entry)?;
+            // Only the first of several equivalent opcodes is kept
+            let opcode = parse_opcode("INSTRUCTION_OPCODE", &codes[0])?;
+            if opcode.len() != instruction_length {
+                return Err(invalid("INSTRUCTION_OPCODE",
+                                   format!("{} is not {} bits long", name, instruction_length)));
+            }
+            opcodes.insert(name.to_ascii_uppercase(), opcode);
+        }
+
+        let (idcode, idcode_mask) = match attributes.get("IDCODE_REGISTER") {
+            Some(s) => {
+                if s.len() != 32 {
+                    return Err(invalid("IDCODE_REGISTER", "expected 32 bits"));
+                }
+                let mut value = 0;
+                let mut mask = 0;
+                for c in s.chars() {
+                    value <<= 1;
+                    mask <<= 1;
+                    match c {
+                        '0' => mask |= 1,
+                        '1' => {
+                            value |= 1;
+                            mask |= 1;
+                        }
+                        'X' | 'x' => (),
+                        _ => return Err(invalid("IDCODE_REGISTER", format!("bad bit {}", c))),
+                    }
+                }
+                (Some(value), mask)
+            }
+            None => (None, 0),
+        };
+
+        let boundary_length = parse_number("BOUNDARY_LENGTH", get("BOUNDARY_LENGTH")?)?;
+        let mut boundary_cells = split_top_level(get("BOUNDARY_REGISTER")?).into_iter()
+            .filter(|x| !x.is_empty())
+            .map(parse_cell)
+            .collect::<Result<Vec<_>, _>>()?;
+        boundary_cells.sort_by_key(|x| x.number);
+        if boundary_cells.len() != boundary_length ||
+            boundary_cells.iter().enumerate().any(|(i, x)| x.number != i) {
+            return Err(invalid("BOUNDARY_REGISTER",
+                               format!("cells don't match BOUNDARY_LENGTH {}", boundary_length)));
+        }
+        if let Some(x) = boundary_cells.iter().find(|x| x.control.is_some_and(|(c, _)| c >= boundary_length)) {
+            return Err(invalid("BOUNDARY_REGISTER",
+                               format!("cell {} has control cell outside BOUNDARY_LENGTH {}", x.number, boundary_length)));
+        }
+
+        Ok(Bsdl {
+            instruction_length,
+            idcode,
+            idcode_mask,
+            boundary_length,
+            boundary_cells,
+            opcodes,
+        })
+    }
+
+    /// Look up the opcode for instruction `name`, such as "EXTEST".  The first bit is the first
+    /// one to shift into the instruction register, i.e. the rightmost bit in the BSDL file.
+    pub fn ir_opcode(&self, name: &str) -> Option<&[bool]> {
+        self.opcodes.get(&name.to_ascii_uppercase()).map(|x| x.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNIPPET: &str = r#"
+entity example is
+    generic (PHYSICAL_PIN_MAP : string := "TQFP");
+    port (PA0: inout bit; TCK, TDI, TMS: in bit; TDO: out bit);
+    use STD_1149_1_2001.all;
+    attribute INSTRUCTION_LENGTH of example : entity is 4;
+    attribute INSTRUCTION_OPCODE of example : entity is
+        "BYPASS (1111)," &
+        "EXTEST (0000)," &
+        "SAMPLE (0001, 0010)," &   -- two equivalent opcodes
+        "IDCODE (0110)";
+    attribute IDCODE_REGISTER of example : entity is
+        "XXXX" &              -- version
+        "0110010000110010" &  -- part
+        "00000010101" &       -- manufacturer
+        "1";
+    attribute BOUNDARY_LENGTH of example : entity is 3;
+    attribute BOUNDARY_REGISTER of example : entity is
+        "2 (BC_1, *, control, 1)," &
+        "1 (BC_1, PA0, output3, X, 2, 1, Z)," &
+        "0 (BC_1, PA0, input, X)";
+end example;
+"#;
+
+    #[test]
+    fn parse_snippet() {
+        let bsdl = Bsdl::parse(SNIPPET).unwrap();
+        assert_eq!(bsdl.instruction_length, 4);
+        assert_eq!(bsdl.ir_opcode("bypass"), Some(&[true; 4][..]));
+        // The rightmost bit in the file is shifted first
+        assert_eq!(bsdl.ir_opcode("SAMPLE"), Some(&[true, false, false, false][..]));
+        assert_eq!(bsdl.ir_opcode("IDCODE"), Some(&[false, true, true, false][..]));
+        assert_eq!(bsdl.ir_opcode("INTEST"), None);
+
+        assert_eq!(bsdl.idcode, Some(0x0643202b));
+        assert_eq!(bsdl.idcode_mask, 0x0fffffff);
+
+        assert_eq!(bsdl.boundary_length, 3);
+        assert_eq!(bsdl.boundary_cells[0], BoundaryCell {
+            number: 0,
+            cell: "BC_1".to_string(),
+            port: "PA0".to_string(),
+            function: "input".to_string(),
+            safe: None,
+            control: None,
+        });
+        assert_eq!(bsdl.boundary_cells[1].control, Some((2, true)));
+        assert_eq!(bsdl.boundary_cells[2].safe, Some(true));
+    }
+
+    #[test]
+    fn wrong_opcode_length() {
+        let s = SNIPPET.replace("EXTEST (0000)", "EXTEST (000)");
+        assert!(matches!(Bsdl::parse(&s), Err(BsdlError::Invalid { .. })));
+    }
+
+    #[test]
+    fn missing_instruction_length() {
+        let s = SNIPPET.replace("INSTRUCTION_LENGTH", "INSTRUCTION_LEN");
+        assert!(matches!(Bsdl::parse(&s), Err(BsdlError::Missing("INSTRUCTION_LENGTH"))));
+    }
+
+    #[test]
+    fn control_cell_out_of_range() {
+        let s = SNIPPET.replace("X, 2, 1, Z", "X, 3, 1, Z");
+        assert!(matches!(Bsdl::parse(&s), Err(BsdlError::Invalid { .. })));
+    }
+}
@@ -1,61 +1,789 @@
 //! Implementations for different JTAG hardware adapters will here.  Hardware adapters should
 //! implement the `Cable` trait.
+//!
+//! Queued reads are only faster on adapters that can batch them: `mpsse::Mpsse`, `jlink::JLink`,
+//! and `remote_bitbang::RemoteBitbang` queue natively.  `ft232r::Ft232r`,
+//! `usbblaster::UsbBlaster`, `gpio::GpioCable`, and `cmsis_dap::CmsisDap` perform each read
+//! immediately and hold the result until `finish_read`.  A new cable without a queue of its own
+//! only needs the synchronous methods if it is wrapped in `QueuedCable`.
+#[cfg(feature = "ftd2xx")]
 pub mod mpsse;
+pub mod mpsse_common;
+pub mod mpsse_libusb;
+#[cfg(feature = "ftd2xx")]
 pub mod ft232r;
+#[cfg(feature = "ftd2xx")]
 pub mod usbblaster;
 pub mod jlink;
+pub mod gpio;
+pub mod remote_bitbang;
+pub mod cmsis_dap;
+pub mod mock;
+pub mod loopback;
+pub mod counting;
+pub mod logging;
+pub mod vcd;
+pub mod recording;
+pub mod retry;
+pub mod chunking;
+pub mod async_cable;
+
+use crate::bits::Bits;
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use rusb::UsbContext;
+
+/// Errors reported by a `Cable` implementation.
+#[derive(Debug)]
+pub enum CableError {
+    /// The FTDI driver reported an error
+    #[cfg(feature = "ftd2xx")]
+    Usb(libftd2xx::TimeoutError),
+    /// libusb reported an error
+    LibUsb(rusb::Error),
+    /// The FTDI device that was opened isn't the type the cable expects
+    #[cfg(feature = "ftd2xx")]
+    WrongDevice(libftd2xx::DeviceTypeError),
+    /// An I/O error from a file- or socket-based cable
+    Io(std::io::Error),
+    /// The adapter responded with something we didn't expect
+    Protocol(String),
+    /// The cable was given settings it can't use, such as two signals on the same pin
+    Config(String),
+    /// A transfer didn't complete within the cable's timeout, usually because the adapter is
+    /// hung
+    Timeout,
+    /// The cable doesn't support the requested operation
+    Unsupported,
+    /// The adapter doesn't have room to queue any more reads.  Call `finish_read()` to drain the
+    /// queue before trying again.
+    OutOfQueueSpace,
+}
+
+impl fmt::Display for CableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "ftd2xx")]
+            CableError::Usb(e) => write!(f, "usb error: {}", e),
+            CableError::LibUsb(e) => write!(f, "libusb error: {}", e),
+            #[cfg(feature = "ftd2xx")]
+            CableError::WrongDevice(e) => write!(f, "wrong device: {}", e),
+            CableError::Io(e) => write!(f, "io error: {}", e),
+            CableError::Protocol(s) => write!(f, "protocol error: {}", s),
+            CableError::Config(s) => write!(f, "invalid configuration: {}", s),
+            CableError::Timeout => write!(f, "transfer timed out"),
+            CableError::Unsupported => write!(f, "operation not supported by this cable"),
+            CableError::OutOfQueueSpace => write!(f, "out of queue space"),
+        }
+    }
+}
+
+impl std::error::Error for CableError {}
+
+#[cfg(feature = "ftd2xx")]
+impl From<libftd2xx::TimeoutError> for CableError {
+    fn from(e: libftd2xx::TimeoutError) -> Self {
+        match e {
+            libftd2xx::TimeoutError::Timeout { .. } => CableError::Timeout,
+            e => CableError::Usb(e),
+        }
+    }
+}
+
+#[cfg(feature = "ftd2xx")]
+impl From<libftd2xx::FtStatus> for CableError {
+    fn from(e: libftd2xx::FtStatus) -> Self {
+        CableError::Usb(e.into())
+    }
+}
+
+#[cfg(feature = "ftd2xx")]
+impl From<libftd2xx::DeviceTypeError> for CableError {
+    fn from(e: libftd2xx::DeviceTypeError) -> Self {
+        CableError::WrongDevice(e)
+    }
+}
+
+impl From<std::io::Error> for CableError {
+    fn from(e: std::io::Error) -> Self {
+        CableError::Io(e)
+    }
+}
+
+impl From<rusb::Error> for CableError {
+    fn from(e: rusb::Error) -> Self {
+        CableError::LibUsb(e)
+    }
+}
+
+/// The order in which the bits of each byte are shifted onto the wire
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum BitOrder {
+    /// Bit 0 of each byte first.  This is the default.
+    #[default]
+    Lsb,
+    /// Bit 7 of each byte first.  When only part of the last byte is shifted, the valid bits are
+    /// the most significant ones, for both data written and data read.
+    Msb,
+}
+
+/// The sensed state of the reset lines, from `Cable::read_gpio`.  Each field is `true` if the
+/// line is asserted, i.e. low, whether by the cable or something else on the board, and `None` if
+/// the cable can't read that line.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct CableGpio {
+    pub srst: Option<bool>,
+    pub trst: Option<bool>,
+}
+
+/// What a cable can do beyond shifting bits, so that a tool can leave out what isn't there
+/// instead of trying it and getting `CableError::Unsupported`, or nothing at all where the
+/// trait's default does nothing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CableCapabilities {
+    /// `assert_srst` drives a system reset line
+    pub srst: bool,
+    /// `assert_trst` drives a TAP reset line
+    pub trst: bool,
+    /// `set_clock` changes the speed of TCK
+    pub adjustable_clock: bool,
+    /// Queued reads are batched by the cable, rather than performed immediately
+    pub native_queue: bool,
+    /// `set_output_enable` switches the JTAG output buffer
+    pub output_enable: bool,
+    /// The cable can follow RTCK, through a method of its own
+    pub adaptive_clock: bool,
+}
 
 pub trait Cable {
     /// Clock out a series of TMS values to change the state of the JTAG chain.  Each element of
     /// `tms` determines the value of the TMS line, zero for low and any other value for high.
-    /// `tdo` controls the state of the TDI line during mode changes.
-    fn change_mode(&mut self, tms: &[usize], tdo: bool);
+    /// `tdi` is driven onto the target's TDI line, the cable's output, for every clock.  It only
+    /// matters on the clock that leaves ShiftDR or ShiftIR, when the target still shifts it into
+    /// the register, and is ignored by the target in every other state.  Use
+    /// `change_mode_capture` to see what comes back on TDO.
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError>;
+
+    /// Like `change_mode`, but also samples TDO on each clock and returns the samples in order.
+    /// Must not be called while reads are queued.  Cables that can't sample TDO during mode
+    /// changes return `CableError::Unsupported`.
+    fn change_mode_capture(&mut self, _tms: &[usize], _tdi: bool) -> Result<Vec<bool>, CableError> {
+        Err(CableError::Unsupported)
+    }
+
+    /// Put the TAPs into Test-Logic-Reset by clocking TMS high five times, which reaches it from
+    /// any state.  This only resets the JTAG logic, unlike `assert_srst`, and needs no reset pin,
+    /// unlike `assert_trst`.  Anything tracking the TAP state, like `JtagSM`, must be told about
+    /// the change.
+    fn reset_tap(&mut self) -> Result<(), CableError> {
+        self.change_mode(&[1, 1, 1, 1, 1], false)
+    }
+
+    /// Clock TCK `cycles` times with TMS held at `tms` and TDI at `tdi`, for wake-up and bring-up
+    /// sequences that need particular levels on both lines.  Like `reset_tap`, this moves the TAP
+    /// without telling anything tracking its state.
+    fn clock_idle(&mut self, cycles: usize, tms: bool, tdi: bool) -> Result<(), CableError> {
+        self.change_mode(&vec![tms as usize; cycles], tdi)
+    }
+
     /// Shift in bits from the TDO line.  `bits` is the total number of bits to read.  Should be
     /// called with state = ShiftIR or ShiftDR, and will remain in that state.  Should clock out
     /// all ones.
-    fn read_data(&mut self, bits: usize) -> Vec<u8>;
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError>;
+
+    /// Like `read_data`, but the result carries its own length, and any bits the cable returned
+    /// past the end are dropped.
+    fn read_bits(&mut self, bits: usize) -> Result<Bits, CableError> {
+        let data = self.read_data(bits)?;
+        Ok(Bits::new(data, bits))
+    }
     /// Shift out bits on the TDI line.  `bits` is the number of bits to send from the last byte.
     /// Should be called with state = ShiftIR or ShiftDR.  State won't change unless `pause_after`
     /// is true, in which case it will be PauseIR or PauseDR on exit.
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool);
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError>;
 
     /// Shift out bits on the TDI line.  `bits` is the number of bits to send from the last byte.
     /// Should be called with state = ShiftIR or ShiftDR.  State won't change unless `pause_after`
     /// is true, in which case it will be PauseIR or PauseDR on exit.  Also captures and returns
     /// the bits that were shifted in from TDO
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8>;
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError>;
 
-    /// If the cable implements any queueing, flush to hardware.
-    fn flush(&mut self) {
+    /// If the cable implements any queueing, flush to hardware.  Returns the number of bytes of
+    /// buffered commands that were sent, which is 0 if nothing was buffered, so tools can check
+    /// that their operations are really being batched.  Cables that don't buffer return 0.
+    fn flush(&mut self) -> Result<usize, CableError> {
+        Ok(0)
     }
 
     /// Request that data be read without immediately returning the data.  This allows for multiple
-    /// read requests to be queued, which can allow for better performance.  Returns false if the
-    /// adapter doesn't have any more queue space.
-    fn queue_read(&mut self, bits: usize) -> bool;
+    /// read requests to be queued, which can allow for better performance.  Returns
+    /// `CableError::OutOfQueueSpace` if the adapter doesn't have any more queue space.  Cables
+    /// that don't implement queueing return `CableError::Unsupported`, and can be wrapped in
+    /// `QueuedCable` to emulate it.
+    fn queue_read(&mut self, _bits: usize) -> Result<(), CableError> {
+        Err(CableError::Unsupported)
+    }
 
     /// Shift out bits on the TDI line.  `bits` is the number of bits to send from the last byte.
     /// Should be called with state = ShiftIR or ShiftDR.  State won't change unless `pause_after`
     /// is true, in which case it will be PauseIR or PauseDR on exit.  Also captures
     /// the bits that were shifted in from TDO, which can be retrieved with a queue to
-    /// `finish_read()`.  Returns false if the adapter doesn't have any more queue space.
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool;
+    /// `finish_read()`.  Returns `CableError::OutOfQueueSpace` if the adapter doesn't have any
+    /// more queue space.  Cables that don't implement queueing return `CableError::Unsupported`.
+    fn queue_read_write(&mut self, _data: &[u8], _bits: u8, _pause_after: bool) -> Result<(), CableError> {
+        Err(CableError::Unsupported)
+    }
+
+    /// How much can be queued before `queue_read` and `queue_read_write` return
+    /// `CableError::OutOfQueueSpace`, in bytes.  A shift of `n` bytes fits if `queue_len() + n <=
+    /// queue_capacity()`.  What counts against the limit depends on the cable: the MPSSE only
+    /// counts the data returned by reads, while the J-Link counts everything shifted since the
+    /// last transfer.  Cables without a limit return `usize::MAX`.
+    fn queue_capacity(&self) -> usize {
+        usize::MAX
+    }
+
+    /// How much of `queue_capacity` is already taken up, in bytes
+    fn queue_len(&self) -> usize {
+        0
+    }
+
+    /// Set the order in which the bits of each byte are shifted.  This only affects the order on
+    /// the wire; `bits` still counts the number of bits used from the last byte.  Must not be
+    /// called while reads are queued.  Cables that only shift LSB first return
+    /// `CableError::Unsupported` when asked for `BitOrder::Msb`.
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        match order {
+            BitOrder::Lsb => Ok(()),
+            BitOrder::Msb => Err(CableError::Unsupported),
+        }
+    }
+
+    /// Change the speed of TCK to `hz` hertz, or as close to it as the adapter allows.  Any queued
+    /// commands run at the old speed.  Cables whose speed can't be changed return
+    /// `CableError::Unsupported`.
+    fn set_clock(&mut self, _hz: u32) -> Result<(), CableError> {
+        Err(CableError::Unsupported)
+    }
+
+    /// The fastest TCK the adapter can run at, in hertz.  Requests for a faster clock are rounded
+    /// down to this.  Cables that don't know their limit return `u32::MAX`.
+    fn max_clock(&self) -> u32 {
+        u32::MAX
+    }
+
+    /// What the cable supports.  Cables report nothing unless they say otherwise.
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities::default()
+    }
+
+    /// Check that the adapter is still connected without touching the JTAG signals, so that
+    /// long-running programs can notice an unplugged cable and reconnect.  Cables that have no
+    /// way to check return `Ok(())`.
+    fn ping(&mut self) -> Result<(), CableError> {
+        Ok(())
+    }
+
+    /// Put the system in reset using the SRST line.  Cables without an SRST line do nothing.
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        Ok(())
+    }
+
+    /// Take the system out of reset using the SRST line.  Cables without an SRST line do nothing.
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        Ok(())
+    }
+
+    /// Put the TAPs in reset using the dedicated TRST line.  Cables without a TRST line do
+    /// nothing; use `JtagSM::mode_reset` to reset the TAPs with TMS instead.
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        Ok(())
+    }
+
+    /// Take the TAPs out of reset using the dedicated TRST line.  Cables without a TRST line do
+    /// nothing.
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        Ok(())
+    }
+
+    /// Enable or disable the cable's JTAG output buffer.  While disabled, TCK, TMS, and TDI are
+    /// tri-stated so another debugger on the same header can drive the chain.  Cables without a
+    /// switchable buffer do nothing.
+    fn set_output_enable(&mut self, _enable: bool) -> Result<(), CableError> {
+        Ok(())
+    }
+
+    /// Read back the actual state of the SRST and TRST lines, which may differ from what the cable
+    /// is driving if they are open drain.  Cables that can't sense either line return
+    /// `CableError::Unsupported`.
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        Err(CableError::Unsupported)
+    }
 
     /// Return the data from a previously queued read.  `bits` must exactly match the corresponding
     /// call to `queue_read()`, otherwise the behavior is undefined.  Once you finish a read, you
     /// must finish all the queued reads by calling `finish_read()` as many times as `queue_read()`
     /// was called.
-    fn finish_read(&mut self, bits: usize) -> Vec<u8>;
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        Err(CableError::Unsupported)
+    }
+}
+
+/// Emulates queued reads for a cable that only implements the synchronous methods.  Each queued
+/// read is performed immediately and its result held until `finish_read`, so there is no
+/// performance benefit, but code written against the queueing API works unchanged.
+pub struct QueuedCable<T> {
+    pub cable: T,
+    results: VecDeque<Vec<u8>>,
+}
+
+impl<T, U> QueuedCable<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Emulate queueing on top of `cable`
+    pub fn new(cable: T) -> Self {
+        Self {
+            cable,
+            results: VecDeque::new(),
+        }
+    }
 }
 
+impl<T, U> Cable for QueuedCable<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.cable.change_mode(tms, tdi)
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        self.cable.change_mode_capture(tms, tdi)
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.cable.read_data(bits)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.cable.write_data(data, bits, pause_after)
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.cable.read_write_data(data, bits, pause_after)
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.cable.flush()
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let data = self.cable.read_data(bits)?;
+        self.results.push_back(data);
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let data = self.cable.read_write_data(data, bits, pause_after)?;
+        self.results.push_back(data);
+        Ok(())
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.cable.set_bit_order(order)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.cable.set_clock(hz)
+    }
+
+    fn max_clock(&self) -> u32 {
+        self.cable.max_clock()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.cable.ping()
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_srst()
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_srst()
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_trst()
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_trst()
+    }
+
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.cable.set_output_enable(enable)
+    }
+
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        self.cable.read_gpio()
+    }
+
+    /// Returns `CableError::Protocol` if no reads are queued
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        self.results.pop_front()
+            .ok_or_else(|| CableError::Protocol("finish_read without a queued read".to_string()))
+    }
+
+    /// The inner cable's, except that queueing is emulated
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            native_queue: false,
+            ..self.cable.capabilities()
+        }
+    }
+}
+
+/// How long `CableBuilder::with_retry` waits before each retry
+const BUILDER_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Stacks the wrapper cables around a base cable.  Each `with_` method wraps everything added so
+/// far, so the first one called ends up closest to the hardware.  The recommended order is the
+/// order of the methods below: logging first, so the log shows what actually reaches the
+/// cable, including every retry and every piece of a split shift; then retry, so only the piece
+/// that failed is repeated; then chunking.
+///
+/// ```no_run
+/// use jtag_taps::cable::{self, CableBuilder};
+///
+/// let base = cable::new_from_string("jtagkey", 1_000_000).expect("cable");
+/// let cable = CableBuilder::new(base).with_logging().with_retry(3).with_chunking(65535).build();
+/// ```
+pub struct CableBuilder {
+    cable: Box<dyn Cable>,
+}
+
+impl CableBuilder {
+    /// Start a stack with `base` at the bottom
+    pub fn new(base: Box<dyn Cable>) -> Self {
+        Self {
+            cable: base,
+        }
+    }
+
+    /// Log every operation that reaches the layers added so far.  See `logging::LoggingCable`.
+    pub fn with_logging(self) -> Self {
+        Self::new(Box::new(logging::LoggingCable::new(self.cable)))
+    }
+
+    /// Retry transient failures up to `retries` times.  See `retry::RetryCable`.
+    pub fn with_retry(self, retries: usize) -> Self {
+        Self::new(Box::new(retry::RetryCable::new(self.cable, retries, BUILDER_RETRY_DELAY)))
+    }
+
+    /// Split shifts longer than `max_bits` bits.  See `chunking::ChunkingCable`.
+    pub fn with_chunking(self, max_bits: usize) -> Self {
+        Self::new(Box::new(chunking::ChunkingCable::new(self.cable, max_bits)))
+    }
+
+    /// Return the finished stack
+    pub fn build(self) -> Box<dyn Cable> {
+        self.cable
+    }
+}
+
+/// An attached adapter found by `list_cables`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CableInfo {
+    pub serial_number: String,
+    pub description: String,
+    /// The name to pass to `new_from_string` to open this adapter, if it is a known type
+    pub cable_type: Option<String>,
+}
+
+/// The `new_from_string` name for an FTDI device with the USB product description `description`
+#[cfg(feature = "ftd2xx")]
+fn ftdi_cable_type(description: &str) -> Option<&'static str> {
+    match description {
+        "Dual RS232-HS A" => Some("jtagkey"),
+        "Quad RS232-HS A" => Some("ft4232h:A"),
+        "Quad RS232-HS B" => Some("ft4232h:B"),
+        "EasyFlash 3" => Some("ef3"),
+        "USB-JTAG-IF" => Some("usbblaster"),
+        _ => None,
+    }
+}
+
+/// List the JTAG adapters that are attached: every FTDI device, if the `ftd2xx` feature is on,
+/// and any J-Link, USB Blaster, or CMSIS-DAP probe found on the USB bus.  Backends whose
+/// enumeration fails are skipped, as are string descriptors that can't be read, so this never
+/// fails; it just finds less.
+pub fn list_cables() -> Vec<CableInfo> {
+    let mut cables: Vec<CableInfo> = vec![];
+
+    #[cfg(feature = "ftd2xx")]
+    if let Ok(devices) = libftd2xx::list_devices() {
+        for d in devices {
+            cables.push(CableInfo {
+                cable_type: ftdi_cable_type(&d.description).map(|x| x.to_string()),
+                serial_number: d.serial_number,
+                description: d.description,
+            });
+        }
+    }
+
+    // rusb::devices() panics if libusb can't start, so use a context of our own
+    if let Ok(devices) = rusb::Context::new().and_then(|c| c.devices()) {
+        for device in devices.iter() {
+            let Ok(descriptor) = device.device_descriptor() else {
+                continue;
+            };
+            let (serial_number, description) = match device.open() {
+                Ok(handle) => (
+                    handle.read_serial_number_string_ascii(&descriptor).unwrap_or_default(),
+                    handle.read_product_string_ascii(&descriptor).unwrap_or_default(),
+                ),
+                Err(_) => (String::new(), String::new()),
+            };
+
+            let cable_type = match (descriptor.vendor_id(), descriptor.product_id()) {
+                (0x1366, 0x0105) => "jlink",
+                (0x0403, 0x6010) if cfg!(not(feature = "ftd2xx")) => "jtagkey-libusb",
+                (0x16c0, 0x06ad) | (0x09fb, 0x6001) => "usbblaster",
+                _ if description.contains("CMSIS-DAP") => "cmsis-dap",
+                _ => continue,
+            };
+
+            // The FTDI driver may have already found it
+            if cables.iter().any(|c| c.serial_number == serial_number && c.description == description) {
+                continue;
+            }
+
+            cables.push(CableInfo {
+                serial_number,
+                description,
+                cable_type: Some(cable_type.to_string()),
+            });
+        }
+    }
+
+    cables
+}
+
+/// Parse a comma-separated list of exactly `N` pin numbers, e.g. "11,25,10,9".  Numbers starting
+/// with "0x" are hex.
+fn parse_pins<const N: usize>(pins: &str) -> Result<[u32; N], String> {
+    let parse = |x: &str| match x.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => x.parse(),
+    };
+    let pins = pins.split(',')
+        .map(|x| parse(x.trim()).map_err(|e| format!("bad pin number {}: {}", x, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    pins.try_into().map_err(|p: Vec<u32>| format!("expected {} pins, got {}", N, p.len()))
+}
+
+/// Parse a frequency such as "10MHz", "400kHz", "1.5mhz", or "9600", which is in hertz
+pub fn parse_frequency(s: &str) -> Result<u32, String> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (number, scale) = if let Some(x) = lower.strip_suffix("mhz") {
+        (x, 1e6)
+    } else if let Some(x) = lower.strip_suffix("khz") {
+        (x, 1e3)
+    } else if let Some(x) = lower.strip_suffix("hz") {
+        (x, 1.0)
+    } else {
+        (lower.as_str(), 1.0)
+    };
+    let hz = number.trim().parse::<f64>().map_err(|e| format!("bad frequency {}: {}", s, e))? * scale;
+    if !(0.0..=u32::MAX as f64).contains(&hz) {
+        return Err(format!("frequency {} out of range", s));
+    }
+    Ok(hz.round() as u32)
+}
+
+/// Construct a cable from a spec of the form "NAME@FREQUENCY", such as "jtagkey@10MHz" or
+/// "remote_bitbang:localhost:2424@1MHz", so that every CLI tool takes the same syntax.  NAME is
+/// anything accepted by `new_from_string`, and FREQUENCY anything accepted by
+/// `parse_frequency`.  Without "@FREQUENCY", the cable's default clock is used.
+pub fn parse_cable_spec(spec: &str) -> Result<Box<dyn Cable>, String> {
+    match spec.rsplit_once('@') {
+        Some((name, freq)) => new_from_string(name, parse_frequency(freq)?),
+        None => new_from_string(spec, 0),
+    }
+}
+
+/// The names `new_from_string` accepts for cables driven through libftd2xx
+#[cfg(not(feature = "ftd2xx"))]
+const FTDI_CABLES: &[&str] = &["jtagkey", "jtagkey+rtck", "jtagkey+3phase", "tigard", "ef3", "usbblaster"];
+
 /// Helper function for constructing a cable from a string.  This is expected to be used by CLI
 /// utilities where the cable is passed in as an argument, rather than constructed by code.
+///
+/// GPIO bit-banging is selected with "gpio:TCK,TMS,TDI,TDO", where each pin is a BCM GPIO
+/// number.  OpenOCD's remote_bitbang protocol is selected with "remote_bitbang:HOST:PORT"; the
+/// clock is ignored since the remote end decides how fast to run.  "jtagkey+rtck" is a jtagkey
+/// with adaptive clocking enabled, for targets that need it, and "jtagkey+3phase" is one with
+/// 3-phase data clocking, for targets that sample unreliably otherwise.  Other FT232R bitbang
+/// boards are selected with "ft232r:DESCRIPTION:TCK,TMS,TDI,TDO", where each pin is a bit mask
+/// such as 0x04.  One channel of an FT4232H is selected with "ft4232h:A" or "ft4232h:B".
+/// "tigard" is a Tigard's JTAG header.  "jtagkey-libusb" is a jtagkey driven through libusb
+/// rather than the D2XX driver.
+///
+/// The FTDI cables, jtagkey, tigard, ft4232h, ft232r, ef3, and usbblaster, are only available with the
+/// `ftd2xx` feature, which is on by default.  Without it they are an error.
+///
+/// A `clock` of 0 picks each cable's conservative default: 1MHz for jtagkey, 1MBaud for the
+/// FT232R cables, 100kHz for gpio, and whatever speed a jlink or cmsis-dap probe is already set
+/// to.
 pub fn new_from_string(name: &str, clock: u32) -> Result<Box<dyn Cable>,String> {
+    if let Some(pins) = name.strip_prefix("gpio:") {
+        let [tck, tms, tdi, tdo] = parse_pins(pins)?;
+        let cable = gpio::GpioCable::try_new(tck, tms, tdi, tdo, clock).map_err(|e| e.to_string())?;
+        return Ok(Box::new(cable));
+    }
+    #[cfg(not(feature = "ftd2xx"))]
+    if FTDI_CABLES.contains(&name) || name.starts_with("ft4232h:") || name.starts_with("ft232r:") {
+        return Err(format!("{} cables need the ftd2xx feature", name));
+    }
+    #[cfg(feature = "ftd2xx")]
+    if let Some(interface) = name.strip_prefix("ft4232h:") {
+        let interface = match interface {
+            "a" | "A" => mpsse::Interface::A,
+            "b" | "B" => mpsse::Interface::B,
+            _ => return Err(format!("ft4232h interface must be A or B, not {}", interface)),
+        };
+        let cable = mpsse::Ft4232hJtag::try_new(interface, clock).map_err(|e| e.to_string())?;
+        return Ok(Box::new(cable));
+    }
+    #[cfg(feature = "ftd2xx")]
+    if let Some((description, pins)) = name.strip_prefix("ft232r:").and_then(|x| x.rsplit_once(':')) {
+        let [tck, tms, tdi, tdo] = parse_pins(pins)?;
+        let mask = |x: u32| u8::try_from(x).map_err(|_| format!("pin mask {:#x} doesn't fit in a byte", x));
+        let cable = ft232r::Ft232r::try_new(description, mask(tck)?, mask(tms)?, mask(tdi)?, mask(tdo)?, clock)
+            .map_err(|e| e.to_string())?;
+        return Ok(Box::new(cable));
+    }
+    if let Some(addr) = name.strip_prefix("remote_bitbang:") {
+        let cable = remote_bitbang::RemoteBitbang::connect(addr).map_err(|e| e.to_string())?;
+        return Ok(Box::new(cable));
+    }
+
     match name {
-        "jtagkey" => Ok(Box::new(mpsse::JtagKey::new(clock, true))),
+        #[cfg(feature = "ftd2xx")]
+        "jtagkey" => Ok(Box::new(mpsse::JtagKey::try_new(clock, true).map_err(|e| e.to_string())?)),
+        #[cfg(feature = "ftd2xx")]
+        "jtagkey+rtck" => {
+            let mut cable = mpsse::JtagKey::try_new(clock, true).map_err(|e| e.to_string())?;
+            cable.set_adaptive_clocking(true).map_err(|e| e.to_string())?;
+            Ok(Box::new(cable))
+        }
+        #[cfg(feature = "ftd2xx")]
+        "jtagkey+3phase" => {
+            let mut cable = mpsse::JtagKey::try_new(clock, true).map_err(|e| e.to_string())?;
+            cable.set_three_phase_clocking(true).map_err(|e| e.to_string())?;
+            Ok(Box::new(cable))
+        }
+        #[cfg(feature = "ftd2xx")]
+        "tigard" => Ok(Box::new(mpsse::JtagKey::tigard(clock).map_err(|e| e.to_string())?)),
+        #[cfg(feature = "ftd2xx")]
         "ef3" => Ok(Box::new(ft232r::Ft232r::easyflash3(clock))),
+        #[cfg(feature = "ftd2xx")]
         "usbblaster" => Ok(Box::new(usbblaster::UsbBlaster::new())),
+        "jtagkey-libusb" => Ok(Box::new(mpsse_libusb::JtagKey::try_new(clock, true).map_err(|e| e.to_string())?)),
         "jlink" => Ok(Box::new(jlink::JLink::new(clock))),
+        "cmsis-dap" => Ok(Box::new(cmsis_dap::CmsisDap::try_new(clock).map_err(|e| e.to_string())?)),
         _ => Err(format!("unknown cable type: {}", name)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that what `cable` claims in `capabilities` matches what it does.  A cable with a
+    /// native queue is sent a queued read, so its TDO must have an answer ready.
+    pub(crate) fn check_capabilities(cable: &mut dyn Cable) {
+        let caps = cable.capabilities();
+        let clock = cable.set_clock(1_000_000);
+        assert_eq!(caps.adjustable_clock, !matches!(clock, Err(CableError::Unsupported)), "{:?}", clock);
+        if caps.native_queue {
+            cable.queue_read(8).unwrap();
+            assert_eq!(cable.finish_read(8).unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn frequency_suffixes() {
+        assert_eq!(parse_frequency("10MHz"), Ok(10_000_000));
+        assert_eq!(parse_frequency("400kHz"), Ok(400_000));
+        assert_eq!(parse_frequency("1.5mhz"), Ok(1_500_000));
+        assert_eq!(parse_frequency("100 Hz"), Ok(100));
+        assert_eq!(parse_frequency("9600"), Ok(9600));
+    }
+
+    #[test]
+    fn bad_frequencies() {
+        assert!(parse_frequency("fast").is_err());
+        assert!(parse_frequency("-1MHz").is_err());
+        assert!(parse_frequency("5000MHz").is_err());
+    }
+
+    #[test]
+    fn queued_reads_in_order() {
+        let mut mock = mock::MockCable::with_tdo(vec![vec![0x11], vec![0x22]]);
+        let mut cable = QueuedCable::new(&mut mock);
+        cable.queue_read(8).unwrap();
+        cable.queue_read_write(&[0xaa], 8, false).unwrap();
+        assert_eq!(cable.finish_read(8).unwrap(), vec![0x11]);
+        assert_eq!(cable.finish_read(8).unwrap(), vec![0x22]);
+        assert!(matches!(cable.finish_read(8), Err(CableError::Protocol(_))));
+    }
+
+    #[test]
+    fn reset_tap_clocks_tms_high() {
+        let mut mock = mock::MockCable::new();
+        let cable: &mut dyn Cable = &mut mock;
+        cable.reset_tap().unwrap();
+        assert_eq!(mock.recorded(), &[mock::Operation::ChangeMode { tms: vec![1; 5], tdi: false }]);
+    }
+
+    #[test]
+    fn clock_idle_levels() {
+        let mut mock = mock::MockCable::new();
+        mock.clock_idle(3, true, true).unwrap();
+        mock.clock_idle(4, false, true).unwrap();
+        mock.clock_idle(2, true, false).unwrap();
+        assert_eq!(mock.recorded(), &[
+            mock::Operation::ChangeMode { tms: vec![1; 3], tdi: true },
+            mock::Operation::ChangeMode { tms: vec![0; 4], tdi: true },
+            mock::Operation::ChangeMode { tms: vec![1; 2], tdi: false },
+        ]);
+    }
+
+    #[test]
+    fn builder_two_layers() {
+        let mut mock = mock::MockCable::with_tdo(vec![vec![0x11], vec![0x22], vec![0x33]]);
+        mock.set_capabilities(CableCapabilities { srst: true, ..Default::default() });
+        let mut cable = CableBuilder::new(Box::new(mock))
+            .with_chunking(8)
+            .with_retry(2)
+            .build();
+        // The chunking layer splits the read into one per scripted response
+        assert_eq!(cable.read_data(24).unwrap(), vec![0x11, 0x22, 0x33]);
+        // and both layers pass the base's capabilities through
+        assert!(cable.capabilities().srst);
+    }
+
+
+    #[test]
+    fn queued_capabilities_match() {
+        let mut counting = counting::CountingCable::new();
+        check_capabilities(&mut QueuedCable::new(&mut counting));
+        let mut loopback = loopback::LoopbackCable::new();
+        check_capabilities(&mut QueuedCable::new(&mut loopback));
+    }
+}
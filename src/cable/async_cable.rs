@@ -0,0 +1,253 @@
+//! Use a blocking `Cable` from async code.  Every cable driver blocks on USB or socket I/O,
+//! which would stall an async runtime, so `AsyncCable` moves the cable to a thread of its own and
+//! sends it each operation over a channel.  The futures it returns are woken when the operation
+//! finishes, and don't depend on any particular runtime.
+//!
+//! Operations reach the cable in the order the `AsyncCable` methods are called, not the order
+//! their futures are awaited, and one at a time.  That keeps every ordering guarantee the
+//! `Cable` trait makes, so for example the results of queued reads come back in order even if
+//! several tasks share the cable.
+use crate::cable::{Cable, CableCapabilities, CableError};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce(&mut dyn Cable) + Send>;
+
+struct Slot<R> {
+    result: Option<Result<R, CableError>>,
+    waker: Option<Waker>,
+}
+
+/// Fills in the result of a job.  If the job is dropped without running, because the cable
+/// thread exited, the reply gets an error instead of waiting forever.
+struct Completer<R> {
+    slot: Option<Arc<Mutex<Slot<R>>>>,
+}
+
+fn fill<R>(slot: Arc<Mutex<Slot<R>>>, result: Result<R, CableError>) {
+    let mut slot = slot.lock().unwrap();
+    slot.result = Some(result);
+    if let Some(waker) = slot.waker.take() {
+        waker.wake();
+    }
+}
+
+impl<R> Completer<R> {
+    fn complete(mut self, result: Result<R, CableError>) {
+        if let Some(slot) = self.slot.take() {
+            fill(slot, result);
+        }
+    }
+}
+
+impl<R> Drop for Completer<R> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            let err = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "cable thread exited");
+            fill(slot, Err(err.into()));
+        }
+    }
+}
+
+/// The result of an operation on an `AsyncCable`, which resolves once the cable thread has
+/// performed it
+pub struct Reply<R> {
+    slot: Arc<Mutex<Slot<R>>>,
+}
+
+impl<R> Future for Reply<R> {
+    type Output = Result<R, CableError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub struct AsyncCable {
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AsyncCable {
+    /// Move `cable` to a new thread, which runs until the `AsyncCable` is dropped
+    pub fn new(mut cable: Box<dyn Cable + Send>) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let thread = std::thread::spawn(move || {
+            for job in rx {
+                job(&mut *cable);
+            }
+        });
+        Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        }
+    }
+
+    /// Run `op` on the cable thread and return its result.  This is for anything the other
+    /// methods don't cover, or for a sequence of operations that must not be interleaved with
+    /// other tasks' operations.
+    pub fn run<R, F>(&self, op: F) -> Reply<R>
+        where R: Send + 'static,
+              F: FnOnce(&mut dyn Cable) -> Result<R, CableError> + Send + 'static
+    {
+        let slot = Arc::new(Mutex::new(Slot { result: None, waker: None }));
+        let completer = Completer { slot: Some(slot.clone()) };
+        let job: Job = Box::new(move |cable| completer.complete(op(cable)));
+        // If the thread has exited, the job is dropped and the reply gets an error
+        let _ = self.jobs.as_ref().unwrap().send(job);
+        Reply { slot }
+    }
+
+    pub fn change_mode(&self, tms: Vec<usize>, tdi: bool) -> Reply<()> {
+        self.run(move |c| c.change_mode(&tms, tdi))
+    }
+
+    pub fn read_data(&self, bits: usize) -> Reply<Vec<u8>> {
+        self.run(move |c| c.read_data(bits))
+    }
+
+    pub fn write_data(&self, data: Vec<u8>, bits: u8, pause_after: bool) -> Reply<()> {
+        self.run(move |c| c.write_data(&data, bits, pause_after))
+    }
+
+    pub fn read_write_data(&self, data: Vec<u8>, bits: u8, pause_after: bool) -> Reply<Vec<u8>> {
+        self.run(move |c| c.read_write_data(&data, bits, pause_after))
+    }
+
+    pub fn flush(&self) -> Reply<usize> {
+        self.run(|c| c.flush())
+    }
+
+    pub fn queue_read(&self, bits: usize) -> Reply<()> {
+        self.run(move |c| c.queue_read(bits))
+    }
+
+    pub fn queue_read_write(&self, data: Vec<u8>, bits: u8, pause_after: bool) -> Reply<()> {
+        self.run(move |c| c.queue_read_write(&data, bits, pause_after))
+    }
+
+    pub fn finish_read(&self, bits: usize) -> Reply<Vec<u8>> {
+        self.run(move |c| c.finish_read(bits))
+    }
+
+    pub fn set_clock(&self, hz: u32) -> Reply<()> {
+        self.run(move |c| c.set_clock(hz))
+    }
+
+    pub fn ping(&self) -> Reply<()> {
+        self.run(|c| c.ping())
+    }
+
+    pub fn assert_srst(&self) -> Reply<()> {
+        self.run(|c| c.assert_srst())
+    }
+
+    pub fn deassert_srst(&self) -> Reply<()> {
+        self.run(|c| c.deassert_srst())
+    }
+
+    pub fn assert_trst(&self) -> Reply<()> {
+        self.run(|c| c.assert_trst())
+    }
+
+    pub fn deassert_trst(&self) -> Reply<()> {
+        self.run(|c| c.deassert_trst())
+    }
+
+    pub fn set_output_enable(&self, enable: bool) -> Reply<()> {
+        self.run(move |c| c.set_output_enable(enable))
+    }
+
+    pub fn capabilities(&self) -> Reply<CableCapabilities> {
+        self.run(|c| Ok(c.capabilities()))
+    }
+}
+
+/// Waits for the operations already sent to finish, then drops the cable on its own thread
+impl Drop for AsyncCable {
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::warn!("cable thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::MockCable;
+
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    /// Wakes the thread blocked in `block_on`
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// The smallest executor there is, which is enough to show the replies don't depend on any
+    /// particular runtime
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(x) => return x,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_in_call_order() {
+        let responses: Vec<Vec<u8>> = (0..20).map(|x| vec![x]).collect();
+        let cable = AsyncCable::new(Box::new(MockCable::with_tdo(responses.clone())));
+        let replies: Vec<_> = (0..20).map(|_| cable.read_data(8)).collect();
+        // Awaiting them backwards doesn't change the order they reached the cable in
+        let mut results: Vec<_> = replies.into_iter().rev().map(|x| block_on(x).unwrap()).collect();
+        results.reverse();
+        assert_eq!(results, responses);
+    }
+
+    #[test]
+    fn reads_from_several_threads() {
+        let cable = Arc::new(AsyncCable::new(Box::new(MockCable::with_tdo(
+            (0..100).map(|x| vec![x]).collect()))));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let cable = cable.clone();
+            std::thread::spawn(move || {
+                (0..25).map(|_| block_on(cable.read_data(8)).unwrap()[0]).collect::<Vec<_>>()
+            })
+        }).collect();
+
+        // Each response is handed out once, and each thread sees its own in the order it asked
+        let mut all = vec![];
+        for thread in threads {
+            let results = thread.join().unwrap();
+            assert!(results.windows(2).all(|x| x[0] < x[1]));
+            all.extend(results);
+        }
+        all.sort();
+        assert_eq!(all, (0..100).collect::<Vec<u8>>());
+    }
+}
@@ -0,0 +1,223 @@
+//! A `Cable` that splits any shift longer than a limit into several shifts of the cable it
+//! wraps, for adapters or drivers that can't take arbitrarily long transfers in one call.  The
+//! results are joined back together, so callers see one shift of the full length.
+//!
+//! Every piece but the last is a whole number of bytes, and only the last one is given the
+//! caller's partial last byte and `pause_after`, so the target stays in ShiftIR or ShiftDR
+//! between pieces and sees the same bits as an unsplit shift.
+use crate::cable::{BitOrder, Cable, CableCapabilities, CableError, CableGpio};
+
+pub struct ChunkingCable<T> {
+    pub cable: T,
+    max_bytes: usize,
+}
+
+impl<T, U> ChunkingCable<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Pass shifts of up to `max_bits` bits straight to `cable`, and split longer ones.
+    /// `max_bits` is rounded down to a whole number of bytes, and must be at least 8.
+    pub fn new(cable: T, max_bits: usize) -> Self {
+        assert!(max_bits >= 8, "can't split shifts into pieces of {} bits", max_bits);
+        Self {
+            cable,
+            max_bytes: max_bits / 8,
+        }
+    }
+
+    /// The number of bits in each piece of a shift of `bits` bits
+    fn pieces(&self, bits: usize) -> impl Iterator<Item = usize> {
+        let max = self.max_bytes * 8;
+        (0..bits.div_ceil(max).max(1)).map(move |i| (bits - i * max).min(max))
+    }
+
+    /// The bits in each piece of a shift of `data`, and the bytes of `data` each one sends
+    fn split<'a>(&self, data: &'a [u8], bits: u8) -> impl Iterator<Item = (&'a [u8], u8, bool)> {
+        let n = data.chunks(self.max_bytes).len();
+        data.chunks(self.max_bytes).enumerate().map(move |(i, chunk)| {
+            let last = i + 1 == n;
+            (chunk, if last { bits } else { 8 }, last)
+        })
+    }
+
+    /// Join the result of each piece of a shift, dropping any bytes past the end of the piece
+    fn join(pieces: impl Iterator<Item = (usize, Result<Vec<u8>, CableError>)>) -> Result<Vec<u8>, CableError> {
+        let mut result = vec![];
+        for (bits, data) in pieces {
+            let mut data = data?;
+            data.truncate(bits.div_ceil(8));
+            result.append(&mut data);
+        }
+        Ok(result)
+    }
+}
+
+impl<T, U> Cable for ChunkingCable<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.cable.change_mode(tms, tdi)
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        self.cable.change_mode_capture(tms, tdi)
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let pieces: Vec<_> = self.pieces(bits).collect();
+        Self::join(pieces.into_iter().map(|bits| (bits, self.cable.read_data(bits))))
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        if data.len() <= self.max_bytes {
+            return self.cable.write_data(data, bits, pause_after);
+        }
+        let pieces: Vec<_> = self.split(data, bits).collect();
+        for (chunk, bits, last) in pieces {
+            self.cable.write_data(chunk, bits, pause_after && last)?;
+        }
+        Ok(())
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        if data.len() <= self.max_bytes {
+            return self.cable.read_write_data(data, bits, pause_after);
+        }
+        let pieces: Vec<_> = self.split(data, bits).collect();
+        Self::join(pieces.into_iter().map(|(chunk, bits, last)| {
+            let len = (chunk.len() - 1) * 8 + bits as usize;
+            (len, self.cable.read_write_data(chunk, bits, pause_after && last))
+        }))
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.cable.flush()
+    }
+
+    /// Queues one read for each piece.  If the cable runs out of queue space part way through,
+    /// the pieces already queued stay queued.
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let pieces: Vec<_> = self.pieces(bits).collect();
+        for bits in pieces {
+            self.cable.queue_read(bits)?;
+        }
+        Ok(())
+    }
+
+    /// Queues one shift for each piece.  If the cable runs out of queue space part way through,
+    /// the pieces already queued stay queued.
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        if data.len() <= self.max_bytes {
+            return self.cable.queue_read_write(data, bits, pause_after);
+        }
+        let pieces: Vec<_> = self.split(data, bits).collect();
+        for (chunk, bits, last) in pieces {
+            self.cable.queue_read_write(chunk, bits, pause_after && last)?;
+        }
+        Ok(())
+    }
+
+    fn queue_capacity(&self) -> usize {
+        self.cable.queue_capacity()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.cable.queue_len()
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.cable.set_bit_order(order)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.cable.set_clock(hz)
+    }
+
+    fn max_clock(&self) -> u32 {
+        self.cable.max_clock()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.cable.ping()
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_srst()
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_srst()
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_trst()
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_trst()
+    }
+
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.cable.set_output_enable(enable)
+    }
+
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        self.cable.read_gpio()
+    }
+
+    /// Finishes the read queued for each piece, which follow from `bits` alone, and joins them
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let pieces: Vec<_> = self.pieces(bits).collect();
+        Self::join(pieces.into_iter().map(|bits| (bits, self.cable.finish_read(bits))))
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        self.cable.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::loopback::LoopbackCable;
+    use crate::cable::mock::{MockCable, Operation};
+
+    #[test]
+    fn split_500_bits() {
+        let mut mock = MockCable::new();
+        let mut cable = ChunkingCable::new(&mut mock, 64);
+        let data: Vec<u8> = (0..63).collect();
+        cable.write_data(&data, 4, true).unwrap();
+
+        let writes: Vec<_> = mock.recorded().iter().map(|op| match op {
+            Operation::Write { data, bits, pause_after } => (data.len(), *bits, *pause_after),
+            op => panic!("unexpected {:?}", op),
+        }).collect();
+        let mut expected = vec![(8, 8, false); 7];
+        expected.push((7, 4, true));
+        assert_eq!(writes, expected);
+    }
+
+    #[test]
+    fn read_write_500_bits() {
+        let mut loopback = LoopbackCable::new();
+        let mut cable = ChunkingCable::new(&mut loopback, 64);
+        let mut data: Vec<u8> = (0..63).map(|x| x * 3).collect();
+        data[62] &= 0x0f;
+        assert_eq!(cable.read_write_data(&data, 4, true).unwrap(), data);
+    }
+
+    #[test]
+    fn read_500_bits() {
+        let mut mock = MockCable::new();
+        let mut cable = ChunkingCable::new(&mut mock, 64);
+        assert_eq!(cable.read_data(500).unwrap().len(), 63);
+        let reads: Vec<_> = mock.recorded().iter().map(|op| match op {
+            Operation::Read { bits } => *bits,
+            op => panic!("unexpected {:?}", op),
+        }).collect();
+        assert_eq!(reads, vec![64, 64, 64, 64, 64, 64, 64, 52]);
+    }
+}
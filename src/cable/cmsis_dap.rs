@@ -0,0 +1,410 @@
+//! Implement the `Cable` trait for CMSIS-DAP debug probes.  Only CMSIS-DAP v2 (USB bulk
+//! endpoints) is supported, which is what current probe firmware exposes.
+use crate::cable::{Cable, CableCapabilities, CableError};
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use rusb::{DeviceHandle, Direction, GlobalContext, TransferType};
+use rusb::constants::*;
+
+const DAP_INFO: u8 = 0x00;
+const DAP_CONNECT: u8 = 0x02;
+const DAP_SWJ_CLOCK: u8 = 0x11;
+const DAP_JTAG_SEQUENCE: u8 = 0x14;
+
+const DAP_INFO_PACKET_SIZE: u8 = 0xff;
+const DAP_PORT_JTAG: u8 = 2;
+const DAP_OK: u8 = 0;
+
+// Sequence info byte for DAP_JTAG_Sequence
+const SEQ_TMS: u8 = 1 << 6;
+const SEQ_TDO_CAPTURE: u8 = 1 << 7;
+// A single sequence can clock at most 64 bits, encoded as a count of 0
+const SEQ_MAX_BITS: usize = 64;
+
+const TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Return `bits` bits from `src` starting at bit `start`, packed LSB-first
+fn bit_slice(src: &[u8], start: usize, bits: usize) -> Vec<u8> {
+    let mut dst = vec![0; bits.div_ceil(8)];
+    for i in 0..bits {
+        let j = start + i;
+        if src[j / 8] & (1 << (j % 8)) != 0 {
+            dst[i / 8] |= 1 << (i % 8);
+        }
+    }
+    dst
+}
+
+/// Append `bits` bits from `src` to the `dst_bits` bits already in `dst`
+fn bit_append(dst: &mut Vec<u8>, dst_bits: usize, src: &[u8], bits: usize) {
+    dst.resize((dst_bits + bits).div_ceil(8), 0);
+    for i in 0..bits {
+        if src[i / 8] & (1 << (i % 8)) != 0 {
+            let j = dst_bits + i;
+            dst[j / 8] |= 1 << (j % 8);
+        }
+    }
+}
+
+struct Sequence {
+    bits: usize,
+    tms: bool,
+    capture: bool,
+    tdi: Vec<u8>,
+}
+
+/// The sequences that shift out `data`, going on to PauseDR or PauseIR afterwards if
+/// `pause_after` is set
+fn shift_sequences(data: &[u8], bits: u8, pause_after: bool, capture: bool) -> Vec<Sequence> {
+    if data.is_empty() {
+        if !pause_after {
+            return vec![];
+        }
+        // Go through Exit1 to Pause without capturing anything
+        return vec![
+            Sequence { bits: 1, tms: true, capture: false, tdi: vec![0xff] },
+            Sequence { bits: 1, tms: false, capture: false, tdi: vec![0xff] },
+        ];
+    }
+    assert!(bits <= 8);
+    assert!(bits != 0);
+
+    let total_bits = (data.len()-1) * 8 + bits as usize;
+    // If we are pausing, the last bit is clocked with TMS high to get to Exit1
+    let shift_bits = if pause_after {
+        total_bits - 1
+    } else {
+        total_bits
+    };
+
+    let mut seqs = vec![];
+    let mut start = 0;
+    while start < shift_bits {
+        let len = std::cmp::min(SEQ_MAX_BITS, shift_bits - start);
+        seqs.push(Sequence {
+            bits: len,
+            tms: false,
+            capture,
+            tdi: bit_slice(data, start, len),
+        });
+        start += len;
+    }
+
+    if pause_after {
+        seqs.push(Sequence {
+            bits: 1,
+            tms: true,
+            capture,
+            tdi: bit_slice(data, shift_bits, 1),
+        });
+        // Go from Exit1 to Pause
+        seqs.push(Sequence {
+            bits: 1,
+            tms: false,
+            capture: false,
+            tdi: vec![0xff],
+        });
+    }
+
+    seqs
+}
+
+pub struct CmsisDap {
+    device: DeviceHandle<GlobalContext>,
+    read_endpoint: u8,
+    write_endpoint: u8,
+    packet_size: usize,
+    read_queue: VecDeque<Vec<u8>>,
+}
+
+impl CmsisDap {
+    /// Create a new CmsisDap using the first attached probe.  `clock` controls the speed of TCK in
+    /// hertz, or 0 to keep the probe's own default.
+    pub fn new(clock: u32) -> Self {
+        Self::try_new(clock).expect("no cmsis-dap attached")
+    }
+
+    /// Like `new`, but returns an error instead of panicking if no probe can be opened.
+    pub fn try_new(clock: u32) -> Result<Self, CableError> {
+        for device in rusb::devices()?.iter() {
+            let descriptor = match device.active_config_descriptor() {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+            let handle = match device.open() {
+                Ok(h) => h,
+                Err(_) => continue,
+            };
+
+            for i in descriptor.interfaces() {
+                for d in i.descriptors() {
+                    if d.class_code() != LIBUSB_CLASS_VENDOR_SPEC || d.num_endpoints() < 2 {
+                        continue;
+                    }
+
+                    // CMSIS-DAP v2 probes are identified by their interface string
+                    let name = d.description_string_index()
+                        .and_then(|idx| handle.read_string_descriptor_ascii(idx).ok());
+                    if !name.is_some_and(|n| n.contains("CMSIS-DAP")) {
+                        continue;
+                    }
+
+                    let mut read_endpoint = None;
+                    let mut write_endpoint = None;
+                    for e in d.endpoint_descriptors() {
+                        if e.transfer_type() != TransferType::Bulk {
+                            continue;
+                        }
+                        match e.direction() {
+                            Direction::In if read_endpoint.is_none() => read_endpoint = Some(e.address()),
+                            Direction::Out if write_endpoint.is_none() => write_endpoint = Some(e.address()),
+                            _ => (),
+                        }
+                    }
+
+                    let (read_endpoint, write_endpoint) = match (read_endpoint, write_endpoint) {
+                        (Some(r), Some(w)) => (r, w),
+                        _ => continue,
+                    };
+
+                    handle.claim_interface(d.interface_number())?;
+                    let mut dap = Self {
+                        device: handle,
+                        read_endpoint,
+                        write_endpoint,
+                        // The smallest packet size allowed, until the probe tells us otherwise
+                        packet_size: 64,
+                        read_queue: VecDeque::new(),
+                    };
+                    dap.negotiate_packet_size()?;
+                    dap.connect()?;
+                    if clock != 0 {
+                        dap.set_clock(clock)?;
+                    }
+                    return Ok(dap);
+                }
+            }
+        }
+        Err(CableError::Protocol("no cmsis-dap attached".to_string()))
+    }
+
+    /// Send `request` and return the response.  The first byte of the response is checked
+    /// against the command ID and stripped.
+    fn command(&mut self, request: &[u8]) -> Result<Vec<u8>, CableError> {
+        let wr = self.device.write_bulk(self.write_endpoint, request, TIMEOUT)?;
+        if wr != request.len() {
+            return Err(CableError::Protocol(format!("short write: {} of {}", wr, request.len())));
+        }
+
+        let mut response = vec![0; self.packet_size];
+        let len = self.device.read_bulk(self.read_endpoint, &mut response, TIMEOUT)?;
+        response.truncate(len);
+        if response.first() != Some(&request[0]) {
+            return Err(CableError::Protocol(format!("bad response to command {:#x}", request[0])));
+        }
+        response.remove(0);
+        Ok(response)
+    }
+
+    fn check_status(cmd: u8, response: &[u8]) -> Result<(), CableError> {
+        if response.first() != Some(&DAP_OK) {
+            return Err(CableError::Protocol(format!("command {:#x} failed", cmd)));
+        }
+        Ok(())
+    }
+
+    /// Ask the probe for its maximum packet size with DAP_Info
+    fn negotiate_packet_size(&mut self) -> Result<(), CableError> {
+        let response = self.command(&[DAP_INFO, DAP_INFO_PACKET_SIZE])?;
+        if response.len() < 3 || response[0] != 2 {
+            return Err(CableError::Protocol("bad DAP_Info packet size".to_string()));
+        }
+        self.packet_size = u16::from_le_bytes([response[1], response[2]]) as usize;
+        Ok(())
+    }
+
+    /// Switch the probe into JTAG mode with DAP_Connect
+    fn connect(&mut self) -> Result<(), CableError> {
+        let response = self.command(&[DAP_CONNECT, DAP_PORT_JTAG])?;
+        if response.first() != Some(&DAP_PORT_JTAG) {
+            return Err(CableError::Protocol("probe doesn't support JTAG".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Set the speed of TCK in hertz with DAP_SWJ_Clock
+    pub fn set_clock(&mut self, clock: u32) -> Result<(), CableError> {
+        let mut request = vec![DAP_SWJ_CLOCK];
+        request.extend_from_slice(&clock.to_le_bytes());
+        let response = self.command(&request)?;
+        Self::check_status(DAP_SWJ_CLOCK, &response)
+    }
+
+    /// Execute `seqs` with as few DAP_JTAG_Sequence commands as the packet size allows, returning
+    /// the concatenated TDO bits from the sequences that capture.
+    fn run_sequences(&mut self, seqs: &[Sequence]) -> Result<Vec<u8>, CableError> {
+        let mut tdo = vec![];
+        let mut tdo_bits = 0;
+
+        let mut start = 0;
+        while start < seqs.len() {
+            // Each command has a two byte header in both directions, and at most 255 sequences
+            let mut request_len = 2;
+            let mut response_len = 2;
+            let mut end = start;
+            while end < seqs.len() && end - start < 255 {
+                let bytes = seqs[end].bits.div_ceil(8);
+                let resp = if seqs[end].capture {
+                    bytes
+                } else {
+                    0
+                };
+                if request_len + 1 + bytes > self.packet_size || response_len + resp > self.packet_size {
+                    break;
+                }
+                request_len += 1 + bytes;
+                response_len += resp;
+                end += 1;
+            }
+
+            let mut request = vec![DAP_JTAG_SEQUENCE, (end - start) as u8];
+            for s in &seqs[start..end] {
+                let mut info = (s.bits % SEQ_MAX_BITS) as u8;
+                if s.tms {
+                    info |= SEQ_TMS;
+                }
+                if s.capture {
+                    info |= SEQ_TDO_CAPTURE;
+                }
+                request.push(info);
+                request.extend_from_slice(&s.tdi);
+            }
+
+            let response = self.command(&request)?;
+            Self::check_status(DAP_JTAG_SEQUENCE, &response)?;
+
+            let mut offset = 1;
+            for s in &seqs[start..end] {
+                if s.capture {
+                    let bytes = s.bits.div_ceil(8);
+                    let data = response.get(offset..offset+bytes)
+                        .ok_or_else(|| CableError::Protocol("short DAP_JTAG_Sequence response".to_string()))?;
+                    bit_append(&mut tdo, tdo_bits, data, s.bits);
+                    tdo_bits += s.bits;
+                    offset += bytes;
+                }
+            }
+            start = end;
+        }
+        Ok(tdo)
+    }
+
+    /// Shifting no data clocks nothing except, if `pause_after` is set, the way to PauseDR or
+    /// PauseIR, and returns an empty vector
+    fn shift(&mut self, data: &[u8], bits: u8, pause_after: bool, capture: bool) -> Result<Vec<u8>, CableError> {
+        self.run_sequences(&shift_sequences(data, bits, pause_after, capture))
+    }
+}
+
+impl Cable for CmsisDap {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        let tdi = if tdi {
+            0xff
+        } else {
+            0
+        };
+
+        // Each sequence has a fixed TMS value, so group runs of the same value together
+        let mut seqs: Vec<Sequence> = vec![];
+        for x in tms {
+            let tms = *x != 0;
+            match seqs.last_mut() {
+                Some(s) if s.tms == tms && s.bits < SEQ_MAX_BITS => {
+                    s.bits += 1;
+                    s.tdi = vec![tdi; s.bits.div_ceil(8)];
+                }
+                _ => seqs.push(Sequence {
+                    bits: 1,
+                    tms,
+                    capture: false,
+                    tdi: vec![tdi],
+                }),
+            }
+        }
+
+        self.run_sequences(&seqs)?;
+        Ok(())
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let buf = vec![0xff; bits.div_ceil(8)];
+        let mut last = (bits % 8) as u8;
+        if last == 0 {
+            last = 8;
+        }
+        self.shift(&buf, last, false, true)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.shift(data, bits, pause_after, false)?;
+        Ok(())
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.shift(data, bits, pause_after, true)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let data = self.read_data(bits)?;
+        self.read_queue.push_back(data);
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let result = self.read_write_data(data, bits, pause_after)?;
+        self.read_queue.push_back(result);
+        Ok(())
+    }
+
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        self.read_queue.pop_front()
+            .ok_or_else(|| CableError::Protocol("finish_read without a queued read".to_string()))
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        CmsisDap::set_clock(self, hz)
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            adjustable_clock: true,
+            ..CableCapabilities::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_length_shift() {
+        assert!(shift_sequences(&[], 8, false, true).is_empty());
+
+        let seqs = shift_sequences(&[], 8, true, true);
+        assert_eq!(seqs.len(), 2);
+        assert!(seqs[0].tms && !seqs[1].tms);
+        assert!(seqs.iter().all(|s| s.bits == 1 && !s.capture));
+    }
+
+    #[test]
+    fn pause_on_last_bit() {
+        let seqs = shift_sequences(&[0xff, 0x01], 1, true, true);
+        assert_eq!(seqs.len(), 3);
+        assert_eq!((seqs[0].bits, seqs[0].tms, seqs[0].tdi.clone()), (8, false, vec![0xff]));
+        assert_eq!((seqs[1].bits, seqs[1].tms, seqs[1].tdi.clone()), (1, true, vec![0x01]));
+        assert!(!seqs[2].tms && !seqs[2].capture);
+    }
+}
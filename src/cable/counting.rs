@@ -0,0 +1,165 @@
+//! A `Cable` with nothing attached that counts the TCK cycles and bytes each operation would
+//! take.  Playing an SVF file or running any other sequence against it gives an estimate of how
+//! long the sequence will take on real hardware, without touching any.
+//!
+//! The estimate only covers the time spent clocking.  USB latency isn't counted, and nor are
+//! waits measured in seconds rather than clocks, such as an SVF `RUNTEST` with a minimum time.
+use crate::cable::{Cable, CableCapabilities, CableError};
+
+use std::collections::VecDeque;
+
+/// Every read returns zeros, so scans that check TDO will fail.  Use
+/// `svf::SvfPlayer::set_check_tdo` to play files that have expected values.
+#[derive(Default)]
+pub struct CountingCable {
+    clocks: u64,
+    bytes: u64,
+    operations: u64,
+    read_queue: VecDeque<usize>,
+}
+
+impl CountingCable {
+    /// Create a CountingCable with all of its counts at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of TCK cycles clocked so far
+    pub fn total_clocks(&self) -> u64 {
+        self.clocks
+    }
+
+    /// The number of bytes shifted so far, counting TDI and TDO separately
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The number of `Cable` methods called so far
+    pub fn operations(&self) -> u64 {
+        self.operations
+    }
+
+    /// How long the clocks counted so far would take with TCK running at `clock_hz`
+    pub fn estimated_seconds(&self, clock_hz: u32) -> f64 {
+        assert!(clock_hz > 0);
+        self.clocks as f64 / clock_hz as f64
+    }
+
+    /// Set every count back to zero
+    pub fn reset_counts(&mut self) {
+        self.clocks = 0;
+        self.bytes = 0;
+        self.operations = 0;
+    }
+
+    fn count(&mut self, clocks: usize, bytes: usize) {
+        self.clocks += clocks as u64;
+        self.bytes += bytes as u64;
+        self.operations += 1;
+    }
+
+    /// Count a shift of `data`, plus the clock from Exit1 to Pause if `pause_after` is set
+    fn count_shift(&mut self, data: &[u8], bits: u8, pause_after: bool, read: bool) {
+        assert!(!data.is_empty());
+        assert!(bits > 0 && bits <= 8);
+        let clocks = (data.len() - 1) * 8 + bits as usize + pause_after as usize;
+        let bytes = if read { data.len() * 2 } else { data.len() };
+        self.count(clocks, bytes);
+    }
+}
+
+impl Cable for CountingCable {
+    fn change_mode(&mut self, tms: &[usize], _tdi: bool) -> Result<(), CableError> {
+        self.count(tms.len(), 0);
+        Ok(())
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], _tdi: bool) -> Result<Vec<bool>, CableError> {
+        self.count(tms.len(), 0);
+        Ok(vec![false; tms.len()])
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.count(bits, bits.div_ceil(8));
+        Ok(vec![0; bits.div_ceil(8)])
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.count_shift(data, bits, pause_after, false);
+        Ok(())
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.count_shift(data, bits, pause_after, true);
+        Ok(vec![0; data.len()])
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        self.count(bits, bits.div_ceil(8));
+        self.read_queue.push_back(bits.div_ceil(8));
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.count_shift(data, bits, pause_after, true);
+        self.read_queue.push_back(data.len());
+        Ok(())
+    }
+
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        let bytes = self.read_queue.pop_front()
+            .ok_or_else(|| CableError::Protocol("finish_read without a queued read".to_string()))?;
+        Ok(vec![0; bytes])
+    }
+
+    /// The speed doesn't change the count, so any speed is accepted
+    fn set_clock(&mut self, _hz: u32) -> Result<(), CableError> {
+        Ok(())
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            adjustable_clock: true,
+            ..CableCapabilities::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statemachine::JtagSM;
+    use crate::svf::SvfPlayer;
+
+    const SVF: &str = "
+        STATE RESET;
+        SIR 8 TDI(fe);
+        ENDDR DRPAUSE;
+        SDR 32 TDI(00000000) TDO(4ba00477);
+        RUNTEST IDLE 100 TCK;
+    ";
+
+    #[test]
+    fn svf_clocks() {
+        let mut cable = CountingCable::new();
+        let mut player = SvfPlayer::new(JtagSM::new(&mut cable).unwrap());
+        player.set_check_tdo(false);
+        player.sm.cable.reset_counts();
+        player.play(SVF.as_bytes()).unwrap();
+        drop(player);
+
+        // STATE RESET: 5 clocks with TMS high
+        // SIR: 5 to ShiftIR, 8 bits, 1 to PauseIR, 3 to Idle
+        // SDR: 3 to ShiftDR, 32 bits, 1 to PauseDR
+        // RUNTEST: 3 to Idle, then 100
+        assert_eq!(cable.total_clocks(), 5 + (5 + 8 + 1 + 3) + (3 + 32 + 1) + (3 + 100));
+        assert_eq!(cable.total_bytes(), 1 + 4);
+        assert_eq!(cable.estimated_seconds(1_000_000), 161e-6);
+    }
+
+
+    #[test]
+    fn capabilities_match() {
+        crate::cable::tests::check_capabilities(&mut CountingCable::new());
+    }
+}
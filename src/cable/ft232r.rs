@@ -1,41 +1,67 @@
 //! Implement the `Cable` trait for FTDI RS232R-based adapters
-use crate::cable::Cable;
+use crate::cable::{Cable, CableCapabilities, CableError};
+
+use std::collections::VecDeque;
 
 use libftd2xx::{Ftdi, FtdiCommon, BitMode};
 
+const MAX_BAUD: u32 = 3_000_000;
+// The baud rate used when the constructor is given a baud rate of 0
+const DEFAULT_BAUD: u32 = 1_000_000;
+
 pub struct Ft232r {
     ft: Ftdi,
+    // Bit numbers in the bitbang byte.  These are named from the cable's point of view, so `tdi`
+    // is the input connected to the target's TDO and `tdo` is the output to the target's TDI.
     tdi: u8,
     tdo: u8,
     tms: u8,
     clk: u8,
-    read_queue: Vec<Vec<u8>>,
+    read_queue: VecDeque<Vec<u8>>,
 }
 
 impl Ft232r {
-    /// Create a new Ft232r.  `description` is the value passed to `Ftd::with_description` to
-    /// select which hardware to use.
+    /// Create a new Ft232r for the EasyFlash 3 cartridge.  Its pins are TDI on D0 (TXD), TMS on
+    /// D1 (RXD), TCK on D2 (RTS#), and TDO on D3 (CTS#).
     pub fn easyflash3(baud: u32) -> Self {
         libftd2xx::set_vid_pid(0x0403, 0x8738).expect("vid");
-        Ft232r::new("EasyFlash 3", baud, 3, 0, 1, 2)
+        Ft232r::try_new("EasyFlash 3", 1 << 2, 1 << 1, 1 << 0, 1 << 3, baud).expect("new")
     }
 
     /// Create a new Ft232r.  `description` is the value passed to `Ftd::with_description` to
-    /// select which hardware to use.
+    /// select which hardware to use.  `tdi` is the bit number of the input connected to the
+    /// target's TDO, and `tdo` is the bit number of the output connected to the target's TDI.
     pub fn new(description: &str, baud: u32, tdi: u8, tdo: u8, tms: u8, clk: u8) -> Self {
-        let mut ft = Ftdi::with_description(description).expect("new");
-        ft.set_baud_rate(baud).expect("set_baud_rate");
-        ft.set_bit_mode(1 << tdo | 1 << tms | 1 << clk, BitMode::SyncBitbang).expect("set bit mode");
-        ft.purge_all().expect("purge");
+        Ft232r::try_new(description, 1 << clk, 1 << tms, 1 << tdo, 1 << tdi, baud).expect("new")
+    }
 
-        Self {
-            ft,
-            tdi,
-            tdo,
-            tms,
-            clk,
-            read_queue: vec![],
+    /// Create a new Ft232r with the JTAG signals on any of the bitbang pins.  `description` is the
+    /// value passed to `Ftd::with_description` to select which hardware to use.  `tck`, `tms`,
+    /// `tdi`, and `tdo` are masks selecting one bit of the bitbang byte each, named for the
+    /// target's signal they connect to.  `baud` sets the bitbang rate, and hence TCK, with 0
+    /// meaning 1MBaud.  Returns
+    /// `CableError::Config` if a mask isn't a single bit or two signals share a bit.
+    pub fn try_new(description: &str, tck: u8, tms: u8, tdi: u8, tdo: u8, baud: u32) -> Result<Self, CableError> {
+        if [tck, tms, tdi, tdo].iter().any(|x| x.count_ones() != 1) {
+            return Err(CableError::Config("each pin mask must have exactly one bit set".to_string()));
         }
+        if (tck | tms | tdi | tdo).count_ones() != 4 {
+            return Err(CableError::Config("two pins share the same bit".to_string()));
+        }
+
+        let mut ft = Ftdi::with_description(description)?;
+        ft.set_baud_rate(if baud == 0 { DEFAULT_BAUD } else { baud })?;
+        ft.set_bit_mode(tck | tms | tdi, BitMode::SyncBitbang)?;
+        ft.purge_all()?;
+
+        Ok(Self {
+            ft,
+            tdi: tdo.trailing_zeros() as u8,
+            tdo: tdi.trailing_zeros() as u8,
+            tms: tms.trailing_zeros() as u8,
+            clk: tck.trailing_zeros() as u8,
+            read_queue: VecDeque::new(),
+        })
     }
 
     fn select_bit(mut recv: Vec<u8>, tdi: u8) -> Vec<u8> {
@@ -75,9 +101,9 @@ impl Ft232r {
 }
 
 impl Cable for Ft232r {
-    fn change_mode(&mut self, tms: &[usize], tdo: bool) {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
         let mut buf = vec![];
-        let tdo = if tdo {
+        let tdi = if tdi {
             1
         } else {
             0
@@ -89,15 +115,16 @@ impl Cable for Ft232r {
             } else {
                 0
             };
-            buf.push(x << self.tms | tdo << self.tdo);
-            buf.push(x << self.tms | tdo << self.tdo | 1 << self.clk);
+            buf.push(x << self.tms | tdi << self.tdo);
+            buf.push(x << self.tms | tdi << self.tdo | 1 << self.clk);
         }
         let mut recv = vec![0; buf.len()];
-        self.ft.write(&buf).expect("send");
-        self.ft.read(&mut recv).expect("send");
+        self.ft.write(&buf)?;
+        self.ft.read(&mut recv)?;
+        Ok(())
     }
 
-    fn read_data(&mut self, bits: usize) -> Vec<u8>
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError>
     {
         let mut buf = vec![];
         for _ in 0..bits {
@@ -106,17 +133,18 @@ impl Cable for Ft232r {
         }
 
         let mut recv = vec![0; buf.len()];
-        self.ft.write(&buf).expect("send");
-        self.ft.read(&mut recv).expect("send");
-        Self::select_bit(recv, self.tdi)
+        self.ft.write(&buf)?;
+        self.ft.read(&mut recv)?;
+        Ok(Self::select_bit(recv, self.tdi))
     }
 
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool)
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError>
     {
-        self.read_write_data(data, bits, pause_after);
+        self.read_write_data(data, bits, pause_after)?;
+        Ok(())
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
         let mut buf = vec![];
 
         assert!(bits <= 8);
@@ -143,24 +171,48 @@ impl Cable for Ft232r {
         }
 
         let mut recv = vec![0; buf.len()];
-        self.ft.write(&buf).expect("send");
-        self.ft.read(&mut recv).expect("send");
-        Self::select_bit(recv, self.tdi)
+        self.ft.write(&buf)?;
+        self.ft.read(&mut recv)?;
+        Ok(Self::select_bit(recv, self.tdi))
     }
 
-    fn queue_read(&mut self, bits: usize) -> bool {
-        let data = self.read_data(bits);
-        self.read_queue.push(data);
-        true
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let data = self.read_data(bits)?;
+        self.read_queue.push_back(data);
+        Ok(())
     }
 
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        let result = self.read_write_data(data, bits, pause_after);
-        self.read_queue.push(result);
-        true
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let result = self.read_write_data(data, bits, pause_after)?;
+        self.read_queue.push_back(result);
+        Ok(())
     }
 
-    fn finish_read(&mut self, _bits: usize) -> Vec<u8> {
-        self.read_queue.remove(0)
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        self.read_queue.pop_front()
+            .ok_or_else(|| CableError::Protocol("finish_read without a queued read".to_string()))
+    }
+    /// The highest baud rate the FT232R supports
+    fn max_clock(&self) -> u32 {
+        MAX_BAUD
+    }
+
+    /// The bitbang sample rate follows the baud rate, so this sets the baud rate to `hz`.
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.ft.set_baud_rate(hz)?;
+        Ok(())
+    }
+
+    /// Reads the modem status, which doesn't affect the bitbang pins
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.ft.modem_status()?;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            adjustable_clock: true,
+            ..CableCapabilities::default()
+        }
     }
 }
@@ -0,0 +1,285 @@
+//! Implement the `Cable` trait by bit-banging GPIO pins through the Linux sysfs interface, e.g. on
+//! a Raspberry Pi wired directly to the target's JTAG header
+use crate::cable::{Cable, CableCapabilities, CableError};
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+const SYSFS_GPIO: &str = "/sys/class/gpio";
+
+/// How the output pins drive the JTAG lines
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum GpioDrive {
+    /// Actively drive both high and low
+    #[default]
+    PushPull,
+    /// Only ever pull low, and float the pin as an input to let the line go high.  The board
+    /// must have pull-up resistors on TCK, TMS, and TDI.  This is slower, since the rising edges
+    /// are only as fast as the pull-ups can charge the line, and each change of level switches
+    /// the pin's direction.
+    OpenDrain,
+}
+
+struct Pin {
+    value: File,
+    // For an open-drain output, the direction file, which is used to drive the pin instead
+    direction: Option<File>,
+}
+
+impl Pin {
+    /// Export `pin` under `root`, which is `SYSFS_GPIO` except in tests
+    fn open(root: &str, pin: u32, output: bool, drive: GpioDrive) -> Result<Self, CableError> {
+        let dir = format!("{}/gpio{}", root, pin);
+        if fs::metadata(&dir).is_err() {
+            fs::write(format!("{}/export", root), pin.to_string())?;
+        }
+
+        // Open-drain outputs start out floating, and only become outputs to pull low
+        let direction = if output && drive == GpioDrive::PushPull {
+            "out"
+        } else {
+            "in"
+        };
+        fs::write(format!("{}/direction", dir), direction)?;
+
+        let value = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("{}/value", dir))?;
+        let direction = if output && drive == GpioDrive::OpenDrain {
+            Some(OpenOptions::new().write(true).open(format!("{}/direction", dir))?)
+        } else {
+            None
+        };
+        Ok(Self {
+            value,
+            direction,
+        })
+    }
+
+    fn set(&mut self, high: bool) -> Result<(), CableError> {
+        if let Some(direction) = &mut self.direction {
+            // "low" switches to an output and drives it low in one step
+            let val: &[u8] = if high {
+                b"in"
+            } else {
+                b"low"
+            };
+            direction.seek(SeekFrom::Start(0))?;
+            direction.write_all(val)?;
+            return Ok(());
+        }
+
+        let val = if high {
+            b"1"
+        } else {
+            b"0"
+        };
+        self.value.seek(SeekFrom::Start(0))?;
+        self.value.write_all(val)?;
+        Ok(())
+    }
+
+    fn get(&mut self) -> Result<bool, CableError> {
+        let mut buf = [0; 1];
+        self.value.seek(SeekFrom::Start(0))?;
+        self.value.read_exact(&mut buf)?;
+        Ok(buf[0] == b'1')
+    }
+}
+
+pub struct GpioCable {
+    tck: Pin,
+    tms: Pin,
+    tdi: Pin,
+    tdo: Pin,
+    half_period: Duration,
+    read_queue: VecDeque<Vec<u8>>,
+}
+
+/// The TCK frequency used when the constructor is given a clock of 0
+const DEFAULT_CLOCK: u32 = 100_000;
+
+/// The time to wait between TCK edges to run at `clock_hz`
+fn half_period(clock_hz: u32) -> Duration {
+    Duration::from_nanos(500_000_000 / clock_hz.max(1) as u64)
+}
+
+impl GpioCable {
+    /// Create a new GpioCable.  The pins are BCM GPIO numbers for the TCK, TMS, TDI, and TDO
+    /// lines.  `clock_hz` is the target TCK frequency; the actual frequency will be lower, since
+    /// we only sleep between edges and sysfs accesses aren't free.  A `clock_hz` of 0 means 100kHz.
+    pub fn new(tck: u32, tms: u32, tdi: u32, tdo: u32, clock_hz: u32) -> Self {
+        Self::try_new(tck, tms, tdi, tdo, clock_hz).expect("gpio")
+    }
+
+    /// Like `new`, but returns an error instead of panicking if the pins can't be exported.
+    pub fn try_new(tck: u32, tms: u32, tdi: u32, tdo: u32, clock_hz: u32) -> Result<Self, CableError> {
+        Self::with_drive(tck, tms, tdi, tdo, clock_hz, GpioDrive::PushPull)
+    }
+
+    /// Like `try_new`, but drives TCK, TMS, and TDI as chosen by `drive`.  See `GpioDrive`.
+    pub fn with_drive(tck: u32, tms: u32, tdi: u32, tdo: u32, clock_hz: u32, drive: GpioDrive)
+        -> Result<Self, CableError>
+    {
+        let mut cable = Self {
+            tck: Pin::open(SYSFS_GPIO, tck, true, drive)?,
+            tms: Pin::open(SYSFS_GPIO, tms, true, drive)?,
+            tdi: Pin::open(SYSFS_GPIO, tdi, true, drive)?,
+            tdo: Pin::open(SYSFS_GPIO, tdo, false, drive)?,
+            half_period: half_period(if clock_hz == 0 { DEFAULT_CLOCK } else { clock_hz }),
+            read_queue: VecDeque::new(),
+        };
+        cable.tck.set(false)?;
+        cable.tms.set(true)?;
+        cable.tdi.set(true)?;
+        Ok(cable)
+    }
+
+    /// Clock one bit with the given TMS and TDI values, returning the value of TDO sampled on the
+    /// rising edge of TCK.
+    fn clock(&mut self, tms: bool, tdi: bool) -> Result<bool, CableError> {
+        self.tms.set(tms)?;
+        self.tdi.set(tdi)?;
+        std::thread::sleep(self.half_period);
+        let tdo = self.tdo.get()?;
+        self.tck.set(true)?;
+        std::thread::sleep(self.half_period);
+        self.tck.set(false)?;
+        Ok(tdo)
+    }
+
+    fn shift(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        assert!(bits <= 8);
+        assert!(bits != 0);
+
+        let total_bits = (data.len()-1) * 8 + bits as usize;
+        let mut recv = vec![0; total_bits.div_ceil(8)];
+        for i in 0..total_bits {
+            let tdi = data[i / 8] & (1 << (i % 8)) != 0;
+            let tms = pause_after && i == total_bits-1;
+            if self.clock(tms, tdi)? {
+                recv[i / 8] |= 1 << (i % 8);
+            }
+        }
+
+        // Go from Exit1 to Pause
+        if pause_after {
+            self.clock(false, true)?;
+        }
+        Ok(recv)
+    }
+}
+
+impl Cable for GpioCable {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        for x in tms {
+            self.clock(*x != 0, tdi)?;
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let buf = vec![0xff; bits.div_ceil(8)];
+        let mut last = (bits % 8) as u8;
+        if last == 0 {
+            last = 8;
+        }
+        self.shift(&buf, last, false)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.shift(data, bits, pause_after)?;
+        Ok(())
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.shift(data, bits, pause_after)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let data = self.read_data(bits)?;
+        self.read_queue.push_back(data);
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let result = self.read_write_data(data, bits, pause_after)?;
+        self.read_queue.push_back(result);
+        Ok(())
+    }
+
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        self.read_queue.pop_front()
+            .ok_or_else(|| CableError::Protocol("finish_read without a queued read".to_string()))
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.half_period = half_period(hz);
+        Ok(())
+    }
+
+    /// Reads TDO, which fails if the GPIO has been taken away
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.tdo.get()?;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            adjustable_clock: true,
+            ..CableCapabilities::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory laid out like /sys/class/gpio with `pin` already exported
+    fn fake_sysfs(name: &str, pin: u32) -> String {
+        let root = std::env::temp_dir().join(format!("jtag-taps-{}-{}", name, std::process::id()));
+        let dir = root.join(format!("gpio{}", pin));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("direction"), "out").unwrap();
+        fs::write(dir.join("value"), "0").unwrap();
+        root.to_str().unwrap().to_string()
+    }
+
+    fn read(root: &str, pin: u32, file: &str) -> String {
+        fs::read_to_string(format!("{}/gpio{}/{}", root, pin, file)).unwrap()
+    }
+
+    #[test]
+    fn open_drain_toggles_direction() {
+        let root = fake_sysfs("open-drain", 4);
+        let mut pin = Pin::open(&root, 4, true, GpioDrive::OpenDrain).unwrap();
+        // Floating until driven low
+        assert_eq!(read(&root, 4, "direction"), "in");
+        pin.set(false).unwrap();
+        assert_eq!(read(&root, 4, "direction"), "low");
+        // sysfs takes each write whole, but a plain file keeps the tail of the longer one
+        pin.set(true).unwrap();
+        assert!(read(&root, 4, "direction").starts_with("in"));
+        pin.set(false).unwrap();
+        assert_eq!(read(&root, 4, "direction"), "low");
+        // The level only ever comes from the direction
+        assert_eq!(read(&root, 4, "value"), "0");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn push_pull_writes_value() {
+        let root = fake_sysfs("push-pull", 5);
+        let mut pin = Pin::open(&root, 5, true, GpioDrive::PushPull).unwrap();
+        pin.set(true).unwrap();
+        assert_eq!(read(&root, 5, "value"), "1");
+        pin.set(false).unwrap();
+        assert_eq!(read(&root, 5, "value"), "0");
+        assert_eq!(read(&root, 5, "direction"), "out");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,5 +1,5 @@
 //! Implement the `Cable` trait for "jlink" compatible hardware adapters
-use crate::cable::Cable;
+use crate::cable::{Cable, CableCapabilities, CableError};
 
 use std::time::Duration;
 
@@ -8,6 +8,20 @@ use rusb::constants::*;
 
 const TAP_SEQUENCE_MAX: usize = 390;
 
+// EMU_CMD_VERSION: replies with a 2 byte little endian length, then the firmware version string
+const CMD_VERSION: u8 = 0x01;
+// EMU_CMD_SET_SPEED: takes the TCK speed as 2 little endian bytes in kHz, or SPEED_ADAPTIVE
+const CMD_SET_SPEED: u8 = 0x05;
+// EMU_CMD_GET_STATE: replies with 8 bytes, the first two being the target voltage in millivolts
+// and the rest the state of TCK, TDI, TDO, TMS, TRES, and TRST
+const CMD_GET_STATE: u8 = 0x07;
+// EMU_CMD_GET_SPEEDS: replies with the base frequency in hertz as 4 little endian bytes, then
+// the smallest divider as 2
+const CMD_GET_SPEEDS: u8 = 0xc0;
+
+// The speed that makes the probe follow RTCK
+const SPEED_ADAPTIVE: u16 = 0xffff;
+
 pub struct JLink {
     device: DeviceHandle<GlobalContext>,
     // queued bytes to send
@@ -28,10 +42,52 @@ pub struct JLink {
     send_bits: usize,
     read_endpoint: u8,
     write_endpoint: u8,
+    // Frequency TCK is divided down from, and the smallest divider allowed
+    base_freq: u32,
+    min_div: u32,
+}
+
+/// The speed of TCK closest to `hz` that can be reached by dividing `base` by `min_div` or more
+fn nearest_speed(base: u32, min_div: u32, hz: u32) -> u32 {
+    let div = (base / hz.max(1)).max(min_div);
+    let (fast, slow) = (base / div, base / (div + 1));
+    if hz.abs_diff(slow) < hz.abs_diff(fast) {
+        slow
+    } else {
+        fast
+    }
+}
+
+/// Every speed of TCK that can be reached by dividing `base` by `min_div` or more, fastest first.
+/// Speeds are set in kHz, so the list stops at 1 kHz and has one entry per kHz at most.
+fn speeds(base: u32, min_div: u32) -> Vec<u32> {
+    let mut speeds: Vec<u32> = vec![];
+    for div in min_div.. {
+        let hz = base / div;
+        if hz < 1000 {
+            break;
+        }
+        if speeds.last().is_none_or(|last| last / 1000 != hz / 1000) {
+            speeds.push(hz);
+        }
+    }
+    speeds
+}
+
+/// The base frequency and smallest divider from a GET_SPEEDS reply
+fn parse_speeds(data: &[u8]) -> Result<(u32, u16), CableError> {
+    match data {
+        [b0, b1, b2, b3, d0, d1, ..] => {
+            let base = u32::from_le_bytes([*b0, *b1, *b2, *b3]);
+            let min_div = u16::from_le_bytes([*d0, *d1]);
+            Ok((base, min_div))
+        }
+        _ => Err(CableError::Protocol("short GET_SPEEDS reply".to_string())),
+    }
 }
 
 fn bit_append (dst: &mut Vec<u8>, mut dst_bits: usize, src: &[u8], src_bits: usize, src_skip: usize) {
-    let mut byte = if !dst.is_empty() && dst_bits % 8 != 0 {
+    let mut byte = if !dst.is_empty() && !dst_bits.is_multiple_of(8) {
         dst.pop().unwrap()
     } else {
         0
@@ -43,17 +99,20 @@ fn bit_append (dst: &mut Vec<u8>, mut dst_bits: usize, src: &[u8], src_bits: usi
         }
 
         dst_bits += 1;
-        if dst_bits % 8 == 0 {
+        if dst_bits.is_multiple_of(8) {
             dst.push(byte);
             byte = 0;
         }
     }
-    if dst_bits % 8 != 0 {
+    if !dst_bits.is_multiple_of(8) {
         dst.push(byte);
     }
 }
 
 impl JLink {
+    /// Open the first attached J-Link.  `clock` controls the speed of TCK in hertz, or 0 to keep
+    /// the speed the probe is already set to.  It is rounded to the nearest of
+    /// `supported_speeds`.
     pub fn new(clock: u32) -> Self {
         let device = rusb::open_device_with_vid_pid(0x1366, 0x0105).expect("no jlink attached");
         let descriptor = device.device().active_config_descriptor().expect("active config");
@@ -96,10 +155,17 @@ impl JLink {
                     recv_bytes: 0,
                     read_endpoint,
                     write_endpoint,
+                    base_freq: u32::MAX,
+                    min_div: 1,
                 };
 
                 jlink.get_status();
-                jlink.set_clock(clock);
+                let (base, min_div) = jlink.get_speeds();
+                jlink.base_freq = base;
+                jlink.min_div = min_div.max(1) as u32;
+                if clock != 0 {
+                    jlink.set_clock(clock);
+                }
                 jlink.set_interface(0);
                 jlink.deassert_trst();
                 jlink.deassert_srst();
@@ -184,7 +250,7 @@ impl JLink {
     }
 
     pub fn get_status(&mut self) -> Vec<u8> {
-        self.send_command(0x7, vec![]);
+        self.send_command(CMD_GET_STATE, vec![]);
         let data = self.read_data(8).expect("read status");
 
         let vref = (data[0] as u16) + ((data[1] as u16) << 8);
@@ -194,10 +260,59 @@ impl JLink {
         data
     }
 
-    pub fn set_clock(&mut self, mut clock: u32) {
-        clock /= 1000;
-        let buf = vec![(clock & 0xff) as u8, ((clock >> 8) & 0xff) as u8];
-        self.send_command(0x5, buf);
+    /// Return the target's reference voltage in millivolts, from the GET_STATE command (0x07).
+    /// This is close to zero if the target is unpowered or not connected.
+    pub fn target_voltage(&mut self) -> Result<u32, CableError> {
+        self.send_command(CMD_GET_STATE, vec![]);
+        let data = self.read_data(8)?;
+        Ok(u16::from_le_bytes([data[0], data[1]]) as u32)
+    }
+
+    /// Return the probe's firmware version string, such as "J-Link V9 compiled ...", from the
+    /// VERSION command (0x01)
+    pub fn firmware_string(&mut self) -> Result<String, CableError> {
+        self.send_command(CMD_VERSION, vec![]);
+        let len = self.read_data(2)?;
+        let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+        let data = self.read_data(len)?;
+        // The string is padded with NULs to the length given
+        let end = data.iter().position(|x| *x == 0).unwrap_or(data.len());
+        Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+    }
+
+    /// Return the adapter's base frequency in hertz and the smallest divider it allows.  The
+    /// fastest TCK is the base frequency divided by the divider.
+    pub fn get_speeds(&mut self) -> (u32, u16) {
+        self.read_speeds().expect("read speeds")
+    }
+
+    fn read_speeds(&mut self) -> Result<(u32, u16), CableError> {
+        self.send_command(CMD_GET_SPEEDS, vec![]);
+        let data = self.read_data(6)?;
+        parse_speeds(&data)
+    }
+
+    /// Return every speed of TCK the adapter supports in hertz, fastest first, from the
+    /// GET_SPEEDS command (0xc0).  The adapter divides a base frequency by a whole number, and
+    /// takes speeds in kHz, so there is one speed per divider down to 1 kHz, skipping dividers
+    /// that give the same kHz.
+    pub fn supported_speeds(&mut self) -> Result<Vec<u32>, CableError> {
+        let (base, min_div) = self.read_speeds()?;
+        Ok(speeds(base, min_div.max(1) as u32))
+    }
+
+    /// Set the speed of TCK to the nearest of `supported_speeds` to `clock` hertz
+    pub fn set_clock(&mut self, clock: u32) {
+        let khz = nearest_speed(self.base_freq, self.min_div, clock) / 1000;
+        let khz = khz.clamp(1, SPEED_ADAPTIVE as u32 - 1) as u16;
+        self.send_command(CMD_SET_SPEED, khz.to_le_bytes().to_vec());
+    }
+
+    /// Let the adapter pace TCK by waiting for each edge to come back on RTCK, for targets whose
+    /// JTAG clock must be kept in step with a slow or changing core clock.  Use `set_clock` to go
+    /// back to a fixed speed.
+    pub fn set_speed_auto(&mut self) {
+        self.send_command(CMD_SET_SPEED, SPEED_ADAPTIVE.to_le_bytes().to_vec());
     }
 
     pub fn set_interface(&mut self, intf: u8) {
@@ -260,7 +375,7 @@ impl JLink {
             tms[len-1] |= 1 << (bits-1);
 
             // Add an extra clock for the transition to pause state
-            if total_bits % 8 == 0 {
+            if total_bits.is_multiple_of(8) {
                 data.push(0xff);
                 tms.push(0);
             }
@@ -271,7 +386,7 @@ impl JLink {
     }
 
     fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        if self.tms_buf.len() + data.len() + (bits as usize + 7) / 8 + 1 >= TAP_SEQUENCE_MAX {
+        if self.tms_buf.len() + data.len() + (bits as usize).div_ceil(8) + 1 >= TAP_SEQUENCE_MAX {
             return false;
         }
         self.queued_read_offsets.push(self.recv_bytes);
@@ -283,8 +398,18 @@ impl JLink {
 
 }
 
+/// Send any buffered commands, so the last writes aren't lost.  The responses aren't read, since
+/// that could block.  Errors are logged, since they can't be returned.
+impl Drop for JLink {
+    fn drop(&mut self) {
+        if let Err(e) = self.send_data() {
+            log::warn!("failed to flush jlink on drop: {}", e);
+        }
+    }
+}
+
 impl Cable for JLink {
-    fn change_mode(&mut self, tms: &[usize], tdo: bool) {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
         let mut buf = vec![];
         let mut byte = 0u8;
         for (i, x) in tms.iter().enumerate() {
@@ -299,54 +424,148 @@ impl Cable for JLink {
 
         // Push the last byte for cases when we don't have a multiple of 8
         // transitions.
-        if tms.len() % 8 != 0 {
+        if !tms.len().is_multiple_of(8) {
             buf.push(byte);
         }
 
-        let tdo_bytes = if tdo {
+        let tdi_bytes = if tdi {
             vec![0xff; buf.len()]
         } else {
             vec![0; buf.len()]
         };
 
-        self.tap_sequence(buf, tdo_bytes, tms.len());
+        self.tap_sequence(buf, tdi_bytes, tms.len());
+        Ok(())
     }
 
-    fn queue_read(&mut self, mut bits: usize) -> bool {
-        let bytes = (bits + 7) / 8;
+    fn queue_read(&mut self, mut bits: usize) -> Result<(), CableError> {
+        let bytes = bits.div_ceil(8);
         let buf = vec![0xff; bytes];
 
         bits %= 8;
         if bits == 0 {
             bits = 8;
         }
-        self.queue_read_write(&buf, bits as u8, false)
+        Cable::queue_read_write(self, &buf, bits as u8, false)
     }
 
-    fn read_data(&mut self, bits: usize) -> Vec<u8> {
-        self.queue_read(bits);
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        Cable::queue_read(self, bits)?;
         Cable::finish_read(self, bits)
     }
 
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) {
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
         self.send_tdo(data, bits, pause_after);
+        Ok(())
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
-        self.queue_read_write(data, bits, pause_after);
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        Cable::queue_read_write(self, data, bits, pause_after)?;
         let total_bits = (data.len()-1) * 8 + (bits as usize);
         Cable::finish_read(self, total_bits)
     }
 
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        self.queue_read_write(data, bits, pause_after)
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        if self.queue_read_write(data, bits, pause_after) {
+            Ok(())
+        } else {
+            Err(CableError::OutOfQueueSpace)
+        }
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        Ok(self.finish_read(bits)?)
+    }
+
+    /// Limited by the size of a single TAP sequence command, less the bytes `queue_read_write`
+    /// keeps in reserve for the last partial byte and the move to Pause
+    fn queue_capacity(&self) -> usize {
+        TAP_SEQUENCE_MAX - 3
+    }
+
+    fn queue_len(&self) -> usize {
+        self.tms_buf.len()
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.flush_tap_sequence();
+        let bytes = self.buffer.len();
+        self.read_data(0)?;
+        Ok(bytes)
+    }
+
+    /// The fastest speed reported by the adapter when it was opened
+    fn max_clock(&self) -> u32 {
+        self.base_freq / self.min_div
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        JLink::set_clock(self, hz);
+        Ok(())
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        JLink::assert_srst(self);
+        Ok(())
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        JLink::deassert_srst(self);
+        Ok(())
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        JLink::assert_trst(self);
+        Ok(())
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        JLink::deassert_trst(self);
+        Ok(())
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            srst: true,
+            trst: true,
+            adjustable_clock: true,
+            native_queue: true,
+            adaptive_clock: true,
+            ..CableCapabilities::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_speeds_reply() {
+        // Recorded from a J-Link V11: 60MHz base frequency, divider at least 4
+        let reply = [0x00, 0x87, 0x93, 0x03, 0x04, 0x00];
+        assert_eq!(parse_speeds(&reply).unwrap(), (60_000_000, 4));
+        assert!(parse_speeds(&reply[..5]).is_err());
     }
 
-    fn finish_read(&mut self, bits: usize) -> Vec<u8> {
-        self.finish_read(bits).expect("finish_read")
+    #[test]
+    fn nearest_speeds() {
+        assert_eq!(nearest_speed(60_000_000, 4, 15_000_000), 15_000_000);
+        // Faster than the adapter can go
+        assert_eq!(nearest_speed(60_000_000, 4, 100_000_000), 15_000_000);
+        assert_eq!(nearest_speed(60_000_000, 4, u32::MAX), 15_000_000);
+        // Between 15MHz and 12MHz
+        assert_eq!(nearest_speed(60_000_000, 4, 14_000_000), 15_000_000);
+        assert_eq!(nearest_speed(60_000_000, 4, 13_000_000), 12_000_000);
+        assert_eq!(nearest_speed(60_000_000, 4, 1_000), 1_000);
     }
 
-    fn flush(&mut self) {
-        self.read_data(0).expect("flush");
+    #[test]
+    fn speed_list() {
+        let list = speeds(60_000_000, 4);
+        assert_eq!(list[..4], [15_000_000, 12_000_000, 10_000_000, 8_571_428]);
+        assert_eq!(list.last().unwrap() / 1000, 1);
+        // One entry per kHz at most, fastest first
+        assert!(list.windows(2).all(|x| x[0] / 1000 > x[1] / 1000));
     }
 }
@@ -0,0 +1,213 @@
+//! A `Cable` that logs every operation at trace level with the `log` crate before passing it on
+//! to another cable.  Wrap a cable in `LoggingCable` to see the TMS, TDI, and TDO bits on the
+//! wire without changing the backend.
+use crate::cable::{BitOrder, Cable, CableCapabilities, CableError, CableGpio};
+
+use log::trace;
+
+/// Format `data` as hex bytes, in the order they are shifted
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().join(" ")
+}
+
+fn total_bits(data: &[u8], bits: u8) -> usize {
+    (data.len()-1) * 8 + bits as usize
+}
+
+pub struct LoggingCable<T> {
+    pub cable: T,
+}
+
+impl<T, U> LoggingCable<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Log every operation performed on `cable`
+    pub fn new(cable: T) -> Self {
+        Self {
+            cable,
+        }
+    }
+}
+
+impl<T, U> Cable for LoggingCable<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        let bits: String = tms.iter().map(|x| if *x != 0 { '1' } else { '0' }).collect();
+        trace!("change_mode tms={} tdi={}", bits, tdi as u8);
+        self.cable.change_mode(tms, tdi)
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        let bits: String = tms.iter().map(|x| if *x != 0 { '1' } else { '0' }).collect();
+        let tdo = self.cable.change_mode_capture(tms, tdi)?;
+        let tdo_bits: String = tdo.iter().map(|x| if *x { '1' } else { '0' }).collect();
+        trace!("change_mode_capture tms={} tdi={} tdo={}", bits, tdi as u8, tdo_bits);
+        Ok(tdo)
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.read_data(bits)?;
+        trace!("read_data bits={} tdo={}", bits, hex(&tdo));
+        Ok(tdo)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        trace!("write_data bits={} tdi={} pause_after={}", total_bits(data, bits), hex(data), pause_after);
+        self.cable.write_data(data, bits, pause_after)
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.read_write_data(data, bits, pause_after)?;
+        trace!("read_write_data bits={} tdi={} tdo={} pause_after={}",
+               total_bits(data, bits), hex(data), hex(&tdo), pause_after);
+        Ok(tdo)
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        trace!("flush");
+        self.cable.flush()
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        trace!("queue_read bits={}", bits);
+        self.cable.queue_read(bits)
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        trace!("queue_read_write bits={} tdi={} pause_after={}", total_bits(data, bits), hex(data), pause_after);
+        self.cable.queue_read_write(data, bits, pause_after)
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        trace!("set_bit_order {:?}", order);
+        self.cable.set_bit_order(order)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        trace!("set_clock hz={}", hz);
+        self.cable.set_clock(hz)
+    }
+
+    fn max_clock(&self) -> u32 {
+        self.cable.max_clock()
+    }
+
+    fn queue_capacity(&self) -> usize {
+        self.cable.queue_capacity()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.cable.queue_len()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        trace!("ping");
+        self.cable.ping()
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        trace!("assert_srst");
+        self.cable.assert_srst()
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        trace!("deassert_srst");
+        self.cable.deassert_srst()
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        trace!("assert_trst");
+        self.cable.assert_trst()
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        trace!("deassert_trst");
+        self.cable.deassert_trst()
+    }
+
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        trace!("set_output_enable enable={}", enable as u8);
+        self.cable.set_output_enable(enable)
+    }
+
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        let gpio = self.cable.read_gpio()?;
+        trace!("read_gpio srst={:?} trst={:?}", gpio.srst, gpio.trst);
+        Ok(gpio)
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.finish_read(bits)?;
+        trace!("finish_read bits={} tdo={}", bits, hex(&tdo));
+        Ok(tdo)
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        self.cable.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::MockCable;
+
+    use std::cell::RefCell;
+
+    thread_local! {
+        // Each test runs on its own thread, so it only sees its own messages
+        static LINES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target() == "jtag_taps::cable::logging" {
+                LINES.with(|x| x.borrow_mut().push(record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger;
+
+    fn capture(f: impl FnOnce()) -> Vec<String> {
+        // Another test may already have installed it
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        LINES.with(|x| x.borrow_mut().clear());
+        f();
+        LINES.with(|x| x.take())
+    }
+
+    #[test]
+    fn log_format() {
+        let mut mock = MockCable::with_tdo(vec![vec![0x5a, 0x01], vec![0x05]]);
+        let mut cable = LoggingCable::new(&mut mock);
+        let lines = capture(|| {
+            cable.change_mode(&[1, 1, 0], true).unwrap();
+            cable.write_data(&[0xa5, 0x03], 2, true).unwrap();
+            cable.read_write_data(&[0x12, 0x34], 1, false).unwrap();
+            cable.read_data(3).unwrap();
+            cable.set_clock(1_000_000).unwrap();
+            cable.flush().unwrap();
+        });
+        assert_eq!(lines, vec![
+            "change_mode tms=110 tdi=1",
+            "write_data bits=10 tdi=a5 03 pause_after=true",
+            "read_write_data bits=9 tdi=12 34 tdo=5a 01 pause_after=false",
+            "read_data bits=3 tdo=05",
+            "set_clock hz=1000000",
+            "flush",
+        ]);
+    }
+}
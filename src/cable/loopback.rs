@@ -0,0 +1,116 @@
+//! A `Cable` that behaves as if TDI were wired straight to TDO, with no TAP in between.  Every
+//! bit shifted out comes straight back in the same clock, so `read_write_data` returns exactly
+//! the data it was given.  Unlike `mock::MockCable`, nothing is scripted; this models a physical
+//! loopback, which makes it useful for checking that higher level code preserves bit patterns.
+use crate::cable::{Cable, CableError};
+
+use std::collections::VecDeque;
+
+#[derive(Default)]
+pub struct LoopbackCable {
+    read_queue: VecDeque<Vec<u8>>,
+}
+
+impl LoopbackCable {
+    /// Create a LoopbackCable
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Cable for LoopbackCable {
+    /// TMS doesn't affect a loopback, so this does nothing
+    fn change_mode(&mut self, _tms: &[usize], _tdi: bool) -> Result<(), CableError> {
+        Ok(())
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        Ok(vec![tdi; tms.len()])
+    }
+
+    /// `read_data` clocks out all ones, so that's what comes back
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let mut data = vec![0xff; bits.div_ceil(8)];
+        if !bits.is_multiple_of(8) {
+            let end = data.len() - 1;
+            data[end] = (1 << (bits % 8)) - 1;
+        }
+        Ok(data)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.read_write_data(data, bits, pause_after)?;
+        Ok(())
+    }
+
+    /// Returns `data` with any bits past the end of the shift cleared
+    fn read_write_data(&mut self, data: &[u8], bits: u8, _pause_after: bool) -> Result<Vec<u8>, CableError> {
+        if data.is_empty() {
+            return Err(CableError::Protocol("nothing to shift".to_string()));
+        }
+        if bits == 0 || bits > 8 {
+            return Err(CableError::Protocol(format!("bad bit count {} for the last byte", bits)));
+        }
+
+        let mut data = data.to_vec();
+        let end = data.len() - 1;
+        data[end] &= (0xff_u16 >> (8 - bits)) as u8;
+        Ok(data)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let data = self.read_data(bits)?;
+        self.read_queue.push_back(data);
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let result = self.read_write_data(data, bits, pause_after)?;
+        self.read_queue.push_back(result);
+        Ok(())
+    }
+
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        self.read_queue.pop_front()
+            .ok_or_else(|| CableError::Protocol("finish_read without a queued read".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_round_trip() {
+        let mut cable = LoopbackCable::new();
+        // A xorshift generator, so the pattern is the same every run
+        let mut x = 0x2545f491_u32;
+        for len in 1..64 {
+            let data: Vec<u8> = (0..len).map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            }).collect();
+            let bits = (x % 8) as u8 + 1;
+
+            let mut expected = data.clone();
+            expected[len - 1] &= (0xff_u16 >> (8 - bits)) as u8;
+            assert_eq!(cable.read_write_data(&data, bits, false).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn bad_shifts() {
+        let mut cable = LoopbackCable::new();
+        assert!(matches!(cable.read_write_data(&[], 8, false), Err(CableError::Protocol(_))));
+        assert!(matches!(cable.read_write_data(&[0xff], 0, false), Err(CableError::Protocol(_))));
+        assert!(matches!(cable.read_write_data(&[0xff], 9, false), Err(CableError::Protocol(_))));
+    }
+
+
+    #[test]
+    fn capabilities_match() {
+        crate::cable::tests::check_capabilities(&mut LoopbackCable::new());
+    }
+}
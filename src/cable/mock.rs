@@ -0,0 +1,185 @@
+//! An in-memory `Cable` for testing higher level code without any hardware attached.  It replays
+//! scripted TDO responses and records every operation so they can be checked afterwards.
+use crate::bits::Bits;
+use crate::cable::{Cable, CableCapabilities, CableError, CableGpio};
+
+use std::collections::VecDeque;
+
+/// One call made on a `MockCable`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    ChangeMode { tms: Vec<usize>, tdi: bool },
+    ChangeModeCapture { tms: Vec<usize>, tdi: bool },
+    Read { bits: usize },
+    Write { data: Vec<u8>, bits: u8, pause_after: bool },
+    ReadWrite { data: Vec<u8>, bits: u8, pause_after: bool },
+    Flush,
+    SetClock { hz: u32 },
+    SetOutputEnable { enable: bool },
+}
+
+#[derive(Default)]
+pub struct MockCable {
+    // Scripted responses for reads, in order
+    tdo: VecDeque<Vec<u8>>,
+    recorded: Vec<Operation>,
+    read_queue: VecDeque<Vec<u8>>,
+    // What read_gpio returns, if the mock should support it
+    gpio: Option<CableGpio>,
+    capabilities: CableCapabilities,
+}
+
+impl MockCable {
+    /// Create a MockCable with no scripted responses
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a MockCable that answers each read, in order, with the next entry of `responses`.
+    /// Once `responses` runs out, reads return all ones, like a TDO line with nothing driving it.
+    pub fn with_tdo(responses: Vec<Vec<u8>>) -> Self {
+        Self {
+            tdo: responses.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Make `read_gpio` return `gpio`.  Until this is called, it returns
+    /// `CableError::Unsupported`.
+    pub fn set_gpio(&mut self, gpio: CableGpio) {
+        self.gpio = Some(gpio);
+    }
+
+    /// Make `capabilities` return `capabilities`.  Until this is called, it reports none.
+    pub fn set_capabilities(&mut self, capabilities: CableCapabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Every operation performed on the cable so far
+    pub fn recorded(&self) -> &[Operation] {
+        &self.recorded
+    }
+
+    fn next_tdo(&mut self, bits: usize) -> Vec<u8> {
+        self.tdo.pop_front().unwrap_or_else(|| vec![0xff; bits.div_ceil(8)])
+    }
+}
+
+impl Cable for MockCable {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.recorded.push(Operation::ChangeMode { tms: tms.to_vec(), tdi });
+        Ok(())
+    }
+
+    /// The TDO samples are the bits of the next scripted response
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        self.recorded.push(Operation::ChangeModeCapture { tms: tms.to_vec(), tdi });
+        let mut tdo = self.next_tdo(tms.len());
+        tdo.resize(tms.len().div_ceil(8), 0xff);
+        Ok(Bits::new(tdo, tms.len()).iter().collect())
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.recorded.push(Operation::Read { bits });
+        Ok(self.next_tdo(bits))
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.recorded.push(Operation::Write { data: data.to_vec(), bits, pause_after });
+        Ok(())
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.recorded.push(Operation::ReadWrite { data: data.to_vec(), bits, pause_after });
+        Ok(self.next_tdo(data.len() * 8))
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.recorded.push(Operation::Flush);
+        Ok(0)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.recorded.push(Operation::SetClock { hz });
+        Ok(())
+    }
+
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.recorded.push(Operation::SetOutputEnable { enable });
+        Ok(())
+    }
+
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        self.gpio.ok_or(CableError::Unsupported)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let data = self.read_data(bits)?;
+        self.read_queue.push_back(data);
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let result = self.read_write_data(data, bits, pause_after)?;
+        self.read_queue.push_back(result);
+        Ok(())
+    }
+
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        self.read_queue.pop_front()
+            .ok_or_else(|| CableError::Protocol("finish_read without a queued read".to_string()))
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        self.capabilities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_length() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x05]]);
+        let tdo = cable.change_mode_capture(&[1, 1, 0], false).unwrap();
+        assert_eq!(tdo, vec![true, false, true]);
+        // Past the scripted responses, TDO reads as ones
+        assert_eq!(cable.change_mode_capture(&[0; 10], false).unwrap(), vec![true; 10]);
+    }
+
+    #[test]
+    fn records_operations() {
+        let mut cable = MockCable::new();
+        cable.change_mode(&[1, 0], true).unwrap();
+        cable.write_data(&[0xa5, 0x01], 1, true).unwrap();
+        cable.flush().unwrap();
+        cable.set_clock(1_000_000).unwrap();
+        assert_eq!(cable.recorded(), &[
+            Operation::ChangeMode { tms: vec![1, 0], tdi: true },
+            Operation::Write { data: vec![0xa5, 0x01], bits: 1, pause_after: true },
+            Operation::Flush,
+            Operation::SetClock { hz: 1_000_000 },
+        ]);
+    }
+
+    #[test]
+    fn scripted_tdo_in_order() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x11], vec![0x22, 0x33]]);
+        assert_eq!(cable.read_data(8).unwrap(), vec![0x11]);
+        assert_eq!(cable.read_write_data(&[0x00, 0x00], 8, false).unwrap(), vec![0x22, 0x33]);
+        assert_eq!(cable.read_data(12).unwrap(), vec![0xff, 0xff]);
+    }
+
+    #[test]
+    fn queued_reads_in_order() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x11], vec![0x22], vec![0x33]]);
+        cable.queue_read(8).unwrap();
+        cable.queue_read_write(&[0xaa], 8, false).unwrap();
+        cable.queue_read(8).unwrap();
+        assert_eq!(cable.finish_read(8).unwrap(), vec![0x11]);
+        assert_eq!(cable.finish_read(8).unwrap(), vec![0x22]);
+        assert_eq!(cable.finish_read(8).unwrap(), vec![0x33]);
+        assert!(matches!(cable.finish_read(8), Err(CableError::Protocol(_))));
+    }
+}
@@ -1,337 +1,412 @@
-//! Implement the `Cable` trait for "jtagkey" compatible hardware adapters like the Bus Blaster
-use crate::cable::Cable;
+//! Implement the `Cable` trait for "jtagkey" compatible hardware adapters like the Bus Blaster,
+//! through the FTDI D2XX driver
+use crate::cable::{BitOrder, Cable, CableCapabilities, CableError, CableGpio};
+use crate::cable::mpsse_common::*;
+
+pub use crate::cable::mpsse_common::{Interface, Mpsse, PinMap};
 
 use std::time::Duration;
 
-use libftd2xx::{Ft2232h, Ftdi, FtdiMpsse, MpsseCmdBuilder, MpsseCmdExecutor, FtdiCommon};
-use ftdi_mpsse::{ClockTMSOut, ClockTMS};
-use libftd2xx::{ClockData, ClockDataOut, ClockBits, ClockBitsOut};
+use libftd2xx::{DeviceType, Ft2232h, Ft4232h, Ftdi, FtdiMpsse, MpsseCmdExecutor, FtdiCommon, TimeoutError};
 
-const MAX_BUFFER_SIZE: usize = 4096;
+impl<T: FtdiMpsse + MpsseCmdExecutor<Error = TimeoutError>> MpsseDevice for T {
+    fn init(&mut self) -> Result<(), CableError> {
+        Ok(self.initialize_mpsse_default()?)
+    }
 
-pub struct Mpsse<T> {
-    ft: T,
-    // Data to send to the adapter
-    buffer: Vec<u8>,
-    // Data we have read from the adapter and not yet returned
-    queued_reads: Vec<u8>,
-    // (bits, bytes, write, pause)
-    queued_read_state: Vec<(usize, usize, bool, bool)>,
-}
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        Ok(FtdiMpsse::set_clock(self, hz)?)
+    }
 
-impl<T: FtdiMpsse + MpsseCmdExecutor> Mpsse<T>
-    where <T as MpsseCmdExecutor>::Error: std::fmt::Debug
-{
-    pub fn new(mut ft: T, clock: u32) -> Self
-    {
-        ft.initialize_mpsse_default().expect("init");
-        ft.set_clock(clock).expect("set clock");
-
-        let builder = MpsseCmdBuilder::new()
-            .disable_3phase_data_clocking()
-            .disable_adaptive_data_clocking();
-        ft.send(builder.as_slice()).expect("send");
-
-        Self {
-            ft,
-            buffer: vec![],
-            queued_reads: vec![],
-            queued_read_state: vec![],
-        }
+    fn send(&mut self, data: &[u8]) -> Result<(), CableError> {
+        Ok(MpsseCmdExecutor::send(self, data)?)
+    }
+
+    fn recv(&mut self, response: &mut [u8]) -> Result<(), CableError> {
+        Ok(MpsseCmdExecutor::recv(self, response)?)
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.modem_status()?;
+        Ok(())
     }
 }
 
-impl<T: FtdiMpsse + MpsseCmdExecutor> Cable for Mpsse<T>
-    where <T as MpsseCmdExecutor>::Error: std::fmt::Debug
-{
-    fn change_mode(&mut self, tms: &[usize], tdo: bool) {
-        let mut count = 0;
-        let mut buf = 0;
-        let mut builder = MpsseCmdBuilder::new();
-
-        for x in tms {
-            if *x != 0 {
-                buf |= 1 << count;
-            }
-            count += 1;
-
-            if count == 7 {
-                builder = builder.clock_tms_out(ClockTMSOut::NegEdge, buf, tdo, count);
-                count = 0;
-                buf = 0;
-            }
-        }
-        builder = builder.clock_tms_out(ClockTMSOut::NegEdge, buf, tdo, count);
-        let len = builder.as_slice().len();
-        if len + self.buffer.len() > MAX_BUFFER_SIZE {
-            self.flush();
-        }
-        self.buffer.append(&mut builder.as_slice().to_vec());
+// USB read and write timeout until JtagKey::set_timeouts is called
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct JtagKey {
+    ft: Mpsse<Ft2232h>,
+    pins: JtagPins,
+}
+
+impl JtagKey {
+    /// Create a new JtagKey.  FT2232-based adapters like JtagKey have both an "A" interface and a
+    /// "B" interface.  `primary` controls which to use. `clock` controls the speed of TCLK in hertz.
+    pub fn new(clock: u32, primary: bool) -> Self {
+        Self::try_new(clock, primary).expect("new")
     }
 
-    fn queue_read(&mut self, mut bits: usize) -> bool
-    {
-        let orig_bits = bits;
+    /// Like `new`, but returns an error instead of panicking if the adapter isn't attached or
+    /// can't be initialized.  The device is closed again if any step of the setup fails.
+    pub fn try_new(clock: u32, primary: bool) -> Result<Self, CableError> {
+        let interface = if primary {
+            Interface::A
+        } else {
+            Interface::B
+        };
+        Self::with_interface("Dual RS232-HS", interface, clock)
+    }
 
-        let mut bytes = bits / 8;
-        let mut builder = MpsseCmdBuilder::new();
-        if bytes > 0 {
-            bits -= bytes * 8;
-            builder = builder.clock_data(ClockData::LsbPosIn, &vec![0xff; bytes]);
-        }
+    /// Open interface `interface` of the adapter whose USB product description is
+    /// `description`, such as "Dual RS232-HS" for a stock FT2232H.  The FTDI driver names each
+    /// interface by appending " A" or " B" to the product description, so `description` must
+    /// not include the suffix.  `clock` controls the speed of TCLK in hertz.
+    pub fn with_interface(description: &str, interface: Interface, clock: u32) -> Result<Self, CableError> {
+        let suffix = match interface {
+            Interface::A => "A",
+            Interface::B => "B",
+        };
+        let ft = Ftdi::with_description(&format!("{} {}", description, suffix))?;
+        Self::init(ft, PinMap::jtagkey(), clock)
+    }
 
-        if bits > 0 {
-            builder = builder.clock_bits(ClockBits::LsbPosIn, 0xff, bits as u8);
-            bytes += 1;
-        }
+    /// Like `with_interface`, for adapters whose reset lines and buffer enables aren't wired
+    /// like a jtagkey's
+    pub fn with_pins(description: &str, interface: Interface, pins: PinMap, clock: u32) -> Result<Self, CableError> {
+        let suffix = match interface {
+            Interface::A => "A",
+            Interface::B => "B",
+        };
+        let ft = Ftdi::with_description(&format!("{} {}", description, suffix))?;
+        Self::init(ft, pins, clock)
+    }
 
-        let len = builder.as_slice().len();
-        if len + self.buffer.len() > MAX_BUFFER_SIZE {
-            self.flush();
-        }
+    /// Open a Tigard.  `clock` controls the speed of TCLK in hertz.
+    pub fn tigard(clock: u32) -> Result<Self, CableError> {
+        Self::with_pins("Tigard V1.1", Interface::B, PinMap::tigard(), clock)
+    }
 
-        let total_bytes = bytes + self.queued_read_state.iter()
-            .map(|x| (x.0 + 7) / 8)
-            .sum::<usize>();
+    /// Open the adapter interface whose serial number is `serial`.  Unlike descriptions, serial
+    /// numbers are unique, so this picks out one of several identical adapters.  The driver
+    /// gives each interface a serial number of its own, usually the chip's serial number with
+    /// "A" or "B" appended, so use a serial number from `list`.  Only the serial number is
+    /// matched; the description isn't looked at, even if it would match another adapter.
+    pub fn with_serial(serial: &str, clock: u32) -> Result<Self, CableError> {
+        let ft = Ftdi::with_serial_number(serial)?;
+        Self::init(ft, PinMap::jtagkey(), clock)
+    }
 
-        if total_bytes < MAX_BUFFER_SIZE {
-            self.queued_read_state.push((orig_bits, bytes, false, false));
-            self.buffer.append(&mut builder.as_slice().to_vec());
-            true
-        } else {
-            false
-        }
+    /// List the (serial number, description) of every FT2232H interface attached, whether or
+    /// not it's in use, for choosing one to pass to `with_serial`
+    pub fn list() -> Result<Vec<(String, String)>, CableError> {
+        Ok(libftd2xx::list_devices()?.into_iter()
+            .filter(|x| x.device_type == DeviceType::FT2232H)
+            .map(|x| (x.serial_number, x.description))
+            .collect())
     }
 
-    fn finish_read(&mut self, mut bits: usize) -> Vec<u8>
-    {
-        let (orig_bits, bytes, pause_after, write) = self.queued_read_state.remove(0);
-        assert_eq!(bits, orig_bits);
+    fn init(ft: Ftdi, pins: PinMap, clock: u32) -> Result<Self, CableError> {
+        let ft = Ft2232h::try_from(ft)?;
+        let mut ft = Mpsse::try_new(ft, clock)?;
+        ft.ft.set_latency_timer(Duration::from_millis(0))?;
+        ft.ft.set_timeouts(DEFAULT_TIMEOUT, DEFAULT_TIMEOUT)?;
+        let pins = JtagPins::init(pins, &mut ft)?;
 
-        if self.queued_reads.is_empty() {
-            // Read all of the pending bytes
-            let total_bytes = bytes + self.queued_read_state.iter()
-                .map(|x| x.1)
-                .sum::<usize>();
-            self.queued_reads.resize(total_bytes, 0);
-            self.ft.xfer(&self.buffer, &mut self.queued_reads).expect("send");
-            self.buffer.clear();
-        }
+        Ok(JtagKey { ft, pins })
+    }
 
-        let mut buf = self.queued_reads.split_off(bytes);
-        // split_off returns the second half of the vec, but we want the first half
-        std::mem::swap(&mut buf, &mut self.queued_reads);
+    /// Set how long a single USB read or write may take before it fails with
+    /// `CableError::Timeout`, so a hung adapter doesn't block forever.  The default is 5 seconds
+    /// each way.  Long shifts are split into transfers of at most 4096 bytes, and the timeout
+    /// applies to each transfer rather than the whole shift, so it only needs raising for very
+    /// slow clocks: 4096 bytes take about 33ms at 1MHz, but 6 minutes at 92Hz.
+    pub fn set_timeouts(&mut self, read_ms: u32, write_ms: u32) -> Result<(), CableError> {
+        let read = Duration::from_millis(read_ms.into());
+        let write = Duration::from_millis(write_ms.into());
+        self.ft.ft.set_timeouts(read, write)?;
+        Ok(())
+    }
 
-        if pause_after {
-            buf.pop();
-        }
+    /// Set the maximum number of bytes sent in a single MPSSE command.  See
+    /// `Mpsse::set_max_chunk`.
+    pub fn set_max_chunk(&mut self, max_chunk: usize) {
+        self.ft.set_max_chunk(max_chunk);
+    }
 
-        if write {
-            let len = buf.len();
-            buf[len-1] >>= 7;
+    /// Enable or disable adaptive clocking with RTCK.  JtagKey compatible adapters route RTCK to
+    /// ADBUS7, which is left as an input.  See `Mpsse::set_adaptive_clocking`.
+    pub fn set_adaptive_clocking(&mut self, enable: bool) -> Result<(), CableError> {
+        self.ft.set_adaptive_clocking(enable)
+    }
 
-            bits -= 1;
-            if bits >= 1 {
-                // Shift the bits from clock_bits
-                buf[len-2] >>= 8 - (bits % 8);
+    /// Enable or disable 3-phase data clocking.  See `Mpsse::set_three_phase_clocking`.
+    pub fn set_three_phase_clocking(&mut self, enable: bool) -> Result<(), CableError> {
+        self.ft.set_three_phase_clocking(enable)
+    }
 
-                // Need to repack the bit from clock_tms into the bits from clock_bits
-                let last_recv = buf[len-1] & 1;
-                buf[len-2] |=  last_recv << (bits % 8);
-                buf.pop();
-            }
-        } else {
-            if bits % 8 != 0 {
-                let last_idx = buf.len()-1;
-                buf[last_idx] >>= 8 - (bits % 8);
-            }
-        }
-        buf
+    /// Send raw MPSSE commands to the adapter, such as ones driving a GPIO this crate doesn't
+    /// use.  This can leave the TAP state tracking out of step.  See `Mpsse::send_raw`.
+    pub fn send_raw(&mut self, cmd: &[u8]) -> Result<(), CableError> {
+        self.ft.send_raw(cmd)
     }
 
-    fn read_data(&mut self, bits: usize) -> Vec<u8>
-    {
-        assert!(self.queued_read_state.is_empty());
-        self.queue_read(bits);
-        self.finish_read(bits)
+    /// Send raw MPSSE commands and read back `read_len` bytes.  See `Mpsse::xfer_raw`.
+    pub fn xfer_raw(&mut self, cmd: &[u8], read_len: usize) -> Result<Vec<u8>, CableError> {
+        self.ft.xfer_raw(cmd, read_len)
     }
 
-    fn write_data(&mut self, data: &[u8], mut bits: u8, pause_after: bool)
-    {
-        let mut builder = MpsseCmdBuilder::new();
-        assert!(bits <= 8);
-        assert!(bits != 0);
+    /// The TCK frequency the adapter is really running at.  See `Mpsse::actual_clock`.
+    pub fn actual_clock(&self) -> u32 {
+        self.ft.actual_clock()
+    }
 
-        // We will send the last bit using clock_tms
-        bits -= 1;
+    #[deprecated(note = "use Cable::deassert_srst")]
+    pub fn dessert_srst(&mut self) -> Result<(), CableError> {
+        self.deassert_srst()
+    }
+}
 
-        if data.len() > 1 {
-            builder = builder.clock_data_out(ClockDataOut::LsbNeg, &data[..data.len()-1]);
-        }
-        let last_byte = data[data.len()-1];
-        if bits >= 1 {
-            builder = builder.clock_bits_out(ClockBitsOut::LsbNeg, last_byte, bits);
-        }
-        let last_bit = last_byte & (1 << bits) != 0;
-        // Change to pause state
-        if pause_after {
-            builder = builder.clock_tms_out(ClockTMSOut::NegEdge, 1, last_bit, 2);
-        } else {
-            builder = builder.clock_tms_out(ClockTMSOut::NegEdge, 0, last_bit, 1);
+/// Send any buffered commands, so the last writes aren't lost.  Queued reads are never waited
+/// for.  Errors are logged, since they can't be returned.
+impl Drop for JtagKey {
+    fn drop(&mut self) {
+        if let Err(e) = self.ft.flush() {
+            log::warn!("failed to flush jtagkey on drop: {}", e);
         }
+    }
+}
 
-        let len = builder.as_slice().len();
-        if len + self.buffer.len() > MAX_BUFFER_SIZE {
-            self.flush();
-        }
-        self.buffer.append(&mut builder.as_slice().to_vec());
+impl Cable for JtagKey {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.ft.change_mode(tms, tdi)
     }
 
-    fn queue_read_write(&mut self, data: &[u8], mut bits: u8, pause_after: bool) -> bool {
-        let total_bits = (data.len()-1) * 8 + bits as usize;
-        let mut read_bytes = 1;
-        let mut builder = MpsseCmdBuilder::new();
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        self.ft.change_mode_capture(tms, tdi)
+    }
 
-        assert!(bits <= 8);
-        assert!(bits != 0);
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.ft.read_data(bits)
+    }
 
-        // We will send the last bit using clock_tms
-        bits -= 1;
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.ft.write_data(data, bits, pause_after)
+    }
 
-        if data.len() > 1 {
-            builder = builder.clock_data(ClockData::LsbPosIn, &data[..data.len()-1]);
-            read_bytes += data.len()-1;
-        }
-        let last_byte = data[data.len()-1];
-        if bits >= 1 {
-            builder = builder.clock_bits(ClockBits::LsbPosIn, last_byte, bits);
-            read_bytes += 1;
-        }
-        let last_bit = last_byte & (1 << bits) != 0;
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.ft.read_write_data(data, bits, pause_after)
+    }
 
-        // Change to pause state
-        if pause_after {
-            builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, 1, last_bit, 1);
-            read_bytes += 1;
-        }
-        builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, 0, last_bit, 1);
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.ft.queue_read_write(data, bits, pause_after)
+    }
 
-        let len = builder.as_slice().len();
-        if len + self.buffer.len() > MAX_BUFFER_SIZE {
-            self.flush();
-        }
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.ft.flush()
+    }
 
-        let total_bytes = read_bytes + self.queued_read_state.iter()
-            .map(|x| x.1)
-            .sum::<usize>();
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        self.ft.queue_read(bits)
+    }
 
-        if total_bytes < MAX_BUFFER_SIZE {
-            self.queued_read_state.push((total_bits, read_bytes, true, pause_after));
-            self.buffer.append(&mut builder.as_slice().to_vec());
-            true
-        } else {
-            false
-        }
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.ft.finish_read(bits)
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
-        assert!(self.queued_read_state.is_empty());
-        self.queue_read_write(data, bits, pause_after);
-        let total_bits = (data.len()-1) * 8 + bits as usize;
-        self.finish_read(total_bits)
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.ft.set_bit_order(order)
     }
 
-    fn flush(&mut self) {
-        self.ft.send(&self.buffer).expect("flush");
-        self.buffer.clear();
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.ft.set_clock(hz)
     }
-}
 
-// Lower pins
-const PIN_TCK: u8 = 1;
-const PIN_TDI: u8 = 1 << 1;
-//const PIN_TDO: u8 = 1 << 2;
-const PIN_TMS: u8 = 1 << 3;
-const PIN_N_OE: u8 = 1 << 4;
-const LOWER_OUTPUT_PINS: u8 = PIN_TCK | PIN_TDI | PIN_TMS | PIN_N_OE;
-
-// Upper pins
-const PIN_N_TRST: u8 = 1;
-const PIN_N_SRST: u8 = 1 << 1;
-const PIN_N_TRST_OE: u8 = 1 << 2;
-const PIN_N_SRST_OE: u8 = 1 << 3;
-const UPPER_OUTPUT_PINS: u8 = PIN_N_TRST | PIN_N_SRST | PIN_N_TRST_OE | PIN_N_SRST_OE;
+    fn max_clock(&self) -> u32 {
+        self.ft.max_clock()
+    }
 
-pub struct JtagKey {
-    ft: Mpsse<Ft2232h>,
+    fn queue_capacity(&self) -> usize {
+        self.ft.queue_capacity()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.ft.queue_len()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.ft.ping()
+    }
+
+    /// Puts the system in reset, if the adapter has an SRST line
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.pins.set_srst(&mut self.ft, true)
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.pins.set_srst(&mut self.ft, false)
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.pins.set_trst(&mut self.ft, true)
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.pins.set_trst(&mut self.ft, false)
+    }
+
+    /// Drives the active low output enable, ADBUS4 on a jtagkey, high to tri-state the JTAG
+    /// buffer and low to enable it.  Queued commands are flushed first.
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.pins.set_output_enable(&mut self.ft, enable)
+    }
+
+    /// Reads the GPIO pins.  Any queued JTAG commands are flushed first.  Panics if reads are
+    /// queued, since their results would be mixed up with the pin state.
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        self.pins.read(&mut self.ft)
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        self.pins.capabilities()
+    }
 }
 
-impl JtagKey {
-    /// Create a new JtagKey.  FT2232-based adapters like JtagKey have both an "A" interface and a
-    /// "B" interface.  `primary` controls which to use. `clock` controls the speed of TCLK in hertz.
-    pub fn new(clock: u32, primary: bool) -> Self {
-        let description = if primary {
-            "Dual RS232-HS A"
-        } else {
-            "Dual RS232-HS B"
+/// A JTAG chain on one channel of an FT4232H.  Only interfaces A and B of the FT4232H have an
+/// MPSSE, so those are the only ones that can be used; C and D are UART only.  Each interface is
+/// opened with its own handle, so the two can drive separate JTAG chains at the same time.
+/// Unlike the FT2232H, the FT4232H has no upper GPIO pins, so there are no reset lines.
+pub struct Ft4232hJtag {
+    ft: Mpsse<Ft4232h>,
+}
+
+impl Ft4232hJtag {
+    /// Open interface `interface` of a stock FT4232H, whose product description is
+    /// "Quad RS232-HS".  `clock` controls the speed of TCLK in hertz.
+    pub fn try_new(interface: Interface, clock: u32) -> Result<Self, CableError> {
+        Self::with_interface("Quad RS232-HS", interface, clock)
+    }
+
+    /// Open interface `interface` of the FT4232H whose USB product description is
+    /// `description`, without the " A" or " B" the FTDI driver appends for each interface.  See
+    /// `JtagKey::with_interface`.
+    pub fn with_interface(description: &str, interface: Interface, clock: u32) -> Result<Self, CableError> {
+        let suffix = match interface {
+            Interface::A => "A",
+            Interface::B => "B",
         };
-        let ft = Ftdi::with_description(description).expect("new");
-        let ft = Ft2232h::try_from(ft).expect("try");
-        let mut ft = Mpsse::new(ft, clock);
-        ft.ft.set_latency_timer(Duration::from_millis(0)).expect("latency");
-        ft.ft.set_gpio_upper(PIN_N_TRST | PIN_N_SRST, UPPER_OUTPUT_PINS).expect("pins");
-
-        let builder = MpsseCmdBuilder::new()
-            .set_gpio_lower(PIN_TMS, LOWER_OUTPUT_PINS);
-        ft.ft.send(builder.as_slice()).expect("send");
-
-        JtagKey {
-            ft,
-        }
+        let ft = Ftdi::with_description(&format!("{} {}", description, suffix))?;
+        let ft = Ft4232h::try_from(ft)?;
+        let mut ft = Mpsse::try_new(ft, clock)?;
+        ft.ft.set_latency_timer(Duration::from_millis(0))?;
+        ft.ft.set_timeouts(DEFAULT_TIMEOUT, DEFAULT_TIMEOUT)?;
+
+        ft.set_gpio(PIN_TMS as u16, (PIN_TCK | PIN_TDI | PIN_TMS) as u16, 0xff)?;
+
+        Ok(Self { ft })
+    }
+
+    /// See `JtagKey::set_timeouts`
+    pub fn set_timeouts(&mut self, read_ms: u32, write_ms: u32) -> Result<(), CableError> {
+        let read = Duration::from_millis(read_ms.into());
+        let write = Duration::from_millis(write_ms.into());
+        self.ft.ft.set_timeouts(read, write)?;
+        Ok(())
     }
 
-    /// JtagKey adapters implement the option SRST signal.  This function puts the system in reset.
-    pub fn assert_srst(&mut self) {
-        self.ft.ft.set_gpio_upper(PIN_N_TRST, UPPER_OUTPUT_PINS).expect("pins");
+    /// Set the maximum number of bytes sent in a single MPSSE command.  See
+    /// `Mpsse::set_max_chunk`.
+    pub fn set_max_chunk(&mut self, max_chunk: usize) {
+        self.ft.set_max_chunk(max_chunk);
     }
 
-    /// JtagKey adapters implement the option SRST signal.  This function takes the system out of
-    /// reset.
-    pub fn dessert_srst(&mut self) {
-        self.ft.ft.set_gpio_upper(PIN_N_TRST | PIN_N_SRST, UPPER_OUTPUT_PINS).expect("pins");
+    /// Enable or disable 3-phase data clocking.  See `Mpsse::set_three_phase_clocking`.
+    pub fn set_three_phase_clocking(&mut self, enable: bool) -> Result<(), CableError> {
+        self.ft.set_three_phase_clocking(enable)
+    }
+
+    /// The TCK frequency the adapter is really running at.  See `Mpsse::actual_clock`.
+    pub fn actual_clock(&self) -> u32 {
+        self.ft.actual_clock()
     }
 }
 
-impl Cable for JtagKey {
-    fn change_mode(&mut self, tms: &[usize], tdo: bool) {
-        self.ft.change_mode(tms, tdo)
+/// Send any buffered commands, like `JtagKey` does
+impl Drop for Ft4232hJtag {
+    fn drop(&mut self) {
+        if let Err(e) = self.ft.flush() {
+            log::warn!("failed to flush ft4232h on drop: {}", e);
+        }
+    }
+}
+
+impl Cable for Ft4232hJtag {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.ft.change_mode(tms, tdi)
     }
 
-    fn read_data(&mut self, bits: usize) -> Vec<u8> {
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        self.ft.change_mode_capture(tms, tdi)
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
         self.ft.read_data(bits)
     }
 
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) {
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
         self.ft.write_data(data, bits, pause_after)
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
         self.ft.read_write_data(data, bits, pause_after)
     }
 
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
         self.ft.queue_read_write(data, bits, pause_after)
     }
 
-    fn flush(&mut self) {
-        self.ft.flush();
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.ft.flush()
     }
 
-    fn queue_read(&mut self, bits: usize) -> bool {
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
         self.ft.queue_read(bits)
     }
 
-    fn finish_read(&mut self, bits: usize) -> Vec<u8> {
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
         self.ft.finish_read(bits)
     }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.ft.set_bit_order(order)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.ft.set_clock(hz)
+    }
+
+    fn max_clock(&self) -> u32 {
+        self.ft.max_clock()
+    }
+
+    fn queue_capacity(&self) -> usize {
+        self.ft.queue_capacity()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.ft.queue_len()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.ft.ping()
+    }
+
+    /// `Ft4232hJtag` has no reset pins, no output enable, and no way to turn on adaptive clocking
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            adjustable_clock: true,
+            native_queue: true,
+            ..CableCapabilities::default()
+        }
+    }
 }
@@ -0,0 +1,1093 @@
+//! The MPSSE engine shared by the D2XX backend in `mpsse` and the libusb backend in
+//! `mpsse_libusb`.  Everything that builds MPSSE commands lives here, so the two backends only
+//! differ in how the commands reach the chip.
+use crate::cable::{BitOrder, Cable, CableCapabilities, CableError, CableGpio};
+
+use std::collections::VecDeque;
+
+use ftdi_mpsse::{ClockBits, ClockBitsOut, ClockData, ClockDataOut, ClockTMS, ClockTMSOut, MpsseCmdBuilder};
+
+/// How an `Mpsse` talks to its chip.  Implementations only move bytes; they never build
+/// commands themselves, apart from the clock divisor.
+pub trait MpsseDevice {
+    /// Put the chip in MPSSE mode and check that the engine is responding
+    fn init(&mut self) -> Result<(), CableError>;
+    /// Set the TCK frequency to `hz`, which is within the range the chip supports
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError>;
+    /// Send raw MPSSE commands
+    fn send(&mut self, data: &[u8]) -> Result<(), CableError>;
+    /// Send raw MPSSE commands, then read back exactly `response.len()` bytes
+    fn xfer(&mut self, data: &[u8], response: &mut [u8]) -> Result<(), CableError> {
+        self.send(data)?;
+        self.recv(response)
+    }
+    /// Read exactly `response.len()` bytes
+    fn recv(&mut self, response: &mut [u8]) -> Result<(), CableError>;
+    /// Check that the chip is still attached, without going through the MPSSE engine
+    fn ping(&mut self) -> Result<(), CableError>;
+}
+
+const MAX_BUFFER_SIZE: usize = 4096;
+// A single MPSSE clock_data command can carry at most 65536 bytes
+const DEFAULT_MAX_CHUNK: usize = 65535;
+// Range of TCK frequencies the high speed MPSSE chips can generate
+const MIN_CLOCK: u32 = 92;
+const MAX_CLOCK: u32 = 30_000_000;
+// The TCK frequency used when the constructor is given a clock of 0
+const DEFAULT_CLOCK: u32 = 1_000_000;
+
+/// The base clock and divisor a high speed MPSSE chip uses for `hz`: 30MHz, or 6MHz with the
+/// divide by 5 enabled for speeds up to 6MHz, divided by a whole number.  This is how libftd2xx
+/// picks the divisor, which rounds down, so the result can be faster than `hz`.
+pub(crate) fn clock_divisor(hz: u32) -> (u32, u32) {
+    let base = if hz <= 6_000_000 { 6_000_000 } else { 30_000_000 };
+    (base, base / hz)
+}
+
+/// The TCK frequency a high speed MPSSE chip really runs at when asked for `hz`
+fn achieved_clock(hz: u32) -> u32 {
+    let (base, divisor) = clock_divisor(hz);
+    base / divisor
+}
+
+/// A clock_tms command carries at most 7 TMS bits
+const MAX_TMS_BITS: usize = 7;
+
+/// Split `tms` into the (bits, count) pairs for a run of clock_tms commands, LSB first.  Every
+/// pair but the last holds `MAX_TMS_BITS` bits, and none of them are empty.
+fn tms_chunks(tms: &[usize]) -> impl Iterator<Item = (u8, u8)> + '_ {
+    tms.chunks(MAX_TMS_BITS).map(|chunk| {
+        let buf = chunk.iter().enumerate()
+            .filter(|(_, x)| **x != 0)
+            .fold(0, |acc, (i, _)| acc | (1 << i));
+        (buf, chunk.len() as u8)
+    })
+}
+
+/// Add clock_tms_out commands to `builder` for the whole of `tms`, holding TDI at `tdi`
+fn emit_tms(mut builder: MpsseCmdBuilder, tms: &[usize], tdi: bool) -> MpsseCmdBuilder {
+    for (buf, count) in tms_chunks(tms) {
+        builder = builder.clock_tms_out(ClockTMSOut::NegEdge, buf, tdi, count);
+    }
+    builder
+}
+
+/// A `Cable` for any FTDI chip with an MPSSE engine.  Commands are not sent as they are issued;
+/// they accumulate in a buffer that goes out in a single USB transfer when it would exceed
+/// `MAX_BUFFER_SIZE` bytes, when `flush` is called, or when `finish_read` needs the result of a
+/// queued read.  A long run of `write_data` and `change_mode` calls therefore costs one USB
+/// round-trip per few kilobytes of commands rather than one per call, so callers that only write
+/// must call `flush` before relying on the commands having reached the target.  `JtagKey`
+/// flushes when it is dropped, but a bare `Mpsse` doesn't, and either way an explicit `flush` is
+/// the only way to find out whether the commands were sent successfully.
+///
+/// Queued reads are answered by the first `finish_read`, which sends the buffer and reads back
+/// the results of every read queued so far in one `xfer`.  The results are cached and handed out
+/// in order by the following calls.  The chip can only hold so much read data, so `queue_read`
+/// and `queue_read_write` return `CableError::OutOfQueueSpace` once the queued reads would
+/// produce `MAX_BUFFER_SIZE` bytes.
+pub struct Mpsse<T> {
+    pub(crate) ft: T,
+    // Data to send to the adapter
+    buffer: Vec<u8>,
+    // Data we have read from the adapter and not yet returned
+    queued_reads: Vec<u8>,
+    // (bits, bytes, write, pause)
+    queued_read_state: VecDeque<(usize, usize, bool, bool)>,
+    bit_order: BitOrder,
+    // Maximum number of bytes in a single clock_data command
+    max_chunk: usize,
+    // The TCK frequency last requested, after clamping
+    clock: u32,
+}
+
+impl<T: MpsseDevice> Mpsse<T> {
+    pub fn new(ft: T, clock: u32) -> Self
+    {
+        Self::try_new(ft, clock).expect("init")
+    }
+
+    /// Like `new`, but returns an error instead of panicking if the adapter can't be set up.  A
+    /// `clock` of 0 means 1MHz, which is slow enough for nearly any target.
+    pub fn try_new(mut ft: T, clock: u32) -> Result<Self, CableError>
+    {
+        ft.init()?;
+        let clock = if clock == 0 { DEFAULT_CLOCK } else { clock }.clamp(MIN_CLOCK, MAX_CLOCK);
+        ft.set_clock(clock)?;
+
+        let builder = MpsseCmdBuilder::new()
+            .disable_3phase_data_clocking()
+            .disable_adaptive_data_clocking();
+        ft.send(builder.as_slice())?;
+
+        Ok(Self {
+            ft,
+            buffer: vec![],
+            queued_reads: vec![],
+            queued_read_state: VecDeque::new(),
+            bit_order: BitOrder::Lsb,
+            max_chunk: DEFAULT_MAX_CHUNK,
+            clock,
+        })
+    }
+
+    /// The TCK frequency the chip is really running at, which is the nearest the clock divider
+    /// can get to the speed last asked for.  For example, asking for 7MHz gives 7.5MHz.  This
+    /// is worked out from the divider rather than measured, so it doesn't account for adaptive
+    /// clocking, and the data rate with 3-phase clocking is two thirds of it.
+    pub fn actual_clock(&self) -> u32 {
+        achieved_clock(self.clock)
+    }
+
+    /// Set the maximum number of bytes sent in a single MPSSE command.  Longer shifts are split
+    /// into several commands, which is transparent to the caller.  The default is 65535, and
+    /// values above the MPSSE limit of 65536 are not allowed.
+    pub fn set_max_chunk(&mut self, max_chunk: usize) {
+        assert!(max_chunk > 0 && max_chunk <= 65536);
+        self.max_chunk = max_chunk;
+    }
+
+    /// Enable or disable adaptive clocking.  When enabled, the MPSSE engine waits after each TCK
+    /// edge for the target to echo it back on RTCK, which must be wired to GPIOL3 (ADBUS7).  The
+    /// effective clock is then set by the target, and can't be faster than about a third of the
+    /// configured clock.  Any buffered commands are sent first.
+    pub fn set_adaptive_clocking(&mut self, enable: bool) -> Result<(), CableError> {
+        self.flush()?;
+        let builder = if enable {
+            MpsseCmdBuilder::new().enable_adaptive_data_clocking()
+        } else {
+            MpsseCmdBuilder::new().disable_adaptive_data_clocking()
+        };
+        self.ft.send(builder.as_slice())?;
+        Ok(())
+    }
+
+    /// Enable or disable 3-phase data clocking, which holds data valid on both edges of TCK by
+    /// stretching each cycle to three half periods.  This helps targets with marginal signal
+    /// integrity that sample too close to the edge, at the cost of running TCK at two thirds of
+    /// the configured clock.  It is off by default.  Any buffered commands are sent first.
+    pub fn set_three_phase_clocking(&mut self, enable: bool) -> Result<(), CableError> {
+        self.flush()?;
+        let builder = if enable {
+            MpsseCmdBuilder::new().enable_3phase_data_clocking()
+        } else {
+            MpsseCmdBuilder::new().disable_3phase_data_clocking()
+        };
+        self.ft.send(builder.as_slice())?;
+        Ok(())
+    }
+
+    /// Set the GPIO pins to `state`, with `direction` giving the outputs.  The lower pins are in
+    /// the low byte and the upper pins in the high byte; only the bytes that `mask` touches are
+    /// sent.  Any buffered commands are sent first, so the pins change in order with them.
+    pub(crate) fn set_gpio(&mut self, state: u16, direction: u16, mask: u16) -> Result<(), CableError> {
+        self.flush()?;
+        let [lower, upper] = state.to_le_bytes();
+        let [lower_dir, upper_dir] = direction.to_le_bytes();
+        let mut builder = MpsseCmdBuilder::new();
+        if mask & 0xff != 0 {
+            builder = builder.set_gpio_lower(lower, lower_dir);
+        }
+        if mask & 0xff00 != 0 {
+            builder = builder.set_gpio_upper(upper, upper_dir);
+        }
+        self.ft.send(builder.as_slice())
+    }
+
+    /// Read all 16 GPIO pins, arranged as for `set_gpio`.  Any buffered commands are sent first.
+    /// Panics if reads are queued, since their results would be mixed up with the pin state.
+    pub(crate) fn gpio(&mut self) -> Result<u16, CableError> {
+        self.flush()?;
+        assert!(self.queued_read_state.is_empty(), "can't read GPIO while reads are queued");
+        let builder = MpsseCmdBuilder::new().gpio_lower().gpio_upper().send_immediate();
+        let mut pins = [0; 2];
+        self.ft.xfer(builder.as_slice(), &mut pins)?;
+        Ok(u16::from_le_bytes(pins))
+    }
+
+    /// Send raw MPSSE commands straight to the chip, for anything this crate doesn't have a
+    /// method for.  Any buffered commands are sent first, so these run in order with them.
+    /// Nothing checks what the commands do: one that clocks TCK or changes the pins used for
+    /// JTAG leaves `JtagSM` with the wrong idea of the TAP state.
+    pub fn send_raw(&mut self, cmd: &[u8]) -> Result<(), CableError> {
+        self.flush()?;
+        self.ft.send(cmd)
+    }
+
+    /// Like `send_raw`, then read back exactly `read_len` bytes.  Panics if reads are queued,
+    /// since their results would be mixed up with the response.
+    pub fn xfer_raw(&mut self, cmd: &[u8], read_len: usize) -> Result<Vec<u8>, CableError> {
+        self.flush()?;
+        assert!(self.queued_read_state.is_empty(), "can't send raw commands while reads are queued");
+        let mut response = vec![0; read_len];
+        self.ft.xfer(cmd, &mut response)?;
+        Ok(response)
+    }
+
+    /// The largest number of bytes to read in one go from `read_data` or `read_write_data`, so
+    /// that the result fits in the chip's buffer.
+    fn read_chunk(&self) -> usize {
+        std::cmp::min(self.max_chunk, MAX_BUFFER_SIZE / 2)
+    }
+
+    /// Add clock_data commands that shift `data` in and out to `builder`, using as many commands
+    /// as `max_chunk` requires.
+    fn clock_data_chunked(&self, mut builder: MpsseCmdBuilder, data: &[u8]) -> MpsseCmdBuilder {
+        for chunk in data.chunks(self.max_chunk) {
+            builder = builder.clock_data(self.clock_data_mode(), chunk);
+        }
+        builder
+    }
+
+    fn clock_data_mode(&self) -> ClockData {
+        match self.bit_order {
+            BitOrder::Lsb => ClockData::LsbPosIn,
+            BitOrder::Msb => ClockData::MsbPosIn,
+        }
+    }
+
+    fn clock_data_out_mode(&self) -> ClockDataOut {
+        match self.bit_order {
+            BitOrder::Lsb => ClockDataOut::LsbNeg,
+            BitOrder::Msb => ClockDataOut::MsbNeg,
+        }
+    }
+
+    fn clock_bits_mode(&self) -> ClockBits {
+        match self.bit_order {
+            BitOrder::Lsb => ClockBits::LsbPosIn,
+            BitOrder::Msb => ClockBits::MsbPosIn,
+        }
+    }
+
+    fn clock_bits_out_mode(&self) -> ClockBitsOut {
+        match self.bit_order {
+            BitOrder::Lsb => ClockBitsOut::LsbNeg,
+            BitOrder::Msb => ClockBitsOut::MsbNeg,
+        }
+    }
+
+    /// The bit of the last byte that gets sent last, and so must be sent with clock_tms.  `bits`
+    /// is the number of bits already sent from the last byte.
+    fn last_bit_mask(&self, bits: u8) -> u8 {
+        match self.bit_order {
+            BitOrder::Lsb => 1 << bits,
+            BitOrder::Msb => 0x80 >> bits,
+        }
+    }
+
+    /// Do the TMS part of a zero length shift, which is nothing unless `pause_after` is set
+    fn pause_without_data(&mut self, pause_after: bool) -> Result<(), CableError> {
+        if pause_after {
+            self.change_mode(&[1, 0], true)?;
+        }
+        Ok(())
+    }
+
+    /// Add the commands in `builder` to the buffer, sending what is already buffered first if
+    /// they wouldn't fit.
+    fn buffer_commands(&mut self, builder: MpsseCmdBuilder) -> Result<(), CableError> {
+        let len = builder.as_slice().len();
+        if len + self.buffer.len() > MAX_BUFFER_SIZE {
+            self.flush()?;
+        }
+        self.buffer.extend_from_slice(builder.as_slice());
+        Ok(())
+    }
+
+    /// Buffer the commands for a read that will return `bytes` bytes, unless that would need
+    /// more queue space than the chip has.
+    fn buffer_read(&mut self, builder: MpsseCmdBuilder, state: (usize, usize, bool, bool)) -> Result<(), CableError> {
+        let total_bytes = state.1 + self.queued_read_state.iter()
+            .map(|x| x.1)
+            .sum::<usize>();
+        if total_bytes >= MAX_BUFFER_SIZE {
+            return Err(CableError::OutOfQueueSpace);
+        }
+
+        self.buffer_commands(builder)?;
+        self.queued_read_state.push_back(state);
+        Ok(())
+    }
+}
+
+impl<T: MpsseDevice> Cable for Mpsse<T> {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        let builder = emit_tms(MpsseCmdBuilder::new(), tms, tdi);
+        self.buffer_commands(builder)
+    }
+
+    /// Each group of up to 7 TMS bits returns one byte, with the TDO samples shifted in from
+    /// the top
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        assert!(self.queued_read_state.is_empty());
+        if tms.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut builder = MpsseCmdBuilder::new();
+        for (buf, count) in tms_chunks(tms) {
+            builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, buf, tdi, count);
+        }
+        self.buffer_commands(builder)?;
+
+        let mut tdo = vec![0; tms.len().div_ceil(MAX_TMS_BITS)];
+        self.ft.xfer(&self.buffer, &mut tdo)?;
+        self.buffer.clear();
+
+        Ok(tms.chunks(MAX_TMS_BITS).zip(tdo)
+            .flat_map(|(chunk, byte)| {
+                let first = 8 - chunk.len();
+                (0..chunk.len()).map(move |i| byte & (1 << (first + i)) != 0)
+            })
+            .collect())
+    }
+
+    fn queue_read(&mut self, mut bits: usize) -> Result<(), CableError>
+    {
+        let orig_bits = bits;
+
+        let mut bytes = bits / 8;
+        let mut builder = MpsseCmdBuilder::new();
+        if bytes > 0 {
+            bits -= bytes * 8;
+            builder = self.clock_data_chunked(builder, &vec![0xff; bytes]);
+        }
+
+        if bits > 0 {
+            builder = builder.clock_bits(self.clock_bits_mode(), 0xff, bits as u8);
+            bytes += 1;
+        }
+
+        self.buffer_read(builder, (orig_bits, bytes, false, false))
+    }
+
+    fn finish_read(&mut self, mut bits: usize) -> Result<Vec<u8>, CableError>
+    {
+        let (orig_bits, bytes, write, pause_after) = self.queued_read_state.pop_front()
+            .expect("finish_read without a queued read");
+        assert_eq!(bits, orig_bits);
+
+        if self.queued_reads.is_empty() {
+            // Read all of the pending bytes
+            let total_bytes = bytes + self.queued_read_state.iter()
+                .map(|x| x.1)
+                .sum::<usize>();
+            self.queued_reads.resize(total_bytes, 0);
+            self.ft.xfer(&self.buffer, &mut self.queued_reads)?;
+            self.buffer.clear();
+        }
+
+        let mut buf = self.queued_reads.split_off(bytes);
+        // split_off returns the second half of the vec, but we want the first half
+        std::mem::swap(&mut buf, &mut self.queued_reads);
+
+        if pause_after {
+            buf.pop();
+        }
+
+        if write {
+            let len = buf.len();
+            buf[len-1] >>= 7;
+
+            bits -= 1;
+            // If the last byte only had one bit, the bit from clock_tms is the whole last byte
+            if !bits.is_multiple_of(8) {
+                let last_recv = buf[len-1] & 1;
+                match self.bit_order {
+                    BitOrder::Lsb => {
+                        // Shift the bits from clock_bits
+                        buf[len-2] >>= 8 - (bits % 8);
+
+                        // Need to repack the bit from clock_tms into the bits from clock_bits
+                        buf[len-2] |= last_recv << (bits % 8);
+                    }
+                    BitOrder::Msb => {
+                        // MSB first bits from clock_bits arrive in the low bits
+                        buf[len-2] <<= 8 - (bits % 8);
+                        buf[len-2] |= last_recv << (7 - (bits % 8));
+                    }
+                }
+                buf.pop();
+            } else if self.bit_order == BitOrder::Msb {
+                buf[len-1] <<= 7;
+            }
+        } else if !bits.is_multiple_of(8) {
+            let last_idx = buf.len()-1;
+            match self.bit_order {
+                BitOrder::Lsb => buf[last_idx] >>= 8 - (bits % 8),
+                BitOrder::Msb => buf[last_idx] <<= 8 - (bits % 8),
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Reading no bits does nothing and returns an empty vector
+    fn read_data(&mut self, mut bits: usize) -> Result<Vec<u8>, CableError>
+    {
+        assert!(self.queued_read_state.is_empty());
+        if bits == 0 {
+            return Ok(vec![]);
+        }
+
+        // Reads that don't fit in the chip's buffer are done in pieces
+        let mut data = vec![];
+        let chunk_bits = self.read_chunk() * 8;
+        while bits > chunk_bits {
+            self.queue_read(chunk_bits)?;
+            data.append(&mut self.finish_read(chunk_bits)?);
+            bits -= chunk_bits;
+        }
+
+        self.queue_read(bits)?;
+        data.append(&mut self.finish_read(bits)?);
+        Ok(data)
+    }
+
+    /// If `data` is empty, nothing is shifted, and the only clocks are those to go to PauseDR or
+    /// PauseIR if `pause_after` is set.  Leaving the shift state still shifts one bit, with TDI
+    /// high.
+    fn write_data(&mut self, data: &[u8], mut bits: u8, pause_after: bool) -> Result<(), CableError>
+    {
+        if data.is_empty() {
+            return self.pause_without_data(pause_after);
+        }
+        assert!(bits <= 8);
+        assert!(bits != 0);
+
+        // We will send the last bit using clock_tms
+        bits -= 1;
+
+        if data.len() > 1 {
+            for chunk in data[..data.len()-1].chunks(self.max_chunk) {
+                let builder = MpsseCmdBuilder::new()
+                    .clock_data_out(self.clock_data_out_mode(), chunk);
+                self.buffer_commands(builder)?;
+            }
+        }
+
+        let mut builder = MpsseCmdBuilder::new();
+        let last_byte = data[data.len()-1];
+        if bits >= 1 {
+            builder = builder.clock_bits_out(self.clock_bits_out_mode(), last_byte, bits);
+        }
+        let last_bit = last_byte & self.last_bit_mask(bits) != 0;
+        // Change to pause state
+        if pause_after {
+            builder = builder.clock_tms_out(ClockTMSOut::NegEdge, 1, last_bit, 2);
+        } else {
+            builder = builder.clock_tms_out(ClockTMSOut::NegEdge, 0, last_bit, 1);
+        }
+        self.buffer_commands(builder)
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], mut bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let total_bits = (data.len()-1) * 8 + bits as usize;
+        let mut read_bytes = 1;
+        let mut builder = MpsseCmdBuilder::new();
+
+        assert!(bits <= 8);
+        assert!(bits != 0);
+
+        // We will send the last bit using clock_tms
+        bits -= 1;
+
+        if data.len() > 1 {
+            builder = self.clock_data_chunked(builder, &data[..data.len()-1]);
+            read_bytes += data.len()-1;
+        }
+        let last_byte = data[data.len()-1];
+        if bits >= 1 {
+            builder = builder.clock_bits(self.clock_bits_mode(), last_byte, bits);
+            read_bytes += 1;
+        }
+        let last_bit = last_byte & self.last_bit_mask(bits) != 0;
+
+        // Change to pause state
+        if pause_after {
+            builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, 1, last_bit, 1);
+            read_bytes += 1;
+        }
+        builder = builder.clock_tms(ClockTMS::NegTMSPosTDO, 0, last_bit, 1);
+
+        self.buffer_read(builder, (total_bits, read_bytes, true, pause_after))
+    }
+
+    /// An empty `data` is handled as for `write_data`, and returns an empty vector
+    fn read_write_data(&mut self, mut data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        assert!(self.queued_read_state.is_empty());
+        if data.is_empty() {
+            self.pause_without_data(pause_after)?;
+            return Ok(vec![]);
+        }
+
+        // Shifts that don't fit in the chip's buffer are done in pieces.  Only the last piece
+        // includes the partial last byte and the TMS change.
+        let mut result = vec![];
+        let chunk = self.read_chunk();
+        while data.len() > chunk {
+            let (head, tail) = data.split_at(chunk);
+            let builder = self.clock_data_chunked(MpsseCmdBuilder::new(), head);
+            self.buffer_read(builder, (chunk * 8, chunk, false, false))?;
+            result.append(&mut self.finish_read(chunk * 8)?);
+            data = tail;
+        }
+
+        self.queue_read_write(data, bits, pause_after)?;
+        let total_bits = (data.len()-1) * 8 + bits as usize;
+        result.append(&mut self.finish_read(total_bits)?);
+        Ok(result)
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        if self.buffer.is_empty() {
+            return Ok(0);
+        }
+        self.ft.send(&self.buffer)?;
+        let bytes = self.buffer.len();
+        self.buffer.clear();
+        Ok(bytes)
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        assert!(self.queued_read_state.is_empty());
+        self.bit_order = order;
+        Ok(())
+    }
+
+    /// Requests outside the 92Hz to 30MHz range the chip supports are clamped to that range.
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.flush()?;
+        let hz = hz.clamp(MIN_CLOCK, MAX_CLOCK);
+        self.ft.set_clock(hz)?;
+        self.clock = hz;
+        Ok(())
+    }
+
+    fn max_clock(&self) -> u32 {
+        MAX_CLOCK
+    }
+
+    /// Limited by the chip's receive buffer.  Commands that don't read are sent whenever the
+    /// command buffer fills, so they don't count.
+    fn queue_capacity(&self) -> usize {
+        MAX_BUFFER_SIZE - 1
+    }
+
+    fn queue_len(&self) -> usize {
+        self.queued_read_state.iter().map(|x| x.1).sum()
+    }
+
+    /// Reads the modem status, which doesn't go through the MPSSE engine and so doesn't disturb
+    /// any buffered commands or queued reads.
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.ft.ping()
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            adjustable_clock: true,
+            native_queue: true,
+            adaptive_clock: true,
+            ..CableCapabilities::default()
+        }
+    }
+}
+
+// The pins the MPSSE engine uses for JTAG, which are the same on every adapter
+pub(crate) const PIN_TCK: u8 = 1;
+pub(crate) const PIN_TDI: u8 = 1 << 1;
+//pub(crate) const PIN_TDO: u8 = 1 << 2;
+pub(crate) const PIN_TMS: u8 = 1 << 3;
+
+/// Which GPIO pins an FT2232H adapter wires to its reset lines and buffer enables.  TCK, TDI,
+/// TDO, and TMS are always ADBUS0 to ADBUS3, since those are the only pins the MPSSE engine can
+/// clock, so only the other signals differ between adapters.  Each field is a mask with the
+/// lower pins, ADBUS0 to ADBUS7, in the low byte and the upper pins, ACBUS0 to ACBUS7, in the
+/// high byte.  All the signals are active low, and a mask of 0 means the adapter doesn't have
+/// that signal.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PinMap {
+    /// Enables the JTAG output buffer
+    pub n_oe: u16,
+    /// Resets the TAPs
+    pub n_trst: u16,
+    /// Resets the system
+    pub n_srst: u16,
+    /// Enables the driver for nTRST
+    pub n_trst_oe: u16,
+    /// Enables the driver for nSRST
+    pub n_srst_oe: u16,
+}
+
+impl PinMap {
+    /// The Amontec JTAGkey layout, which the Bus Blaster and many other adapters copy
+    pub fn jtagkey() -> Self {
+        Self {
+            n_oe: 1 << 4,
+            n_trst: 1 << 8,
+            n_srst: 1 << 9,
+            n_trst_oe: 1 << 10,
+            n_srst_oe: 1 << 11,
+        }
+    }
+
+    /// The Tigard, which has its JTAG header on interface B, with nTRST on ADBUS4 and nSRST on
+    /// ADBUS5 and no buffer enables
+    pub fn tigard() -> Self {
+        Self {
+            n_trst: 1 << 4,
+            n_srst: 1 << 5,
+            ..Self::default()
+        }
+    }
+
+    fn outputs(&self) -> u16 {
+        (PIN_TCK | PIN_TDI | PIN_TMS) as u16 | self.n_oe | self.n_trst | self.n_srst | self.n_trst_oe | self.n_srst_oe
+    }
+}
+
+/// The GPIO pins of an adapter laid out by a `PinMap`, and the state they are being driven to
+pub(crate) struct JtagPins {
+    map: PinMap,
+    state: u16,
+}
+
+impl JtagPins {
+    /// Drive TMS high, the reset lines high, so they are not asserted, and every enable low.
+    /// The upper pins are left alone unless `map` uses them, since not every chip has them.
+    pub(crate) fn init<T: MpsseDevice>(map: PinMap, ft: &mut Mpsse<T>) -> Result<Self, CableError> {
+        let pins = Self {
+            map,
+            state: PIN_TMS as u16 | map.n_trst | map.n_srst,
+        };
+        ft.set_gpio(pins.state, map.outputs(), map.outputs())?;
+        Ok(pins)
+    }
+
+    /// Drive the pins in `mask` high or low.  Does nothing if `mask` is 0, so signals the
+    /// adapter doesn't have are ignored, like the `Cable` defaults.
+    fn set<T: MpsseDevice>(&mut self, ft: &mut Mpsse<T>, mask: u16, high: bool) -> Result<(), CableError> {
+        if mask == 0 {
+            return Ok(());
+        }
+        if high {
+            self.state |= mask;
+        } else {
+            self.state &= !mask;
+        }
+        ft.set_gpio(self.state, self.map.outputs(), mask)
+    }
+
+    pub(crate) fn set_srst<T: MpsseDevice>(&mut self, ft: &mut Mpsse<T>, assert: bool) -> Result<(), CableError> {
+        self.set(ft, self.map.n_srst, !assert)
+    }
+
+    pub(crate) fn set_trst<T: MpsseDevice>(&mut self, ft: &mut Mpsse<T>, assert: bool) -> Result<(), CableError> {
+        self.set(ft, self.map.n_trst, !assert)
+    }
+
+    /// Drive the output enable high to tri-state the JTAG buffer, or low to enable it
+    pub(crate) fn set_output_enable<T: MpsseDevice>(&mut self, ft: &mut Mpsse<T>, enable: bool)
+        -> Result<(), CableError>
+    {
+        self.set(ft, self.map.n_oe, !enable)
+    }
+
+    /// Read back the reset lines the adapter has
+    pub(crate) fn read<T: MpsseDevice>(&self, ft: &mut Mpsse<T>) -> Result<CableGpio, CableError> {
+        let pins = ft.gpio()?;
+        let asserted = |mask: u16| (mask != 0).then_some(pins & mask == 0);
+        Ok(CableGpio {
+            srst: asserted(self.map.n_srst),
+            trst: asserted(self.map.n_trst),
+        })
+    }
+
+    /// The capabilities of an `Mpsse` with these pins
+    pub(crate) fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            srst: self.map.n_srst != 0,
+            trst: self.map.n_trst != 0,
+            adjustable_clock: true,
+            native_queue: true,
+            output_enable: self.map.n_oe != 0,
+            adaptive_clock: true,
+        }
+    }
+}
+
+/// One of the two MPSSE channels of an FT2232H or FT4232H
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Interface {
+    #[default]
+    A,
+    B,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statemachine::{JtagSM, JtagState, Register};
+
+    /// An `MpsseDevice` that keeps everything sent to it and answers reads with the bytes in
+    /// `replies`, then zeros
+    #[derive(Default)]
+    struct FakeDevice {
+        sent: Vec<u8>,
+        replies: VecDeque<u8>,
+    }
+
+    impl MpsseDevice for FakeDevice {
+        fn init(&mut self) -> Result<(), CableError> {
+            Ok(())
+        }
+
+        fn set_clock(&mut self, _hz: u32) -> Result<(), CableError> {
+            Ok(())
+        }
+
+        fn send(&mut self, data: &[u8]) -> Result<(), CableError> {
+            self.sent.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn recv(&mut self, response: &mut [u8]) -> Result<(), CableError> {
+            for x in response {
+                *x = self.replies.pop_front().unwrap_or(0);
+            }
+            Ok(())
+        }
+
+        fn ping(&mut self) -> Result<(), CableError> {
+            Ok(())
+        }
+    }
+
+    /// An `MpsseDevice` that runs the MPSSE commands sent to it as if TDI were wired to TDO, so
+    /// every bit read is the bit written on the same clock
+    #[derive(Default)]
+    struct LoopbackDevice {
+        sent: Vec<u8>,
+        response: VecDeque<u8>,
+        // The most bytes shifted by a single command
+        longest: usize,
+    }
+
+    impl LoopbackDevice {
+        fn run(&mut self, mut cmd: &[u8]) {
+            while let Some((&op, rest)) = cmd.split_first() {
+                cmd = rest;
+                match op {
+                    // Clock divisor, then the loopback, divide by 5, 3-phase, and adaptive
+                    // clocking switches
+                    0x86 => cmd = &cmd[2..],
+                    0x84 | 0x85 | 0x87 | 0x8a..=0x8d | 0x96 | 0x97 => (),
+                    // Set the lower or upper pins
+                    0x80 | 0x82 => cmd = &cmd[2..],
+                    0x81 | 0x83 => self.response.push_back(0),
+                    // Clock TMS, with bit 7 of the data held on TDI
+                    op if op & 0x40 != 0 => {
+                        let (bits, data) = (cmd[0] + 1, cmd[1]);
+                        cmd = &cmd[2..];
+                        if op & 0x20 != 0 {
+                            let tdi = if data & 0x80 != 0 { 0xff } else { 0 };
+                            self.response.push_back(tdi << (8 - bits));
+                        }
+                    }
+                    // Clock bits, which come back in the top bits for LSB first, and the bottom
+                    // bits for MSB first
+                    op if op & 0x02 != 0 => {
+                        let (bits, data) = (cmd[0] + 1, cmd[1]);
+                        cmd = &cmd[2..];
+                        if op & 0x20 != 0 {
+                            self.response.push_back(if op & 0x08 != 0 {
+                                data << (8 - bits)
+                            } else {
+                                data >> (8 - bits)
+                            });
+                        }
+                    }
+                    // Clock bytes
+                    op if op < 0x80 => {
+                        let len = u16::from_le_bytes([cmd[0], cmd[1]]) as usize + 1;
+                        let data = &cmd[2..2 + len];
+                        cmd = &cmd[2 + len..];
+                        self.longest = self.longest.max(len);
+                        if op & 0x20 != 0 {
+                            self.response.extend(data);
+                        }
+                    }
+                    op => panic!("unexpected command {:#x}", op),
+                }
+            }
+        }
+    }
+
+    impl MpsseDevice for LoopbackDevice {
+        fn init(&mut self) -> Result<(), CableError> {
+            Ok(())
+        }
+
+        fn set_clock(&mut self, _hz: u32) -> Result<(), CableError> {
+            Ok(())
+        }
+
+        fn send(&mut self, data: &[u8]) -> Result<(), CableError> {
+            self.sent.extend_from_slice(data);
+            self.run(data);
+            Ok(())
+        }
+
+        fn recv(&mut self, response: &mut [u8]) -> Result<(), CableError> {
+            assert!(self.response.len() >= response.len(), "read more than the commands return");
+            for x in response {
+                *x = self.response.pop_front().unwrap();
+            }
+            Ok(())
+        }
+
+        fn ping(&mut self) -> Result<(), CableError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_counts_bytes() {
+        let mut ft = Mpsse::try_new(FakeDevice::default(), 1_000_000).unwrap();
+        let setup = ft.ft.sent.len();
+        ft.change_mode(&[1; 10], true).unwrap();
+        ft.write_data(&[0xa5, 0x5a], 8, true).unwrap();
+        let sent = ft.flush().unwrap();
+        assert!(sent > 0);
+        assert_eq!(ft.ft.sent.len() - setup, sent);
+        // Nothing is left to send
+        assert_eq!(ft.flush().unwrap(), 0);
+    }
+
+    fn chunk_lengths(bits: usize) -> Vec<u8> {
+        tms_chunks(&vec![1; bits]).map(|(_, count)| count).collect()
+    }
+
+    #[test]
+    fn tms_chunk_lengths() {
+        assert_eq!(chunk_lengths(0), Vec::<u8>::new());
+        assert_eq!(chunk_lengths(1), vec![1]);
+        assert_eq!(chunk_lengths(7), vec![7]);
+        assert_eq!(chunk_lengths(8), vec![7, 1]);
+        assert_eq!(chunk_lengths(14), vec![7, 7]);
+    }
+
+    #[test]
+    fn tms_chunk_bits() {
+        let chunks: Vec<_> = tms_chunks(&[1, 0, 1, 1, 0, 0, 0, 1, 1]).collect();
+        assert_eq!(chunks, vec![(0x0d, 7), (0x03, 2)]);
+    }
+
+    #[test]
+    fn clock_divisors() {
+        assert_eq!(clock_divisor(30_000_000), (30_000_000, 1));
+        assert_eq!(clock_divisor(7_000_000), (30_000_000, 4));
+        assert_eq!(clock_divisor(6_000_000), (6_000_000, 1));
+        assert_eq!(clock_divisor(100_000), (6_000_000, 60));
+    }
+
+    #[test]
+    fn achieved_clocks() {
+        // The divisor rounds down, so the clock can come out faster than asked
+        assert_eq!(achieved_clock(7_000_000), 7_500_000);
+        assert_eq!(achieved_clock(1_000_000), 1_000_000);
+        assert_eq!(achieved_clock(4_000_000), 6_000_000);
+        assert_eq!(achieved_clock(2_500_000), 3_000_000);
+        assert_eq!(achieved_clock(MIN_CLOCK), 92);
+    }
+
+    #[test]
+    fn emit_tms_commands() {
+        // Each clock_tms_out command is three bytes, and none is sent for no bits
+        assert!(emit_tms(MpsseCmdBuilder::new(), &[], true).as_slice().is_empty());
+        assert_eq!(emit_tms(MpsseCmdBuilder::new(), &[1; 14], true).as_slice().len(), 6);
+    }
+
+    fn loopback() -> Mpsse<LoopbackDevice> {
+        Mpsse::try_new(LoopbackDevice::default(), 1_000_000).unwrap()
+    }
+
+    #[test]
+    fn loopback_one_byte() {
+        let mut ft = loopback();
+        for pause_after in [false, true] {
+            for bits in 1..=8 {
+                let mask = (0xff_u16 >> (8 - bits)) as u8;
+                for x in [0x00, 0xff, 0xa5, 0x5a, 0x01, 0x80] {
+                    let tdo = ft.read_write_data(&[x], bits, pause_after).unwrap();
+                    assert_eq!(tdo, vec![x & mask], "{:#x} bits={} pause_after={}", x, bits, pause_after);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn loopback_multiple_bytes() {
+        let mut ft = loopback();
+        for pause_after in [false, true] {
+            for bits in [1, 5, 8] {
+                let tdo = ft.read_write_data(&[0x12, 0x34, 0xff], bits, pause_after).unwrap();
+                assert_eq!(tdo, vec![0x12, 0x34, (0xff_u16 >> (8 - bits)) as u8]);
+            }
+        }
+    }
+
+    #[test]
+    fn chunked_200kb() {
+        let data: Vec<u8> = (0..200 * 1024).map(|i| (i * 7 + i / 256) as u8).collect();
+        for max_chunk in [DEFAULT_MAX_CHUNK, 1000] {
+            let mut ft = loopback();
+            ft.set_max_chunk(max_chunk);
+            let tdo = ft.read_write_data(&data, 3, true).unwrap();
+            assert_eq!(tdo.len(), data.len());
+            assert_eq!(tdo[..data.len() - 1], data[..data.len() - 1]);
+            assert_eq!(tdo[data.len() - 1], data[data.len() - 1] & 0x07);
+
+            ft.write_data(&data, 8, false).unwrap();
+            assert_eq!(ft.read_data(data.len() * 8).unwrap(), vec![0xff; data.len()]);
+            ft.flush().unwrap();
+            assert!(ft.ft.longest <= max_chunk);
+            assert!(ft.ft.response.is_empty());
+        }
+    }
+
+    #[test]
+    fn three_queued_reads() {
+        let mut ft = loopback();
+        let setup = ft.ft.sent.len();
+        ft.queue_read_write(&[0x11, 0x22], 8, false).unwrap();
+        ft.queue_read(5).unwrap();
+        ft.queue_read_write(&[0x33, 0x05], 3, true).unwrap();
+        // Nothing is sent until the first finish_read
+        assert_eq!(ft.ft.sent.len(), setup);
+
+        assert_eq!(ft.finish_read(16).unwrap(), vec![0x11, 0x22]);
+        assert_eq!(ft.finish_read(5).unwrap(), vec![0x1f]);
+        assert_eq!(ft.finish_read(11).unwrap(), vec![0x33, 0x05]);
+        assert_eq!(ft.queue_len(), 0);
+    }
+
+    fn init_pins(map: PinMap) -> Vec<u8> {
+        let mut ft = Mpsse::try_new(FakeDevice::default(), 1_000_000).unwrap();
+        let setup = ft.ft.sent.len();
+        JtagPins::init(map, &mut ft).unwrap();
+        ft.ft.sent.split_off(setup)
+    }
+
+    #[test]
+    fn initial_pins() {
+        // TMS and the reset lines high, with nOE and the reset line enables also outputs
+        assert_eq!(init_pins(PinMap::jtagkey()), vec![0x80, 0x08, 0x1b, 0x82, 0x03, 0x0f]);
+        // The Tigard only uses the lower pins, so the upper ones are left alone
+        assert_eq!(init_pins(PinMap::tigard()), vec![0x80, 0x38, 0x3b]);
+    }
+
+    #[test]
+    fn capture_length() {
+        let mut ft = loopback();
+        for len in [0, 1, 7, 8, 15] {
+            let tms: Vec<usize> = (0..len).map(|i| i % 2).collect();
+            for tdi in [false, true] {
+                assert_eq!(ft.change_mode_capture(&tms, tdi).unwrap(), vec![tdi; len]);
+            }
+        }
+    }
+
+    #[test]
+    fn loopback_msb_first() {
+        let mut ft = loopback();
+        ft.set_bit_order(BitOrder::Msb).unwrap();
+        for pause_after in [false, true] {
+            for bits in 1..=8 {
+                // The valid bits of the last byte are the most significant ones
+                let mask = (0xff_u16 << (8 - bits)) as u8;
+                let tdo = ft.read_write_data(&[0x3c, 0xa5], bits, pause_after).unwrap();
+                assert_eq!(tdo, vec![0x3c, 0xa5 & mask], "bits={} pause_after={}", bits, pause_after);
+            }
+        }
+        assert_eq!(ft.read_data(12).unwrap(), vec![0xff, 0xf0]);
+    }
+
+    /// The bytes of MPSSE commands sent to write a data register from Idle and return to Idle,
+    /// with `shift_dr_fast` or without it
+    fn dr_write_bytes(dr: &[u8], bits: u8, fast: bool) -> usize {
+        let mut ft = Mpsse::try_new(FakeDevice::default(), 1_000_000).unwrap();
+        let mut sm = JtagSM::new(&mut ft).unwrap();
+        sm.cable.flush().unwrap();
+        if fast {
+            sm.shift_dr_fast(dr, bits).unwrap();
+        } else {
+            sm.write_reg(Register::Data, dr, bits, true).unwrap();
+            sm.change_mode(JtagState::Idle).unwrap();
+        }
+        sm.cable.flush().unwrap()
+    }
+
+    #[test]
+    fn fast_shift_saves_command() {
+        // 10 and 18 bits leave a single bit in the last byte, which goes out with TMS, so the
+        // clock_bits_out command isn't needed
+        for (dr, bits) in [(&[0xa5, 0x02][..], 2), (&[0xa5, 0x5a, 0x03][..], 2)] {
+            assert_eq!(dr_write_bytes(dr, bits, false), dr_write_bytes(dr, bits, true) + 3);
+        }
+        // With 12 bits it's one command either way
+        assert_eq!(dr_write_bytes(&[0xa5, 0x05], 4, false), dr_write_bytes(&[0xa5, 0x05], 4, true));
+    }
+
+    fn read_pins(map: PinMap, pins: u16) -> CableGpio {
+        let mut ft = Mpsse::try_new(FakeDevice::default(), 1_000_000).unwrap();
+        let jtag_pins = JtagPins::init(map, &mut ft).unwrap();
+        ft.ft.replies.extend(pins.to_le_bytes());
+        jtag_pins.read(&mut ft).unwrap()
+    }
+
+    #[test]
+    fn read_reset_lines() {
+        // nSRST is ACBUS1 and nTRST is ACBUS0 on a jtagkey, and low means asserted
+        assert_eq!(read_pins(PinMap::jtagkey(), 0x0100), CableGpio { srst: Some(true), trst: Some(false) });
+        assert_eq!(read_pins(PinMap::jtagkey(), 0x0200), CableGpio { srst: Some(false), trst: Some(true) });
+        assert_eq!(read_pins(PinMap::tigard(), 0x0030), CableGpio { srst: Some(false), trst: Some(false) });
+        // Lines the adapter doesn't have aren't reported
+        let map = PinMap { n_srst: 1 << 5, ..PinMap::default() };
+        assert_eq!(read_pins(map, 0), CableGpio { srst: Some(true), trst: None });
+    }
+
+    #[test]
+    fn clock_idle_commands() {
+        let mut ft = Mpsse::try_new(FakeDevice::default(), 1_000_000).unwrap();
+        ft.flush().unwrap();
+        let setup = ft.ft.sent.len();
+        ft.clock_idle(10, true, true).unwrap();
+        ft.clock_idle(4, false, true).unwrap();
+        ft.clock_idle(3, true, false).unwrap();
+        ft.flush().unwrap();
+        // clock_tms_out with TDI in bit 7, seven TMS bits at most per command
+        assert_eq!(ft.ft.sent[setup..], [
+            0x4b, 6, 0xff, 0x4b, 2, 0x87,
+            0x4b, 3, 0x80,
+            0x4b, 2, 0x07,
+        ]);
+    }
+
+
+    #[test]
+    fn capabilities_match() {
+        crate::cable::tests::check_capabilities(&mut loopback());
+
+        // The reset lines are readable exactly when the pin map says they exist
+        for map in [PinMap::jtagkey(), PinMap::tigard()] {
+            let mut ft = Mpsse::try_new(FakeDevice::default(), 1_000_000).unwrap();
+            let caps = JtagPins::init(map, &mut ft).unwrap().capabilities();
+            let gpio = read_pins(map, 0);
+            assert_eq!(caps.srst, gpio.srst.is_some());
+            assert_eq!(caps.trst, gpio.trst.is_some());
+            assert_eq!(caps.output_enable, map.n_oe != 0);
+        }
+    }
+}
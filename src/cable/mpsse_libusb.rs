@@ -0,0 +1,342 @@
+//! Implement the `Cable` trait for "jtagkey" compatible hardware adapters through libusb, for
+//! systems without the proprietary D2XX driver.  The chip is driven with the same vendor requests
+//! libftdi uses, and the MPSSE engine is shared with `mpsse`, so `JtagKey` here behaves the same
+//! as `mpsse::JtagKey`.  On Linux, the ftdi_sio kernel driver is detached from the interface
+//! while it's open.
+use crate::cable::{BitOrder, Cable, CableCapabilities, CableError, CableGpio};
+use crate::cable::mpsse_common::*;
+
+use std::time::{Duration, Instant};
+
+use ftdi_mpsse::MpsseCmdBuilder;
+use rusb::{DeviceHandle, Direction, GlobalContext, Recipient, RequestType};
+
+const FTDI_VID: u16 = 0x0403;
+const FT2232H_PID: u16 = 0x6010;
+
+// Vendor requests, and their values
+const SIO_RESET: u8 = 0x00;
+const SIO_POLL_MODEM_STATUS: u8 = 0x05;
+const SIO_SET_LATENCY_TIMER: u8 = 0x09;
+const SIO_SET_BITMODE: u8 = 0x0b;
+const SIO_RESET_SIO: u16 = 0;
+const SIO_RESET_PURGE_RX: u16 = 1;
+const SIO_RESET_PURGE_TX: u16 = 2;
+const BITMODE_RESET: u16 = 0x00;
+const BITMODE_MPSSE: u16 = 0x02;
+
+// Every bulk IN packet starts with two modem status bytes
+const STATUS_BYTES: usize = 2;
+
+// An MPSSE command that doesn't exist, which the chip answers with 0xfa and the command
+const BAD_COMMAND: u8 = 0xaa;
+
+// USB read and write timeout until JtagKey::set_timeouts is called
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Report a libusb timeout the same way the D2XX backend does
+fn usb_error(e: rusb::Error) -> CableError {
+    match e {
+        rusb::Error::Timeout => CableError::Timeout,
+        e => e.into(),
+    }
+}
+
+/// One interface of an FTDI chip, opened through libusb
+struct LibusbFtdi {
+    handle: DeviceHandle<GlobalContext>,
+    // wIndex of the vendor requests, which is 1 for interface A and 2 for B
+    index: u16,
+    read_endpoint: u8,
+    write_endpoint: u8,
+    packet_size: usize,
+    read_timeout: Duration,
+    write_timeout: Duration,
+}
+
+impl LibusbFtdi {
+    fn open(vid: u16, pid: u16, interface: Interface) -> Result<Self, CableError> {
+        let handle = rusb::open_device_with_vid_pid(vid, pid)
+            .ok_or_else(|| CableError::Protocol(format!("no FTDI device {:04x}:{:04x} attached", vid, pid)))?;
+        let (number, index, read_endpoint, write_endpoint) = match interface {
+            Interface::A => (0, 1, 0x81, 0x02),
+            Interface::B => (1, 2, 0x83, 0x04),
+        };
+        let packet_size = match handle.device().speed() {
+            rusb::Speed::High | rusb::Speed::Super | rusb::Speed::SuperPlus => 512,
+            _ => 64,
+        };
+
+        // Not every platform can detach kernel drivers, and those that can't don't need to
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle.claim_interface(number)?;
+
+        Ok(Self {
+            handle,
+            index,
+            read_endpoint,
+            write_endpoint,
+            packet_size,
+            read_timeout: DEFAULT_TIMEOUT,
+            write_timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    fn control(&self, request: u8, value: u16) -> Result<(), CableError> {
+        let request_type = rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Device);
+        self.handle.write_control(request_type, request, value, self.index, &[], self.write_timeout)
+            .map_err(usb_error)?;
+        Ok(())
+    }
+}
+
+impl MpsseDevice for LibusbFtdi {
+    /// Does what libftd2xx's `initialize_mpsse_default` does, apart from the flow control, which
+    /// doesn't matter in MPSSE mode
+    fn init(&mut self) -> Result<(), CableError> {
+        self.control(SIO_RESET, SIO_RESET_SIO)?;
+        // The shortest the chip allows, so short reads come back promptly
+        self.control(SIO_SET_LATENCY_TIMER, 1)?;
+        self.control(SIO_SET_BITMODE, BITMODE_RESET << 8)?;
+        self.control(SIO_SET_BITMODE, BITMODE_MPSSE << 8)?;
+        self.control(SIO_RESET, SIO_RESET_PURGE_RX)?;
+        self.control(SIO_RESET, SIO_RESET_PURGE_TX)?;
+
+        let mut echo = [0; 2];
+        self.xfer(&[BAD_COMMAND], &mut echo)?;
+        if echo != [0xfa, BAD_COMMAND] {
+            return Err(CableError::Protocol(format!("MPSSE didn't synchronize: {:02x?}", echo)));
+        }
+        self.send(MpsseCmdBuilder::new().disable_loopback().as_slice())
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        let (base, divisor) = clock_divisor(hz);
+        let builder = MpsseCmdBuilder::new().set_clock(divisor - 1, Some(base == 6_000_000));
+        self.send(builder.as_slice())
+    }
+
+    fn send(&mut self, mut data: &[u8]) -> Result<(), CableError> {
+        while !data.is_empty() {
+            let len = self.handle.write_bulk(self.write_endpoint, data, self.write_timeout)
+                .map_err(usb_error)?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// The chip sends a packet of just the status bytes whenever the latency timer expires with
+    /// nothing to send, so this keeps reading until it has everything or the read timeout has
+    /// passed.
+    fn recv(&mut self, response: &mut [u8]) -> Result<(), CableError> {
+        let start = Instant::now();
+        let mut got = 0;
+        while got < response.len() {
+            let packets = (response.len() - got).div_ceil(self.packet_size - STATUS_BYTES);
+            let mut buf = vec![0; packets * self.packet_size];
+            let len = self.handle.read_bulk(self.read_endpoint, &mut buf, self.read_timeout)
+                .map_err(usb_error)?;
+            for packet in buf[..len].chunks(self.packet_size) {
+                let data = packet.get(STATUS_BYTES..).unwrap_or_default();
+                let n = std::cmp::min(data.len(), response.len() - got);
+                response[got..got + n].copy_from_slice(&data[..n]);
+                got += n;
+            }
+            if got < response.len() && start.elapsed() > self.read_timeout {
+                return Err(CableError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        let request_type = rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Device);
+        let mut status = [0; 2];
+        self.handle.read_control(request_type, SIO_POLL_MODEM_STATUS, 0, self.index, &mut status,
+                                 self.read_timeout)
+            .map_err(usb_error)?;
+        Ok(())
+    }
+}
+
+pub struct JtagKey {
+    ft: Mpsse<LibusbFtdi>,
+    pins: JtagPins,
+}
+
+impl JtagKey {
+    /// Create a new JtagKey from the first FT2232H attached.  `primary` selects interface "A"
+    /// rather than "B", and `clock` controls the speed of TCLK in hertz.
+    pub fn new(clock: u32, primary: bool) -> Self {
+        Self::try_new(clock, primary).expect("new")
+    }
+
+    /// Like `new`, but returns an error instead of panicking if the adapter isn't attached or
+    /// can't be initialized
+    pub fn try_new(clock: u32, primary: bool) -> Result<Self, CableError> {
+        let interface = if primary {
+            Interface::A
+        } else {
+            Interface::B
+        };
+        Self::with_vid_pid(FTDI_VID, FT2232H_PID, interface, PinMap::jtagkey(), clock)
+    }
+
+    /// Open interface `interface` of the first adapter with USB vendor ID `vid` and product ID
+    /// `pid`, for adapters that have been given IDs of their own, with its reset lines and buffer
+    /// enables wired as `pins` says.  The chip must be an FT2232H.
+    pub fn with_vid_pid(vid: u16, pid: u16, interface: Interface, pins: PinMap, clock: u32)
+        -> Result<Self, CableError>
+    {
+        let ft = LibusbFtdi::open(vid, pid, interface)?;
+        let mut ft = Mpsse::try_new(ft, clock)?;
+        let pins = JtagPins::init(pins, &mut ft)?;
+
+        Ok(Self { ft, pins })
+    }
+
+    /// See `mpsse::JtagKey::set_timeouts`.  A read only times out once no data has arrived for
+    /// the whole timeout.
+    pub fn set_timeouts(&mut self, read_ms: u32, write_ms: u32) -> Result<(), CableError> {
+        self.ft.ft.read_timeout = Duration::from_millis(read_ms.into());
+        self.ft.ft.write_timeout = Duration::from_millis(write_ms.into());
+        Ok(())
+    }
+
+    /// Set the maximum number of bytes sent in a single MPSSE command.  See
+    /// `Mpsse::set_max_chunk`.
+    pub fn set_max_chunk(&mut self, max_chunk: usize) {
+        self.ft.set_max_chunk(max_chunk);
+    }
+
+    /// Enable or disable adaptive clocking with RTCK on ADBUS7.  See
+    /// `Mpsse::set_adaptive_clocking`.
+    pub fn set_adaptive_clocking(&mut self, enable: bool) -> Result<(), CableError> {
+        self.ft.set_adaptive_clocking(enable)
+    }
+
+    /// Enable or disable 3-phase data clocking.  See `Mpsse::set_three_phase_clocking`.
+    pub fn set_three_phase_clocking(&mut self, enable: bool) -> Result<(), CableError> {
+        self.ft.set_three_phase_clocking(enable)
+    }
+
+    /// Send raw MPSSE commands to the adapter, such as ones driving a GPIO this crate doesn't
+    /// use.  This can leave the TAP state tracking out of step.  See `Mpsse::send_raw`.
+    pub fn send_raw(&mut self, cmd: &[u8]) -> Result<(), CableError> {
+        self.ft.send_raw(cmd)
+    }
+
+    /// Send raw MPSSE commands and read back `read_len` bytes.  See `Mpsse::xfer_raw`.
+    pub fn xfer_raw(&mut self, cmd: &[u8], read_len: usize) -> Result<Vec<u8>, CableError> {
+        self.ft.xfer_raw(cmd, read_len)
+    }
+
+    /// The TCK frequency the adapter is really running at.  See `Mpsse::actual_clock`.
+    pub fn actual_clock(&self) -> u32 {
+        self.ft.actual_clock()
+    }
+}
+
+/// Send any buffered commands, like `mpsse::JtagKey` does
+impl Drop for JtagKey {
+    fn drop(&mut self) {
+        if let Err(e) = self.ft.flush() {
+            log::warn!("failed to flush jtagkey on drop: {}", e);
+        }
+    }
+}
+
+impl Cable for JtagKey {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.ft.change_mode(tms, tdi)
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        self.ft.change_mode_capture(tms, tdi)
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.ft.read_data(bits)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.ft.write_data(data, bits, pause_after)
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.ft.read_write_data(data, bits, pause_after)
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.ft.queue_read_write(data, bits, pause_after)
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.ft.flush()
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        self.ft.queue_read(bits)
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.ft.finish_read(bits)
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.ft.set_bit_order(order)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.ft.set_clock(hz)
+    }
+
+    fn max_clock(&self) -> u32 {
+        self.ft.max_clock()
+    }
+
+    fn queue_capacity(&self) -> usize {
+        self.ft.queue_capacity()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.ft.queue_len()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.ft.ping()
+    }
+
+    /// Puts the system in reset, if the adapter has an SRST line
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.pins.set_srst(&mut self.ft, true)
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.pins.set_srst(&mut self.ft, false)
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.pins.set_trst(&mut self.ft, true)
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.pins.set_trst(&mut self.ft, false)
+    }
+
+    /// Drives the active low output enable, ADBUS4 on a jtagkey, high to tri-state the JTAG
+    /// buffer and low to enable it.  Queued commands are flushed first.
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.pins.set_output_enable(&mut self.ft, enable)
+    }
+
+    /// Reads the GPIO pins.  Any queued JTAG commands are flushed first.  Panics if reads are
+    /// queued, since their results would be mixed up with the pin state.
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        self.pins.read(&mut self.ft)
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        self.pins.capabilities()
+    }
+}
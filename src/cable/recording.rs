@@ -0,0 +1,352 @@
+//! Record every operation on a cable to a file, and replay the file later without any hardware.
+//! A recording made while reproducing a problem can be attached to a bug report, and replaying
+//! it checks that the code drives the cable exactly as it did when recorded.
+//!
+//! The format is plain text with one operation per line: the method name and its arguments,
+//! followed by " = " and the TDO returned for methods that read.  Byte strings are in hex, in
+//! the order they are shifted, and TMS sequences are strings of 0s and 1s.  For example:
+//!
+//! ```text
+//! change_mode 11111 1
+//! read_write_data a5 4 1 = 0c
+//! flush
+//! ```
+//!
+//! Only operations that succeeded are recorded.
+use crate::bits::Bits;
+use crate::cable::{BitOrder, Cable, CableCapabilities, CableError, CableGpio};
+
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn parse_hex(line: &str, s: &str) -> Result<Vec<u8>, CableError> {
+    if !s.len().is_multiple_of(2) {
+        return Err(CableError::Config(format!("bad TDO in recording: {}", line)));
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i+2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| CableError::Config(format!("bad TDO in recording: {}", line)))
+}
+
+fn tms_string(tms: &[usize]) -> String {
+    tms.iter().map(|x| if *x != 0 { '1' } else { '0' }).collect()
+}
+
+fn order_name(order: BitOrder) -> &'static str {
+    match order {
+        BitOrder::Lsb => "lsb",
+        BitOrder::Msb => "msb",
+    }
+}
+
+pub struct RecordingCable<T, W> {
+    pub cable: T,
+    sink: W,
+}
+
+impl<T, U, W> RecordingCable<T, W>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized,
+          W: Write
+{
+    /// Record every operation performed on `cable` into `sink`
+    pub fn new(cable: T, sink: W) -> Self {
+        Self {
+            cable,
+            sink,
+        }
+    }
+
+    fn record(&mut self, op: &str, tdo: Option<&[u8]>) -> Result<(), CableError> {
+        match tdo {
+            Some(tdo) => writeln!(self.sink, "{} = {}", op, hex(tdo))?,
+            None => writeln!(self.sink, "{}", op)?,
+        }
+        Ok(())
+    }
+}
+
+impl<T, U, W> Cable for RecordingCable<T, W>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized,
+          W: Write
+{
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.cable.change_mode(tms, tdi)?;
+        self.record(&format!("change_mode {} {}", tms_string(tms), tdi as u8), None)
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        let tdo = self.cable.change_mode_capture(tms, tdi)?;
+        let samples = Bits::from_bools(tdo.iter().copied());
+        self.record(&format!("change_mode_capture {} {}", tms_string(tms), tdi as u8), Some(samples.as_bytes()))?;
+        Ok(tdo)
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.read_data(bits)?;
+        self.record(&format!("read_data {}", bits), Some(&tdo))?;
+        Ok(tdo)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.cable.write_data(data, bits, pause_after)?;
+        self.record(&format!("write_data {} {} {}", hex(data), bits, pause_after as u8), None)
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.read_write_data(data, bits, pause_after)?;
+        self.record(&format!("read_write_data {} {} {}", hex(data), bits, pause_after as u8), Some(&tdo))?;
+        Ok(tdo)
+    }
+
+    /// Flushes the sink as well, so the recording is complete up to this point
+    fn flush(&mut self) -> Result<usize, CableError> {
+        let bytes = self.cable.flush()?;
+        self.record("flush", None)?;
+        self.sink.flush()?;
+        Ok(bytes)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        self.cable.queue_read(bits)?;
+        self.record(&format!("queue_read {}", bits), None)
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.cable.queue_read_write(data, bits, pause_after)?;
+        self.record(&format!("queue_read_write {} {} {}", hex(data), bits, pause_after as u8), None)
+    }
+
+    fn queue_capacity(&self) -> usize {
+        self.cable.queue_capacity()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.cable.queue_len()
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.cable.set_bit_order(order)?;
+        self.record(&format!("set_bit_order {}", order_name(order)), None)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.cable.set_clock(hz)?;
+        self.record(&format!("set_clock {}", hz), None)
+    }
+
+    fn max_clock(&self) -> u32 {
+        self.cable.max_clock()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.cable.ping()
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_srst()?;
+        self.record("assert_srst", None)
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_srst()?;
+        self.record("deassert_srst", None)
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_trst()?;
+        self.record("assert_trst", None)
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_trst()?;
+        self.record("deassert_trst", None)
+    }
+
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.cable.set_output_enable(enable)?;
+        self.record(&format!("set_output_enable {}", enable as u8), None)
+    }
+
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        self.cable.read_gpio()
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.finish_read(bits)?;
+        self.record(&format!("finish_read {}", bits), Some(&tdo))?;
+        Ok(tdo)
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        self.cable.capabilities()
+    }
+}
+
+/// Plays back a recording made by `RecordingCable`.  Each call must be the same, with the same
+/// arguments, as the next operation in the recording, and reads return the recorded TDO.  Any
+/// difference is a bug in the code driving the cable, so it panics with both versions of the
+/// call, as does running past the end of the recording.
+pub struct ReplayCable {
+    lines: VecDeque<String>,
+}
+
+impl ReplayCable {
+    /// Read a whole recording from `reader`.  Blank lines are skipped.
+    pub fn new(reader: impl BufRead) -> Result<Self, CableError> {
+        let mut lines = VecDeque::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                lines.push_back(line);
+            }
+        }
+        Ok(Self { lines })
+    }
+
+    /// The number of recorded operations that haven't been replayed yet, so a test can check
+    /// that the whole recording was used
+    pub fn remaining(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Check that `op` is the next recorded operation, and return its TDO if it has one
+    fn replay(&mut self, op: &str) -> Result<Option<Vec<u8>>, CableError> {
+        let line = self.lines.pop_front()
+            .unwrap_or_else(|| panic!("recording ended before {}", op));
+        let (recorded, tdo) = match line.split_once(" =") {
+            Some((recorded, tdo)) => (recorded, Some(tdo.trim())),
+            None => (line.as_str(), None),
+        };
+        assert_eq!(recorded, op, "cable operation doesn't match the recording");
+        tdo.map(|x| parse_hex(&line, x)).transpose()
+    }
+
+    fn replay_tdo(&mut self, op: &str) -> Result<Vec<u8>, CableError> {
+        self.replay(op)?.ok_or_else(|| CableError::Config(format!("no TDO in recording for {}", op)))
+    }
+}
+
+impl Cable for ReplayCable {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.replay(&format!("change_mode {} {}", tms_string(tms), tdi as u8))?;
+        Ok(())
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        let tdo = self.replay_tdo(&format!("change_mode_capture {} {}", tms_string(tms), tdi as u8))?;
+        if tdo.len() * 8 < tms.len() {
+            return Err(CableError::Config("short TDO in recording for change_mode_capture".to_string()));
+        }
+        Ok(Bits::new(tdo, tms.len()).iter().collect())
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.replay_tdo(&format!("read_data {}", bits))
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.replay(&format!("write_data {} {} {}", hex(data), bits, pause_after as u8))?;
+        Ok(())
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.replay_tdo(&format!("read_write_data {} {} {}", hex(data), bits, pause_after as u8))
+    }
+
+    /// Nothing really reaches hardware, so this returns 0
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.replay("flush")?;
+        Ok(0)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        self.replay(&format!("queue_read {}", bits))?;
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.replay(&format!("queue_read_write {} {} {}", hex(data), bits, pause_after as u8))?;
+        Ok(())
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.replay(&format!("set_bit_order {}", order_name(order)))?;
+        Ok(())
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.replay(&format!("set_clock {}", hz))?;
+        Ok(())
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.replay("assert_srst")?;
+        Ok(())
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.replay("deassert_srst")?;
+        Ok(())
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.replay("assert_trst")?;
+        Ok(())
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.replay("deassert_trst")?;
+        Ok(())
+    }
+
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.replay(&format!("set_output_enable {}", enable as u8))?;
+        Ok(())
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.replay_tdo(&format!("finish_read {}", bits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::MockCable;
+    use crate::statemachine::JtagSM;
+
+    /// Reset, read an IDCODE, and shift an instruction, returning what was read
+    fn session(cable: &mut dyn Cable) -> Vec<u8> {
+        let mut sm = JtagSM::new(cable).unwrap();
+        let idcode = sm.shift_dr(&[0; 4], 8).unwrap();
+        sm.shift_ir(&[0x0e], 4).unwrap();
+        sm.cable.flush().unwrap();
+        idcode
+    }
+
+    #[test]
+    fn record_then_replay() {
+        let mut mock = MockCable::with_tdo(vec![vec![0x77, 0x04, 0xa0, 0x4b], vec![0x01]]);
+        let mut recording = vec![];
+        let idcode = session(&mut RecordingCable::new(&mut mock, &mut recording));
+        assert_eq!(idcode, vec![0x77, 0x04, 0xa0, 0x4b]);
+
+        let mut replay = ReplayCable::new(&recording[..]).unwrap();
+        assert_eq!(session(&mut replay), idcode);
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't match the recording")]
+    fn replay_mismatch() {
+        let mut replay = ReplayCable::new("write_data a5 8 1\n".as_bytes()).unwrap();
+        replay.write_data(&[0x5a], 8, true).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+//! Implement the `Cable` trait for OpenOCD's `remote_bitbang` protocol.  This is a simple ASCII
+//! protocol spoken over a TCP socket, and is supported by simulators like QEMU and Verilator.
+//!
+//! Each command is a single character:
+//! - '0' through '7' set TCK, TMS, and TDI, with TCK as bit 2, TMS as bit 1, and TDI as bit 0
+//! - 'R' samples TDO, and the remote side responds with '0' or '1'
+//! - 'r', 's', 't', and 'u' set TRST and SRST, with 'r' deasserting both and 'u' asserting both
+use crate::cable::{Cable, CableCapabilities, CableError};
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+pub struct RemoteBitbang {
+    stream: TcpStream,
+    // Commands we haven't sent yet
+    buffer: Vec<u8>,
+    // Number of 'R' commands sent or queued whose responses haven't been read
+    pending_samples: usize,
+    // TDO samples we have received and not yet returned
+    samples: Vec<bool>,
+    // Number of bits for each queued read
+    queued_reads: Vec<usize>,
+    trst: bool,
+    srst: bool,
+}
+
+impl RemoteBitbang {
+    /// Connect to a remote_bitbang server.  `addr` is anything accepted by
+    /// `TcpStream::connect`, such as "localhost:2430".
+    pub fn connect(addr: &str) -> Result<Self, CableError> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            stream,
+            buffer: vec![],
+            pending_samples: 0,
+            samples: vec![],
+            queued_reads: vec![],
+            trst: false,
+            srst: false,
+        })
+    }
+
+    /// Set the state of the TRST and SRST reset lines, `true` meaning asserted.
+    pub fn reset(&mut self, trst: bool, srst: bool) {
+        self.trst = trst;
+        self.srst = srst;
+        let cmd = match (trst, srst) {
+            (false, false) => b'r',
+            (false, true) => b's',
+            (true, false) => b't',
+            (true, true) => b'u',
+        };
+        self.buffer.push(cmd);
+    }
+
+    fn write(&mut self, tck: bool, tms: bool, tdi: bool) {
+        let cmd = (tck as u8) << 2 | (tms as u8) << 1 | tdi as u8;
+        self.buffer.push(b'0' + cmd);
+    }
+
+    /// Clock one bit, optionally sampling TDO before the rising edge of TCK.
+    fn clock(&mut self, tms: bool, tdi: bool, sample: bool) {
+        self.write(false, tms, tdi);
+        if sample {
+            self.buffer.push(b'R');
+            self.pending_samples += 1;
+        }
+        self.write(true, tms, tdi);
+    }
+
+    /// An empty `data` clocks nothing except the way to PauseDR or PauseIR if `pause_after` is set
+    fn shift(&mut self, data: &[u8], bits: u8, pause_after: bool, sample: bool) -> usize {
+        if data.is_empty() {
+            if pause_after {
+                self.clock(true, true, false);
+                self.clock(false, true, false);
+                self.write(false, false, true);
+            }
+            return 0;
+        }
+        assert!(bits <= 8);
+        assert!(bits != 0);
+
+        let total_bits = (data.len()-1) * 8 + bits as usize;
+        for i in 0..total_bits {
+            let tdi = data[i / 8] & (1 << (i % 8)) != 0;
+            let tms = pause_after && i == total_bits-1;
+            self.clock(tms, tdi, sample);
+        }
+
+        // Go from Exit1 to Pause
+        if pause_after {
+            self.clock(false, true, false);
+        }
+        self.write(false, false, true);
+        total_bits
+    }
+
+    /// Send any buffered commands and collect the responses to every outstanding sample.
+    fn collect_samples(&mut self) -> Result<(), CableError> {
+        self.flush()?;
+
+        let mut buf = vec![0; self.pending_samples];
+        self.stream.read_exact(&mut buf)?;
+        self.pending_samples = 0;
+        for x in buf {
+            match x {
+                b'0' => self.samples.push(false),
+                b'1' => self.samples.push(true),
+                _ => return Err(CableError::Protocol(format!("unexpected response {:#x}", x))),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send any buffered commands, so the last writes aren't lost.  Errors are logged, since they
+/// can't be returned.
+impl Drop for RemoteBitbang {
+    fn drop(&mut self) {
+        if let Err(e) = self.stream.write_all(&self.buffer) {
+            log::warn!("failed to flush remote_bitbang on drop: {}", e);
+        }
+    }
+}
+
+impl Cable for RemoteBitbang {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        for x in tms {
+            self.clock(*x != 0, tdi, false);
+        }
+        self.write(false, false, tdi);
+        Ok(())
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.queue_read(bits)?;
+        self.finish_read(bits)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.shift(data, bits, pause_after, false);
+        Ok(())
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.queue_read_write(data, bits, pause_after)?;
+        let total_bits = if data.is_empty() {
+            0
+        } else {
+            (data.len()-1) * 8 + bits as usize
+        };
+        self.finish_read(total_bits)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let buf = vec![0xff; bits.div_ceil(8)];
+        let mut last = (bits % 8) as u8;
+        if last == 0 {
+            last = 8;
+        }
+        self.queue_read_write(&buf, last, false)
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let total_bits = self.shift(data, bits, pause_after, true);
+        self.queued_reads.push(total_bits);
+        Ok(())
+    }
+
+    fn queue_len(&self) -> usize {
+        self.queued_reads.iter().map(|x| x.div_ceil(8)).sum()
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let orig_bits = self.queued_reads.remove(0);
+        assert_eq!(bits, orig_bits);
+
+        if self.samples.len() < bits {
+            self.collect_samples()?;
+        }
+
+        let mut buf = vec![0; bits.div_ceil(8)];
+        for (i, x) in self.samples.drain(..bits).enumerate() {
+            if x {
+                buf[i / 8] |= 1 << (i % 8);
+            }
+        }
+        Ok(buf)
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        let bytes = self.buffer.len();
+        self.stream.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(bytes)
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.reset(self.trst, true);
+        Ok(())
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.reset(self.trst, false);
+        Ok(())
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.reset(true, self.srst);
+        Ok(())
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.reset(false, self.srst);
+        Ok(())
+    }
+
+    /// The clock is set by the remote end
+    fn capabilities(&self) -> CableCapabilities {
+        CableCapabilities {
+            srst: true,
+            trst: true,
+            native_queue: true,
+            ..CableCapabilities::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    #[test]
+    fn zero_length() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let mut cable = RemoteBitbang::connect(&addr).unwrap();
+
+        assert!(cable.read_data(0).unwrap().is_empty());
+        assert!(cable.read_write_data(&[], 8, false).unwrap().is_empty());
+        cable.write_data(&[], 8, false).unwrap();
+        assert_eq!(cable.flush().unwrap(), 0);
+
+        cable.write_data(&[], 8, true).unwrap();
+        assert_eq!(cable.buffer, b"37151");
+    }
+
+
+    #[test]
+    fn capabilities_match() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // Answer every sample request with a one, until the cable hangs up
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 64];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                let ones = vec![b'1'; buf[..n].iter().filter(|&&x| x == b'R').count()];
+                stream.write_all(&ones).unwrap();
+            }
+        });
+
+        let mut cable = RemoteBitbang::connect(&addr).unwrap();
+        crate::cable::tests::check_capabilities(&mut cable);
+        drop(cable);
+        server.join().unwrap();
+    }
+}
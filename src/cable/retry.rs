@@ -0,0 +1,216 @@
+//! A `Cable` that retries operations that fail with a transient USB error, such as the spurious
+//! errors some hubs produce, before passing the error on.
+//!
+//! Only operations that can be repeated without changing what happens on the wire are retried:
+//! `read_data`, `set_clock`, `ping`, and `read_gpio`.  Anything that writes, or that pops state
+//! off a queue like `finish_read`, may have partly happened before the error, so repeating it
+//! could clock the target twice.  Those errors are passed straight on.
+use crate::cable::{BitOrder, Cable, CableCapabilities, CableError, CableGpio};
+
+use std::time::Duration;
+
+fn is_transient(e: &CableError) -> bool {
+    match e {
+        #[cfg(feature = "ftd2xx")]
+        CableError::Usb(_) => true,
+        CableError::LibUsb(rusb::Error::Timeout | rusb::Error::Pipe | rusb::Error::Io | rusb::Error::Busy) => true,
+        CableError::Timeout => true,
+        _ => false,
+    }
+}
+
+pub struct RetryCable<T> {
+    pub cable: T,
+    retries: usize,
+    delay: Duration,
+}
+
+impl<T, U> RetryCable<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Retry failed reads on `cable` up to `retries` times, waiting `delay` before each retry
+    pub fn new(cable: T, retries: usize, delay: Duration) -> Self {
+        Self {
+            cable,
+            retries,
+            delay,
+        }
+    }
+
+    fn retry<R>(&mut self, mut op: impl FnMut(&mut U) -> Result<R, CableError>) -> Result<R, CableError> {
+        let mut tries = 0;
+        loop {
+            match op(&mut self.cable) {
+                Err(e) if is_transient(&e) && tries < self.retries => {
+                    log::debug!("retrying after {}", e);
+                    tries += 1;
+                    std::thread::sleep(self.delay);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<T, U> Cable for RetryCable<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        self.cable.change_mode(tms, tdi)
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        self.cable.change_mode_capture(tms, tdi)
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.retry(|c| c.read_data(bits))
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.cable.write_data(data, bits, pause_after)
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        self.cable.read_write_data(data, bits, pause_after)
+    }
+
+    fn flush(&mut self) -> Result<usize, CableError> {
+        self.cable.flush()
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        self.cable.queue_read(bits)
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.cable.queue_read_write(data, bits, pause_after)
+    }
+
+    fn queue_capacity(&self) -> usize {
+        self.cable.queue_capacity()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.cable.queue_len()
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.cable.set_bit_order(order)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.retry(|c| c.set_clock(hz))
+    }
+
+    fn max_clock(&self) -> u32 {
+        self.cable.max_clock()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.retry(|c| c.ping())
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_srst()
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_srst()
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_trst()
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_trst()
+    }
+
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.cable.set_output_enable(enable)
+    }
+
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        self.retry(|c| c.read_gpio())
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        self.cable.finish_read(bits)
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        self.cable.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fails the first `failures` reads and writes with a libusb timeout
+    struct FlakyCable {
+        failures: usize,
+        reads: usize,
+        writes: usize,
+    }
+
+    impl FlakyCable {
+        fn fail(&mut self) -> Result<(), CableError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(CableError::LibUsb(rusb::Error::Timeout));
+            }
+            Ok(())
+        }
+    }
+
+    impl Cable for FlakyCable {
+        fn change_mode(&mut self, _tms: &[usize], _tdi: bool) -> Result<(), CableError> {
+            Ok(())
+        }
+
+        fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+            self.reads += 1;
+            self.fail()?;
+            Ok(vec![0x5a; bits.div_ceil(8)])
+        }
+
+        fn write_data(&mut self, _data: &[u8], _bits: u8, _pause_after: bool) -> Result<(), CableError> {
+            self.writes += 1;
+            self.fail()
+        }
+
+        fn read_write_data(&mut self, data: &[u8], _bits: u8, _pause_after: bool) -> Result<Vec<u8>, CableError> {
+            self.writes += 1;
+            self.fail()?;
+            Ok(data.to_vec())
+        }
+    }
+
+    #[test]
+    fn read_succeeds_on_third_try() {
+        let mut flaky = FlakyCable { failures: 2, reads: 0, writes: 0 };
+        let mut cable = RetryCable::new(&mut flaky, 2, Duration::ZERO);
+        assert_eq!(cable.read_data(8).unwrap(), vec![0x5a]);
+        assert_eq!(flaky.reads, 3);
+    }
+
+    #[test]
+    fn read_gives_up() {
+        let mut flaky = FlakyCable { failures: 3, reads: 0, writes: 0 };
+        let mut cable = RetryCable::new(&mut flaky, 2, Duration::ZERO);
+        assert!(matches!(cable.read_data(8), Err(CableError::LibUsb(rusb::Error::Timeout))));
+        assert_eq!(flaky.reads, 3);
+    }
+
+    #[test]
+    fn write_not_retried() {
+        let mut flaky = FlakyCable { failures: 1, reads: 0, writes: 0 };
+        let mut cable = RetryCable::new(&mut flaky, 2, Duration::ZERO);
+        assert!(cable.write_data(&[0xff], 8, false).is_err());
+        assert_eq!(flaky.writes, 1);
+    }
+}
@@ -1,18 +1,52 @@
 //! Implement the `Cable` trait for Altera USB Blaster and clones
-use crate::cable::Cable;
+use crate::cable::{Cable, CableError};
+
+use std::collections::VecDeque;
 
 use libftd2xx::{Ftdi, FtdiCommon};
 
-pub struct UsbBlaster {
-    ft: Ftdi,
+/// How a `UsbBlaster` talks to its FTDI chip.  Implementations only move bytes.
+pub trait BlasterDevice {
+    /// Send bytes to the Blaster's command interpreter
+    fn write(&mut self, data: &[u8]) -> Result<(), CableError>;
+    /// Read exactly `data.len()` bytes of TDO samples
+    fn read(&mut self, data: &mut [u8]) -> Result<(), CableError>;
+    /// Check that the chip is still attached, without touching the JTAG pins
+    fn ping(&mut self) -> Result<(), CableError>;
+}
+
+impl BlasterDevice for Ftdi {
+    fn write(&mut self, data: &[u8]) -> Result<(), CableError> {
+        FtdiCommon::write(self, data)?;
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<(), CableError> {
+        FtdiCommon::read(self, data)?;
+        Ok(())
+    }
+
+    /// Reads the modem status, which doesn't affect the JTAG pins
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.modem_status()?;
+        Ok(())
+    }
+}
+
+pub struct UsbBlaster<T = Ftdi> {
+    ft: T,
     tdi: u8,
     tdo: u8,
     tms: u8,
     clk: u8,
-    read_queue: Vec<Vec<u8>>,
+    read_queue: VecDeque<Vec<u8>>,
 }
 
 const READ_CMD: u8 = 1 << 6;
+// Switches to byte-shift mode for the number of bytes in the low 6 bits.  Each following byte is
+// shifted out on TDI LSB first, 8 clocks per byte, with TMS low.
+const BYTE_SHIFT_CMD: u8 = 1 << 7;
+const MAX_BYTE_SHIFT: usize = 63;
 
 impl Default for UsbBlaster {
     fn default() -> Self {
@@ -26,14 +60,20 @@ impl UsbBlaster {
         libftd2xx::set_vid_pid(0x16c0, 0x06ad).expect("vid");
         let mut ft = Ftdi::with_description("USB-JTAG-IF").expect("new");
         ft.purge_all().expect("purge");
+        Self::with_device(ft)
+    }
+}
 
+impl<T: BlasterDevice> UsbBlaster<T> {
+    /// Create a UsbBlaster that talks through `ft`
+    pub fn with_device(ft: T) -> Self {
         Self {
             ft,
             tdi: 0,
             tdo: 4,
             tms: 1,
             clk: 0,
-            read_queue: vec![],
+            read_queue: VecDeque::new(),
         }
     }
 
@@ -70,10 +110,10 @@ impl UsbBlaster {
     }
 }
 
-impl Cable for UsbBlaster {
-    fn change_mode(&mut self, tms: &[usize], tdo: bool) {
+impl<T: BlasterDevice> Cable for UsbBlaster<T> {
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
         let mut buf = vec![];
-        let tdo = if tdo {
+        let tdi = if tdi {
             1
         } else {
             0
@@ -85,15 +125,16 @@ impl Cable for UsbBlaster {
             } else {
                 0
             };
-            buf.push(x << self.tms | tdo << self.tdo);
-            buf.push(x << self.tms | tdo << self.tdo | 1 << self.clk);
+            buf.push(x << self.tms | tdi << self.tdo);
+            buf.push(x << self.tms | tdi << self.tdo | 1 << self.clk);
         }
-        self.ft.write(&buf).expect("send");
+        self.ft.write(&buf)?;
+        Ok(())
     }
 
-    fn read_data(&mut self, mut bits: usize) -> Vec<u8>
+    fn read_data(&mut self, mut bits: usize) -> Result<Vec<u8>, CableError>
     {
-        let bytes = (bits + 7) / 8;
+        let bytes = bits.div_ceil(8);
         let buf = vec![0xff; bytes];
 
         bits %= 8;
@@ -103,24 +144,38 @@ impl Cable for UsbBlaster {
         self.read_write_data(&buf, bits as u8, false) 
     }
 
-    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool)
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError>
     {
-        self.read_write_data(data, bits, pause_after);
+        self.read_write_data(data, bits, pause_after)?;
+        Ok(())
     }
 
-    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
+    /// Whole bytes are sent in byte-shift mode, which is much faster than bit-banging, and only the
+    /// last byte is bit-banged so that TMS can be raised on its last bit.  An empty `data` clocks
+    /// nothing except the way to PauseDR or PauseIR if `pause_after` is set, and returns an empty
+    /// vector.
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        if data.is_empty() {
+            if pause_after {
+                self.change_mode(&[1, 0], true)?;
+            }
+            return Ok(vec![]);
+        }
         let mut buf = vec![];
 
         assert!(bits <= 8);
         assert!(bits != 0);
 
-        for x in &data[0..data.len()-1] {
-            for bit in 0..8 {
-                let tdo = (x >> bit) & 1;
-                buf.push(tdo << self.tdo);
-                buf.push(READ_CMD | tdo << self.tdo | 1 << self.clk);
+        let whole = &data[0..data.len()-1];
+        if !whole.is_empty() {
+            // Byte-shift mode clocks TCK itself, so start with it low
+            buf.push(0);
+            for chunk in whole.chunks(MAX_BYTE_SHIFT) {
+                buf.push(BYTE_SHIFT_CMD | READ_CMD | chunk.len() as u8);
+                buf.extend_from_slice(chunk);
             }
         }
+        let bitbang_start = buf.len();
 
         // handle last byte
         let x = data[data.len()-1];
@@ -134,25 +189,121 @@ impl Cable for UsbBlaster {
             buf.push(READ_CMD | tdo << self.tdo | 1 << self.clk);
         }
 
-        let mut recv = vec![0; buf.len()/2];
-        self.ft.write(&buf).expect("send");
-        self.ft.read(&mut recv).expect("send");
-        Self::select_bit(recv, self.tdi)
+        // Byte-shift mode returns one byte of TDO per byte shifted, and bit-banging returns one
+        // byte per bit
+        let mut recv = vec![0; whole.len() + (buf.len() - bitbang_start) / 2];
+        self.ft.write(&buf)?;
+        self.ft.read(&mut recv)?;
+        let tail = recv.split_off(whole.len());
+        recv.append(&mut Self::select_bit(tail, self.tdi));
+        Ok(recv)
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        let data = self.read_data(bits)?;
+        self.read_queue.push_back(data);
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        let result = self.read_write_data(data, bits, pause_after)?;
+        self.read_queue.push_back(result);
+        Ok(())
+    }
+
+    fn finish_read(&mut self, _bits: usize) -> Result<Vec<u8>, CableError> {
+        self.read_queue.pop_front()
+            .ok_or_else(|| CableError::Protocol("finish_read without a queued read".to_string()))
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.ft.ping()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records everything written, and answers reads from a scripted reply
+    #[derive(Default)]
+    struct FakeBlaster {
+        sent: Vec<u8>,
+        reply: VecDeque<u8>,
+    }
+
+    impl BlasterDevice for FakeBlaster {
+        fn write(&mut self, data: &[u8]) -> Result<(), CableError> {
+            self.sent.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self, data: &mut [u8]) -> Result<(), CableError> {
+            for x in data {
+                *x = self.reply.pop_front().expect("read past the scripted reply");
+            }
+            Ok(())
+        }
+
+        fn ping(&mut self) -> Result<(), CableError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn byte_shift_framing() {
+        // 129 whole bytes are split into blocks of 63, 63, and 3, then 3 bits are bit-banged
+        let data: Vec<u8> = (0..130).map(|i| i as u8).collect();
+        let mut reply = vec![0xa5; 129];
+        reply.extend_from_slice(&[1, 0, 1]);
+        let mut blaster = UsbBlaster::with_device(FakeBlaster {
+            reply: reply.into(),
+            ..Default::default()
+        });
+
+        let tdo = blaster.read_write_data(&data, 3, false).unwrap();
+        assert_eq!(tdo.len(), 130);
+        assert!(tdo[..129].iter().all(|x| *x == 0xa5));
+        assert_eq!(tdo[129], 0b101);
+
+        let sent = &blaster.ft.sent;
+        let mut expected = vec![0];
+        for block in data[..129].chunks(MAX_BYTE_SHIFT) {
+            expected.push(BYTE_SHIFT_CMD | READ_CMD | block.len() as u8);
+            expected.extend_from_slice(block);
+        }
+        // The last byte is 129, so TDI is 1, 0, 0 for its three bits
+        expected.extend_from_slice(&[
+            1 << 4, READ_CMD | 1 << 4 | 1,
+            0, READ_CMD | 1,
+            0, READ_CMD | 1,
+        ]);
+        assert_eq!(sent, &expected);
+        assert!(blaster.ft.reply.is_empty());
     }
 
-    fn queue_read(&mut self, bits: usize) -> bool {
-        let data = self.read_data(bits);
-        self.read_queue.push(data);
-        true
+    #[test]
+    fn single_byte_is_bit_banged() {
+        let mut blaster = UsbBlaster::with_device(FakeBlaster {
+            reply: vec![0; 8].into(),
+            ..Default::default()
+        });
+        blaster.write_data(&[0xff], 8, false).unwrap();
+        assert!(!blaster.ft.sent.contains(&0));
+        assert_eq!(blaster.ft.sent.len(), 16);
     }
 
-    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> bool {
-        let result = self.read_write_data(data, bits, pause_after);
-        self.read_queue.push(result);
-        true
+    #[test]
+    fn zero_length() {
+        let mut blaster = UsbBlaster::with_device(FakeBlaster::default());
+        assert!(blaster.read_data(0).unwrap().is_empty());
+        blaster.write_data(&[], 8, false).unwrap();
+        assert!(blaster.ft.sent.is_empty());
     }
 
-    fn finish_read(&mut self, _bits: usize) -> Vec<u8> {
-        self.read_queue.remove(0)
+
+    #[test]
+    fn capabilities_match() {
+        crate::cable::tests::check_capabilities(&mut UsbBlaster::with_device(FakeBlaster::default()));
     }
 }
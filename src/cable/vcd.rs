@@ -0,0 +1,346 @@
+//! A `Cable` that records the activity on the JTAG pins as a VCD (Value Change Dump) file, which
+//! can be viewed with tools like GTKWave, while passing every operation on to another cable.
+//!
+//! The waveform is reconstructed from the operations rather than measured, so the timescale is
+//! synthetic: one TCK period is 10ns regardless of the real clock.  TDO is only known for reads,
+//! and is shown as 'x' elsewhere.  Clocks are kept in memory until `flush`, since the TDO for a
+//! queued read isn't known until `finish_read`.
+use crate::cable::{BitOrder, Cable, CableCapabilities, CableError, CableGpio};
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+const HALF_PERIOD: u64 = 5;
+
+/// The state of the pins during one TCK cycle
+struct Clock {
+    tms: bool,
+    tdi: bool,
+    tdo: Option<bool>,
+}
+
+fn bit(data: &[u8], i: usize) -> bool {
+    data[i / 8] & (1 << (i % 8)) != 0
+}
+
+/// Like `bit`, but `None` if `data` is too short, in case a cable returns fewer bytes than asked
+fn tdo_bit(data: &[u8], i: usize) -> Option<bool> {
+    data.get(i / 8).map(|x| x & (1 << (i % 8)) != 0)
+}
+
+fn total_bits(data: &[u8], bits: u8) -> usize {
+    (data.len()-1) * 8 + bits as usize
+}
+
+fn value(x: Option<bool>) -> char {
+    match x {
+        Some(false) => '0',
+        Some(true) => '1',
+        None => 'x',
+    }
+}
+
+pub struct VcdCable<T, W> {
+    pub cable: T,
+    sink: W,
+    // Clocks that haven't been written to `sink` yet
+    clocks: Vec<Clock>,
+    // Index into `clocks` and number of bits of each queued read
+    queued: VecDeque<(usize, usize)>,
+    header_written: bool,
+    time: u64,
+    // The last values written for TMS, TDI, and TDO
+    last: [char; 3],
+}
+
+impl<T, U, W> VcdCable<T, W>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized,
+          W: Write
+{
+    /// Record every operation performed on `cable` into `sink`
+    pub fn new(cable: T, sink: W) -> Self {
+        Self {
+            cable,
+            sink,
+            clocks: vec![],
+            queued: VecDeque::new(),
+            header_written: false,
+            time: 0,
+            last: ['x'; 3],
+        }
+    }
+
+    /// Record a shift of `data`, ending with TMS high if `pause_after`.  `tdo` holds the bits
+    /// that were read, if known.
+    fn shift(&mut self, data: &[u8], bits: usize, pause_after: bool, tdo: Option<&[u8]>) {
+        for i in 0..bits {
+            self.clocks.push(Clock {
+                tms: pause_after && i == bits-1,
+                tdi: bit(data, i),
+                tdo: tdo.and_then(|x| tdo_bit(x, i)),
+            });
+        }
+        // Go from Exit1 to Pause
+        if pause_after {
+            self.clocks.push(Clock {
+                tms: false,
+                tdi: true,
+                tdo: None,
+            });
+        }
+    }
+
+    fn write_header(&mut self) -> Result<(), CableError> {
+        writeln!(self.sink, "$timescale 1ns $end")?;
+        writeln!(self.sink, "$scope module jtag $end")?;
+        writeln!(self.sink, "$var wire 1 ! tck $end")?;
+        writeln!(self.sink, "$var wire 1 \" tms $end")?;
+        writeln!(self.sink, "$var wire 1 # tdi $end")?;
+        writeln!(self.sink, "$var wire 1 $ tdo $end")?;
+        writeln!(self.sink, "$upscope $end")?;
+        writeln!(self.sink, "$enddefinitions $end")?;
+        writeln!(self.sink, "#0")?;
+        writeln!(self.sink, "$dumpvars")?;
+        writeln!(self.sink, "0!")?;
+        writeln!(self.sink, "x\"")?;
+        writeln!(self.sink, "x#")?;
+        writeln!(self.sink, "x$")?;
+        writeln!(self.sink, "$end")?;
+        self.time = 2 * HALF_PERIOD;
+        self.header_written = true;
+        Ok(())
+    }
+
+    /// Write out the first `count` recorded clocks.  Each clock sets TMS and TDI while TCK is
+    /// low, then raises TCK half a period later.
+    fn write_clocks(&mut self, count: usize) -> Result<(), CableError> {
+        if !self.header_written {
+            self.write_header()?;
+        }
+
+        for c in self.clocks.drain(..count) {
+            writeln!(self.sink, "#{}", self.time)?;
+            writeln!(self.sink, "0!")?;
+            let values = [value(Some(c.tms)), value(Some(c.tdi)), value(c.tdo)];
+            for (i, id) in ['"', '#', '$'].iter().enumerate() {
+                if values[i] != self.last[i] {
+                    writeln!(self.sink, "{}{}", values[i], id)?;
+                }
+            }
+            self.last = values;
+
+            writeln!(self.sink, "#{}", self.time + HALF_PERIOD)?;
+            writeln!(self.sink, "1!")?;
+            self.time += 2 * HALF_PERIOD;
+        }
+
+        for q in &mut self.queued {
+            q.0 -= count;
+        }
+        Ok(())
+    }
+}
+
+impl<T, U, W> Cable for VcdCable<T, W>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized,
+          W: Write
+{
+    fn change_mode(&mut self, tms: &[usize], tdi: bool) -> Result<(), CableError> {
+        for x in tms {
+            self.clocks.push(Clock {
+                tms: *x != 0,
+                tdi,
+                tdo: None,
+            });
+        }
+        self.cable.change_mode(tms, tdi)
+    }
+
+    fn change_mode_capture(&mut self, tms: &[usize], tdi: bool) -> Result<Vec<bool>, CableError> {
+        let tdo = self.cable.change_mode_capture(tms, tdi)?;
+        for (x, sample) in tms.iter().zip(&tdo) {
+            self.clocks.push(Clock {
+                tms: *x != 0,
+                tdi,
+                tdo: Some(*sample),
+            });
+        }
+        Ok(tdo)
+    }
+
+    fn read_data(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.read_data(bits)?;
+        self.shift(&vec![0xff; bits.div_ceil(8)], bits, false, Some(&tdo));
+        Ok(tdo)
+    }
+
+    fn write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.cable.write_data(data, bits, pause_after)?;
+        self.shift(data, total_bits(data, bits), pause_after, None);
+        Ok(())
+    }
+
+    fn read_write_data(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.read_write_data(data, bits, pause_after)?;
+        self.shift(data, total_bits(data, bits), pause_after, Some(&tdo));
+        Ok(tdo)
+    }
+
+    /// Write out everything recorded so far, except for any queued reads that haven't been
+    /// finished, and flush both the sink and the inner cable.
+    fn flush(&mut self) -> Result<usize, CableError> {
+        let count = match self.queued.front() {
+            Some(q) => q.0,
+            None => self.clocks.len(),
+        };
+        self.write_clocks(count)?;
+        self.sink.flush()?;
+        self.cable.flush()
+    }
+
+    fn queue_read(&mut self, bits: usize) -> Result<(), CableError> {
+        self.cable.queue_read(bits)?;
+        self.queued.push_back((self.clocks.len(), bits));
+        self.shift(&vec![0xff; bits.div_ceil(8)], bits, false, None);
+        Ok(())
+    }
+
+    fn queue_read_write(&mut self, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
+        self.cable.queue_read_write(data, bits, pause_after)?;
+        let total = total_bits(data, bits);
+        self.queued.push_back((self.clocks.len(), total));
+        self.shift(data, total, pause_after, None);
+        Ok(())
+    }
+
+    fn set_bit_order(&mut self, order: BitOrder) -> Result<(), CableError> {
+        self.cable.set_bit_order(order)
+    }
+
+    fn set_clock(&mut self, hz: u32) -> Result<(), CableError> {
+        self.cable.set_clock(hz)
+    }
+
+    fn max_clock(&self) -> u32 {
+        self.cable.max_clock()
+    }
+
+    fn queue_capacity(&self) -> usize {
+        self.cable.queue_capacity()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.cable.queue_len()
+    }
+
+    fn ping(&mut self) -> Result<(), CableError> {
+        self.cable.ping()
+    }
+
+    fn assert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_srst()
+    }
+
+    fn deassert_srst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_srst()
+    }
+
+    fn assert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.assert_trst()
+    }
+
+    fn deassert_trst(&mut self) -> Result<(), CableError> {
+        self.cable.deassert_trst()
+    }
+
+    fn set_output_enable(&mut self, enable: bool) -> Result<(), CableError> {
+        self.cable.set_output_enable(enable)
+    }
+
+    fn read_gpio(&mut self) -> Result<CableGpio, CableError> {
+        self.cable.read_gpio()
+    }
+
+    fn finish_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
+        let tdo = self.cable.finish_read(bits)?;
+        if let Some((start, bits)) = self.queued.pop_front() {
+            for i in 0..bits {
+                self.clocks[start + i].tdo = tdo_bit(&tdo, i);
+            }
+        }
+        Ok(tdo)
+    }
+
+    fn capabilities(&self) -> CableCapabilities {
+        self.cable.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::MockCable;
+
+    /// The TMS, TDI and TDO values at each rising edge of TCK in `vcd`
+    fn samples(vcd: &str) -> Vec<[char; 3]> {
+        let body = vcd.split("$enddefinitions $end\n").nth(1).unwrap();
+        let mut values = ['x'; 3];
+        let mut out = vec![];
+        for line in body.lines() {
+            match line {
+                "1!" => out.push(values),
+                "0!" | "$dumpvars" | "$end" => (),
+                _ if line.starts_with('#') => (),
+                _ => {
+                    let (value, id) = line.split_at(1);
+                    let i = ["\"", "#", "$"].iter().position(|x| *x == id).unwrap();
+                    values[i] = value.chars().next().unwrap();
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn header_and_changes() {
+        let mut mock = MockCable::with_tdo(vec![vec![0x06]]);
+        let mut out = vec![];
+        let mut cable = VcdCable::new(&mut mock, &mut out);
+        cable.change_mode(&[1, 0], true).unwrap();
+        cable.read_write_data(&[0x05], 3, false).unwrap();
+        cable.flush().unwrap();
+        drop(cable);
+
+        let vcd = String::from_utf8(out).unwrap();
+        assert!(vcd.starts_with("$timescale 1ns $end\n$scope module jtag $end\n$var wire 1 ! tck $end\n"));
+        assert_eq!(samples(&vcd), vec![
+            ['1', '1', 'x'],
+            ['0', '1', 'x'],
+            ['0', '1', '0'],
+            ['0', '0', '1'],
+            ['0', '1', '1'],
+        ]);
+        // Only the values that change are written, and each clock is 10ns
+        assert!(vcd.ends_with("#40\n0!\n0#\n1$\n#45\n1!\n#50\n0!\n1#\n#55\n1!\n"));
+    }
+
+    #[test]
+    fn queued_read_backfilled() {
+        let mut mock = MockCable::with_tdo(vec![vec![0x09]]);
+        let mut out = vec![];
+        let mut cable = VcdCable::new(&mut mock, &mut out);
+        cable.change_mode(&[0], true).unwrap();
+        cable.queue_read(4).unwrap();
+        // The read's TDO isn't known yet, so only the clock before it is written
+        cable.flush().unwrap();
+        assert_eq!(cable.finish_read(4).unwrap(), vec![0x09]);
+        cable.flush().unwrap();
+        drop(cable);
+
+        let vcd = String::from_utf8(out).unwrap();
+        let tdo: String = samples(&vcd).iter().map(|x| x[2]).collect();
+        assert_eq!(tdo, "x1001");
+    }
+}
@@ -0,0 +1,107 @@
+//! Decode the fields of a 32-bit IDCODE, as returned by `Taps::scan_idcodes`.
+use std::fmt;
+
+/// JEDEC JEP106 manufacturer codes, in the 11-bit form used by IDCODE: the continuation count
+/// (bank number minus one) in the top four bits, and the code without its parity bit below.
+const MANUFACTURERS: &[(u16, &str)] = &[
+    (0x009, "Intel"),
+    (0x00e, "Freescale"),
+    (0x015, "NXP"),
+    (0x017, "Texas Instruments"),
+    (0x01f, "Atmel"),
+    (0x020, "STMicroelectronics"),
+    (0x021, "Lattice"),
+    (0x034, "Cypress"),
+    (0x041, "Infineon"),
+    (0x049, "Xilinx"),
+    (0x065, "Analog Devices"),
+    (0x06e, "Altera"),
+    (0x0bf, "Broadcom"),
+    (0x0e7, "Actel"),
+    (0x23b, "ARM"),
+    (0x489, "SiFive"),
+];
+
+/// A device's IDCODE register
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IdCode(pub u32);
+
+impl IdCode {
+    /// The JEDEC manufacturer code in bits 1 to 11
+    pub fn manufacturer_id(&self) -> u16 {
+        ((self.0 >> 1) & 0x7ff) as u16
+    }
+
+    /// The manufacturer's name, if it is one of a few common ones
+    pub fn manufacturer_name(&self) -> Option<&'static str> {
+        let id = self.manufacturer_id();
+        MANUFACTURERS.iter().find(|(x, _)| *x == id).map(|(_, name)| *name)
+    }
+
+    /// The part number in bits 12 to 27
+    pub fn part_number(&self) -> u16 {
+        ((self.0 >> 12) & 0xffff) as u16
+    }
+
+    /// The version in bits 28 to 31
+    pub fn version(&self) -> u8 {
+        (self.0 >> 28) as u8
+    }
+}
+
+impl From<u32> for IdCode {
+    fn from(x: u32) -> Self {
+        IdCode(x)
+    }
+}
+
+/// e.g. "0x4ba00477 (ARM part 0xba00 version 4)"
+impl fmt::Display for IdCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010x} (", self.0)?;
+        match self.manufacturer_name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "manufacturer {:#05x}", self.manufacturer_id())?,
+        }
+        write!(f, " part {:#06x} version {})", self.part_number(), self.version())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arm() {
+        let id = IdCode(0x4ba00477);
+        assert_eq!(id.manufacturer_name(), Some("ARM"));
+        assert_eq!(id.part_number(), 0xba00);
+        assert_eq!(id.version(), 4);
+        assert_eq!(id.to_string(), "0x4ba00477 (ARM part 0xba00 version 4)");
+    }
+
+    #[test]
+    fn xilinx() {
+        // An Artix-7 XC7A35T
+        let id = IdCode(0x0362d093);
+        assert_eq!(id.manufacturer_name(), Some("Xilinx"));
+        assert_eq!(id.part_number(), 0x362d);
+        assert_eq!(id.version(), 0);
+    }
+
+    #[test]
+    fn nxp() {
+        // NXP is 0x15 in bank 0, which puts 0x02b in the low 12 bits
+        let id = IdCode(0x0643202b);
+        assert_eq!(id.manufacturer_id(), 0x015);
+        assert_eq!(id.manufacturer_name(), Some("NXP"));
+        assert_eq!(id.part_number(), 0x6432);
+    }
+
+    #[test]
+    fn unknown_manufacturer() {
+        let id = IdCode(0x12345679);
+        assert_eq!(id.manufacturer_name(), None);
+        assert_eq!(id.to_string(), "0x12345679 (manufacturer 0x33c part 0x2345 version 1)");
+    }
+}
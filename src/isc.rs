@@ -0,0 +1,192 @@
+//! Program devices that support IEEE 1532 In-System Configuration, which many CPLDs do.  The
+//! standard fixes the sequence of instructions, ISC_ENABLE, ISC_ERASE, ISC_PROGRAM, ISC_READ,
+//! and ISC_DISABLE, but each device has its own opcodes, data register layout, and the time it
+//! needs to spend in Idle after each step.  The opcodes are in the device's BSDL file, and the
+//! times in its ISC_FLOW attribute or datasheet.
+use crate::bits::Bits;
+use crate::bsdl::Bsdl;
+use crate::cable::{Cable, CableError};
+use crate::statemachine::{first_mismatch, JtagSM};
+
+use std::time::Duration;
+
+/// The opcodes of the ISC instructions, as loaded by `JtagSM::shift_ir_value`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct IscOpcodes {
+    pub enable: u32,
+    pub disable: u32,
+    pub erase: u32,
+    pub program: u32,
+    pub read: u32,
+}
+
+impl IscOpcodes {
+    /// Look up the ISC instructions in `bsdl`.  Returns `None` if any of them is missing or the
+    /// instruction register is longer than 32 bits.
+    pub fn from_bsdl(bsdl: &Bsdl) -> Option<Self> {
+        if bsdl.instruction_length > 32 {
+            return None;
+        }
+        let opcode = |name| {
+            bsdl.ir_opcode(name).map(|bits| {
+                bits.iter().enumerate()
+                    .filter(|(_, x)| **x)
+                    .fold(0, |acc, (i, _)| acc | (1 << i))
+            })
+        };
+        Some(Self {
+            enable: opcode("ISC_ENABLE")?,
+            disable: opcode("ISC_DISABLE")?,
+            erase: opcode("ISC_ERASE")?,
+            program: opcode("ISC_PROGRAM")?,
+            read: opcode("ISC_READ")?,
+        })
+    }
+}
+
+/// Time to spend in Idle after a step, as both a number of TCKs and a minimum time, like an
+/// SVF `RUNTEST`.  Both are waited for.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Dwell {
+    pub cycles: usize,
+    pub time: Duration,
+}
+
+/// The dwell after each step.  All of them default to nothing.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct IscTiming {
+    pub enable: Dwell,
+    pub erase: Dwell,
+    /// After each `program`
+    pub program: Dwell,
+    /// Between loading ISC_READ and reading the data it captured
+    pub read: Dwell,
+    pub disable: Dwell,
+}
+
+pub struct Isc<T> {
+    pub sm: JtagSM<T>,
+    irlen: usize,
+    opcodes: IscOpcodes,
+    timing: IscTiming,
+}
+
+impl<T, U> Isc<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Program the device on `sm`, whose instruction register is `irlen` bits long.  Any other
+    /// devices on the chain must be covered by `JtagSM::set_ir_prefix` and friends.
+    pub fn new(sm: JtagSM<T>, irlen: usize, opcodes: IscOpcodes, timing: IscTiming) -> Self {
+        assert!(irlen > 0 && irlen <= 32, "instruction register of {} bits", irlen);
+        Self {
+            sm,
+            irlen,
+            opcodes,
+            timing,
+        }
+    }
+
+    fn dwell(&mut self, dwell: Dwell) -> Result<(), CableError> {
+        self.sm.run_test(dwell.cycles)?;
+        if !dwell.time.is_zero() {
+            // Make sure the cycles have really been clocked before timing from them
+            self.sm.cable.flush()?;
+            std::thread::sleep(dwell.time);
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, opcode: u32, dwell: Dwell) -> Result<(), CableError> {
+        self.sm.shift_ir_value(opcode, self.irlen)?;
+        self.dwell(dwell)
+    }
+
+    /// Put the device in ISC mode, which stops its normal operation until `disable`
+    pub fn enable(&mut self) -> Result<(), CableError> {
+        self.instruction(self.opcodes.enable, self.timing.enable)
+    }
+
+    /// Erase the whole device
+    pub fn erase(&mut self) -> Result<(), CableError> {
+        self.instruction(self.opcodes.erase, self.timing.erase)
+    }
+
+    /// Load ISC_PROGRAM and program `data` into the device.  `bits` indicates how many bits of
+    /// the last byte are used.  Devices that program a row at a time take one call per row.
+    pub fn program(&mut self, data: &[u8], bits: u8) -> Result<(), CableError> {
+        self.sm.shift_ir_value(self.opcodes.program, self.irlen)?;
+        self.sm.shift_dr(data, bits)?;
+        self.dwell(self.timing.program)
+    }
+
+    /// Load ISC_READ, read back as many bits as `data` holds, and check that they match it.
+    /// Panics if `data` is empty.
+    pub fn verify(&mut self, data: &[u8], bits: u8) -> Result<bool, CableError> {
+        assert!(!data.is_empty(), "nothing to verify");
+        self.instruction(self.opcodes.read, self.timing.read)?;
+        let len = (data.len() - 1) * 8 + bits as usize;
+        let got = self.sm.shift_dr(&vec![0; data.len()], bits)?;
+        let mask = Bits::from_bools(std::iter::repeat_n(true, len));
+        Ok(first_mismatch(&got, data, mask.as_bytes(), len).is_none())
+    }
+
+    /// Leave ISC mode, so the device starts running what was programmed.  The device is left
+    /// with ISC_DISABLE loaded, so load BYPASS or another instruction afterwards.
+    pub fn disable(&mut self) -> Result<(), CableError> {
+        self.instruction(self.opcodes.disable, self.timing.disable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    use std::time::Instant;
+
+    const OPCODES: IscOpcodes = IscOpcodes {
+        enable: 0xe8,
+        disable: 0xf0,
+        erase: 0xed,
+        program: 0xea,
+        read: 0xee,
+    };
+
+    fn tms(tms: &[usize]) -> Operation {
+        Operation::ChangeMode { tms: tms.to_vec(), tdi: true }
+    }
+
+    fn shift(data: &[u8]) -> Operation {
+        Operation::ReadWrite { data: data.to_vec(), bits: 8, pause_after: true }
+    }
+
+    #[test]
+    fn enable_program_disable() {
+        let timing = IscTiming {
+            enable: Dwell { cycles: 10, time: Duration::ZERO },
+            program: Dwell { cycles: 100, time: Duration::from_millis(20) },
+            disable: Dwell { cycles: 5, time: Duration::ZERO },
+            ..IscTiming::default()
+        };
+        let mut cable = MockCable::new();
+        let mut isc = Isc::new(JtagSM::new(&mut cable).unwrap(), 8, OPCODES, timing);
+        let start = Instant::now();
+        isc.enable().unwrap();
+        isc.program(&[0x12, 0x34], 8).unwrap();
+        isc.disable().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        drop(isc);
+
+        assert_eq!(cable.recorded()[1..], [
+            tms(&[1, 1, 0, 0]), shift(&[0xe8]), tms(&[1, 1, 0]),
+            tms(&[0; 10]),
+            tms(&[1, 1, 0, 0]), shift(&[0xea]), tms(&[1, 1, 0]),
+            tms(&[1, 0, 0]), shift(&[0x12, 0x34]), tms(&[1, 1, 0]),
+            // The cycles are sent before the time starts
+            tms(&[0; 100]), Operation::Flush,
+            tms(&[1, 1, 0, 0]), shift(&[0xf0]), tms(&[1, 1, 0]),
+            tms(&[0; 5]),
+        ]);
+    }
+}
@@ -18,24 +18,46 @@
 //! also has some support for automatically detecting the IR lengths and ID codes
 //! of the TAPs.
 //! 
+//! For operations prepared by other tools, `svf::SvfPlayer` replays Serial Vector Format files
+//! on a `JtagSM`, and `xsvf::XsvfPlayer` does the same for their binary XSVF form.
+//! 
 //! # Example
-//! ```
-//! use jtag_taps::cable::jtagkey::JtagKey;
+//! ```no_run
+//! use jtag_taps::cable;
 //! use jtag_taps::statemachine::JtagSM;
 //! use jtag_taps::taps::Taps;
-//! let cable = JtagKey::new("Dual RS232-HS A", 1 << 20);
-//! let jtag = JtagSM::new(Box::new(cable));
+//! # fn main() -> Result<(), jtag_taps::cable::CableError> {
+//! let cable = cable::new_from_string("jtagkey", 1 << 20).expect("cable");
+//! let jtag = JtagSM::new(cable)?;
 //! let mut taps = Taps::new(jtag);
-//! taps.detect();
+//! taps.detect()?;
 //! 
 //! let ir = vec![235, 0];
-//! taps.select_tap(0, &ir);
+//! taps.select_tap(0, &ir)?;
 //! let buf = vec![
 //!     0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
 //! ];
-//! taps.write_dr(&buf, 8);
+//! taps.write_dr(&buf, 8)?;
+//! # Ok(())
+//! # }
 //! ```
+//!
+//! # Errors
+//! Every `Cable` method returns `Result<_, CableError>`, and `JtagSM` and `Taps` pass those
+//! errors through, so a USB disconnect or stalled transfer surfaces as an `Err` instead of a
+//! panic.  Code that used `new_from_string` keeps the same `Box<dyn Cable>`, but each call on it
+//! (or on the `JtagSM`/`Taps` built from it) now needs a `?` or `.expect()`.
 
 pub mod cable;
 pub mod statemachine;
 pub mod taps;
+pub mod svf;
+pub mod xsvf;
+pub mod bsdl;
+pub mod bits;
+pub mod idcode;
+pub mod boundary;
+pub mod arm_dap;
+pub mod riscv_dtm;
+pub mod spi_over_jtag;
+pub mod isc;
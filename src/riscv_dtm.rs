@@ -0,0 +1,231 @@
+//! Access the Debug Module of a RISC-V core through its JTAG Debug Transport Module, as described
+//! by version 0.13 of the RISC-V debug specification.  Debug Module registers are reached by
+//! scanning the DMI register, which holds an address, 32 bits of data, and a 2-bit op.  The
+//! result of each access comes out of the following scan, and `Dtm` takes care of that and of
+//! retrying accesses the DTM reports as busy.
+use crate::bits::{dr_to_u32, Bits};
+use crate::cable::{Cable, CableError};
+use crate::statemachine::JtagSM;
+
+use std::fmt;
+
+const IR_LENGTH: u8 = 5;
+const IR_DTMCS: u8 = 0x10;
+const IR_DMI: u8 = 0x11;
+
+const OP_NOP: u8 = 0;
+const OP_READ: u8 = 1;
+const OP_WRITE: u8 = 2;
+
+const STATUS_SUCCESS: u8 = 0;
+const STATUS_FAILED: u8 = 2;
+const STATUS_BUSY: u8 = 3;
+
+/// Clears a sticky busy or failed status
+const DTMCS_DMIRESET: u32 = 1 << 16;
+
+/// The only DTM version this supports, 0.13 and later
+const DTM_VERSION: u8 = 1;
+
+const DEFAULT_MAX_RETRIES: usize = 100;
+
+#[derive(Debug)]
+pub enum DtmError {
+    Cable(CableError),
+    /// dtmcs reports a version other than 0.13, or nothing is driving TDO
+    Version(u8),
+    /// The Debug Module reported that an access failed
+    Failed,
+    /// The DTM was still busy after retrying
+    Timeout { tries: usize },
+}
+
+impl fmt::Display for DtmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DtmError::Cable(e) => write!(f, "{}", e),
+            DtmError::Version(v) => write!(f, "unsupported DTM version {}", v),
+            DtmError::Failed => write!(f, "DMI access failed"),
+            DtmError::Timeout { tries } => write!(f, "DMI still busy after {} tries", tries),
+        }
+    }
+}
+
+impl std::error::Error for DtmError {}
+
+impl From<CableError> for DtmError {
+    fn from(e: CableError) -> Self {
+        DtmError::Cable(e)
+    }
+}
+
+pub struct Dtm<T> {
+    pub sm: JtagSM<T>,
+    /// The width of a DMI address, as reported by dtmcs
+    pub abits: usize,
+    // Cycles to spend in Idle after each DMI access.  This starts at what dtmcs suggests and
+    // grows every time the DTM is busy.
+    idle: usize,
+    max_retries: usize,
+    // The instruction last loaded into the IR
+    ir: u8,
+}
+
+impl<T, U> Dtm<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Read dtmcs from the DTM on `sm` to find its version and address width.  Any other devices
+    /// on the chain must be covered by `JtagSM::set_ir_prefix` and friends.
+    pub fn new(mut sm: JtagSM<T>) -> Result<Self, DtmError> {
+        sm.shift_ir(&[IR_DTMCS], IR_LENGTH)?;
+        let dtmcs = dr_to_u32(&sm.shift_dr(&[0; 4], 8)?, 32);
+        let version = (dtmcs & 0xf) as u8;
+        if version != DTM_VERSION {
+            return Err(DtmError::Version(version));
+        }
+
+        Ok(Self {
+            sm,
+            abits: ((dtmcs >> 4) & 0x3f) as usize,
+            idle: ((dtmcs >> 12) & 7) as usize,
+            max_retries: DEFAULT_MAX_RETRIES,
+            ir: IR_DTMCS,
+        })
+    }
+
+    /// Set how many times an access the DTM is busy for is tried before giving up with
+    /// `DtmError::Timeout`
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.max_retries = max_retries;
+    }
+
+    fn select(&mut self, ir: u8) -> Result<(), DtmError> {
+        if self.ir != ir {
+            self.sm.shift_ir(&[ir], IR_LENGTH)?;
+            self.ir = ir;
+        }
+        Ok(())
+    }
+
+    /// Clear the busy status with dmireset, and allow more time for each access from now on
+    fn clear_busy(&mut self) -> Result<(), DtmError> {
+        self.select(IR_DTMCS)?;
+        self.sm.shift_dr(&DTMCS_DMIRESET.to_le_bytes(), 8)?;
+        self.idle = (self.idle * 2).max(1);
+        Ok(())
+    }
+
+    /// Scan one request into DMI and return the status and data shifted out, which belong to
+    /// the previous request
+    fn scan(&mut self, op: u8, addr: u32, value: u32) -> Result<(u8, u32), DtmError> {
+        self.select(IR_DMI)?;
+        let request = ((addr as u128) << 34) | ((value as u128) << 2) | op as u128;
+        let len = self.abits + 34;
+        let request = Bits::new(request.to_le_bytes().to_vec(), len);
+        let tdo = self.sm.shift_dr(request.as_bytes(), request.last_bits())?;
+        self.sm.run_test(self.idle)?;
+
+        let mut tdo = Bits::new(tdo, len).into_bytes();
+        tdo.resize(16, 0);
+        let response = u128::from_le_bytes(tdo.try_into().unwrap());
+        Ok(((response & 3) as u8, (response >> 2) as u32))
+    }
+
+    /// Make a request, repeating it for as long as the DTM is busy, and return the data from
+    /// the scan that collects its result
+    fn access(&mut self, op: u8, addr: u32, value: u32) -> Result<u32, DtmError> {
+        for _ in 0..self.max_retries {
+            self.scan(op, addr, value)?;
+            let (status, data) = self.scan(OP_NOP, 0, 0)?;
+            match status {
+                STATUS_SUCCESS => return Ok(data),
+                STATUS_FAILED => {
+                    self.clear_busy()?;
+                    return Err(DtmError::Failed);
+                }
+                // The request was dropped, so it has to be made again
+                STATUS_BUSY => self.clear_busy()?,
+                _ => return Err(DtmError::Failed),
+            }
+        }
+        Err(DtmError::Timeout { tries: self.max_retries })
+    }
+
+    /// Read Debug Module register `addr`
+    pub fn read_dmi(&mut self, addr: u32) -> Result<u32, DtmError> {
+        self.access(OP_READ, addr, 0)
+    }
+
+    /// Write `value` to Debug Module register `addr`, and wait for the write to finish
+    pub fn write_dmi(&mut self, addr: u32, value: u32) -> Result<(), DtmError> {
+        self.access(OP_WRITE, addr, value)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    const DMCONTROL: u32 = 0x10;
+
+    /// dtmcs for a version 0.13 DTM with 7 address bits that wants 1 cycle in Idle
+    const DTMCS: u32 = 0x1071;
+
+    /// The TDO of a DMI scan with `status` and `data`, for 7 address bits
+    fn dmi(status: u8, data: u32) -> Vec<u8> {
+        Bits::new((((data as u64) << 2) | status as u64).to_le_bytes().to_vec(), 41).into_bytes()
+    }
+
+    /// The data of every data register shift, in order
+    fn dr_shifts(cable: &MockCable) -> Vec<Vec<u8>> {
+        cable.recorded().iter().filter_map(|op| match op {
+            Operation::ReadWrite { data, bits, .. } if *bits != IR_LENGTH => Some(data.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn dmcontrol_round_trip() {
+        let mut cable = MockCable::with_tdo(vec![
+            vec![0x01], DTMCS.to_le_bytes().to_vec(),
+            vec![0x01], dmi(0, 0), dmi(STATUS_SUCCESS, 0),
+            dmi(0, 0), dmi(STATUS_SUCCESS, 0x80000001),
+        ]);
+        let mut dtm = Dtm::new(JtagSM::new(&mut cable).unwrap()).unwrap();
+        assert_eq!(dtm.abits, 7);
+        dtm.write_dmi(DMCONTROL, 0x80000001).unwrap();
+        assert_eq!(dtm.read_dmi(DMCONTROL).unwrap(), 0x80000001);
+        drop(dtm);
+
+        // Address 0x10, then the data, then the op, each followed by a NOP to collect it
+        let nop = vec![0; 6];
+        assert_eq!(dr_shifts(&cable)[1..], [
+            vec![0x06, 0x00, 0x00, 0x00, 0x42, 0x00], nop.clone(),
+            vec![0x01, 0x00, 0x00, 0x00, 0x40, 0x00], nop,
+        ]);
+    }
+
+    #[test]
+    fn busy_then_retry() {
+        let mut cable = MockCable::with_tdo(vec![
+            vec![0x01], DTMCS.to_le_bytes().to_vec(),
+            vec![0x01], dmi(0, 0), dmi(STATUS_BUSY, 0),
+            vec![0x01], vec![0; 4],
+            vec![0x01], dmi(0, 0), dmi(STATUS_SUCCESS, 0x1234),
+        ]);
+        let mut dtm = Dtm::new(JtagSM::new(&mut cable).unwrap()).unwrap();
+        assert_eq!(dtm.read_dmi(DMCONTROL).unwrap(), 0x1234);
+        drop(dtm);
+
+        // dmireset between the two tries, and twice as long in Idle after it
+        let shifts = dr_shifts(&cable);
+        assert_eq!(shifts[3], DTMCS_DMIRESET.to_le_bytes());
+        assert_eq!(shifts[4], shifts[1]);
+        let ops = cable.recorded();
+        assert!(ops.contains(&Operation::ChangeMode { tms: vec![0], tdi: true }));
+        assert!(ops.contains(&Operation::ChangeMode { tms: vec![0; 2], tdi: true }));
+    }
+}
@@ -0,0 +1,244 @@
+//! Program an SPI flash wired to a target device, usually an FPGA, through a JTAG to SPI bridge
+//! in the device.  The bridge is reached with a user instruction such as USER1, and while its
+//! data register is being shifted, the flash's chip select is held low and each TCK clocks one
+//! SPI bit: TDI drives MOSI, and MISO comes back on TDO.  Each SPI transaction is therefore one
+//! DR scan.  Bridges differ in the details, which are described by `SpiFraming`.
+use crate::bits::Bits;
+use crate::cable::{BitOrder, Cable, CableError};
+use crate::statemachine::JtagSM;
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ: u8 = 0x03;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_READ_ID: u8 = 0x9f;
+
+/// Write in progress
+const STATUS_WIP: u8 = 1;
+
+/// Page programs can't cross the edge of a page
+pub const PAGE_SIZE: usize = 256;
+
+/// Sector erase clears this many bytes
+pub const SECTOR_SIZE: usize = 4096;
+
+/// How long to wait for a program or erase to finish until `SpiFlash::set_timeout` is called
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum SpiError {
+    Cable(CableError),
+    /// The flash was still busy after the timeout
+    Timeout,
+}
+
+impl fmt::Display for SpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpiError::Cable(e) => write!(f, "{}", e),
+            SpiError::Timeout => write!(f, "SPI flash still busy after timeout"),
+        }
+    }
+}
+
+impl std::error::Error for SpiError {}
+
+impl From<CableError> for SpiError {
+    fn from(e: CableError) -> Self {
+        SpiError::Cable(e)
+    }
+}
+
+/// How the bridge lays an SPI transaction out in its data register
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpiFraming {
+    /// Bits shifted before the SPI data, for bridges that expect a start marker.  They are
+    /// shifted in order, and whatever comes out while they are shifted is ignored.
+    pub header: Vec<bool>,
+    /// The order the bits of each SPI byte are shifted.  SPI sends the most significant bit
+    /// first, so this is `Msb` unless the bridge reverses them itself.
+    pub bit_order: BitOrder,
+    /// Extra clocks at the end of each scan, for bridges that delay MISO.  TDO is read this many
+    /// bits later than the TDI it answers.
+    pub dummy_cycles: usize,
+}
+
+impl Default for SpiFraming {
+    fn default() -> Self {
+        Self {
+            header: vec![],
+            bit_order: BitOrder::Msb,
+            dummy_cycles: 0,
+        }
+    }
+}
+
+pub struct SpiFlash<T> {
+    pub sm: JtagSM<T>,
+    framing: SpiFraming,
+    timeout: Duration,
+}
+
+impl<T, U> SpiFlash<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Load the instruction `ir`, of `bits` bits in its last byte, that connects the bridge to
+    /// the data register.  It must stay loaded, so use `sm` only through the `SpiFlash` from
+    /// then on.  Any other devices on the chain must be covered by `JtagSM::set_ir_prefix` and
+    /// friends.
+    pub fn new(mut sm: JtagSM<T>, ir: &[u8], bits: u8, framing: SpiFraming) -> Result<Self, SpiError> {
+        sm.shift_ir(ir, bits)?;
+        Ok(Self {
+            sm,
+            framing,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Set how long to wait for a program or erase to finish before giving up with
+    /// `SpiError::Timeout`
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Bit `bit` of an SPI byte, counting in the order the bits are shifted
+    fn bit_index(&self, bit: usize) -> usize {
+        match self.framing.bit_order {
+            BitOrder::Lsb => bit,
+            BitOrder::Msb => 7 - bit,
+        }
+    }
+
+    /// Run one SPI transaction: send `tx`, then read back `rx_len` bytes while sending ones
+    pub fn transfer(&mut self, tx: &[u8], rx_len: usize) -> Result<Vec<u8>, SpiError> {
+        let mut tdi = Bits::from_bools(self.framing.header.iter().copied());
+        for byte in tx.iter().chain(std::iter::repeat_n(&0xff, rx_len)) {
+            for bit in 0..8 {
+                tdi.push(byte & (1 << self.bit_index(bit)) != 0);
+            }
+        }
+        for _ in 0..self.framing.dummy_cycles {
+            tdi.push(true);
+        }
+
+        let tdo = self.sm.shift_dr(tdi.as_bytes(), tdi.last_bits())?;
+        let tdo = Bits::new(tdo, tdi.len());
+        let start = self.framing.header.len() + self.framing.dummy_cycles + tx.len() * 8;
+        Ok((0..rx_len).map(|i| {
+            (0..8).filter(|bit| tdo.get(start + i * 8 + bit) == Some(true))
+                .fold(0, |byte, bit| byte | (1 << self.bit_index(bit)))
+        }).collect())
+    }
+
+    /// Read the JEDEC ID: the manufacturer in the top byte, then the memory type and capacity
+    pub fn read_id(&mut self) -> Result<u32, SpiError> {
+        let id = self.transfer(&[CMD_READ_ID], 3)?;
+        Ok(u32::from_be_bytes([0, id[0], id[1], id[2]]))
+    }
+
+    /// Read `len` bytes starting at `addr`
+    pub fn read(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, SpiError> {
+        self.transfer(&command(CMD_READ, addr), len)
+    }
+
+    pub fn read_status(&mut self) -> Result<u8, SpiError> {
+        Ok(self.transfer(&[CMD_READ_STATUS], 1)?[0])
+    }
+
+    fn write_enable(&mut self) -> Result<(), SpiError> {
+        self.transfer(&[CMD_WRITE_ENABLE], 0)?;
+        Ok(())
+    }
+
+    /// Poll the status register until the write in progress has finished
+    fn wait_ready(&mut self) -> Result<(), SpiError> {
+        let start = Instant::now();
+        while self.read_status()? & STATUS_WIP != 0 {
+            if start.elapsed() > self.timeout {
+                return Err(SpiError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Program `data` at `addr` and wait for it to finish.  The bytes must have been erased, and
+    /// must all be in the same `PAGE_SIZE` page.
+    pub fn page_program(&mut self, addr: u32, data: &[u8]) -> Result<(), SpiError> {
+        assert!(!data.is_empty());
+        assert!(addr as usize % PAGE_SIZE + data.len() <= PAGE_SIZE, "page program crosses a page");
+        self.write_enable()?;
+        let mut tx = command(CMD_PAGE_PROGRAM, addr);
+        tx.extend_from_slice(data);
+        self.transfer(&tx, 0)?;
+        self.wait_ready()
+    }
+
+    /// Erase the `SECTOR_SIZE` sector holding `addr` to all ones, and wait for it to finish
+    pub fn sector_erase(&mut self, addr: u32) -> Result<(), SpiError> {
+        self.write_enable()?;
+        self.transfer(&command(CMD_SECTOR_ERASE, addr), 0)?;
+        self.wait_ready()
+    }
+}
+
+/// A command byte followed by a 24-bit address
+fn command(cmd: u8, addr: u32) -> Vec<u8> {
+    assert!(addr < 1 << 24, "address {:#x} needs more than 24 bits", addr);
+    let addr = addr.to_be_bytes();
+    vec![cmd, addr[1], addr[2], addr[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    /// Run read_id against a flash answering with `id` through a bridge with `framing`, checking
+    /// the bits it is sent on the way
+    fn read_id(framing: SpiFraming, id: [u8; 3]) -> u32 {
+        let msb_first = |byte: u8| (0..8).rev().map(move |bit| byte & (1 << bit) != 0);
+        // MISO starts after the header, the command, and the bridge's delay
+        let skip = framing.header.len() + 8 + framing.dummy_cycles;
+        let tdo = Bits::from_bools(std::iter::repeat_n(false, skip)
+            .chain(id.into_iter().flat_map(msb_first)));
+        let mut cable = MockCable::with_tdo(vec![vec![0x01], tdo.into_bytes()]);
+
+        let header = framing.header.clone();
+        let dummy_cycles = framing.dummy_cycles;
+        let mut flash = SpiFlash::new(JtagSM::new(&mut cable).unwrap(), &[0x02], 6, framing).unwrap();
+        let result = flash.read_id().unwrap();
+        drop(flash);
+
+        let tdi = cable.recorded().iter().rev().find_map(|op| match op {
+            Operation::ReadWrite { data, bits, .. } => {
+                Some(Bits::new(data.clone(), (data.len() - 1) * 8 + *bits as usize))
+            }
+            _ => None,
+        }).unwrap();
+        let expected = Bits::from_bools(header.into_iter()
+            .chain(msb_first(CMD_READ_ID))
+            .chain(std::iter::repeat_n(true, 24 + dummy_cycles)));
+        assert_eq!(tdi, expected);
+        result
+    }
+
+    #[test]
+    fn jedec_id() {
+        assert_eq!(read_id(SpiFraming::default(), [0xef, 0x40, 0x18]), 0xef4018);
+    }
+
+    #[test]
+    fn jedec_id_with_header_and_dummy_cycles() {
+        let framing = SpiFraming {
+            header: vec![true, false, true],
+            dummy_cycles: 1,
+            ..SpiFraming::default()
+        };
+        assert_eq!(read_id(framing, [0x20, 0xba, 0x19]), 0x20ba19);
+    }
+}
@@ -1,7 +1,14 @@
 //! This provides a higher-level interface than the `Cable` trait.  Specifically, it keeps track of
 //! the state of the JTAG state machine, and allows setting the state to any desired state.
 //! `JtagSM` will get to that state by the most efficient path, based on the current state.
-use crate::cable::Cable;
+use crate::bits::{dr_to_u32, u32_to_dr, Bits};
+use crate::cable::{Cable, CableError};
+
+use std::fmt;
+use std::io::Read;
+
+/// Number of bytes `JtagSM::shift_dr_stream` reads and shifts at a time
+const STREAM_CHUNK: usize = 4096;
 
 #[derive(Clone,Copy,PartialEq)]
 pub enum Register {
@@ -9,7 +16,7 @@ pub enum Register {
     Instruction
 }
 
-#[derive(Clone,Copy,PartialEq)]
+#[derive(Clone,Copy,PartialEq,Debug)]
 pub enum JtagState {
     Reset = 0,
     Idle = 1,
@@ -29,169 +36,386 @@ pub enum JtagState {
     UpdateIR = 15,
 }
 
-struct Node {
-    edges: Vec<usize>,
+impl JtagState {
+    /// The state the TAP moves to on the next clock, given the value of TMS.
+    pub fn next(self, tms: bool) -> JtagState {
+        use JtagState::*;
+
+        match (self, tms) {
+            (Reset, false) => Idle,
+            (Reset, true) => Reset,
+            (Idle, false) => Idle,
+            (Idle, true) => SelectDR,
+
+            (SelectDR, false) => CaptureDR,
+            (SelectDR, true) => SelectIR,
+            (CaptureDR, false) => ShiftDR,
+            (CaptureDR, true) => Exit1DR,
+            (ShiftDR, false) => ShiftDR,
+            (ShiftDR, true) => Exit1DR,
+            (Exit1DR, false) => PauseDR,
+            (Exit1DR, true) => UpdateDR,
+            (PauseDR, false) => PauseDR,
+            (PauseDR, true) => Exit2DR,
+            (Exit2DR, false) => ShiftDR,
+            (Exit2DR, true) => UpdateDR,
+            (UpdateDR, false) => Idle,
+            (UpdateDR, true) => SelectDR,
+
+            (SelectIR, false) => CaptureIR,
+            (SelectIR, true) => Reset,
+            (CaptureIR, false) => ShiftIR,
+            (CaptureIR, true) => Exit1IR,
+            (ShiftIR, false) => ShiftIR,
+            (ShiftIR, true) => Exit1IR,
+            (Exit1IR, false) => PauseIR,
+            (Exit1IR, true) => UpdateIR,
+            (PauseIR, false) => PauseIR,
+            (PauseIR, true) => Exit2IR,
+            (Exit2IR, false) => ShiftIR,
+            (Exit2IR, true) => UpdateIR,
+            (UpdateIR, false) => Idle,
+            (UpdateIR, true) => SelectDR,
+        }
+    }
+
+    /// The state the TAP ends up in after clocking each value of `tms` in order, starting from
+    /// this one.  This is the inverse of `path_to`, for checking what a recorded or hand-written
+    /// sequence does.
+    pub fn apply_tms(self, tms: &[bool]) -> JtagState {
+        tms.iter().fold(self, |state, &tms| state.next(tms))
+    }
 }
 
-impl Node {
-    fn new() -> Self {
-        Self {
-            edges: Vec::new(),
+/// Return the shortest sequence of TMS values that moves the TAP from `from` to `to`, in the form
+/// expected by `Cable::change_mode`.  The sequence is empty if the states are the same.
+pub fn path_to(from: JtagState, to: JtagState) -> Vec<usize> {
+    let mut visited = [false; 16];
+    visited[from as usize] = true;
+
+    // Breadth-first search, trying TMS low before TMS high so the result is deterministic
+    let mut paths = std::collections::VecDeque::new();
+    paths.push_back((from, vec![]));
+    while let Some((state, path)) = paths.pop_front() {
+        if state == to {
+            return path;
+        }
+
+        for tms in [0, 1] {
+            let next = state.next(tms != 0);
+            if !visited[next as usize] {
+                visited[next as usize] = true;
+                let mut p = path.clone();
+                p.push(tms);
+                paths.push_back((next, p));
+            }
         }
     }
+    unreachable!("every TAP state is reachable from every other");
 }
 
-#[derive(Clone)]
-struct Path {
-    path: Vec<usize>,
-    state: usize,
+/// Where `JtagSM::shift_dr_to` and `JtagSM::shift_ir_to` leave the TAP after shifting
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum EndState {
+    /// Update the register and go to Idle
+    #[default]
+    Idle,
+    /// Stay in PauseDR or PauseIR without updating the register, so the shift can be continued
+    Pause,
+    /// Update the register and go to Reset
+    Reset,
 }
 
-impl Path {
-    fn new(state: usize) -> Self {
-        Self {
-            state,
-            path: Vec::new()
+/// Errors from `JtagSM::shift_dr_compare` and `JtagSM::shift_ir_compare`
+#[derive(Debug)]
+pub enum CompareError {
+    Cable(CableError),
+    /// The first bit under the mask that didn't match.  `bit_index` counts from the first bit
+    /// shifted out.
+    Mismatch { bit_index: usize, got: bool, want: bool },
+}
+
+impl fmt::Display for CompareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompareError::Cable(e) => write!(f, "{}", e),
+            CompareError::Mismatch { bit_index, got, want } =>
+                write!(f, "TDO mismatch at bit {}: got {}, expected {}", bit_index, *got as u8, *want as u8),
         }
     }
 }
 
+impl std::error::Error for CompareError {}
+
+impl From<CableError> for CompareError {
+    fn from(e: CableError) -> Self {
+        CompareError::Cable(e)
+    }
+}
+
+/// Returned by `JtagSM::assert_state` when the TAP isn't where the caller expected
+#[derive(Debug, PartialEq)]
+pub struct StateError {
+    pub expected: JtagState,
+    pub actual: JtagState,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected TAP to be in {:?}, but it is in {:?}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Errors from `JtagSM::poll_dr`
+#[derive(Debug)]
+pub enum PollError {
+    Cable(CableError),
+    /// The register never matched.  `got` is what was captured on the last try.
+    Timeout { tries: usize, got: Vec<u8> },
+}
+
+impl fmt::Display for PollError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PollError::Cable(e) => write!(f, "{}", e),
+            PollError::Timeout { tries, got } =>
+                write!(f, "data register didn't match after {} tries, last got {:02x?}", tries, got),
+        }
+    }
+}
+
+impl std::error::Error for PollError {}
+
+impl From<CableError> for PollError {
+    fn from(e: CableError) -> Self {
+        PollError::Cable(e)
+    }
+}
+
+/// Return the index of the first of `bits` bits where `got` and `expected` differ, ignoring any
+/// bits that are clear in `mask`.  Bits past the end of any of the slices are ignored.
+pub fn first_mismatch(got: &[u8], expected: &[u8], mask: &[u8], bits: usize) -> Option<usize> {
+    (0..bits).find(|&i| {
+        let byte = i / 8;
+        let (Some(g), Some(e), Some(m)) = (got.get(byte), expected.get(byte), mask.get(byte)) else {
+            return false;
+        };
+        (g ^ e) & m & (1 << (i % 8)) != 0
+    })
+}
+
 pub struct JtagSM<T> {
     pub cable: T,
     state: JtagState,
-    graph: Vec<Node>,
+    // Called with (bits done, bits total) as long shifts progress
+    progress: Option<Box<dyn FnMut(usize, usize) + Send>>,
+    // Bits of padding for other devices on the chain, shifted before and after the data
+    ir_prefix: usize,
+    ir_suffix: usize,
+    dr_prefix: usize,
+    dr_suffix: usize,
+    // Cycles to spend in Idle after every reset
+    reset_settle: usize,
 }
 
 impl<T, U> JtagSM<T>
     where T: std::ops::DerefMut<Target=U>,
           U: Cable + ?Sized
 {
-    /// Create a JTAG state machine using an existing `Cable`
-    pub fn new(mut cable: T) -> Self {
-        let mut reset = Node::new();
-        let mut idle = Node::new();
-        let mut selectdr = Node::new();
-        let mut capturedr = Node::new();
-        let mut shiftdr = Node::new();
-        let mut exit1dr = Node::new();
-        let mut pausedr = Node::new();
-        let mut exit2dr = Node::new();
-        let mut updatedr = Node::new();
-        let mut selectir = Node::new();
-        let mut captureir = Node::new();
-        let mut shiftir = Node::new();
-        let mut exit1ir = Node::new();
-        let mut pauseir = Node::new();
-        let mut exit2ir = Node::new();
-        let mut updateir = Node::new();
-
-        reset.edges     = vec![JtagState::Idle as usize, JtagState::Reset as usize];
-        idle.edges      = vec![JtagState::Idle as usize, JtagState::SelectDR as usize];
-        selectdr.edges  = vec![JtagState::CaptureDR as usize,
-                               JtagState::SelectIR as usize];
-        capturedr.edges = vec![JtagState::ShiftDR as usize, JtagState::Exit1DR as usize];
-        shiftdr.edges   = vec![JtagState::ShiftDR as usize, JtagState::Exit1DR as usize];
-        exit1dr.edges   = vec![JtagState::PauseDR as usize, JtagState::UpdateDR as usize];
-        pausedr.edges   = vec![JtagState::PauseDR as usize, JtagState::Exit2DR as usize];
-        exit2dr.edges   = vec![JtagState::ShiftDR as usize, JtagState::UpdateDR as usize];
-        updatedr.edges  = vec![JtagState::Idle as usize, JtagState::SelectDR as usize];
-
-        selectir.edges  = vec![JtagState::CaptureIR as usize,
-                               JtagState::Reset as usize];
-        captureir.edges = vec![JtagState::ShiftIR as usize, JtagState::Exit1IR as usize];
-        shiftir.edges   = vec![JtagState::ShiftIR as usize, JtagState::Exit1IR as usize];
-        exit1ir.edges   = vec![JtagState::PauseIR as usize, JtagState::UpdateIR as usize];
-        pauseir.edges   = vec![JtagState::PauseIR as usize, JtagState::Exit2IR as usize];
-        exit2ir.edges   = vec![JtagState::ShiftIR as usize, JtagState::UpdateIR as usize];
-        updateir.edges  = vec![JtagState::Idle as usize, JtagState::SelectIR as usize];
-
-        let graph = vec![reset, idle,
-            selectdr, capturedr, shiftdr, exit1dr, pausedr, exit2dr, updatedr,
-            selectir, captureir, shiftir, exit1ir, pauseir, exit2ir, updateir,
-        ];
+    /// Create a JTAG state machine using an existing `Cable`.  The TAPs are reset and left in
+    /// Idle.
+    pub fn new(mut cable: T) -> Result<Self, CableError> {
+        cable.change_mode(&[1, 1, 1, 1, 1, 0], true)?;
 
-        cable.change_mode(&[1, 1, 1, 1, 1, 0], true);
-
-        Self {
+        Ok(Self {
             cable,
-            state: JtagState::Reset,
-            graph,
-        }
+            state: JtagState::Idle,
+            progress: None,
+            ir_prefix: 0,
+            ir_suffix: 0,
+            dr_prefix: 0,
+            dr_suffix: 0,
+            reset_settle: 0,
+        })
     }
 
-    /// Reset the scan chain by driving TMS high for 5 clocks
-    pub fn mode_reset(&mut self)
+    /// Reset the scan chain by driving TMS high for 5 clocks, then go to Idle, and spend the
+    /// cycles set by `set_reset_settle` there
+    pub fn mode_reset(&mut self) -> Result<(), CableError>
     {
-        self.cable.change_mode(&[1, 1, 1, 1, 1, 0], true);
+        self.cable.change_mode(&[1, 1, 1, 1, 1, 0], true)?;
+        self.state = JtagState::Idle;
+        self.settle()
+    }
+
+    /// Reset the scan chain by driving TMS high for 5 clocks, which reaches Reset from any state
+    /// whatever we think the current state is, and stay in Reset.  This doesn't need TRST; use
+    /// `Cable::assert_trst` as well for targets that need it.  If `set_reset_settle` has been
+    /// given a number of cycles, they are spent in Idle, so the TAPs end up there instead.
+    pub fn reset(&mut self) -> Result<(), CableError> {
+        self.cable.change_mode(&[1; 5], true)?;
         self.state = JtagState::Reset;
+        self.settle()
     }
 
-    fn get_path(&mut self, state: JtagState) -> Vec<usize> {
-        let mut paths = Vec::new();
+    /// Spend `cycles` TCKs in Idle after every `reset` and `mode_reset`, for targets that don't
+    /// respond on the scan chain straight after a reset.  The default is 0.
+    pub fn set_reset_settle(&mut self, cycles: usize) {
+        self.reset_settle = cycles;
+    }
 
-        let mut p = Path::new(self.graph[self.state as usize].edges[0]);
-        p.path = vec![0];
-        paths.push(p);
+    fn settle(&mut self) -> Result<(), CableError> {
+        if self.reset_settle > 0 {
+            self.run_test(self.reset_settle)?;
+        }
+        Ok(())
+    }
 
-        let mut p = Path::new(self.graph[self.state as usize].edges[1]);
-        p.path = vec![1];
-        paths.push(p);
+    /// Leave the TAPs in Reset and send everything buffered in the cable, so the chain can be
+    /// handed over to another tool in a known state.  Like `reset`, this works from any state,
+    /// including partway through a shift, since TMS high always passes through Exit1 on the way.
+    /// No settle cycles are spent, since that would leave Reset.
+    pub fn park(&mut self) -> Result<(), CableError> {
+        self.cable.change_mode(&[1; 5], true)?;
+        self.state = JtagState::Reset;
+        self.cable.flush()?;
+        Ok(())
+    }
 
-        loop {
-            let mut newpaths = Vec::new();
-
-            for p in paths {
-                let mut p1 = p.clone();
-                p1.state = self.graph[p.state].edges[0];
-                p1.path.push(0);
-
-                if p1.state == state as usize {
-                    return p1.path
-                }
-                newpaths.push(p1);
-
-                let mut p2 = p.clone();
-                p2.state = self.graph[p.state].edges[1];
-                p2.path.push(1);
-
-                if p2.state == state as usize {
-                    return p2.path
-                }
-                newpaths.push(p2);
-            }
-            
-            paths = newpaths;
+    /// The state we believe the TAPs are in
+    pub fn current_state(&self) -> JtagState {
+        self.state
+    }
+
+    /// Check that the TAPs are in `expected`, for code that wants to be sure of where it is
+    /// before shifting
+    pub fn assert_state(&self, expected: JtagState) -> Result<(), StateError> {
+        if self.state == expected {
+            Ok(())
+        } else {
+            Err(StateError { expected, actual: self.state })
         }
     }
 
     /// Use TMS to get into `state` by the most efficient path
-    pub fn change_mode(&mut self, state: JtagState) {
+    pub fn change_mode(&mut self, state: JtagState) -> Result<(), CableError> {
         if self.state == state {
-            return;
+            return Ok(());
         }
 
-        let path = self.get_path(state);
+        let path = path_to(self.state, state);
         //println!("Path from {} to {}: {:?}", self.state as usize, state as usize, path);
-        self.cable.change_mode(&path, true);
+        self.cable.change_mode(&path, true)?;
         self.state = state;
+        Ok(())
+    }
+
+    /// Call `callback` with the number of bits shifted so far and the total number of bits as
+    /// `shift_dr_stream` progresses, e.g. to draw a progress bar.  It is called once for every few
+    /// kilobytes shifted, so it doesn't need to be fast.
+    pub fn set_progress_callback(&mut self, callback: Box<dyn FnMut(usize, usize) + Send>) {
+        self.progress = Some(callback);
+    }
+
+    /// Shift `bits` ones before every instruction register shift by `shift_ir` and friends, to
+    /// put the devices between the target and TDO in BYPASS.  This is a lighter alternative to
+    /// `Taps` when the chain is known.
+    pub fn set_ir_prefix(&mut self, bits: usize) {
+        self.ir_prefix = bits;
+    }
+
+    /// Shift `bits` ones after every instruction register shift by `shift_ir` and friends, to put
+    /// the devices between TDI and the target in BYPASS.
+    pub fn set_ir_suffix(&mut self, bits: usize) {
+        self.ir_suffix = bits;
+    }
+
+    /// Shift `bits` zeros before every data register shift by `shift_dr` and friends, for the
+    /// BYPASS registers of the devices between the target and TDO.  The bits they shift out are
+    /// dropped from the result.
+    pub fn set_dr_prefix(&mut self, bits: usize) {
+        self.dr_prefix = bits;
+    }
+
+    /// Shift `bits` zeros after every data register shift by `shift_dr` and friends, for the
+    /// BYPASS registers of the devices between TDI and the target.  Their bits come out of TDO
+    /// after the target's, so they are dropped from the end of the result.  Together with the
+    /// prefix bits dropped from the start, this leaves the target's register starting at bit 0
+    /// of the first byte, however many bits the prefix and suffix are.
+    pub fn set_dr_suffix(&mut self, bits: usize) {
+        self.dr_suffix = bits;
+    }
+
+    /// Go to Idle and stay there for `cycles` TCKs, for targets that need time in Idle to
+    /// complete an operation
+    pub fn run_test(&mut self, cycles: usize) -> Result<(), CableError> {
+        self.change_mode(JtagState::Idle)?;
+        if cycles > 0 {
+            self.cable.change_mode(&vec![0; cycles], true)?;
+        }
+        Ok(())
+    }
+
+    /// Go to ShiftDR without shifting anything, capturing the data register on the way.  Use
+    /// `clock_bits` to shift it piece by piece and `exit_shift` to leave.
+    pub fn enter_shift_dr(&mut self) -> Result<(), CableError> {
+        self.change_mode(JtagState::ShiftDR)
+    }
+
+    /// Like `enter_shift_dr`, but for the instruction register
+    pub fn enter_shift_ir(&mut self) -> Result<(), CableError> {
+        self.change_mode(JtagState::ShiftIR)
+    }
+
+    /// Shift `bits` bits of `tdi` and return the bits shifted out, with TMS held low so the TAP
+    /// stays in ShiftDR or ShiftIR.  Panics if the TAP isn't in one of those states.
+    pub fn clock_bits(&mut self, tdi: &[u8], bits: usize) -> Result<Vec<u8>, CableError> {
+        assert!(matches!(self.state, JtagState::ShiftDR | JtagState::ShiftIR),
+                "clock_bits in {:?}", self.state);
+        assert!(bits > 0, "can't shift zero bits");
+        assert_eq!(tdi.len(), bits.div_ceil(8), "tdi doesn't hold {} bits", bits);
+        let last_bits = (bits - (tdi.len() - 1) * 8) as u8;
+        self.cable.read_write_data(tdi, last_bits, false)
+    }
+
+    /// Leave a shift started with `enter_shift_dr` or `enter_shift_ir` for `end`.  Going to
+    /// Idle or Reset updates the register; going to Pause doesn't.  Note that the clock that
+    /// leaves ShiftDR or ShiftIR shifts one more bit, with TDI high.  Panics if the TAP isn't
+    /// part way through a shift.
+    pub fn exit_shift(&mut self, end: EndState) -> Result<(), CableError> {
+        let reg = if self.mid_shift(Register::Data) {
+            Register::Data
+        } else if self.mid_shift(Register::Instruction) {
+            Register::Instruction
+        } else {
+            panic!("exit_shift in {:?}", self.state);
+        };
+        match (end, reg) {
+            (EndState::Idle, _) => self.change_mode(JtagState::Idle),
+            (EndState::Pause, Register::Data) => self.change_mode(JtagState::PauseDR),
+            (EndState::Pause, Register::Instruction) => self.change_mode(JtagState::PauseIR),
+            (EndState::Reset, _) => self.change_mode(JtagState::Reset),
+        }
     }
 
     /// Read `bits` from either the instruction or data register
-    pub fn read_reg(&mut self, reg: Register, bits: usize) -> Vec<u8> {
+    pub fn read_reg(&mut self, reg: Register, bits: usize) -> Result<Vec<u8>, CableError> {
         if reg == Register::Data {
-            self.change_mode(JtagState::ShiftDR);
+            self.change_mode(JtagState::ShiftDR)?;
         } else {
-            self.change_mode(JtagState::ShiftIR);
+            self.change_mode(JtagState::ShiftIR)?;
         }
         self.cable.read_data(bits)
     }
 
     /// Read `bits` from either the instruction or data register
-    pub fn queue_read(&mut self, reg: Register, bits: usize) -> bool {
+    pub fn queue_read(&mut self, reg: Register, bits: usize) -> Result<(), CableError> {
         if reg == Register::Data {
-            self.change_mode(JtagState::ShiftDR);
+            self.change_mode(JtagState::ShiftDR)?;
         } else {
-            self.change_mode(JtagState::ShiftIR);
+            self.change_mode(JtagState::ShiftIR)?;
         }
         self.cable.queue_read(bits)
     }
@@ -201,13 +425,13 @@ impl<T, U> JtagSM<T>
     /// The mode will either be ShiftIR / ShiftDR if `pause_after` is false, or PauseIR / PauseDR
     /// if `pause_after` is true.  This allows for setting the register with multiple calls to
     /// `write_reg`, which may be more convenient than manual bit-shifting.
-    pub fn write_reg(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) {
+    pub fn write_reg(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
         if reg == Register::Data {
-            self.change_mode(JtagState::ShiftDR);
+            self.change_mode(JtagState::ShiftDR)?;
         } else {
-            self.change_mode(JtagState::ShiftIR);
+            self.change_mode(JtagState::ShiftIR)?;
         }
-        self.cable.write_data(data, bits, pause_after);
+        self.cable.write_data(data, bits, pause_after)?;
         if pause_after {
             if reg == Register::Data {
                 self.state = JtagState::PauseDR;
@@ -215,6 +439,7 @@ impl<T, U> JtagSM<T>
                 self.state = JtagState::PauseIR;
             }
         }
+        Ok(())
     }
 
     /// Write `data` into either the instruction or data register.  `bits` indicates how many bits
@@ -224,13 +449,13 @@ impl<T, U> JtagSM<T>
     /// `read_write_reg`, which may be more convenient than manual bit-shifting.
     ///
     /// Similar to `write_reg` except it returns the bits that were shifted out during writing.
-    pub fn read_write_reg(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) -> Vec<u8> {
+    pub fn read_write_reg(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) -> Result<Vec<u8>, CableError> {
         if reg == Register::Data {
-            self.change_mode(JtagState::ShiftDR);
+            self.change_mode(JtagState::ShiftDR)?;
         } else {
-            self.change_mode(JtagState::ShiftIR);
+            self.change_mode(JtagState::ShiftIR)?;
         }
-        let data = self.cable.read_write_data(data, bits, pause_after);
+        let data = self.cable.read_write_data(data, bits, pause_after)?;
         if pause_after {
             if reg == Register::Data {
                 self.state = JtagState::PauseDR;
@@ -238,16 +463,16 @@ impl<T, U> JtagSM<T>
                 self.state = JtagState::PauseIR;
             }
         }
-        data
+        Ok(data)
     }
 
-    pub fn queue_read_write(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) -> bool {
+    pub fn queue_read_write(&mut self, reg: Register, data: &[u8], bits: u8, pause_after: bool) -> Result<(), CableError> {
         if reg == Register::Data {
-            self.change_mode(JtagState::ShiftDR);
+            self.change_mode(JtagState::ShiftDR)?;
         } else {
-            self.change_mode(JtagState::ShiftIR);
+            self.change_mode(JtagState::ShiftIR)?;
         }
-        let data = self.cable.queue_read_write(data, bits, pause_after);
+        self.cable.queue_read_write(data, bits, pause_after)?;
         if pause_after {
             if reg == Register::Data {
                 self.state = JtagState::PauseDR;
@@ -255,7 +480,683 @@ impl<T, U> JtagSM<T>
                 self.state = JtagState::PauseIR;
             }
         }
-        data
+        Ok(())
+    }
+
+    /// True if we are part way through shifting `reg`, i.e. between CaptureXR and UpdateXR.
+    /// Navigating away from here would update the register with a partially shifted value.
+    fn mid_shift(&self, reg: Register) -> bool {
+        use JtagState::*;
+
+        match reg {
+            Register::Data => matches!(self.state, ShiftDR | Exit1DR | PauseDR | Exit2DR),
+            Register::Instruction => matches!(self.state, ShiftIR | Exit1IR | PauseIR | Exit2IR),
+        }
+    }
+
+    fn shift(&mut self, reg: Register, data: &[u8], bits: u8, end: EndState) -> Result<Vec<u8>, CableError> {
+        let other = if reg == Register::Data {
+            Register::Instruction
+        } else {
+            Register::Data
+        };
+        assert!(!self.mid_shift(other), "can't shift one register while in the middle of shifting the other");
+
+        let (prefix, suffix, fill) = match reg {
+            Register::Instruction => (self.ir_prefix, self.ir_suffix, true),
+            Register::Data => (self.dr_prefix, self.dr_suffix, false),
+        };
+        let paused = if reg == Register::Data {
+            JtagState::PauseDR
+        } else {
+            JtagState::PauseIR
+        };
+        let data = if prefix == 0 && suffix == 0 {
+            self.read_write_reg(reg, data, bits, true)?
+        } else {
+            let len = (data.len() - 1) * 8 + bits as usize;
+            let data = Bits::new(data.to_vec(), len);
+            let padded = Bits::from_bools(std::iter::repeat_n(fill, prefix)
+                .chain(data.iter())
+                .chain(std::iter::repeat_n(fill, suffix)));
+            let out = self.read_write_reg(reg, padded.as_bytes(), padded.last_bits(), true)?;
+            let out = Bits::new(out, padded.len());
+            Bits::from_bools(out.iter().skip(prefix).take(len)).into_bytes()
+        };
+        debug_assert_eq!(self.state, paused);
+        match end {
+            EndState::Idle => self.change_mode(JtagState::Idle)?,
+            // read_write_reg already left us in PauseDR or PauseIR
+            EndState::Pause => (),
+            EndState::Reset => self.change_mode(JtagState::Reset)?,
+        }
+        Ok(data)
+    }
+
+    /// Shift `ir` into the instruction register and return to Idle.  `bits` indicates how many
+    /// bits of the last byte should be written.  Returns the bits that were shifted out.  Panics
+    /// if a data register shift is in progress.
+    pub fn shift_ir(&mut self, ir: &[u8], bits: u8) -> Result<Vec<u8>, CableError> {
+        self.shift(Register::Instruction, ir, bits, EndState::Idle)
+    }
+
+    /// Shift the low `bits` bits of `opcode` into the instruction register, least significant
+    /// bit first, and return to Idle.  This saves packing long opcodes into bytes by hand.
+    /// Returns the bits that were shifted out, unpacked the same way, which for most devices
+    /// carry status in the low bits.  Panics if `bits` is 0 or more than 32.
+    pub fn shift_ir_value(&mut self, opcode: u32, bits: usize) -> Result<u32, CableError> {
+        assert!(bits > 0, "can't shift zero bits");
+        let ir = Bits::new(u32_to_dr(opcode, bits), bits);
+        let tdo = self.shift_ir(ir.as_bytes(), ir.last_bits())?;
+        Ok(dr_to_u32(&tdo, bits))
+    }
+
+    /// Like `shift_ir`, but leaves the TAP in `end` instead of Idle
+    pub fn shift_ir_to(&mut self, ir: &[u8], bits: u8, end: EndState) -> Result<Vec<u8>, CableError> {
+        self.shift(Register::Instruction, ir, bits, end)
+    }
+
+    /// Shift `ir` into the instruction register, then `dr` into the data register, and return to
+    /// Idle.  `ir_bits` and `dr_bits` indicate how many bits of the last byte of each should be
+    /// written.  The TAP goes from UpdateIR straight to SelectDR instead of through Idle, saving
+    /// a clock per scan and a `change_mode` on the cable, which adds up for files of many short
+    /// vectors.  Returns the bits shifted out of each register.  Panics if a data register
+    /// shift is in progress.
+    pub fn shift_ir_then_dr(&mut self, ir: &[u8], ir_bits: u8, dr: &[u8], dr_bits: u8)
+        -> Result<(Vec<u8>, Vec<u8>), CableError>
+    {
+        let ir_out = self.shift_ir_to(ir, ir_bits, EndState::Pause)?;
+        // The path from PauseIR to ShiftDR passes through UpdateIR, so the instruction still
+        // takes effect before the data register is captured
+        self.change_mode(JtagState::ShiftDR)?;
+        let dr_out = self.shift_dr(dr, dr_bits)?;
+        Ok((ir_out, dr_out))
+    }
+
+    /// Shift `dr` into the data register and return to Idle.  `bits` indicates how many bits of
+    /// the last byte should be written.  Returns the bits that were shifted out.  Panics if an
+    /// instruction register shift is in progress.
+    pub fn shift_dr(&mut self, dr: &[u8], bits: u8) -> Result<Vec<u8>, CableError> {
+        self.shift(Register::Data, dr, bits, EndState::Idle)
+    }
+
+    /// Like `shift_dr`, but without reading TDO, and with the first bit clocked by the same
+    /// `change_mode` that enters ShiftDR: the path is extended by one clock with TMS low, and TDI
+    /// is held at the first bit for the whole path, which only matters on that last clock.  On
+    /// MPSSE cables this saves a command whenever the register is 2 bits longer than a multiple
+    /// of 8, since the partial last byte is then down to the single bit sent as TMS goes high,
+    /// but it costs one when the register is 1 bit longer than a multiple of 8 and more than 9
+    /// bits long.  The DR prefix and suffix are shifted as for `shift_dr`.
+    pub fn shift_dr_fast(&mut self, dr: &[u8], bits: u8) -> Result<(), CableError> {
+        assert!(!self.mid_shift(Register::Instruction), "can't shift one register while in the middle of shifting the other");
+        let len = (dr.len() - 1) * 8 + bits as usize;
+        let data = Bits::new(dr.to_vec(), len);
+        let padded = Bits::from_bools(std::iter::repeat_n(false, self.dr_prefix)
+            .chain(data.iter())
+            .chain(std::iter::repeat_n(false, self.dr_suffix)));
+        if padded.len() < 2 {
+            // The only bit has to be sent as the TAP leaves ShiftDR
+            self.shift_dr(dr, bits)?;
+            return Ok(());
+        }
+
+        let mut path = path_to(self.state, JtagState::ShiftDR);
+        path.push(0);
+        self.cable.change_mode(&path, padded.get(0).unwrap())?;
+        self.state = JtagState::ShiftDR;
+
+        let rest = Bits::from_bools(padded.iter().skip(1));
+        self.cable.write_data(rest.as_bytes(), rest.last_bits(), true)?;
+        self.state = JtagState::PauseDR;
+        self.change_mode(JtagState::Idle)
+    }
+
+    /// Shift `total_bits` bits read from `reader` into the data register and return to Idle,
+    /// without holding more than a few kilobytes in memory at once.  The data is shifted in one
+    /// continuous pass through ShiftDR, only leaving it after the last bit, with the DR prefix
+    /// and suffix shifted as for `shift_dr`.  Returns
+    /// `CableError::Io` if `reader` fails or runs out of data early.  Panics if an instruction
+    /// register shift is in progress.
+    pub fn shift_dr_stream(&mut self, mut reader: impl Read, total_bits: usize) -> Result<(), CableError> {
+        assert!(total_bits > 0, "can't shift zero bits");
+        assert!(!self.mid_shift(Register::Instruction), "can't shift one register while in the middle of shifting the other");
+
+        self.change_mode(JtagState::ShiftDR)?;
+        if self.dr_prefix > 0 {
+            let prefix = Bits::from_bools(std::iter::repeat_n(false, self.dr_prefix));
+            self.cable.write_data(prefix.as_bytes(), prefix.last_bits(), false)?;
+        }
+
+        let mut buf = vec![0; STREAM_CHUNK];
+        let mut remaining = total_bits;
+        while remaining > 0 {
+            let bits = remaining.min(STREAM_CHUNK * 8);
+            let chunk = &mut buf[..bits.div_ceil(8)];
+            reader.read_exact(chunk)?;
+            remaining -= bits;
+
+            // Only the last chunk can end part way through a byte
+            let last_bits = (bits - (chunk.len() - 1) * 8) as u8;
+            self.write_reg(Register::Data, chunk, last_bits, remaining == 0 && self.dr_suffix == 0)?;
+            if let Some(progress) = &mut self.progress {
+                progress(total_bits - remaining, total_bits);
+            }
+        }
+
+        if self.dr_suffix > 0 {
+            let suffix = Bits::from_bools(std::iter::repeat_n(false, self.dr_suffix));
+            self.cable.write_data(suffix.as_bytes(), suffix.last_bits(), true)?;
+            self.state = JtagState::PauseDR;
+        }
+        self.change_mode(JtagState::Idle)
+    }
+
+    /// Shift each of `records`, `bits_per` bits long, into the data register back to back and
+    /// return to Idle, returning the bits shifted out during each record.  The TAP enters
+    /// ShiftDR once and only leaves after the last record: every record but the last ends with
+    /// TMS held low, so its last bit is shifted like any other instead of leaving ShiftDR.  The
+    /// result is the same as one `shift_dr` of all the records joined together, DR prefix and
+    /// suffix included, but without joining them.  Panics if an instruction register shift is
+    /// in progress or a record doesn't hold `bits_per` bits.
+    pub fn shift_dr_burst(&mut self, records: &[&[u8]], bits_per: usize) -> Result<Vec<Vec<u8>>, CableError> {
+        assert!(!records.is_empty(), "no records to shift");
+        assert!(bits_per > 0, "can't shift zero bits");
+        assert!(!self.mid_shift(Register::Instruction), "can't shift one register while in the middle of shifting the other");
+        let bytes = bits_per.div_ceil(8);
+        for record in records {
+            assert_eq!(record.len(), bytes, "record doesn't hold {} bits", bits_per);
+        }
+        let last_bits = (bits_per - (bytes - 1) * 8) as u8;
+
+        self.change_mode(JtagState::ShiftDR)?;
+        if self.dr_prefix > 0 {
+            let prefix = Bits::from_bools(std::iter::repeat_n(false, self.dr_prefix));
+            self.cable.write_data(prefix.as_bytes(), prefix.last_bits(), false)?;
+        }
+        let mut out = Vec::with_capacity(records.len());
+        for (i, record) in records.iter().enumerate() {
+            let last = i == records.len() - 1 && self.dr_suffix == 0;
+            out.push(self.cable.read_write_data(record, last_bits, last)?);
+        }
+        if self.dr_suffix > 0 {
+            let suffix = Bits::from_bools(std::iter::repeat_n(false, self.dr_suffix));
+            self.cable.write_data(suffix.as_bytes(), suffix.last_bits(), true)?;
+        }
+        self.state = JtagState::PauseDR;
+        self.change_mode(JtagState::Idle)?;
+        Ok(out)
+    }
+
+    /// Like `shift_dr`, but leaves the TAP in `end` instead of Idle
+    pub fn shift_dr_to(&mut self, dr: &[u8], bits: u8, end: EndState) -> Result<Vec<u8>, CableError> {
+        self.shift(Register::Data, dr, bits, end)
+    }
+
+    fn shift_compare(&mut self, reg: Register, tdi: &[u8], expected: &[u8], mask: &[u8], bits: usize)
+        -> Result<(), CompareError>
+    {
+        assert!(bits > 0, "can't shift zero bits");
+        assert_eq!(tdi.len(), bits.div_ceil(8), "tdi doesn't hold {} bits", bits);
+        let last_bits = (bits - (tdi.len() - 1) * 8) as u8;
+
+        let got = self.shift(reg, tdi, last_bits, EndState::Idle)?;
+        match first_mismatch(&got, expected, mask, bits) {
+            Some(i) => Err(CompareError::Mismatch {
+                bit_index: i,
+                got: got[i / 8] & (1 << (i % 8)) != 0,
+                want: expected[i / 8] & (1 << (i % 8)) != 0,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Shift `bits` bits of `tdi` into the data register and return to Idle, then check the bits
+    /// that were shifted out against `expected` wherever `mask` is set.  Reports the first bit
+    /// that doesn't match.  Panics if an instruction register shift is in progress.
+    pub fn shift_dr_compare(&mut self, tdi: &[u8], expected: &[u8], mask: &[u8], bits: usize)
+        -> Result<(), CompareError>
+    {
+        self.shift_compare(Register::Data, tdi, expected, mask, bits)
+    }
+
+    /// Shift `bits` bits of `tdi` into the data register and return to Idle, like `shift_dr`, but
+    /// with every bit shifted out where `mask` is clear forced to zero.  The result can then be
+    /// compared directly against an expected value that has zeros in its don't-care bits.
+    /// Panics if an instruction register shift is in progress or `mask` is too short.
+    pub fn shift_dr_masked(&mut self, tdi: &[u8], bits: usize, mask: &[u8]) -> Result<Vec<u8>, CableError> {
+        assert!(bits > 0, "can't shift zero bits");
+        assert_eq!(tdi.len(), bits.div_ceil(8), "tdi doesn't hold {} bits", bits);
+        assert!(mask.len() >= tdi.len(), "mask doesn't hold {} bits", bits);
+        let last_bits = (bits - (tdi.len() - 1) * 8) as u8;
+
+        let got = self.shift(Register::Data, tdi, last_bits, EndState::Idle)?;
+        Ok(got.iter().zip(mask).map(|(x, m)| x & m).collect())
+    }
+
+    /// Repeatedly shift `bits` bits of `tdi` into the data register until the bits shifted out
+    /// match `want` wherever `mask` is set, e.g. to wait for a busy flag to clear.  After each
+    /// try that doesn't match, `run_cycles` TCKs are clocked in Idle before trying again, up to
+    /// `max_tries` tries in total.  Returns the matching value that was shifted out.
+    pub fn poll_dr(&mut self, tdi: &[u8], bits: usize, mask: &[u8], want: &[u8], run_cycles: usize,
+                   max_tries: usize) -> Result<Vec<u8>, PollError>
+    {
+        assert!(bits > 0, "can't shift zero bits");
+        assert!(max_tries > 0, "must try at least once");
+        assert_eq!(tdi.len(), bits.div_ceil(8), "tdi doesn't hold {} bits", bits);
+        let last_bits = (bits - (tdi.len() - 1) * 8) as u8;
+
+        let mut tries = 0;
+        loop {
+            let got = self.shift(Register::Data, tdi, last_bits, EndState::Idle)?;
+            tries += 1;
+            if first_mismatch(&got, want, mask, bits).is_none() {
+                return Ok(got);
+            }
+            if tries == max_tries {
+                return Err(PollError::Timeout { tries, got });
+            }
+            self.run_test(run_cycles)?;
+        }
+    }
+
+    /// Like `shift_dr_compare`, but for the instruction register
+    pub fn shift_ir_compare(&mut self, tdi: &[u8], expected: &[u8], mask: &[u8], bits: usize)
+        -> Result<(), CompareError>
+    {
+        self.shift_compare(Register::Instruction, tdi, expected, mask, bits)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    #[test]
+    fn path_idle_to_shift_dr() {
+        assert_eq!(path_to(JtagState::Idle, JtagState::ShiftDR), vec![1, 0, 0]);
+        assert_eq!(path_to(JtagState::Idle, JtagState::ShiftIR), vec![1, 1, 0, 0]);
+        assert_eq!(path_to(JtagState::PauseIR, JtagState::ShiftDR), vec![1, 1, 1, 0, 0]);
+        assert!(path_to(JtagState::ShiftDR, JtagState::ShiftDR).is_empty());
+    }
+
+    #[test]
+    fn path_between_every_state() {
+        for from in 0..16 {
+            for to in 0..16 {
+                let (from, to) = (state(from), state(to));
+                let tms: Vec<bool> = path_to(from, to).iter().map(|x| *x != 0).collect();
+                assert_eq!(from.apply_tms(&tms), to);
+            }
+        }
+    }
+
+    fn state(i: usize) -> JtagState {
+        use JtagState::*;
+        [Reset, Idle, SelectDR, CaptureDR, ShiftDR, Exit1DR, PauseDR, Exit2DR, UpdateDR,
+         SelectIR, CaptureIR, ShiftIR, Exit1IR, PauseIR, Exit2IR, UpdateIR][i]
+    }
+
+    #[test]
+    fn five_tms_high_resets() {
+        for i in 0..16 {
+            assert_eq!(state(i).apply_tms(&[true; 5]), JtagState::Reset);
+        }
+    }
+
+    #[test]
+    fn apply_tms_sequences() {
+        assert_eq!(JtagState::Reset.apply_tms(&[]), JtagState::Reset);
+        assert_eq!(JtagState::Reset.apply_tms(&[false, true, false, false]), JtagState::ShiftDR);
+        assert_eq!(JtagState::ShiftIR.apply_tms(&[true, false]), JtagState::PauseIR);
+        assert_eq!(JtagState::PauseDR.apply_tms(&[true, true, false]), JtagState::Idle);
+    }
+
+    #[test]
+    fn reset_settle() {
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.set_reset_settle(3);
+        sm.reset().unwrap();
+        assert_eq!(sm.current_state(), JtagState::Idle);
+        assert_eq!(&cable.recorded()[1..], &[
+            Operation::ChangeMode { tms: vec![1; 5], tdi: true },
+            Operation::ChangeMode { tms: vec![0], tdi: true },
+            Operation::ChangeMode { tms: vec![0; 3], tdi: true },
+        ]);
+    }
+
+    #[test]
+    fn no_reset_settle_by_default() {
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.reset().unwrap();
+        assert_eq!(sm.current_state(), JtagState::Reset);
+        assert_eq!(cable.recorded().len(), 2);
+    }
+
+    #[test]
+    fn dr_prefix_and_suffix() {
+        // The target's bits come out of TDO after the 4 prefix bits
+        let mut cable = MockCable::with_tdo(vec![vec![0x50, 0x0a, 0x00]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.set_dr_prefix(4);
+        sm.set_dr_suffix(6);
+        assert_eq!(sm.shift_dr(&[0xa5], 8).unwrap(), vec![0xa5]);
+        assert!(cable.recorded().contains(&Operation::ReadWrite { data: vec![0x50, 0x0a, 0x00], bits: 2, pause_after: true }));
+    }
+
+    #[test]
+    fn realign_after_suffix() {
+        // A 12 bit register followed by 3 suffix bits of 101, which must not leak into the top
+        // of the last byte
+        let mut cable = MockCable::with_tdo(vec![vec![0xbc, 0x5a]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.set_dr_suffix(3);
+        assert_eq!(sm.shift_dr(&[0xa5, 0x05], 4).unwrap(), vec![0xbc, 0x0a]);
+        assert!(cable.recorded().contains(&Operation::ReadWrite { data: vec![0xa5, 0x05], bits: 7, pause_after: true }));
+
+        // With 5 prefix bits as well, the register straddles all three bytes of TDO
+        let mut cable = MockCable::with_tdo(vec![vec![0x9f, 0x57, 0x0b]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.set_dr_prefix(5);
+        sm.set_dr_suffix(3);
+        assert_eq!(sm.shift_dr(&[0xa5, 0x05], 4).unwrap(), vec![0xbc, 0x0a]);
+        assert!(cable.recorded().contains(&Operation::ReadWrite { data: vec![0xa0, 0xb4, 0x00], bits: 4, pause_after: true }));
+    }
+
+    #[test]
+    fn stream_prefix_and_suffix() {
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.set_dr_prefix(4);
+        sm.set_dr_suffix(6);
+        sm.shift_dr_stream(&[0xa5, 0x01][..], 9).unwrap();
+        let writes: Vec<_> = cable.recorded().iter().filter(|op| matches!(op, Operation::Write { .. })).cloned().collect();
+        assert_eq!(writes, vec![
+            Operation::Write { data: vec![0x00], bits: 4, pause_after: false },
+            Operation::Write { data: vec![0xa5, 0x01], bits: 1, pause_after: false },
+            Operation::Write { data: vec![0x00], bits: 6, pause_after: true },
+        ]);
+    }
+
+    fn tms(tms: &[usize]) -> Operation {
+        Operation::ChangeMode { tms: tms.to_vec(), tdi: true }
+    }
+
+    #[test]
+    fn shift_tms_sequences() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x01], vec![0x34, 0x12]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        assert_eq!(sm.shift_ir(&[0x0e], 4).unwrap(), vec![0x01]);
+        assert_eq!(sm.shift_dr(&[0x00, 0x00], 8).unwrap(), vec![0x34, 0x12]);
+        assert_eq!(sm.current_state(), JtagState::Idle);
+        assert_eq!(cable.recorded()[1..], [
+            tms(&[1, 1, 0, 0]),
+            Operation::ReadWrite { data: vec![0x0e], bits: 4, pause_after: true },
+            tms(&[1, 1, 0]),
+            tms(&[1, 0, 0]),
+            Operation::ReadWrite { data: vec![0x00, 0x00], bits: 8, pause_after: true },
+            tms(&[1, 1, 0]),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "in the middle of shifting the other")]
+    fn shift_dr_mid_ir() {
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.shift_ir_to(&[0x0e], 4, EndState::Pause).unwrap();
+        let _ = sm.shift_dr(&[0x00], 8);
+    }
+
+    #[test]
+    fn compare_match_and_mismatch() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x5a, 0x01], vec![0x5a, 0x03], vec![0x01], vec![0x09]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        // Bit 9 differs, but isn't under the mask
+        sm.shift_dr_compare(&[0x00, 0x00], &[0x5a, 0x01], &[0xff, 0x01], 10).unwrap();
+        match sm.shift_dr_compare(&[0x00, 0x00], &[0x5a, 0x01], &[0xff, 0x03], 10) {
+            Err(CompareError::Mismatch { bit_index: 9, got: true, want: false }) => (),
+            x => panic!("expected a mismatch at bit 9, got {:?}", x),
+        }
+        sm.shift_ir_compare(&[0x0f], &[0x01], &[0x0f], 4).unwrap();
+        match sm.shift_ir_compare(&[0x0f], &[0x01], &[0x0f], 4) {
+            Err(CompareError::Mismatch { bit_index: 3, got: true, want: false }) => (),
+            x => panic!("expected a mismatch at bit 3, got {:?}", x),
+        }
+        assert_eq!(sm.current_state(), JtagState::Idle);
+    }
+
+    #[test]
+    fn poll_until_not_busy() {
+        // Busy in bit 0 for the first two tries
+        let mut cable = MockCable::with_tdo(vec![vec![0x81], vec![0x81], vec![0x80]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        assert_eq!(sm.poll_dr(&[0x00], 8, &[0x01], &[0x00], 10, 5).unwrap(), vec![0x80]);
+        let ops = cable.recorded();
+        assert_eq!(ops.iter().filter(|op| matches!(op, Operation::ReadWrite { .. })).count(), 3);
+        assert_eq!(ops.iter().filter(|op| **op == tms(&[0; 10])).count(), 2);
+
+        let mut cable = MockCable::with_tdo(vec![vec![0x81], vec![0x81], vec![0x80]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        match sm.poll_dr(&[0x00], 8, &[0x01], &[0x00], 10, 2) {
+            Err(PollError::Timeout { tries: 2, got }) => assert_eq!(got, vec![0x81]),
+            x => panic!("expected a timeout, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn run_test_idle_clocks() {
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.run_test(100).unwrap();
+        sm.run_test(0).unwrap();
+        sm.shift_dr_to(&[0x00], 8, EndState::Pause).unwrap();
+        sm.run_test(7).unwrap();
+        assert_eq!(sm.current_state(), JtagState::Idle);
+        let ops = cable.recorded();
+        assert_eq!(ops[1], tms(&[0; 100]));
+        // Nothing for zero cycles
+        assert_eq!(ops[2], tms(&[1, 0, 0]));
+        assert_eq!(ops[ops.len() - 2..], [tms(&[1, 1, 0]), tms(&[0; 7])]);
+    }
+
+    #[test]
+    fn end_state_tails() {
+        for (end, tail, state) in [
+            (EndState::Idle, Some(tms(&[1, 1, 0])), JtagState::Idle),
+            (EndState::Pause, None, JtagState::PauseDR),
+            (EndState::Reset, Some(tms(&[1, 1, 1, 1, 1])), JtagState::Reset),
+        ] {
+            let mut cable = MockCable::new();
+            let mut sm = JtagSM::new(&mut cable).unwrap();
+            sm.shift_dr_to(&[0x00], 8, end).unwrap();
+            assert_eq!(sm.current_state(), state);
+            let ops = cable.recorded();
+            assert_eq!(ops[2], Operation::ReadWrite { data: vec![0x00], bits: 8, pause_after: true });
+            assert_eq!(ops.get(3), tail.as_ref());
+        }
+
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.shift_ir_to(&[0x0e], 4, EndState::Pause).unwrap();
+        assert_eq!(sm.current_state(), JtagState::PauseIR);
+        assert_eq!(cable.recorded().len(), 3);
+    }
+
+    #[test]
+    fn stream_1mb() {
+        let data: Vec<u8> = (0..1 << 20).map(|x| x as u8).collect();
+        let total_bits = data.len() * 8 - 3;
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.shift_dr_stream(&data[..], total_bits).unwrap();
+        assert_eq!(sm.current_state(), JtagState::Idle);
+
+        let writes: Vec<_> = cable.recorded().iter().filter_map(|op| match op {
+            Operation::Write { data, bits, pause_after } => Some((data, *bits, *pause_after)),
+            _ => None,
+        }).collect();
+        assert_eq!(writes.len(), data.len() / STREAM_CHUNK);
+        let shifted: usize = writes.iter().map(|(data, bits, _)| (data.len() - 1) * 8 + *bits as usize).sum();
+        assert_eq!(shifted, total_bits);
+        let joined: Vec<u8> = writes.iter().flat_map(|(data, _, _)| data.iter().copied()).collect();
+        assert_eq!(joined, data);
+        // Only the last write leaves ShiftDR
+        assert!(writes[..writes.len() - 1].iter().all(|(_, _, pause_after)| !pause_after));
+        assert!(writes[writes.len() - 1].2);
+    }
+
+    #[test]
+    fn reset_from_any_state() {
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.reset().unwrap();
+        sm.enter_shift_dr().unwrap();
+        sm.reset().unwrap();
+        sm.shift_ir_to(&[0x0e], 4, EndState::Pause).unwrap();
+        sm.reset().unwrap();
+        assert_eq!(sm.current_state(), JtagState::Reset);
+
+        let resets: Vec<_> = cable.recorded().iter().filter_map(|op| match op {
+            Operation::ChangeMode { tms, .. } if tms.iter().all(|x| *x == 1) => Some(tms.len()),
+            _ => None,
+        }).collect();
+        // The one from JtagSM::new ends with a TMS low clock to Idle
+        assert_eq!(resets.len(), 3);
+        assert!(resets.iter().all(|x| *x >= 5));
+    }
+
+    #[test]
+    fn assert_state_mismatch() {
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.assert_state(JtagState::Idle).unwrap();
+        sm.shift_ir_to(&[0x0e], 4, EndState::Pause).unwrap();
+        let err = sm.assert_state(JtagState::ShiftDR).unwrap_err();
+        assert_eq!((err.expected, err.actual), (JtagState::ShiftDR, JtagState::PauseIR));
+    }
+
+    #[test]
+    #[should_panic(expected = "clock_bits in Idle")]
+    fn clock_bits_from_idle() {
+        let mut cable = MockCable::new();
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        let _ = sm.clock_bits(&[0x00], 8);
+    }
+
+    #[test]
+    fn park_from_several_states() {
+        type Setup = fn(&mut JtagSM<&mut MockCable>);
+        let setups: [Setup; 4] = [
+            |_| (),
+            |sm| sm.enter_shift_dr().unwrap(),
+            |sm| { sm.enter_shift_dr().unwrap(); sm.clock_bits(&[0x00], 3).unwrap(); },
+            |sm| { sm.shift_ir_to(&[0x0e], 4, EndState::Pause).unwrap(); },
+        ];
+        for setup in setups {
+            let mut cable = MockCable::new();
+            let mut sm = JtagSM::new(&mut cable).unwrap();
+            setup(&mut sm);
+            let from = sm.current_state();
+            sm.park().unwrap();
+            assert_eq!(sm.current_state(), JtagState::Reset);
+            assert_eq!(from.apply_tms(&[true; 5]), JtagState::Reset);
+
+            let ops = cable.recorded();
+            assert_eq!(ops[ops.len() - 2..], [tms(&[1; 5]), Operation::Flush]);
+        }
+    }
+
+    #[test]
+    fn masked_dont_care_bits() {
+        let mut cable = MockCable::with_tdo(vec![vec![0xa5, 0x3c]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        assert_eq!(sm.shift_dr_masked(&[0x00, 0x00], 14, &[0x0f, 0x30]).unwrap(), vec![0x05, 0x30]);
+        assert!(cable.recorded().contains(&Operation::ReadWrite { data: vec![0x00, 0x00], bits: 6, pause_after: true }));
+    }
+
+    #[test]
+    fn manual_shift_no_extra_tms() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x12], vec![0x05]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        sm.enter_shift_dr().unwrap();
+        assert_eq!(sm.clock_bits(&[0xff], 8).unwrap(), vec![0x12]);
+        assert_eq!(sm.clock_bits(&[0x03], 3).unwrap(), vec![0x05]);
+        sm.exit_shift(EndState::Idle).unwrap();
+        sm.enter_shift_ir().unwrap();
+        sm.clock_bits(&[0x0e], 4).unwrap();
+        sm.exit_shift(EndState::Pause).unwrap();
+        assert_eq!(sm.current_state(), JtagState::PauseIR);
+
+        // TMS only moves on entry and exit
+        assert_eq!(cable.recorded()[1..], [
+            tms(&[1, 0, 0]),
+            Operation::ReadWrite { data: vec![0xff], bits: 8, pause_after: false },
+            Operation::ReadWrite { data: vec![0x03], bits: 3, pause_after: false },
+            tms(&[1, 1, 0]),
+            tms(&[1, 1, 0, 0]),
+            Operation::ReadWrite { data: vec![0x0e], bits: 4, pause_after: false },
+            tms(&[1, 0]),
+        ]);
+    }
+
+    #[test]
+    fn ir_values() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x01], vec![0x5a], vec![0xff, 0xff]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        assert_eq!(sm.shift_ir_value(0xe, 4).unwrap(), 0x1);
+        assert_eq!(sm.shift_ir_value(0xa5, 8).unwrap(), 0x5a);
+        // Bits shifted out past the end of the register are dropped
+        assert_eq!(sm.shift_ir_value(0x1abc, 13).unwrap(), 0x1fff);
+
+        let shifts: Vec<_> = cable.recorded().iter()
+            .filter(|op| matches!(op, Operation::ReadWrite { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(shifts, vec![
+            Operation::ReadWrite { data: vec![0x0e], bits: 4, pause_after: true },
+            Operation::ReadWrite { data: vec![0xa5], bits: 8, pause_after: true },
+            Operation::ReadWrite { data: vec![0xbc, 0x1a], bits: 5, pause_after: true },
+        ]);
+    }
+
+    #[test]
+    fn burst_three_records() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x11, 0x01], vec![0x22, 0x02], vec![0x33, 0x03]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        let records: [&[u8]; 3] = [&[0xa1, 0x0a], &[0xb2, 0x0b], &[0xc3, 0x0c]];
+        assert_eq!(sm.shift_dr_burst(&records, 12).unwrap(), vec![
+            vec![0x11, 0x01], vec![0x22, 0x02], vec![0x33, 0x03],
+        ]);
+        assert_eq!(sm.current_state(), JtagState::Idle);
+
+        // One entry into ShiftDR, and only the last record leaves it
+        assert_eq!(cable.recorded()[1..], [
+            tms(&[1, 0, 0]),
+            Operation::ReadWrite { data: vec![0xa1, 0x0a], bits: 4, pause_after: false },
+            Operation::ReadWrite { data: vec![0xb2, 0x0b], bits: 4, pause_after: false },
+            Operation::ReadWrite { data: vec![0xc3, 0x0c], bits: 4, pause_after: true },
+            tms(&[1, 1, 0]),
+        ]);
+    }
+
+    #[test]
+    fn ir_then_dr_single_path() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x01], vec![0x34, 0x12]]);
+        let mut sm = JtagSM::new(&mut cable).unwrap();
+        let (ir, dr) = sm.shift_ir_then_dr(&[0x0e], 4, &[0x00, 0x00], 8).unwrap();
+        assert_eq!((ir, dr), (vec![0x01], vec![0x34, 0x12]));
+        assert_eq!(sm.current_state(), JtagState::Idle);
+
+        // PauseIR goes through UpdateIR straight to SelectDR, never reaching Idle
+        assert_eq!(cable.recorded()[1..], [
+            tms(&[1, 1, 0, 0]),
+            Operation::ReadWrite { data: vec![0x0e], bits: 4, pause_after: true },
+            tms(&[1, 1, 1, 0, 0]),
+            Operation::ReadWrite { data: vec![0x00, 0x00], bits: 8, pause_after: true },
+            tms(&[1, 1, 0]),
+        ]);
+    }
+}
@@ -0,0 +1,545 @@
+//! A player for Serial Vector Format (SVF) files, the standard interchange format that vendor
+//! tools use to describe JTAG operations such as programming a CPLD.  `SvfPlayer` parses the file
+//! one statement at a time and executes it on a `JtagSM`.
+use crate::cable::{Cable, CableError};
+use crate::statemachine::{first_mismatch, JtagSM, JtagState, Register};
+
+use std::fmt;
+use std::io::BufRead;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum SvfError {
+    /// The cable reported an error
+    Cable(CableError),
+    /// Reading the SVF file failed
+    Io(std::io::Error),
+    /// Statement number `statement` couldn't be parsed
+    Parse { statement: usize, msg: String },
+    /// Statement number `statement` is valid SVF, but isn't supported by this player
+    Unsupported { statement: usize, command: String },
+    /// The TDO captured by the scan in statement number `statement` didn't match the expected
+    /// value in one of the bits selected by the mask
+    TdoMismatch { statement: usize, got: Vec<u8>, expected: Vec<u8>, mask: Vec<u8> },
+}
+
+impl fmt::Display for SvfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SvfError::Cable(e) => write!(f, "cable error: {}", e),
+            SvfError::Io(e) => write!(f, "io error: {}", e),
+            SvfError::Parse { statement, msg } => write!(f, "statement {}: {}", statement, msg),
+            SvfError::Unsupported { statement, command } =>
+                write!(f, "statement {}: unsupported command {}", statement, command),
+            SvfError::TdoMismatch { statement, got, expected, mask } =>
+                write!(f, "statement {}: TDO mismatch, got {:02x?} expected {:02x?} mask {:02x?}",
+                       statement, got, expected, mask),
+        }
+    }
+}
+
+impl std::error::Error for SvfError {}
+
+impl From<CableError> for SvfError {
+    fn from(e: CableError) -> Self {
+        SvfError::Cable(e)
+    }
+}
+
+impl From<std::io::Error> for SvfError {
+    fn from(e: std::io::Error) -> Self {
+        SvfError::Io(e)
+    }
+}
+
+/// The parameters of one of the SDR, SIR, HDR, HIR, TDR, or TIR commands.  TDI, MASK, and SMASK
+/// are remembered from one scan to the next as long as the length doesn't change.  TDO is only
+/// checked on the scan that specifies it.
+#[derive(Default)]
+struct ScanParams {
+    len: usize,
+    tdi: Vec<u8>,
+    tdo: Option<Vec<u8>>,
+    mask: Vec<u8>,
+    smask: Vec<u8>,
+}
+
+/// Convert an SVF hex string, most significant digit first, into `bits` bits packed LSB first
+fn parse_hex(hex: &str, bits: usize) -> Option<Vec<u8>> {
+    let mut out = vec![0; bits.div_ceil(8)];
+    for (i, c) in hex.chars().rev().enumerate() {
+        let nibble = c.to_digit(16)? as u8;
+        let byte = i / 2;
+        if byte < out.len() {
+            out[byte] |= nibble << (4 * (i % 2));
+        } else if nibble != 0 {
+            // Non-zero digits beyond the length of the scan
+            return None;
+        }
+    }
+    if !bits.is_multiple_of(8) {
+        let last = out.len()-1;
+        out[last] &= (1 << (bits % 8)) - 1;
+    }
+    Some(out)
+}
+
+/// Append the first `bits` bits of `src` to the `dst_bits` bits already in `dst`
+fn append_bits(dst: &mut Vec<u8>, dst_bits: usize, src: &[u8], bits: usize) {
+    dst.resize((dst_bits + bits).div_ceil(8), 0);
+    for i in 0..bits {
+        if src[i / 8] & (1 << (i % 8)) != 0 {
+            let j = dst_bits + i;
+            dst[j / 8] |= 1 << (j % 8);
+        }
+    }
+}
+
+fn parse_state(name: &str) -> Option<JtagState> {
+    let state = match name {
+        "RESET" => JtagState::Reset,
+        "IDLE" => JtagState::Idle,
+        "DRSELECT" => JtagState::SelectDR,
+        "DRCAPTURE" => JtagState::CaptureDR,
+        "DRSHIFT" => JtagState::ShiftDR,
+        "DREXIT1" => JtagState::Exit1DR,
+        "DRPAUSE" => JtagState::PauseDR,
+        "DREXIT2" => JtagState::Exit2DR,
+        "DRUPDATE" => JtagState::UpdateDR,
+        "IRSELECT" => JtagState::SelectIR,
+        "IRCAPTURE" => JtagState::CaptureIR,
+        "IRSHIFT" => JtagState::ShiftIR,
+        "IREXIT1" => JtagState::Exit1IR,
+        "IRPAUSE" => JtagState::PauseIR,
+        "IREXIT2" => JtagState::Exit2IR,
+        "IRUPDATE" => JtagState::UpdateIR,
+        _ => return None,
+    };
+    Some(state)
+}
+
+fn is_stable(state: JtagState) -> bool {
+    matches!(state, JtagState::Reset | JtagState::Idle | JtagState::PauseDR | JtagState::PauseIR)
+}
+
+/// Split a statement into words, with each parenthesized hex string becoming a single word
+fn tokenize(statement: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut rest = statement;
+    while let Some(start) = rest.find('(') {
+        tokens.extend(rest[..start].split_whitespace().map(|x| x.to_ascii_uppercase()));
+        let end = rest[start..].find(')').ok_or("unterminated '('")? + start;
+        let hex: String = rest[start+1..end].split_whitespace().collect();
+        tokens.push(format!("({})", hex));
+        rest = &rest[end+1..];
+    }
+    tokens.extend(rest.split_whitespace().map(|x| x.to_ascii_uppercase()));
+    Ok(tokens)
+}
+
+pub struct SvfPlayer<T> {
+    pub sm: JtagSM<T>,
+    enddr: JtagState,
+    endir: JtagState,
+    run_state: JtagState,
+    run_end_state: JtagState,
+    sdr: ScanParams,
+    sir: ScanParams,
+    hdr: ScanParams,
+    hir: ScanParams,
+    tdr: ScanParams,
+    tir: ScanParams,
+    // The speed the cable is running at, if known
+    clock: Option<u32>,
+    // Number of the statement being executed, for error reporting
+    statement: usize,
+    // Whether to compare the TDO of each scan against the expected value
+    check_tdo: bool,
+}
+
+impl<T, U> SvfPlayer<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Create a player that executes SVF files using an existing `JtagSM`
+    pub fn new(sm: JtagSM<T>) -> Self {
+        Self {
+            sm,
+            enddr: JtagState::Idle,
+            endir: JtagState::Idle,
+            run_state: JtagState::Idle,
+            run_end_state: JtagState::Idle,
+            sdr: ScanParams::default(),
+            sir: ScanParams::default(),
+            hdr: ScanParams::default(),
+            hir: ScanParams::default(),
+            tdr: ScanParams::default(),
+            tir: ScanParams::default(),
+            clock: None,
+            statement: 0,
+            check_tdo: true,
+        }
+    }
+
+    /// Tell the player the speed the cable is already running at.  A FREQUENCY statement asking
+    /// for this speed then succeeds even on cables that can't change their speed.
+    pub fn set_current_clock(&mut self, hz: u32) {
+        self.clock = Some(hz);
+    }
+
+    /// Choose whether scans with an expected TDO are checked, which they are by default.  Turning
+    /// the checks off lets a file play against a cable with no target, like
+    /// `counting::CountingCable`.
+    pub fn set_check_tdo(&mut self, check: bool) {
+        self.check_tdo = check;
+    }
+
+    /// Parse and execute every statement in `reader`.  Stops at the first statement that fails,
+    /// including any scan whose captured TDO doesn't match the expected value.
+    pub fn play(&mut self, reader: impl BufRead) -> Result<(), SvfError> {
+        self.statement = 0;
+        let mut statement = String::new();
+        for line in reader.lines() {
+            let line = line?;
+            // Comments run to the end of the line
+            let line = match (line.find('!'), line.find("//")) {
+                (Some(a), Some(b)) => &line[..a.min(b)],
+                (Some(a), None) | (None, Some(a)) => &line[..a],
+                (None, None) => &line[..],
+            };
+
+            let mut rest = line;
+            while let Some(end) = rest.find(';') {
+                statement.push_str(&rest[..end]);
+                self.execute(&statement)?;
+                statement.clear();
+                rest = &rest[end+1..];
+            }
+            statement.push_str(rest);
+            statement.push(' ');
+        }
+
+        if !statement.trim().is_empty() {
+            return Err(self.parse_error("missing ';' at end of file"));
+        }
+        self.sm.cable.flush()?;
+        Ok(())
+    }
+
+    fn parse_error(&self, msg: &str) -> SvfError {
+        SvfError::Parse { statement: self.statement, msg: msg.to_string() }
+    }
+
+    fn parse_number(&self, s: &str) -> Result<f64, SvfError> {
+        s.parse::<f64>().map_err(|_| self.parse_error(&format!("bad number {}", s)))
+    }
+
+    fn parse_end_state(&self, name: &str) -> Result<JtagState, SvfError> {
+        match parse_state(name) {
+            Some(state) if is_stable(state) => Ok(state),
+            _ => Err(self.parse_error(&format!("{} is not a stable state", name))),
+        }
+    }
+
+    fn execute(&mut self, statement: &str) -> Result<(), SvfError> {
+        if statement.trim().is_empty() {
+            return Ok(());
+        }
+        self.statement += 1;
+        let tokens = tokenize(statement).map_err(|e| self.parse_error(&e))?;
+
+        let args = &tokens[1..];
+        match tokens[0].as_str() {
+            "SIR" => {
+                let params = self.parse_scan(args, &self.sir)?;
+                self.sir = params;
+                self.scan(Register::Instruction)
+            }
+            "SDR" => {
+                let params = self.parse_scan(args, &self.sdr)?;
+                self.sdr = params;
+                self.scan(Register::Data)
+            }
+            "HIR" => {
+                self.hir = self.parse_scan(args, &self.hir)?;
+                Ok(())
+            }
+            "HDR" => {
+                self.hdr = self.parse_scan(args, &self.hdr)?;
+                Ok(())
+            }
+            "TIR" => {
+                self.tir = self.parse_scan(args, &self.tir)?;
+                Ok(())
+            }
+            "TDR" => {
+                self.tdr = self.parse_scan(args, &self.tdr)?;
+                Ok(())
+            }
+            "ENDIR" => {
+                let state = args.first().ok_or_else(|| self.parse_error("missing state"))?;
+                self.endir = self.parse_end_state(state)?;
+                Ok(())
+            }
+            "ENDDR" => {
+                let state = args.first().ok_or_else(|| self.parse_error("missing state"))?;
+                self.enddr = self.parse_end_state(state)?;
+                Ok(())
+            }
+            "STATE" => self.state(args),
+            "RUNTEST" => self.runtest(args),
+            "TRST" => self.trst(args),
+            "FREQUENCY" => self.frequency(args),
+            cmd => Err(SvfError::Unsupported { statement: self.statement, command: cmd.to_string() }),
+        }
+    }
+
+    /// Parse the arguments of SIR, SDR, HIR, HDR, TIR, or TDR.  `prev` holds the parameters from
+    /// the previous use of the same command, some of which carry over.
+    fn parse_scan(&self, args: &[String], prev: &ScanParams) -> Result<ScanParams, SvfError> {
+        let len = args.first().ok_or_else(|| self.parse_error("missing length"))?;
+        let len = len.parse::<usize>().map_err(|_| self.parse_error(&format!("bad length {}", len)))?;
+
+        let mut params = ScanParams {
+            len,
+            tdi: vec![0; len.div_ceil(8)],
+            tdo: None,
+            mask: vec![0xff; len.div_ceil(8)],
+            smask: vec![0xff; len.div_ceil(8)],
+        };
+        if len == prev.len {
+            params.tdi = prev.tdi.clone();
+            params.mask = prev.mask.clone();
+            params.smask = prev.smask.clone();
+        }
+
+        let mut tdi_given = len == prev.len || len == 0;
+        let mut i = 1;
+        while i < args.len() {
+            let hex = args.get(i+1)
+                .and_then(|x| x.strip_prefix('('))
+                .and_then(|x| x.strip_suffix(')'))
+                .ok_or_else(|| self.parse_error(&format!("missing value for {}", args[i])))?;
+            let value = parse_hex(hex, len)
+                .ok_or_else(|| self.parse_error(&format!("bad value for {}", args[i])))?;
+            match args[i].as_str() {
+                "TDI" => {
+                    params.tdi = value;
+                    tdi_given = true;
+                }
+                "TDO" => params.tdo = Some(value),
+                "MASK" => params.mask = value,
+                "SMASK" => params.smask = value,
+                x => return Err(self.parse_error(&format!("unknown scan parameter {}", x))),
+            }
+            i += 2;
+        }
+
+        if !tdi_given {
+            return Err(self.parse_error("TDI is required when the length changes"));
+        }
+        Ok(params)
+    }
+
+    fn scan(&mut self, reg: Register) -> Result<(), SvfError> {
+        let (header, body, trailer, end) = match reg {
+            Register::Data => (&self.hdr, &self.sdr, &self.tdr, self.enddr),
+            Register::Instruction => (&self.hir, &self.sir, &self.tir, self.endir),
+        };
+
+        // The header is shifted first, then the body, then the trailer, so the header lands in
+        // the devices nearest TDO and the trailer in those nearest TDI
+        let mut tdi = vec![];
+        let mut tdo = vec![];
+        let mut mask = vec![];
+        let mut check = false;
+        let mut bits = 0;
+        for p in [header, body, trailer] {
+            append_bits(&mut tdi, bits, &p.tdi, p.len);
+            if let Some(expected) = &p.tdo {
+                append_bits(&mut tdo, bits, expected, p.len);
+                append_bits(&mut mask, bits, &p.mask, p.len);
+                check = true;
+            } else {
+                // Don't care about TDO for this part of the scan
+                append_bits(&mut tdo, bits, &vec![0; p.len.div_ceil(8)], p.len);
+                append_bits(&mut mask, bits, &vec![0; p.len.div_ceil(8)], p.len);
+            }
+            bits += p.len;
+        }
+
+        if bits > 0 {
+            let mut last_bits = (bits % 8) as u8;
+            if last_bits == 0 {
+                last_bits = 8;
+            }
+
+            if check && self.check_tdo {
+                let got = self.sm.read_write_reg(reg, &tdi, last_bits, true)?;
+                if first_mismatch(&got, &tdo, &mask, bits).is_some() {
+                    return Err(SvfError::TdoMismatch { statement: self.statement, got, expected: tdo, mask });
+                }
+            } else {
+                self.sm.write_reg(reg, &tdi, last_bits, true)?;
+            }
+        }
+        self.sm.change_mode(end)?;
+        Ok(())
+    }
+
+    fn state(&mut self, args: &[String]) -> Result<(), SvfError> {
+        if args.is_empty() {
+            return Err(self.parse_error("missing state"));
+        }
+        for (i, name) in args.iter().enumerate() {
+            let state = if i == args.len() - 1 {
+                self.parse_end_state(name)?
+            } else {
+                parse_state(name).ok_or_else(|| self.parse_error(&format!("unknown state {}", name)))?
+            };
+            // Always clock TMS high for Reset, even if we think we are already there
+            if state == JtagState::Reset {
+                self.sm.reset()?;
+            } else {
+                self.sm.change_mode(state)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn runtest(&mut self, args: &[String]) -> Result<(), SvfError> {
+        let mut args = args;
+
+        if let Some(state) = args.first().and_then(|x| parse_state(x)) {
+            self.run_state = self.parse_end_state(&args[0])?;
+            // The end state defaults to the run state
+            self.run_end_state = state;
+            args = &args[1..];
+        }
+
+        let mut cycles = 0;
+        let mut min_time = 0.0;
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].as_str() {
+                "ENDSTATE" => {
+                    let state = args.get(i+1).ok_or_else(|| self.parse_error("missing end state"))?;
+                    self.run_end_state = self.parse_end_state(state)?;
+                    i += 2;
+                }
+                "MAXIMUM" => {
+                    // We never take longer than necessary, so there's nothing to enforce
+                    i += 3;
+                }
+                _ => {
+                    let value = self.parse_number(&args[i])?;
+                    match args.get(i+1).map(|x| x.as_str()) {
+                        Some("TCK") => cycles = value as usize,
+                        // The system clock isn't ours to drive; only the minimum time matters
+                        Some("SCK") => (),
+                        Some("SEC") => min_time = value,
+                        _ => return Err(self.parse_error("expected TCK, SCK, or SEC")),
+                    }
+                    i += 2;
+                }
+            }
+        }
+
+        self.sm.change_mode(self.run_state)?;
+        if cycles > 0 {
+            // TMS has to stay high to remain in Reset, and low to remain in the other stable states
+            let tms = if self.run_state == JtagState::Reset {
+                1
+            } else {
+                0
+            };
+            self.sm.cable.change_mode(&vec![tms; cycles], true)?;
+        }
+        if min_time > 0.0 {
+            self.sm.cable.flush()?;
+            std::thread::sleep(Duration::from_secs_f64(min_time));
+        }
+        self.sm.change_mode(self.run_end_state)?;
+        Ok(())
+    }
+
+    fn frequency(&mut self, args: &[String]) -> Result<(), SvfError> {
+        // Without a frequency the file is asking for full speed, which is up to the cable
+        let value = match args.first() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if args.get(1).is_some_and(|x| x != "HZ") {
+            return Err(self.parse_error("expected HZ"));
+        }
+
+        let hz = self.parse_number(value)?.round();
+        if !(1.0..=u32::MAX as f64).contains(&hz) {
+            return Err(self.parse_error(&format!("frequency {} out of range", value)));
+        }
+        // FREQUENCY is a maximum, so running slower than asked is fine
+        let requested = hz as u32;
+        let hz = requested.min(self.sm.cable.max_clock());
+        if hz != requested {
+            log::warn!("FREQUENCY {} Hz is faster than the cable can go, using {} Hz", requested, hz);
+        }
+
+        match self.sm.cable.set_clock(hz) {
+            Ok(()) => self.clock = Some(hz),
+            // A cable that can't change speed is fine if it's already running at this speed
+            Err(CableError::Unsupported) if self.clock == Some(hz) => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    fn trst(&mut self, args: &[String]) -> Result<(), SvfError> {
+        match args.first().map(|x| x.as_str()) {
+            Some("ON") => self.sm.cable.assert_trst()?,
+            Some("OFF") | Some("Z") | Some("ABSENT") => self.sm.cable.deassert_trst()?,
+            _ => return Err(self.parse_error("expected ON, OFF, Z, or ABSENT")),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    fn play(cable: &mut MockCable, svf: &str) -> Result<(), SvfError> {
+        let sm = JtagSM::new(cable)?;
+        SvfPlayer::new(sm).play(svf.as_bytes())
+    }
+
+    /// The TDI of every shift, whether or not TDO was read
+    fn shifts(cable: &MockCable) -> Vec<Vec<u8>> {
+        cable.recorded().iter().filter_map(|op| match op {
+            Operation::ReadWrite { data, .. } | Operation::Write { data, .. } => Some(data.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn idcode_matches() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x77, 0x04, 0xa0, 0x4b]]);
+        play(&mut cable, "SDR 32 TDI(00000000) TDO(4ba00477) MASK(0fffffff);").unwrap();
+        assert_eq!(shifts(&cable), vec![vec![0; 4]]);
+    }
+
+    #[test]
+    fn idcode_mismatch() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x77, 0x04, 0xa0, 0x5b]]);
+        let err = play(&mut cable, "SDR 32 TDI(00000000) TDO(4ba00477) MASK(ffffffff);").unwrap_err();
+        assert!(matches!(err, SvfError::TdoMismatch { statement: 1, .. }));
+    }
+
+    #[test]
+    fn header_shifted_first() {
+        let mut cable = MockCable::new();
+        play(&mut cable, "HDR 1 TDI(1); TDR 2 TDI(0); SDR 8 TDI(a5);").unwrap();
+        // Header at bit 0, then the body, then the trailer
+        assert_eq!(shifts(&cable), vec![vec![0x4b, 0x01]]);
+    }
+}
@@ -3,23 +3,169 @@
 //! client doesn't have to deal with putting the other TAPs into bypass and shifting data through
 //! the bypass registers.
 use crate::statemachine::{JtagSM, JtagState, Register};
-use crate::cable::Cable;
+use crate::bits::Bits;
+use crate::cable::{Cable, CableError};
+use crate::idcode::IdCode;
 
+use std::fmt;
+
+/// Append `shift` one bits after the first `this_len` bits of `input`
 fn add_ones_to_end(input: &[u8], this_len: usize, shift: usize) -> Vec<u8> {
-    let bytes = shift / 8;
-    let mut output = input.to_vec();
+    let mut output = input[..this_len.div_ceil(8)].to_vec();
 
-    let top_bits = (1 << (this_len % 8)) - 1;
-    let end = output.len()-1;
-    output[end] |= !top_bits;
+    if !this_len.is_multiple_of(8) {
+        let top_bits = (1 << (this_len % 8)) - 1;
+        let end = output.len()-1;
+        output[end] |= !top_bits;
+    }
 
-    let mut pad = vec![0xff; bytes];
-    output.append(&mut pad);
+    output.resize((this_len + shift).div_ceil(8), 0xff);
     output
 }
 
+/// Give up looking for the end of the chain after this many devices, in case TDO is floating
+const MAX_DEVICES: usize = 32;
+
+/// Give up looking for the end of the instruction registers after this many bits
+const MAX_IR_BITS: usize = 1024;
+
+/// Why `scan_idcodes` couldn't make sense of the chain.  Each variant carries the raw bits read
+/// from the data registers, LSB first, for debugging.
+#[derive(Debug)]
+pub enum ChainError {
+    Cable(CableError),
+    /// The ones shifted in never came back out within `MAX_DEVICES` devices, so the chain is
+    /// broken or TDO is floating
+    NoTerminator { bits: Vec<u8> },
+    /// TDO read as zero throughout, so it is probably stuck low
+    AllZeros { bits: Vec<u8> },
+    /// TDO read as one from the very first bit, so there are no devices or it is stuck high
+    AllOnes { bits: Vec<u8> },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::Cable(e) => write!(f, "{}", e),
+            ChainError::NoTerminator { .. } => write!(f, "no end of the scan chain after {} devices", MAX_DEVICES),
+            ChainError::AllZeros { .. } => write!(f, "TDO is stuck low"),
+            ChainError::AllOnes { .. } => write!(f, "TDO is stuck high or there are no devices"),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+impl From<CableError> for ChainError {
+    fn from(e: CableError) -> Self {
+        ChainError::Cable(e)
+    }
+}
+
+/// Errors from `Taps::write_ir_bits`
+#[derive(Debug)]
+pub enum TapsError {
+    Cable(CableError),
+    /// The instruction was `actual` bits long, but the selected TAP's instruction register is
+    /// `expected` bits
+    IrLength { expected: usize, actual: usize },
+}
+
+impl fmt::Display for TapsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TapsError::Cable(e) => write!(f, "{}", e),
+            TapsError::IrLength { expected, actual } =>
+                write!(f, "{} bit instruction for a {} bit instruction register", actual, expected),
+        }
+    }
+}
+
+impl std::error::Error for TapsError {}
+
+impl From<CableError> for TapsError {
+    fn from(e: CableError) -> Self {
+        TapsError::Cable(e)
+    }
+}
+
+/// One device found by `probe`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    /// The device's IDCODE, or 0 if it has none.  `IdCode` decodes the manufacturer, part, and
+    /// version.
+    pub idcode: IdCode,
+    /// The length of the instruction register, if `detect_ir_lengths` could tell
+    pub irlen: Option<usize>,
+}
+
+/// Everything `probe` found on the scan chain, in TAP index order
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainReport {
+    pub devices: Vec<DeviceInfo>,
+}
+
+/// A table with one row per device, e.g.
+///
+/// ```text
+/// TAP  IDCODE      MANUFACTURER        PART    VERSION  IR
+/// 0    0x4ba00477  ARM                 0xba00  4        4
+/// ```
+impl fmt::Display for ChainReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<4} {:<11} {:<19} {:<7} {:<8} IR", "TAP", "IDCODE", "MANUFACTURER", "PART", "VERSION")?;
+        for (i, dev) in self.devices.iter().enumerate() {
+            let id = dev.idcode;
+            let irlen = dev.irlen.map_or("?".to_string(), |x| x.to_string());
+            if id.0 == 0 {
+                writeln!(f, "{:<4} {:<11} {:<19} {:<7} {:<8} {}", i, "none", "-", "-", "-", irlen)?;
+                continue;
+            }
+            let manufacturer = id.manufacturer_name().map_or_else(|| format!("{:#05x}", id.manufacturer_id()), str::to_string);
+            writeln!(f, "{:<4} {:<#11x} {:<19} {:<#7x} {:<8} {}", i, id.0, manufacturer, id.part_number(), id.version(), irlen)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find out what is on the scan chain attached to `cable`: reset it, read every IDCODE with
+/// `Taps::scan_idcodes`, and capture the instruction registers once to split them up as
+/// `Taps::detect_ir_lengths` does.  If the captured values don't show where each instruction
+/// register starts, every `irlen` is `None`.
+pub fn probe(cable: &mut dyn Cable) -> Result<ChainReport, ChainError> {
+    let mut taps = Taps::new(JtagSM::new(cable)?);
+    let ids = taps.scan_idcodes()?;
+    let capture = taps.capture_ir()?;
+    let irlens = split_ir_capture(&capture, ids.len());
+    let devices = ids.iter().enumerate().map(|(i, idcode)| DeviceInfo {
+        idcode: *idcode,
+        irlen: irlens.as_ref().map(|x| x[i]),
+    }).collect();
+    Ok(ChainReport { devices })
+}
+
+/// Split the instruction register capture from `Taps::capture_ir` between `devices` devices,
+/// returning their lengths starting with the one closest to TDI, or `None` if the capture has
+/// more or fewer places where a device could start than there are devices.
+fn split_ir_capture(capture: &Bits, devices: usize) -> Option<Vec<usize>> {
+    // The first bits out belong to the device closest to TDO, least significant bit first
+    let starts: Vec<usize> = (0..capture.len())
+        .filter(|i| capture.get(*i) == Some(true) && capture.get(i + 1) == Some(false))
+        .collect();
+    if devices == 0 || starts.len() != devices || starts[0] != 0 {
+        return None;
+    }
+    let mut lens: Vec<usize> = starts.iter().zip(starts.iter().skip(1).chain([&capture.len()]))
+        .map(|(start, end)| end - start)
+        .collect();
+    lens.reverse();
+    Some(lens)
+}
+
 struct Tap {
     irlen: usize,
+    // The instruction that puts this TAP in BYPASS, if it isn't all ones
+    bypass: Option<Bits>,
 }
 
 pub struct Taps<T> {
@@ -27,7 +173,9 @@ pub struct Taps<T> {
     taps: Vec<Tap>,
     active: usize,
     dangling_read: bool,
-    queued_reads: usize
+    queued_reads: usize,
+    // Whether write_ir_bits refuses instructions of the wrong length, rather than warning
+    strict_ir_length: bool,
 }
 
 impl<T, U> Taps<T>
@@ -42,27 +190,75 @@ impl<T, U> Taps<T>
             active: 0,
             dangling_read: false,
             queued_reads: 0,
+            strict_ir_length: true,
+        }
+    }
+
+    /// Create an object for a scan chain whose instruction register lengths are already known,
+    /// e.g. from `detect_ir_lengths`.  `irlens` lists the TAPs in index order, starting with the
+    /// one closest to TDI.
+    pub fn with_ir_lengths(sm: JtagSM<T>, irlens: &[usize]) -> Self {
+        let mut taps = Self::new(sm);
+        for irlen in irlens {
+            assert!(*irlen > 0, "instruction registers are at least one bit long");
+            taps.add_tap(*irlen);
         }
+        taps
     }
 
     /// Add a TAP to the scan chain with the given instruction register length
     pub fn add_tap(&mut self, irlen: usize) {
         let tap = Tap {
-            irlen
+            irlen,
+            bypass: None,
         };
         self.taps.push(tap);
     }
 
+    /// Add a TAP whose BYPASS instruction isn't all ones.  `bypass` holds `irlen` bits, packed
+    /// LSB first like the `ir` passed to `select_tap`, and is shifted into this TAP's
+    /// instruction register whenever another TAP is selected or by `bypass_all`.
+    pub fn add_tap_with_bypass(&mut self, irlen: usize, bypass: &[u8]) {
+        assert_eq!(bypass.len(), irlen.div_ceil(8));
+        let tap = Tap {
+            irlen,
+            bypass: Some(Bits::new(bypass.to_vec(), irlen)),
+        };
+        self.taps.push(tap);
+    }
+
+    /// Build the bits to shift into the whole instruction register chain, with `ir` for the TAP
+    /// selected by `active` and the BYPASS instruction for every other TAP.  The TAP furthest
+    /// from TDI comes first, since its bits are shifted in first.
+    fn ir_chain(&self, active: Option<(usize, &Bits)>) -> Bits {
+        let mut chain = Bits::default();
+        for (i, t) in self.taps.iter().enumerate().rev() {
+            let ir = match active {
+                Some((tap, ir)) if tap == i => ir.clone(),
+                _ => t.bypass.clone().unwrap_or_else(|| Bits::from_bools(std::iter::repeat_n(true, t.irlen))),
+            };
+            for bit in ir.iter() {
+                chain.push(bit);
+            }
+        }
+        chain
+    }
+
+    fn shift_ir_chain(&mut self, chain: Bits) -> Result<(), CableError> {
+        self.sm.write_reg(Register::Instruction, chain.as_bytes(), chain.last_bits(), true)?;
+        self.sm.change_mode(JtagState::Idle)
+    }
+
     /// Attempt to autodetect the number of TAPs on the scan chain and the instruction register
     /// length for each.
-    pub fn detect(&mut self) {
+    pub fn detect(&mut self) -> Result<(), CableError> {
         self.taps = Vec::new();
-        self.sm.mode_reset();
+        self.sm.mode_reset()?;
 
         let mut count: i32 = -1;
         let mut irlen = vec![];
         loop {
-            let bit = self.sm.read_reg(Register::Instruction, 1);
+            let bit = self.sm.read_reg(Register::Instruction, 1)?;
             if bit[0] != 0 {
                 if count > 0 {
                     println!("found IR len {}", count+1);
@@ -77,15 +273,15 @@ impl<T, U> Taps<T>
             }
         }
 
-        self.sm.mode_reset();
+        self.sm.mode_reset()?;
         let mut ids = vec![];
         for _ in 0..irlen.len() {
-            let bit = self.sm.read_reg(Register::Data, 1);
+            let bit = self.sm.read_reg(Register::Data, 1)?;
             if bit[0] == 0 {
                 println!("invalid IDCODE of 0");
                 ids.push(0);
             } else {
-                let bits = self.sm.read_reg(Register::Data, 31);
+                let bits = self.sm.read_reg(Register::Data, 31)?;
                 let idcode = u32::from_le_bytes(bits.try_into().unwrap());
                 // Add back the one we read
                 ids.push((idcode << 1) | 1);
@@ -99,59 +295,177 @@ impl<T, U> Taps<T>
             println!("Adding tap {} idcode {:x}", i, ids[i]);
             self.add_tap(irlen[i] as usize);
         }
+        Ok(())
+    }
+
+    /// Reset the scan chain and read the IDCODE of every device on it, in the same order as the
+    /// TAP indexes used by `select_tap`.  Devices without an IDCODE register load a single BYPASS
+    /// bit on reset instead; these are reported with an IDCODE of 0.  The whole chain is read in
+    /// a single shift and then split up, a bit at a time for BYPASS and 32 bits for an IDCODE.
+    /// A TDO that never changes, or a chain whose end can't be found, is an error rather than a
+    /// list of garbage IDCODEs.
+    pub fn scan_idcodes(&mut self) -> Result<Vec<IdCode>, ChainError> {
+        self.sm.mode_reset()?;
+
+        // Enough for every device to have an IDCODE, plus the ones that follow the last device
+        const SCAN_BITS: usize = (MAX_DEVICES + 1) * 32;
+        let mut raw = self.sm.read_reg(Register::Data, SCAN_BITS)?;
+        raw.resize(SCAN_BITS / 8, 0xff);
+        if raw.iter().all(|x| *x == 0) {
+            return Err(ChainError::AllZeros { bits: raw });
+        }
+        if raw[..4].iter().all(|x| *x == 0xff) {
+            return Err(ChainError::AllOnes { bits: raw });
+        }
+
+        let bits = Bits::new(raw, SCAN_BITS);
+        let mut i = 0;
+        let mut ids = vec![];
+        loop {
+            // Bit 0 of a real IDCODE is always 1, so a 0 is a BYPASS register
+            let (idcode, len) = if bits.get(i) == Some(false) {
+                (0, 1)
+            } else {
+                ((0..32).fold(0_u32, |id, n| id | (bits.get(i + n).unwrap_or(true) as u32) << n), 32)
+            };
+            // Once we are past the last device, we read back the ones we have been shifting in
+            if idcode == 0xffffffff {
+                break;
+            }
+            if ids.len() == MAX_DEVICES {
+                return Err(ChainError::NoTerminator { bits: bits.into_bytes() });
+            }
+            ids.push(IdCode(idcode));
+            i += len;
+        }
+
+        ids.reverse();
+        Ok(ids)
+    }
+
+    /// Measure the instruction register lengths of the devices on the chain.  The instruction
+    /// registers are captured and shifted out in one go, followed by a single zero and then
+    /// ones; the zero is the last one to come out on TDO, and the bits before it are the
+    /// captured values.  Every IR captures a value ending in binary 01, so each device starts
+    /// where a one is followed by a zero.  If there are exactly as many of those as devices
+    /// found by `scan_idcodes`, one entry per device is returned, in index order.  Otherwise the
+    /// split can't be told from the captured values, and the result is a single entry holding
+    /// the total, which the caller has to split up using other knowledge of the devices, such as
+    /// their BSDL files.
+    pub fn detect_ir_lengths(&mut self) -> Result<Vec<usize>, ChainError> {
+        let devices = self.scan_idcodes()?.len();
+        let capture = self.capture_ir()?;
+        Ok(split_ir_capture(&capture, devices).unwrap_or_else(|| vec![capture.len()]))
+    }
+
+    /// Capture every instruction register on the chain and return the captured bits in the
+    /// order they come out of TDO, leaving every TAP in BYPASS
+    fn capture_ir(&mut self) -> Result<Bits, CableError> {
+        self.sm.mode_reset()?;
+
+        // A zero followed by ones, so the zero is the last one we can see come out
+        let mut buf = vec![0xff; MAX_IR_BITS / 8];
+        buf[0] = 0xfe;
+        let tdo = self.sm.read_write_reg(Register::Instruction, &buf, 8, true)?;
+        self.sm.change_mode(JtagState::Idle)?;
+
+        let tdo = Bits::new(tdo, MAX_IR_BITS);
+        let total = (0..MAX_IR_BITS).rev().find(|i| tdo.get(*i) == Some(false))
+            .filter(|i| *i > 0 && *i < MAX_IR_BITS - 1)
+            .ok_or_else(|| CableError::Protocol("couldn't find the end of the IR chain".to_string()))?;
+        Ok(Bits::from_bools(tdo.iter().take(total)))
+    }
+
+    /// Put every TAP in BYPASS.  The instruction registers are filled with ones, which is the
+    /// BYPASS instruction for most devices, except for TAPs added with `add_tap_with_bypass`.
+    /// This is a good state to leave the chain in before handing it to another tool.
+    pub fn bypass_all(&mut self) -> Result<(), CableError> {
+        assert!(!self.taps.is_empty(), "no TAPs on the chain");
+        let chain = self.ir_chain(None);
+        self.shift_ir_chain(chain)
+    }
+
+    /// Check the chain's connectivity after `bypass_all`.  `pattern` is shifted through the
+    /// BYPASS registers, and should come out of TDO delayed by one bit for each TAP.  Returns
+    /// false if it doesn't, which means the number of TAPs is wrong or the chain is broken.
+    pub fn verify_bypass(&mut self, pattern: &[u8]) -> Result<bool, CableError> {
+        assert_eq!(self.queued_reads, 0);
+        assert!(!pattern.is_empty());
+        let delay = self.taps.len();
+        let len = pattern.len() * 8;
+        let mut last_bits = (len + delay) % 8;
+        if last_bits == 0 {
+            last_bits = 8;
+        }
+        let tdi = add_ones_to_end(pattern, len, delay);
+        let tdo = self.sm.read_write_reg(Register::Data, &tdi, last_bits as u8, true)?;
+        self.sm.change_mode(JtagState::Idle)?;
+
+        let tdo = Bits::new(tdo, len + delay);
+        let pattern = Bits::new(pattern.to_vec(), len);
+        let matched = pattern.iter().enumerate().all(|(i, bit)| tdo.get(i + delay) == Some(bit));
+        Ok(matched)
     }
 
     /// Select which TAP in the scan chain to operate upon.  `ir` will be shifted into its
     /// instruction register, and the other TAPs put into bypass.
-    pub fn select_tap(&mut self, tap: usize, ir: &[u8]) {
+    pub fn select_tap(&mut self, tap: usize, ir: &[u8]) -> Result<(), CableError> {
         assert!(tap < self.taps.len());
-        self.sm.mode_reset();
+        self.sm.mode_reset()?;
         self.active = tap;
-        self.write_ir(ir);
+        self.write_ir(ir)
     }
 
-    fn write_ones(&mut self, mut bits: usize) {
-        let bytes = bits / 8;
-        bits %= 8;
-
-        if bytes > 0 {
-            let buf = vec![0xff; bytes];
-            self.sm.write_reg(Register::Instruction, &buf, 8, false);
-        }
-        if bits > 0 {
-            let buf = vec![(1 << bits) - 1];
-            self.sm.write_reg(Register::Instruction, &buf, bits as u8, false);
-        }
+    /// Select which TAP in the scan chain to operate upon, without changing any instruction
+    /// register.  `write_ir` then pads the instruction with BYPASS instructions for every other
+    /// TAP, and the data register methods add one bypass bit for each of the other TAPs
+    /// before and after the selected TAP's data.
+    pub fn select(&mut self, tap: usize) {
+        assert!(tap < self.taps.len());
+        self.active = tap;
     }
 
     /// Shift `ir` into the instruction register of the TAP selected by `select_tap`
-    pub fn write_ir(&mut self, ir: &[u8]) {
+    pub fn write_ir(&mut self, ir: &[u8]) -> Result<(), CableError> {
         assert!(self.active < self.taps.len());
         let this_irlen = self.taps[self.active].irlen;
-        assert_eq!(ir.len(), (this_irlen + 7) / 8);
+        assert_eq!(ir.len(), this_irlen.div_ceil(8));
+        self.shift_active_ir(Bits::new(ir.to_vec(), this_irlen))
+    }
 
-        // Put downstream taps into BYPASS
-        let mut after_pad = 0;
-        for t in &self.taps[self.active+1..] {
-            after_pad += t.irlen;
+    /// Shift the first `bits` bits of `ir` into the instruction register of the TAP selected by
+    /// `select_tap`.  An instruction that isn't as long as the TAP's instruction register would
+    /// leave every TAP after it with the wrong bits, so it returns `TapsError::IrLength` without
+    /// shifting anything, unless `set_strict_ir_length(false)` was called.
+    pub fn write_ir_bits(&mut self, ir: &[u8], bits: usize) -> Result<(), TapsError> {
+        assert!(self.active < self.taps.len());
+        assert_eq!(ir.len(), bits.div_ceil(8));
+        let this_irlen = self.taps[self.active].irlen;
+        if bits != this_irlen {
+            if self.strict_ir_length {
+                return Err(TapsError::IrLength { expected: this_irlen, actual: bits });
+            }
+            log::warn!("shifting {} bit instruction into TAP {} with a {} bit instruction register",
+                       bits, self.active, this_irlen);
         }
-        self.write_ones(after_pad);
+        Ok(self.shift_active_ir(Bits::new(ir.to_vec(), bits))?)
+    }
 
-        let mut pad_bits = 0;
-        for t in &self.taps[0..self.active] {
-            pad_bits += t.irlen;
-        }
-        let mut total_bits = (pad_bits + this_irlen) % 8;
-        if total_bits == 0 {
-            total_bits = 8;
-        }
-        let ir = add_ones_to_end(ir, this_irlen, pad_bits);
-        self.sm.write_reg(Register::Instruction, &ir, total_bits as u8, true);
-        self.sm.change_mode(JtagState::Idle);
+    /// Shift `ir` into the selected TAP's instruction register and BYPASS into the others
+    fn shift_active_ir(&mut self, ir: Bits) -> Result<(), CableError> {
+        let chain = self.ir_chain(Some((self.active, &ir)));
+        self.shift_ir_chain(chain)
+    }
+
+    /// Choose whether `write_ir_bits` refuses an instruction that isn't as long as the selected
+    /// TAP's instruction register, or only logs a warning and shifts it anyway, for chains where
+    /// shifting a partial instruction is intended.  The default is to refuse.
+    pub fn set_strict_ir_length(&mut self, strict: bool) {
+        self.strict_ir_length = strict;
     }
 
     /// Read the instruction register of the TAP selected by `select_tap`
-    pub fn read_ir(&mut self) -> Vec<u8> {
+    pub fn read_ir(&mut self) -> Result<Vec<u8>, CableError> {
         assert!(self.active < self.taps.len());
         let this_irlen = self.taps[self.active].irlen;
         let mut pad_bits = 0;
@@ -160,16 +474,16 @@ impl<T, U> Taps<T>
         }
 
         // Discard the unwanted bits
-        self.sm.change_mode(JtagState::Idle);
+        self.sm.change_mode(JtagState::Idle)?;
         if pad_bits > 0 {
-            self.sm.read_reg(Register::Instruction, pad_bits);
+            self.sm.read_reg(Register::Instruction, pad_bits)?;
         }
         self.sm.read_reg(Register::Instruction, this_irlen)
     }
 
     /// Shift `dr` into the data register of the TAP selected by `select_tap`.  `bits` indicates
     /// how many bits of the final byte should be written (a value of 8 will write the entire byte)
-    pub fn write_dr(&mut self, dr: &[u8], bits: usize) {
+    pub fn write_dr(&mut self, dr: &[u8], bits: usize) -> Result<(), CableError> {
         assert!(self.active < self.taps.len());
         let this_len = (dr.len() - 1) * 8 + bits;
         let pad_bits = self.active;
@@ -179,22 +493,22 @@ impl<T, U> Taps<T>
             total_bits = 8;
         }
         let dr = add_ones_to_end(dr, this_len, pad_bits);
-        self.sm.write_reg(Register::Data, &dr, total_bits as u8, true);
-        self.sm.change_mode(JtagState::Idle);
+        self.sm.write_reg(Register::Data, &dr, total_bits as u8, true)?;
+        self.sm.change_mode(JtagState::Idle)
     }
 
     /// Shift `dr` into the data register of the TAP selected by `select_tap`.  `bits` indicates
     /// how many bits of the final byte should be written (a value of 8 will
     /// write the entire byte).  Returns the bits that were shifted out while `dr` was
     /// shifted in.
-    pub fn read_write_dr(&mut self, dr: &[u8], bits: usize) -> Vec<u8> {
+    pub fn read_write_dr(&mut self, dr: &[u8], bits: usize) -> Result<Vec<u8>, CableError> {
         assert_eq!(self.queued_reads, 0);
-        self.queue_dr_read_write(dr, bits);
+        self.queue_dr_read_write(dr, bits)?;
         let total_bits = (dr.len()-1) * 8 + bits;
         self.finish_dr_read(total_bits)
     }
 
-    pub fn queue_dr_read_write(&mut self, dr: &[u8], bits: usize) -> bool {
+    pub fn queue_dr_read_write(&mut self, dr: &[u8], bits: usize) -> Result<(), CableError> {
         assert!(self.active < self.taps.len());
         let this_len = (dr.len() - 1) * 8 + bits;
         let pad_bits = self.active;
@@ -206,52 +520,51 @@ impl<T, U> Taps<T>
         }
         let dr = add_ones_to_end(dr, this_len, pad_bits);
         if discard_bits > 0 {
-            if !self.sm.queue_read(Register::Data, discard_bits) {
-                return false;
-            }
+            self.sm.queue_read(Register::Data, discard_bits)?;
         }
-        if self.sm.queue_read_write(Register::Data, &dr, total_bits as u8, true) {
-            self.sm.change_mode(JtagState::Idle);
-            self.queued_reads += 1;
-            true
-        } else {
-            self.sm.change_mode(JtagState::Idle);
-            self.dangling_read = discard_bits > 0;
-            false
+        match self.sm.queue_read_write(Register::Data, &dr, total_bits as u8, true) {
+            Ok(()) => {
+                self.sm.change_mode(JtagState::Idle)?;
+                self.queued_reads += 1;
+                Ok(())
+            }
+            Err(e) => {
+                self.dangling_read = discard_bits > 0;
+                self.sm.change_mode(JtagState::Idle)?;
+                Err(e)
+            }
         }
     }
 
     /// Read the data register of the TAP selected by `select_tap`.  `bits` indicates the length of
     /// the data register for the current instruction.
-    pub fn read_dr(&mut self, bits: usize) -> Vec<u8> {
+    pub fn read_dr(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
         assert_eq!(self.queued_reads, 0);
-        self.queue_dr_read(bits);
+        self.queue_dr_read(bits)?;
         self.finish_dr_read(bits)
     }
 
-    pub fn queue_dr_read(&mut self, bits: usize) -> bool {
+    pub fn queue_dr_read(&mut self, bits: usize) -> Result<(), CableError> {
         assert!(self.active < self.taps.len());
         let pad_bits = self.active;
         let discard_bits = self.taps.len() - self.active - 1;
         let total_bits = pad_bits + bits;
 
         // Discard the bypass bits
-        self.sm.change_mode(JtagState::Idle);
+        self.sm.change_mode(JtagState::Idle)?;
         if discard_bits > 0 {
-            if !self.sm.queue_read(Register::Data, discard_bits) {
-                return false;
-            }
+            self.sm.queue_read(Register::Data, discard_bits)?;
         }
-        if !self.sm.queue_read(Register::Data, total_bits) {
+        if let Err(e) = self.sm.queue_read(Register::Data, total_bits) {
             self.dangling_read = discard_bits > 0;
-            false
+            Err(e)
         } else {
             self.queued_reads += 1;
-            true
+            Ok(())
         }
     }
 
-    pub fn finish_dr_read(&mut self, bits: usize) -> Vec<u8> {
+    pub fn finish_dr_read(&mut self, bits: usize) -> Result<Vec<u8>, CableError> {
         assert!(self.active < self.taps.len());
         let pad_bits = self.active;
         let discard_bits = self.taps.len() - self.active - 1;
@@ -259,18 +572,18 @@ impl<T, U> Taps<T>
 
         // Discard the bypass bits
         if discard_bits > 0 {
-            self.sm.cable.finish_read(discard_bits);
+            self.sm.cable.finish_read(discard_bits)?;
         }
-        let mut ret = self.sm.cable.finish_read(total_bits);
+        let mut ret = self.sm.cable.finish_read(total_bits)?;
 
         // Remove the pad bits
         if pad_bits > 0 {
-            let bytes = (bits + 7) / 8;
+            let bytes = bits.div_ceil(8);
             // Trim off any extra bytes
             ret.resize(bytes, 0);
 
             // Mask off high bits
-            if bits % 8 != 0 {
+            if !bits.is_multiple_of(8) {
                 ret[bytes-1] &= (1 << (bits % 8)) - 1;
             }
         }
@@ -280,11 +593,208 @@ impl<T, U> Taps<T>
         self.queued_reads -= 1;
         if self.queued_reads == 0 && self.dangling_read {
             if discard_bits > 0 {
-                self.sm.cable.finish_read(discard_bits);
+                self.sm.cable.finish_read(discard_bits)?;
             }
             self.dangling_read = false;
         }
-        ret
+        Ok(ret)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    /// The response to `scan_idcodes` for a chain of `ids`, listed from TDI
+    fn idcode_scan(ids: &[u32]) -> Vec<u8> {
+        ids.iter().rev().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// The response to an IR capture whose bits, as they come out of TDO, are `capture`,
+    /// followed by the zero and ones shifted in
+    fn ir_capture(capture: &[bool]) -> Vec<u8> {
+        let bits = capture.iter().copied().chain([false]).chain(std::iter::repeat(true));
+        let bits = Bits::from_bools(bits.take(MAX_IR_BITS));
+        bits.into_bytes()
+    }
+
+    #[test]
+    fn ir_lengths_4_and_6() {
+        // The 6 bit IR is closer to TDO, so its capture comes out first
+        let capture = [true, false, false, false, false, false, true, false, false, false];
+        let mut cable = MockCable::with_tdo(vec![
+            idcode_scan(&[0x4ba00477, 0x06413041]),
+            ir_capture(&capture),
+        ]);
+        let mut taps = Taps::new(JtagSM::new(&mut cable).unwrap());
+        assert_eq!(taps.detect_ir_lengths().unwrap(), vec![4, 6]);
+    }
+
+    #[test]
+    fn ambiguous_ir_lengths() {
+        // The second IR captures 0101, which could be two 2 bit IRs
+        let capture = [true, false, false, false, false, false, true, false, true, false];
+        let mut cable = MockCable::with_tdo(vec![
+            idcode_scan(&[0x4ba00477, 0x06413041]),
+            ir_capture(&capture),
+        ]);
+        let mut taps = Taps::new(JtagSM::new(&mut cable).unwrap());
+        assert_eq!(taps.detect_ir_lengths().unwrap(), vec![10]);
+    }
+
+    #[test]
+    fn ir_lengths_stuck_tdo() {
+        let mut cable = MockCable::with_tdo(vec![vec![0; (MAX_DEVICES + 1) * 4]]);
+        let mut taps = Taps::new(JtagSM::new(&mut cable).unwrap());
+        assert!(matches!(taps.detect_ir_lengths(), Err(ChainError::AllZeros { .. })));
+    }
+
+    #[test]
+    fn probe_two_devices() {
+        let capture = [true, false, false, false, false, false, true, false, false, false];
+        let mut cable = MockCable::with_tdo(vec![
+            idcode_scan(&[0x4ba00477, 0x06413041]),
+            ir_capture(&capture),
+        ]);
+        let report = probe(&mut cable).unwrap();
+        assert_eq!(report.devices, vec![
+            DeviceInfo { idcode: IdCode(0x4ba00477), irlen: Some(4) },
+            DeviceInfo { idcode: IdCode(0x06413041), irlen: Some(6) },
+        ]);
+        // One IDCODE scan and one IR capture
+        let reads = cable.recorded().iter()
+            .filter(|op| matches!(op, Operation::Read { .. } | Operation::ReadWrite { .. }))
+            .count();
+        assert_eq!(reads, 2);
+    }
+
+    #[test]
+    fn probe_unknown_ir_lengths() {
+        let capture = [true, false, false, false, false, false, true, false, true, false];
+        let mut cable = MockCable::with_tdo(vec![
+            idcode_scan(&[0x4ba00477, 0x06413041]),
+            ir_capture(&capture),
+        ]);
+        let report = probe(&mut cable).unwrap();
+        assert!(report.devices.iter().all(|x| x.irlen.is_none()));
+    }
+
+    #[test]
+    fn ir_length_mismatch() {
+        let mut cable = MockCable::new();
+        let mut taps = Taps::with_ir_lengths(JtagSM::new(&mut cable).unwrap(), &[4]);
+        let err = taps.write_ir_bits(&[0x1f], 5).unwrap_err();
+        assert!(matches!(err, TapsError::IrLength { expected: 4, actual: 5 }));
+        assert!(!cable.recorded().iter().any(|op| matches!(op, Operation::Write { .. })));
+    }
+
+    #[test]
+    fn ir_length_mismatch_allowed() {
+        let mut cable = MockCable::new();
+        let mut taps = Taps::with_ir_lengths(JtagSM::new(&mut cable).unwrap(), &[4]);
+        taps.set_strict_ir_length(false);
+        taps.write_ir_bits(&[0x1f], 5).unwrap();
+        assert!(cable.recorded().contains(&Operation::Write { data: vec![0x1f], bits: 5, pause_after: true }));
+    }
+
+    #[test]
+    fn scan_two_idcodes() {
+        let mut cable = MockCable::with_tdo(vec![idcode_scan(&[0x4ba00477, 0x06413041])]);
+        let mut taps = Taps::new(JtagSM::new(&mut cable).unwrap());
+        assert_eq!(taps.scan_idcodes().unwrap(), vec![IdCode(0x4ba00477), IdCode(0x06413041)]);
+    }
+
+    #[test]
+    fn scan_with_bypass_device() {
+        // The middle device has no IDCODE, so it puts a single zero on the chain, which leaves
+        // the device nearest TDI misaligned by one bit
+        let idcode = |x: u32| Bits::new(x.to_le_bytes().to_vec(), 32);
+        let bits = Bits::from_bools(idcode(0x06413041).iter()
+            .chain([false])
+            .chain(idcode(0x4ba00477).iter())
+            .chain([true; 71]));
+        let mut cable = MockCable::with_tdo(vec![bits.into_bytes()]);
+        let mut taps = Taps::new(JtagSM::new(&mut cable).unwrap());
+        assert_eq!(taps.scan_idcodes().unwrap(), vec![IdCode(0x4ba00477), IdCode(0), IdCode(0x06413041)]);
+    }
+
+    #[test]
+    fn scan_stuck_tdo() {
+        let mut cable = MockCable::with_tdo(vec![vec![0; (MAX_DEVICES + 1) * 4]]);
+        let mut taps = Taps::new(JtagSM::new(&mut cable).unwrap());
+        match taps.scan_idcodes() {
+            Err(ChainError::AllZeros { bits }) => assert_eq!(bits, vec![0; (MAX_DEVICES + 1) * 4]),
+            x => panic!("expected AllZeros, got {:?}", x),
+        }
+
+        // With nothing scripted, the mock reads all ones
+        let mut cable = MockCable::new();
+        let mut taps = Taps::new(JtagSM::new(&mut cable).unwrap());
+        assert!(matches!(taps.scan_idcodes(), Err(ChainError::AllOnes { .. })));
+    }
+
+    #[test]
+    fn bypass_three_devices() {
+        // The pattern comes back delayed by one bit for each BYPASS register
+        let mut cable = MockCable::with_tdo(vec![vec![0x28, 0x05], vec![0x94, 0x02]]);
+        let mut taps = Taps::with_ir_lengths(JtagSM::new(&mut cable).unwrap(), &[4, 5, 6]);
+        taps.bypass_all().unwrap();
+        assert!(taps.verify_bypass(&[0xa5]).unwrap());
+        // Only delayed by two bits, so a device is missing
+        assert!(!taps.verify_bypass(&[0xa5]).unwrap());
+
+        let ops = cable.recorded();
+        assert!(ops.contains(&Operation::Write { data: vec![0xff, 0x7f], bits: 7, pause_after: true }));
+        assert!(ops.contains(&Operation::ReadWrite { data: vec![0xa5, 0xff], bits: 3, pause_after: true }));
+    }
+
+    #[test]
+    fn custom_bypass_padding() {
+        let mut cable = MockCable::new();
+        let mut taps = Taps::new(JtagSM::new(&mut cable).unwrap());
+        taps.add_tap(4);
+        taps.add_tap_with_bypass(4, &[0b1110]);
+        taps.add_tap(4);
+        taps.select(0);
+        taps.write_ir(&[0x2]).unwrap();
+        taps.bypass_all().unwrap();
+
+        // TAP 2 is shifted first, then TAP 1's 0b1110 LSB first, then TAP 0
+        let writes: Vec<_> = cable.recorded().iter()
+            .filter(|op| matches!(op, Operation::Write { .. }))
+            .collect();
+        assert_eq!(writes, vec![
+            &Operation::Write { data: vec![0xef, 0x02], bits: 4, pause_after: true },
+            &Operation::Write { data: vec![0xef, 0x0f], bits: 4, pause_after: true },
+        ]);
+    }
+
+    #[test]
+    fn ones_after_partial_and_whole_bytes() {
+        assert_eq!(add_ones_to_end(&[0x05], 3, 6), vec![0xfd, 0xff]);
+        // A whole byte of data still gets its padding
+        assert_eq!(add_ones_to_end(&[0x12], 8, 3), vec![0x12, 0xff]);
+        assert_eq!(add_ones_to_end(&[0x12, 0x34], 8, 0), vec![0x12]);
+    }
+
+    #[test]
+    fn middle_tap_padding() {
+        let mut cable = MockCable::with_tdo(vec![vec![0x01], vec![0x5a, 0x01]]);
+        let mut taps = Taps::with_ir_lengths(JtagSM::new(&mut cable).unwrap(), &[4, 5, 6]);
+        taps.select(1);
+        taps.write_ir(&[0x03]).unwrap();
+        taps.write_dr(&[0x5a], 8).unwrap();
+        assert_eq!(taps.read_dr(8).unwrap(), vec![0x5a]);
+
+        // Six ones for TAP 2, the instruction, then four ones for TAP 0
+        let ops = cable.recorded();
+        assert!(ops.contains(&Operation::Write { data: vec![0xff, 0x78], bits: 7, pause_after: true }));
+        // One bypass bit for TAP 0 after the data
+        assert!(ops.contains(&Operation::Write { data: vec![0x5a, 0xff], bits: 1, pause_after: true }));
+        // TAP 2's bypass bit comes out first, then the data, then TAP 0's bypass bit
+        assert!(ops.contains(&Operation::Read { bits: 1 }));
+        assert!(ops.contains(&Operation::Read { bits: 9 }));
+    }
+}
@@ -0,0 +1,397 @@
+//! A player for XSVF files, the compact binary form of SVF produced by Xilinx tools and many CPLD
+//! toolchains.  The opcodes and their semantics follow Xilinx application note XAPP503.
+//!
+//! Multi-byte values in XSVF are big-endian, and bit vectors are stored with their most
+//! significant byte first.  Since the least significant bit is shifted first, vectors are
+//! converted to the LSB-first packing used by the rest of the crate as they are read.
+use crate::cable::{Cable, CableError};
+use crate::statemachine::{first_mismatch, JtagSM, JtagState, Register};
+
+use std::fmt;
+use std::io::Read;
+use std::time::Duration;
+
+const XCOMPLETE: u8 = 0x00;
+const XTDOMASK: u8 = 0x01;
+const XSIR: u8 = 0x02;
+const XSDR: u8 = 0x03;
+const XRUNTEST: u8 = 0x04;
+const XREPEAT: u8 = 0x07;
+const XSDRSIZE: u8 = 0x08;
+const XSDRTDO: u8 = 0x09;
+const XSDRB: u8 = 0x0c;
+const XSDRC: u8 = 0x0d;
+const XSDRE: u8 = 0x0e;
+const XSDRTDOB: u8 = 0x0f;
+const XSDRTDOC: u8 = 0x10;
+const XSDRTDOE: u8 = 0x11;
+const XSTATE: u8 = 0x12;
+const XENDIR: u8 = 0x13;
+const XENDDR: u8 = 0x14;
+const XSIR2: u8 = 0x15;
+const XCOMMENT: u8 = 0x16;
+const XWAIT: u8 = 0x17;
+
+// Number of times a failed XSDR or XSDRTDO is retried unless XREPEAT says otherwise
+const DEFAULT_REPEAT: u8 = 32;
+
+#[derive(Debug)]
+pub enum XsvfError {
+    /// The cable reported an error
+    Cable(CableError),
+    /// Reading the XSVF file failed, including if it ended part way through a command
+    Io(std::io::Error),
+    /// Command number `command` has an opcode or argument this player doesn't understand
+    Unsupported { command: usize, opcode: u8 },
+    /// The TDO captured by command number `command` didn't match the expected value in one of
+    /// the bits selected by XTDOMASK, even after any retries
+    TdoMismatch { command: usize, got: Vec<u8>, expected: Vec<u8>, mask: Vec<u8> },
+}
+
+impl fmt::Display for XsvfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XsvfError::Cable(e) => write!(f, "cable error: {}", e),
+            XsvfError::Io(e) => write!(f, "io error: {}", e),
+            XsvfError::Unsupported { command, opcode } =>
+                write!(f, "command {}: unsupported opcode {:#x}", command, opcode),
+            XsvfError::TdoMismatch { command, got, expected, mask } =>
+                write!(f, "command {}: TDO mismatch, got {:02x?} expected {:02x?} mask {:02x?}",
+                       command, got, expected, mask),
+        }
+    }
+}
+
+impl std::error::Error for XsvfError {}
+
+impl From<CableError> for XsvfError {
+    fn from(e: CableError) -> Self {
+        XsvfError::Cable(e)
+    }
+}
+
+impl From<std::io::Error> for XsvfError {
+    fn from(e: std::io::Error) -> Self {
+        XsvfError::Io(e)
+    }
+}
+
+pub struct XsvfPlayer<T> {
+    pub sm: JtagSM<T>,
+    // Length of data registers in bits, from XSDRSIZE
+    sdr_size: usize,
+    tdo_mask: Vec<u8>,
+    tdo_expected: Vec<u8>,
+    repeat: u8,
+    // Time to spend in Idle after each shift, in microseconds
+    run_test: u32,
+    endir: JtagState,
+    enddr: JtagState,
+    // Number of the command being executed, for error reporting
+    command: usize,
+}
+
+impl<T, U> XsvfPlayer<T>
+    where T: std::ops::DerefMut<Target=U>,
+          U: Cable + ?Sized
+{
+    /// Create a player that executes XSVF files using an existing `JtagSM`
+    pub fn new(sm: JtagSM<T>) -> Self {
+        Self {
+            sm,
+            sdr_size: 0,
+            tdo_mask: vec![],
+            tdo_expected: vec![],
+            repeat: DEFAULT_REPEAT,
+            run_test: 0,
+            endir: JtagState::Idle,
+            enddr: JtagState::Idle,
+            command: 0,
+        }
+    }
+
+    /// Decode and execute every command in `reader`, up to XCOMPLETE.  Stops at the first command
+    /// that fails, including any shift whose TDO still doesn't match after the retries allowed by
+    /// XREPEAT.
+    pub fn play(&mut self, mut reader: impl Read) -> Result<(), XsvfError> {
+        self.command = 0;
+        loop {
+            let opcode = read_u8(&mut reader)?;
+            self.command += 1;
+
+            match opcode {
+                XCOMPLETE => break,
+                XTDOMASK => self.tdo_mask = read_vector(&mut reader, self.sdr_size)?,
+                XSIR => {
+                    let bits = read_u8(&mut reader)? as usize;
+                    let tdi = read_vector(&mut reader, bits)?;
+                    self.shift_ir(&tdi, bits)?;
+                }
+                XSIR2 => {
+                    let bits = read_u16(&mut reader)? as usize;
+                    let tdi = read_vector(&mut reader, bits)?;
+                    self.shift_ir(&tdi, bits)?;
+                }
+                XSDR => {
+                    let tdi = read_vector(&mut reader, self.sdr_size)?;
+                    let expected = self.tdo_expected.clone();
+                    self.shift_dr(&tdi, &expected)?;
+                }
+                XSDRTDO => {
+                    let tdi = read_vector(&mut reader, self.sdr_size)?;
+                    self.tdo_expected = read_vector(&mut reader, self.sdr_size)?;
+                    let expected = self.tdo_expected.clone();
+                    self.shift_dr(&tdi, &expected)?;
+                }
+                XSDRB | XSDRC | XSDRE => {
+                    let tdi = read_vector(&mut reader, self.sdr_size)?;
+                    self.shift_dr_part(&tdi, None, opcode == XSDRE)?;
+                }
+                XSDRTDOB | XSDRTDOC | XSDRTDOE => {
+                    let tdi = read_vector(&mut reader, self.sdr_size)?;
+                    self.tdo_expected = read_vector(&mut reader, self.sdr_size)?;
+                    let expected = self.tdo_expected.clone();
+                    self.shift_dr_part(&tdi, Some(&expected), opcode == XSDRTDOE)?;
+                }
+                XRUNTEST => self.run_test = read_u32(&mut reader)?,
+                XREPEAT => self.repeat = read_u8(&mut reader)?,
+                XSDRSIZE => self.sdr_size = read_u32(&mut reader)? as usize,
+                XSTATE => {
+                    let state = self.read_state(&mut reader, opcode)?;
+                    // Reset is always done with TMS, even if we think we are already there
+                    if state == JtagState::Reset {
+                        self.sm.reset()?;
+                    } else {
+                        self.sm.change_mode(state)?;
+                    }
+                }
+                XENDIR => {
+                    self.endir = match read_u8(&mut reader)? {
+                        0 => JtagState::Idle,
+                        1 => JtagState::PauseIR,
+                        _ => return Err(self.unsupported(opcode)),
+                    };
+                }
+                XENDDR => {
+                    self.enddr = match read_u8(&mut reader)? {
+                        0 => JtagState::Idle,
+                        1 => JtagState::PauseDR,
+                        _ => return Err(self.unsupported(opcode)),
+                    };
+                }
+                XCOMMENT => {
+                    while read_u8(&mut reader)? != 0 {}
+                }
+                XWAIT => {
+                    let wait_state = self.read_state(&mut reader, opcode)?;
+                    let end_state = self.read_state(&mut reader, opcode)?;
+                    let usecs = read_u32(&mut reader)?;
+                    self.sm.change_mode(wait_state)?;
+                    self.wait(usecs)?;
+                    self.sm.change_mode(end_state)?;
+                }
+                _ => return Err(self.unsupported(opcode)),
+            }
+        }
+
+        self.sm.cable.flush()?;
+        Ok(())
+    }
+
+    fn unsupported(&self, opcode: u8) -> XsvfError {
+        XsvfError::Unsupported { command: self.command, opcode }
+    }
+
+    /// Read a state argument, which uses the same numbering as `JtagState`
+    fn read_state(&self, reader: &mut impl Read, opcode: u8) -> Result<JtagState, XsvfError> {
+        use JtagState::*;
+
+        let states = [
+            Reset, Idle, SelectDR, CaptureDR, ShiftDR, Exit1DR, PauseDR, Exit2DR, UpdateDR,
+            SelectIR, CaptureIR, ShiftIR, Exit1IR, PauseIR, Exit2IR, UpdateIR,
+        ];
+        let x = read_u8(reader)?;
+        states.get(x as usize).copied().ok_or_else(|| self.unsupported(opcode))
+    }
+
+    fn wait(&mut self, usecs: u32) -> Result<(), XsvfError> {
+        if usecs > 0 {
+            self.sm.cable.flush()?;
+            std::thread::sleep(Duration::from_micros(usecs as u64));
+        }
+        Ok(())
+    }
+
+    /// Shift `bits` of `tdi` into `reg`, ending in PauseIR or PauseDR, and return what was captured
+    fn shift(&mut self, reg: Register, tdi: &[u8], bits: usize) -> Result<Vec<u8>, XsvfError> {
+        let mut last_bits = (bits % 8) as u8;
+        if last_bits == 0 {
+            last_bits = 8;
+        }
+        Ok(self.sm.read_write_reg(reg, tdi, last_bits, true)?)
+    }
+
+    fn shift_ir(&mut self, tdi: &[u8], bits: usize) -> Result<(), XsvfError> {
+        if bits > 0 {
+            self.shift(Register::Instruction, tdi, bits)?;
+        }
+        self.sm.change_mode(self.endir)?;
+        if self.run_test > 0 {
+            self.sm.change_mode(JtagState::Idle)?;
+            self.wait(self.run_test)?;
+        }
+        Ok(())
+    }
+
+    /// Shift a complete data register, checking the result against `expected` under XTDOMASK.
+    /// On a mismatch the shift is repeated up to XREPEAT times, each time spending a quarter
+    /// longer in Idle first, as XAPP503 describes.
+    fn shift_dr(&mut self, tdi: &[u8], expected: &[u8]) -> Result<(), XsvfError> {
+        let bits = self.sdr_size;
+        let mut run_test = self.run_test;
+        let mut attempts = 0;
+
+        loop {
+            let mismatch = if bits > 0 {
+                let got = self.shift(Register::Data, tdi, bits)?;
+                if first_mismatch(&got, expected, &self.tdo_mask, bits).is_none() {
+                    None
+                } else {
+                    Some(got)
+                }
+            } else {
+                None
+            };
+
+            let retry = mismatch.is_some() && attempts < self.repeat;
+            if retry {
+                // Go back through ShiftDR to UpdateDR, so we don't capture again before retrying
+                for state in [JtagState::Exit2DR, JtagState::ShiftDR, JtagState::Exit1DR, JtagState::UpdateDR] {
+                    self.sm.change_mode(state)?;
+                }
+                attempts += 1;
+                run_test += run_test / 4;
+            } else {
+                self.sm.change_mode(self.enddr)?;
+            }
+
+            if run_test > 0 {
+                self.sm.change_mode(JtagState::Idle)?;
+                self.wait(run_test)?;
+            }
+
+            match mismatch {
+                None => return Ok(()),
+                Some(got) if !retry => {
+                    return Err(XsvfError::TdoMismatch {
+                        command: self.command,
+                        got,
+                        expected: expected.to_vec(),
+                        mask: self.tdo_mask.clone(),
+                    });
+                }
+                Some(_) => (),
+            }
+        }
+    }
+
+    /// Shift one piece of a data register that is split across several commands.  Only the last
+    /// piece leaves ShiftDR, for ENDDR.  These are never retried.
+    fn shift_dr_part(&mut self, tdi: &[u8], expected: Option<&[u8]>, last: bool) -> Result<(), XsvfError> {
+        let bits = self.sdr_size;
+        if bits > 0 {
+            let mut last_bits = (bits % 8) as u8;
+            if last_bits == 0 {
+                last_bits = 8;
+            }
+
+            let got = self.sm.read_write_reg(Register::Data, tdi, last_bits, last)?;
+            if let Some(expected) = expected {
+                if first_mismatch(&got, expected, &self.tdo_mask, bits).is_some() {
+                    return Err(XsvfError::TdoMismatch {
+                        command: self.command,
+                        got,
+                        expected: expected.to_vec(),
+                        mask: self.tdo_mask.clone(),
+                    });
+                }
+            }
+        }
+
+        if last {
+            self.sm.change_mode(self.enddr)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8, std::io::Error> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16, std::io::Error> {
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, std::io::Error> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Read a vector of `bits` bits, stored most significant byte first, and return it packed LSB
+/// first
+fn read_vector(reader: &mut impl Read, bits: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mut buf = vec![0; bits.div_ceil(8)];
+    reader.read_exact(&mut buf)?;
+    buf.reverse();
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cable::mock::{MockCable, Operation};
+
+    #[test]
+    fn retry_on_mismatch() {
+        // XRUNTEST 20ms, XSDRSIZE 8, XTDOMASK ff, XSDRTDO 5a expecting a5, XCOMPLETE
+        let xsvf = include_bytes!("../testdata/retry.xsvf");
+        let mut cable = MockCable::with_tdo(vec![vec![0x00], vec![0xa5]]);
+        let start = std::time::Instant::now();
+        XsvfPlayer::new(JtagSM::new(&mut cable).unwrap()).play(&xsvf[..]).unwrap();
+        // The retry waits 25ms, and the successful shift waits the same again
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let shift = Operation::ReadWrite { data: vec![0x5a], bits: 8, pause_after: true };
+        let tms = |tms: &[usize]| Operation::ChangeMode { tms: tms.to_vec(), tdi: true };
+        assert_eq!(cable.recorded()[1..], [
+            tms(&[1, 0, 0]),
+            shift.clone(),
+            // PauseDR to Exit2DR, ShiftDR, Exit1DR, UpdateDR, and then Idle for the wait
+            tms(&[1]),
+            tms(&[0]),
+            tms(&[1]),
+            tms(&[1]),
+            tms(&[0]),
+            Operation::Flush,
+            tms(&[1, 0, 0]),
+            shift,
+            tms(&[1, 1, 0]),
+            Operation::Flush,
+            Operation::Flush,
+        ]);
+    }
+
+    #[test]
+    fn mismatch_after_retries() {
+        let xsvf = [XREPEAT, 1, XSDRSIZE, 0, 0, 0, 8, XTDOMASK, 0x0f, XSDRTDO, 0x5a, 0xa5, XCOMPLETE];
+        let mut cable = MockCable::with_tdo(vec![vec![0x00], vec![0xf0]]);
+        let err = XsvfPlayer::new(JtagSM::new(&mut cable).unwrap()).play(&xsvf[..]).unwrap_err();
+        assert!(matches!(err, XsvfError::TdoMismatch { command: 4, .. }));
+    }
+}